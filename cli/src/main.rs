@@ -0,0 +1,851 @@
+mod json;
+mod output;
+mod tui;
+
+use clap::{Parser, Subcommand};
+use driver::{
+    DriverError, bench,
+    calibration::Calibration,
+    enroll::{EnrollEvent, RejectReason, TemplateId},
+    firmware::{DeviceState, Firmware},
+    fprint::export,
+    iso_image::IsoImageExport,
+    metadata::{self, MetadataStore},
+    normalize::Normalize,
+    pairing::load_pairing,
+    prelude::*,
+    quality::quality,
+    setup::{self, Permission, SetupEvent, SetupOptions},
+    storage::TemplateOrigin,
+    usb::{OpenOptions, OpenedUsbDevice, UsbDevice},
+};
+use json::Json;
+use output::{NoMatch, Output};
+use std::{
+    error::Error, fs::File, io::BufWriter, path::PathBuf, process::ExitCode, time::Duration,
+};
+
+#[derive(Parser)]
+#[command(name = "validity", about = "Talk to validity fingerprint sensors")]
+struct Cli {
+    /// Use the device at BUS:ADDR instead of the first supported one
+    #[arg(short, long, global = true, value_parser = parse_busaddr)]
+    device: Option<(u8, u8)>,
+
+    /// Use the device with this serial number (see `devices`), stable across reboots
+    #[arg(short, long, global = true, conflicts_with = "device")]
+    serial: Option<String>,
+
+    /// Print a JSON object per line instead of text, the errors with a code
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the supported devices
+    Devices,
+
+    /// Enroll a new finger
+    Enroll {
+        /// Finger id stored with the template (1-10)
+        #[arg(short, long, default_value_t = 1)]
+        finger: u8,
+
+        /// Show a live progress display with hints on where to touch
+        #[arg(long)]
+        tui: bool,
+    },
+
+    /// Scan a finger and check it was enrolled
+    Verify {
+        /// Only accept this template, instead of any
+        #[arg(short, long)]
+        template: Option<u16>,
+    },
+
+    /// Capture a raw image
+    Capture {
+        /// Where to save the image (PGM, or PNG if it ends with `.png`)
+        #[arg(short, long)]
+        out: PathBuf,
+
+        /// Save an 8 bits PGM as libfprint reads it, to compare with its tools
+        #[arg(long)]
+        fprint: bool,
+
+        /// Save an ISO/IEC 19794-4 finger image record
+        #[arg(long, conflicts_with = "fprint")]
+        iso: bool,
+
+        /// Stretch the raw image into a clean 8 bits one
+        #[arg(short, long)]
+        normalize: bool,
+
+        /// Swap the dark and light pixels (with `--normalize`)
+        #[arg(long, requires = "normalize")]
+        invert: bool,
+
+        /// Subtract the average of this many frames read before the touch (with `--normalize`)
+        #[arg(long, value_name = "FRAMES", requires = "normalize")]
+        background: Option<u32>,
+
+        /// Fill the dead pixels of the calibration from their neighbours
+        #[arg(long)]
+        repair: bool,
+
+        /// Analog gain of the pixels (0-7), raise it for faint fingers
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=7))]
+        gain: Option<u8>,
+
+        /// Integration time in microseconds, raise it for dry fingers
+        #[arg(long, value_name = "US", value_parser = clap::value_parser!(u16).range(1..))]
+        integration: Option<u16>,
+    },
+
+    /// List the templates stored on the device
+    List,
+
+    /// Erase every template stored on the device
+    Erase,
+
+    /// Save the pairing and which user owns each template, to use them again after a reinstall
+    Backup {
+        /// Where to save the backup
+        #[arg(short, long)]
+        out: PathBuf,
+
+        /// Pairing data to include (see `setup`)
+        #[arg(short, long)]
+        pairing: Option<PathBuf>,
+
+        /// Directory with a file per user listing their templates
+        #[arg(long, default_value = metadata::DEFAULT_DIR)]
+        templates: PathBuf,
+    },
+
+    /// Restore a backup made with `backup`, for the templates still on the device
+    Restore {
+        /// The backup to restore
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Where to save the pairing data of the backup
+        #[arg(short, long)]
+        pairing: Option<PathBuf>,
+
+        /// Directory with a file per user listing their templates
+        #[arg(long, default_value = metadata::DEFAULT_DIR)]
+        templates: PathBuf,
+    },
+
+    /// Set up a new (or factory reset) sensor: upload the firmware, check the calibration and
+    /// pair with it
+    Setup {
+        /// Firmware (`.xpfwext`) to upload if the sensor has none
+        #[arg(short, long)]
+        firmware: Option<PathBuf>,
+
+        /// Calibration data to write if the one of the sensor is broken
+        #[arg(short, long)]
+        calibration: Option<PathBuf>,
+
+        /// Where to save the pairing data
+        #[arg(short, long)]
+        pairing: Option<PathBuf>,
+    },
+
+    /// Bring back a sensor stuck in its bootloader (after a failed firmware upload)
+    Recover {
+        /// Flash this firmware (`.xpfwext`) again, otherwise the sensor is just rebooted
+        #[arg(short, long)]
+        firmware: Option<PathBuf>,
+    },
+
+    /// Print udev rules giving access to the supported sensors, to save in
+    /// `/etc/udev/rules.d/60-validity.rules`
+    UdevRules,
+
+    /// Look for common problems
+    Doctor {
+        /// Check the current user can open the devices (always done)
+        #[arg(long)]
+        permissions: bool,
+
+        /// Also run the diagnostics of the firmware on the devices that can be opened
+        #[arg(long)]
+        self_test: bool,
+
+        /// Also print the internal status of the devices that can be opened (uptime, last
+        /// error, ...), for the ones failing now and then
+        #[arg(long)]
+        diagnostics: bool,
+    },
+
+    /// Measure the command latency and the bulk read throughput
+    Bench {
+        /// Times each measurement is repeated
+        #[arg(short = 'n', long, default_value_t = 100)]
+        iterations: u32,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let out = Output::new(cli.json);
+
+    match run(cli, out) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            out.error(e.as_ref());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli, out: Output) -> Result<(), Box<dyn Error>> {
+    let device = || open(cli.device, cli.serial.as_deref());
+
+    match cli.command {
+        Command::Devices => devices(out),
+        Command::Enroll { finger, tui: true } => {
+            // Checked here, clap ignores the conflicts with global arguments
+            if cli.json {
+                return Err("--tui can't be used with --json".into());
+            }
+            enroll_tui(tui::Wizard::new(finger)?, &device()?, finger)
+        }
+        Command::Enroll { finger, tui: false } => enroll(out, &device()?, finger),
+        Command::Verify { template } => verify(out, &device()?, template),
+        Command::Capture {
+            out: path,
+            fprint,
+            iso,
+            normalize,
+            invert,
+            background,
+            repair,
+            gain,
+            integration,
+        } => {
+            let normalize = normalize.then(|| Normalize::new().invert(invert));
+            let mut dev = device()?;
+            if repair {
+                let map = dev.read_calibration()?.dead_pixel_map();
+                dev.set_dead_pixels(Some(map));
+            }
+            if gain.is_some() || integration.is_some() {
+                let mut settings = dev.capture_settings()?;
+                settings.gain = gain.unwrap_or(settings.gain);
+                if let Some(us) = integration {
+                    settings.integration_time = Duration::from_micros(us as u64);
+                }
+                dev.set_capture_settings(&settings)?;
+            }
+            capture(out, &dev, path, fprint, iso, normalize, background)
+        }
+        Command::List => list(out, &device()?),
+        Command::Erase => erase(out, &device()?),
+        Command::Backup {
+            out: path,
+            pairing,
+            templates,
+        } => backup(out, &device()?, path, pairing, templates),
+        Command::Restore {
+            input,
+            pairing,
+            templates,
+        } => restore(out, &device()?, input, pairing, templates),
+        Command::Setup {
+            firmware,
+            calibration,
+            pairing,
+        } => setup(
+            out,
+            cli.device,
+            cli.serial.as_deref(),
+            firmware,
+            calibration,
+            pairing,
+        ),
+        Command::Recover { firmware } => recover(out, cli.device, cli.serial.as_deref(), firmware),
+        Command::UdevRules => {
+            let rules = setup::generate_udev_rules();
+            out.emit(
+                rules.trim_end(),
+                Json::object([("rules", rules.as_str().into())]),
+            );
+            Ok(())
+        }
+        // The permissions are checked with or without the flag
+        Command::Doctor {
+            permissions: _,
+            self_test,
+            diagnostics,
+        } => doctor(
+            out,
+            cli.device,
+            cli.serial.as_deref(),
+            self_test,
+            diagnostics,
+        ),
+        Command::Bench { iterations } => bench(out, &device()?, iterations),
+    }
+}
+
+fn parse_busaddr(s: &str) -> Result<(u8, u8), String> {
+    let (bus, addr) = s.split_once(':').ok_or("expected BUS:ADDR")?;
+    let bus = bus.parse().map_err(|_| "invalid bus number")?;
+    let addr = addr.parse().map_err(|_| "invalid address")?;
+    Ok((bus, addr))
+}
+
+/// Open and initialize the selected device
+fn open(device: Option<(u8, u8)>, serial: Option<&str>) -> Result<OpenedUsbDevice, DriverError> {
+    find(device, serial)?.open_with(OpenOptions::new())
+}
+
+/// Find the selected device, the first supported one if none was
+fn find(device: Option<(u8, u8)>, serial: Option<&str>) -> Result<UsbDevice, DriverError> {
+    Ok(match (device, serial) {
+        (Some((bus, addr)), _) => driver::get_device(bus, addr)?,
+        (None, Some(serial)) => driver::get_device_by_serial(serial)?,
+        (None, None) => driver::list_supported_devices()?
+            .into_iter()
+            .next()
+            .ok_or(DriverError::GetDeviceNotFound)?,
+    })
+}
+
+fn devices(out: Output) -> Result<(), Box<dyn Error>> {
+    let (mut text, mut json) = (Vec::new(), Vec::new());
+    for usb in driver::list_supported_devices()? {
+        let UsbDevice(dev, quirks) = &usb;
+        let serial = usb.serial_number();
+        text.push(format!(
+            "{:03}:{:03} {:04x}:{:04x} ({:?} sensor), serial: {}",
+            dev.bus_number(),
+            dev.address(),
+            quirks.vid,
+            quirks.pid,
+            quirks.sensor_type,
+            serial.as_deref().unwrap_or("unknown"),
+        ));
+        json.push(Json::object([
+            ("bus", dev.bus_number().into()),
+            ("address", dev.address().into()),
+            ("vid", quirks.vid.into()),
+            ("pid", quirks.pid.into()),
+            (
+                "sensor",
+                format!("{:?}", quirks.sensor_type).to_lowercase().into(),
+            ),
+            ("serial", serial.into()),
+        ]));
+    }
+
+    if !text.is_empty() || out.is_json() {
+        out.emit(text.join("\n"), Json::object([("devices", json.into())]));
+    }
+    Ok(())
+}
+
+fn enroll(out: Output, dev: &OpenedUsbDevice, finger: u8) -> Result<(), Box<dyn Error>> {
+    out.prompt("Touch the sensor several times");
+
+    dev.enroll_events(finger, |ev| match ev {
+        EnrollEvent::SampleAccepted { remaining } => out.emit(
+            format!("Sample accepted, {remaining} remaining"),
+            Json::object([
+                ("event", "sample_accepted".into()),
+                ("remaining", remaining.into()),
+            ]),
+        ),
+        EnrollEvent::SampleRejected { reason } => {
+            let (text, reason) = match reason {
+                RejectReason::LowQuality => ("Bad sample, touch the sensor again", "low_quality"),
+                RejectReason::NoNewArea => (
+                    "Same area scanned, move your finger slightly",
+                    "no_new_area",
+                ),
+            };
+            out.emit(
+                text,
+                Json::object([
+                    ("event", "sample_rejected".into()),
+                    ("reason", reason.into()),
+                ]),
+            )
+        }
+        EnrollEvent::Completed {
+            template_id: TemplateId(id),
+        } => out.emit(
+            format!("Enrolled, template {id}"),
+            Json::object([("event", "completed".into()), ("template", id.into())]),
+        ),
+    })?;
+
+    Ok(())
+}
+
+fn enroll_tui(
+    mut wizard: tui::Wizard,
+    dev: &OpenedUsbDevice,
+    finger: u8,
+) -> Result<(), Box<dyn Error>> {
+    wizard.draw();
+
+    let id = dev.enroll(finger, |p| wizard.update(p))?;
+    wizard.finish(id);
+    Ok(())
+}
+
+fn verify(out: Output, dev: &OpenedUsbDevice, template: Option<u16>) -> Result<(), Box<dyn Error>> {
+    out.prompt("Touch the sensor");
+
+    let res = match template {
+        Some(id) => dev.verify(TemplateId(id))?,
+        None => dev.identify()?,
+    };
+
+    let m = res.ok_or(NoMatch)?;
+    out.emit(
+        format!(
+            "Match: template {}, finger {}, score {}",
+            m.template.0, m.finger_id, m.score
+        ),
+        Json::object([
+            ("match", true.into()),
+            ("template", m.template.0.into()),
+            ("finger", m.finger_id.into()),
+            ("score", m.score.into()),
+        ]),
+    );
+    Ok(())
+}
+
+fn capture(
+    out: Output,
+    dev: &OpenedUsbDevice,
+    path: PathBuf,
+    fprint: bool,
+    iso: bool,
+    normalize: Option<Normalize>,
+    background: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let normalize = match (normalize, background) {
+        (Some(opts), Some(frames)) => Some(opts.background(dev.capture_background(frames)?)),
+        (opts, _) => opts,
+    };
+    out.prompt("Touch the sensor");
+
+    let frame = match &normalize {
+        Some(opts) => dev.capture_normalized(opts)?,
+        None => dev.capture()?,
+    };
+    let file = BufWriter::new(File::create(&path)?);
+    if fprint {
+        export::write_image(&frame, file)?;
+    } else if iso {
+        IsoImageExport::new()
+            .device(dev.quirks())
+            .write(std::slice::from_ref(&frame), file)?;
+    } else if path.extension().is_some_and(|ext| ext == "png") {
+        frame.write_png(file)?;
+    } else {
+        frame.write_pgm(file)?;
+    }
+
+    let q = quality(&frame);
+    let text = format!(
+        "Saved {}x{} image to {}\nQuality: coverage {}%, contrast {}%, smudge {}%{}",
+        frame.width,
+        frame.height,
+        path.display(),
+        q.coverage,
+        q.contrast,
+        q.smudge,
+        if q.is_acceptable() {
+            ""
+        } else {
+            " (bad, try again)"
+        }
+    );
+    let quality = Json::object([
+        ("coverage", q.coverage.into()),
+        ("contrast", q.contrast.into()),
+        ("smudge", q.smudge.into()),
+        ("acceptable", q.is_acceptable().into()),
+    ]);
+    out.emit(
+        text,
+        Json::object([
+            ("path", path.display().to_string().into()),
+            ("width", frame.width.into()),
+            ("height", frame.height.into()),
+            ("quality", quality),
+        ]),
+    );
+    Ok(())
+}
+
+fn list(out: Output, dev: &OpenedUsbDevice) -> Result<(), Box<dyn Error>> {
+    let templates = dev.list_templates()?;
+
+    let text = templates
+        .iter()
+        .map(|t| {
+            let origin = match t.origin {
+                TemplateOrigin::Host => "",
+                TemplateOrigin::Windows => ", enrolled from Windows",
+            };
+            format!(
+                "template {}: finger {}, user {}{origin}",
+                t.id.0, t.finger_id, t.user
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let json = templates
+        .iter()
+        .map(|t| {
+            Json::object([
+                ("id", t.id.0.into()),
+                ("finger", t.finger_id.into()),
+                ("user", t.user.to_string().into()),
+                ("origin", format!("{:?}", t.origin).to_lowercase().into()),
+            ])
+        })
+        .collect::<Vec<_>>();
+
+    if !templates.is_empty() || out.is_json() {
+        out.emit(text, Json::object([("templates", json.into())]));
+    }
+    Ok(())
+}
+
+fn erase(out: Output, dev: &OpenedUsbDevice) -> Result<(), Box<dyn Error>> {
+    dev.erase_all_templates()?;
+    out.emit(
+        "Every template was erased",
+        Json::object([("erased", true.into())]),
+    );
+    Ok(())
+}
+
+fn backup(
+    out: Output,
+    dev: &OpenedUsbDevice,
+    path: PathBuf,
+    pairing: Option<PathBuf>,
+    templates: PathBuf,
+) -> Result<(), Box<dyn Error>> {
+    let pairing = pairing.map(load_pairing).transpose()?;
+    let count = dev.export_templates(&path, &MetadataStore::new(templates), pairing.as_ref())?;
+    out.emit(
+        format!("{count} templates saved to {}", path.display()),
+        Json::object([
+            ("path", path.display().to_string().into()),
+            ("templates", count.into()),
+            ("pairing", pairing.is_some().into()),
+        ]),
+    );
+    Ok(())
+}
+
+fn restore(
+    out: Output,
+    dev: &OpenedUsbDevice,
+    path: PathBuf,
+    pairing: Option<PathBuf>,
+    templates: PathBuf,
+) -> Result<(), Box<dyn Error>> {
+    let report = dev.import_templates(&path, &MetadataStore::new(templates), pairing.as_deref())?;
+
+    let mut text = format!("{} templates restored", report.restored.len());
+    if !report.missing.is_empty() {
+        let ids = report.missing.iter().map(|id| id.0.to_string());
+        text += &format!(
+            ", not on the device anymore: {}",
+            ids.collect::<Vec<_>>().join(", ")
+        );
+    }
+    if report.pairing {
+        text += "\nPairing data saved";
+    }
+
+    let ids = |ids: &[TemplateId]| ids.iter().map(|id| id.0).collect::<Vec<_>>();
+    out.emit(
+        text,
+        Json::object([
+            ("restored", ids(&report.restored).into()),
+            ("missing", ids(&report.missing).into()),
+            ("pairing", report.pairing.into()),
+        ]),
+    );
+    Ok(())
+}
+
+fn setup(
+    out: Output,
+    device: Option<(u8, u8)>,
+    serial: Option<&str>,
+    firmware: Option<PathBuf>,
+    calibration: Option<PathBuf>,
+    pairing: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let mut opts = SetupOptions::new();
+    if let Some(path) = firmware {
+        opts = opts.firmware(Firmware::parse(&std::fs::read(path)?)?);
+    }
+    if let Some(path) = calibration {
+        opts = opts.calibration(Calibration::parse(&std::fs::read(path)?)?);
+    }
+    if let Some(path) = pairing {
+        opts = opts.pairing(path);
+    }
+
+    // A new sensor has no firmware, the init sequence would fail
+    let mut dev = find(device, serial)?.open()?;
+    dev.initialize_device(&opts, |ev| {
+        let (step, text, status) = match ev {
+            SetupEvent::Started(step) => (step, "started", "started"),
+            SetupEvent::Finished(step) => (step, "done", "finished"),
+            SetupEvent::Skipped(step) => (step, "nothing to do", "skipped"),
+        };
+        out.emit(
+            format!("{step:?}: {text}"),
+            Json::object([
+                ("step", format!("{step:?}").to_lowercase().into()),
+                ("status", status.into()),
+            ]),
+        )
+    })?;
+    Ok(())
+}
+
+fn recover(
+    out: Output,
+    device: Option<(u8, u8)>,
+    serial: Option<&str>,
+    firmware: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let fw = match firmware {
+        Some(path) => Some(Firmware::parse(&std::fs::read(path)?)?),
+        None => None,
+    };
+
+    // The init sequence fails in the bootloader
+    let dev = find(device, serial)?.open()?;
+    let (text, state, action) = match dev.recover(fw.as_ref())? {
+        DeviceState::Firmware => ("The firmware is running, nothing to do", "firmware", "none"),
+        DeviceState::Bootloader if fw.is_some() => {
+            ("Firmware flashed, rebooting", "bootloader", "flashed")
+        }
+        DeviceState::Bootloader => ("Rebooting into the firmware", "bootloader", "rebooted"),
+    };
+    out.emit(
+        text,
+        Json::object([("state", state.into()), ("action", action.into())]),
+    );
+    Ok(())
+}
+
+fn doctor(
+    out: Output,
+    device: Option<(u8, u8)>,
+    serial: Option<&str>,
+    self_test: bool,
+    diagnostics: bool,
+) -> Result<(), Box<dyn Error>> {
+    let devices = match (device, serial) {
+        (None, None) => driver::list_supported_devices()?,
+        _ => vec![find(device, serial)?],
+    };
+    if devices.is_empty() {
+        return Err(DriverError::GetDeviceNotFound.into());
+    }
+
+    let (mut denied, mut failed) = (0, 0);
+    for usb in devices {
+        let path = setup::device_node(&usb);
+        let permission = setup::check_permissions(&usb);
+        let access = match permission {
+            Permission::Granted => "granted",
+            Permission::NoNode => "no_node",
+            Permission::NoRule { .. } => "no_rule",
+            Permission::NotInGroup { .. } => "not_in_group",
+            Permission::Denied(_) => "denied",
+        };
+        if permission != Permission::Granted {
+            denied += 1;
+        }
+
+        let mut text = format!("{}: {permission}", path.display());
+        if let Permission::NoRule { .. } = permission {
+            text += "\n  Install them with: validity udev-rules | sudo tee \
+                     /etc/udev/rules.d/60-validity.rules && sudo udevadm trigger";
+        }
+        out.emit(
+            text,
+            Json::object([
+                ("path", path.display().to_string().into()),
+                ("access", access.into()),
+                ("reason", permission.to_string().into()),
+            ]),
+        );
+
+        if !(self_test || diagnostics) || permission != Permission::Granted {
+            continue;
+        }
+        // Not initialized, the diagnostics are most useful when the init fails
+        let dev = usb.open_with(OpenOptions::new().init(false))?;
+
+        if diagnostics {
+            let diag = dev.diagnostics()?;
+            let temperature = diag
+                .temperature
+                .map_or("unknown".into(), |t| format!("{t:.1} °C"));
+            let last_error = diag
+                .last_status()
+                .map_or("none".into(), |status| status.to_string());
+            out.emit(
+                format!(
+                    "  Uptime: {:.0?}, reset reason: {:?}, last error: {last_error}, temperature: \
+                     {temperature}",
+                    diag.uptime, diag.reset_reason
+                ),
+                Json::object([
+                    ("path", path.display().to_string().into()),
+                    ("uptime_ms", (diag.uptime.as_millis() as u64).into()),
+                    ("reset_reason", format!("{:?}", diag.reset_reason).into()),
+                    ("last_error", diag.last_error.into()),
+                    ("temperature", diag.temperature.into()),
+                ]),
+            );
+        }
+
+        if self_test {
+            let report = dev.self_test()?;
+            if !report.passed() {
+                failed += 1;
+            }
+
+            let status = |ok| if ok { "ok" } else { "failed" };
+            out.emit(
+                format!(
+                    "  ADC: {}, flash: {}, sensor array: {} ({} dead pixels)",
+                    status(report.adc),
+                    status(report.flash),
+                    status(report.sensor_array),
+                    report.dead_pixels
+                ),
+                Json::object([
+                    ("path", path.display().to_string().into()),
+                    ("adc", report.adc.into()),
+                    ("flash", report.flash.into()),
+                    ("sensor_array", report.sensor_array.into()),
+                    ("dead_pixels", report.dead_pixels.into()),
+                ]),
+            );
+        }
+    }
+
+    match (denied, failed) {
+        (0, 0) => Ok(()),
+        (0, n) => Err(format!("{n} device(s) failed the self-test").into()),
+        (n, _) => Err(format!("{n} device(s) can't be opened").into()),
+    }
+}
+
+fn bench(out: Output, dev: &OpenedUsbDevice, iterations: u32) -> Result<(), Box<dyn Error>> {
+    let round_trip = bench::round_trip(dev, iterations)?.latency();
+    let mut text = format!("Round trip: {round_trip:.2?}");
+
+    let partition = dev
+        .partition_table()?
+        .partitions
+        .first()
+        .ok_or("the flash has no partitions")?
+        .id;
+
+    let mut bulk = Vec::new();
+    for size in bench::BULK_SIZES {
+        let res = bench::bulk_read(dev, partition, size, iterations)?;
+        text += &format!(
+            "\nBulk read of {size} bytes: {:.2?}, {:.1} KiB/s",
+            res.latency(),
+            res.throughput() / 1024.0
+        );
+        bulk.push(Json::object([
+            ("size", size.into()),
+            ("latency_us", res.latency().as_micros().into()),
+            ("throughput", res.throughput().into()),
+        ]));
+    }
+
+    out.emit(
+        text,
+        Json::object([
+            ("round_trip_us", round_trip.as_micros().into()),
+            ("bulk", bulk.into()),
+        ]),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn arguments() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn busaddr() {
+        assert_eq!(parse_busaddr("1:23"), Ok((1, 23)));
+        assert!(parse_busaddr("123").is_err());
+        assert!(parse_busaddr("x:1").is_err());
+        assert!(parse_busaddr("1:256").is_err());
+    }
+
+    #[test]
+    fn global_options() {
+        let cli =
+            Cli::try_parse_from(["validity", "enroll", "-f", "3", "--device", "2:5", "--json"])
+                .unwrap();
+        assert_eq!(cli.device, Some((2, 5)));
+        assert!(cli.json);
+        assert!(matches!(
+            cli.command,
+            Command::Enroll {
+                finger: 3,
+                tui: false
+            }
+        ));
+
+        // One way to pick the device
+        assert!(Cli::try_parse_from(["validity", "-d", "1:2", "-s", "ABC", "list"]).is_err());
+    }
+
+    #[test]
+    fn capture_options() {
+        assert!(
+            Cli::try_parse_from(["validity", "capture", "-o", "x.pgm", "--gain", "8"]).is_err()
+        );
+        assert!(Cli::try_parse_from(["validity", "capture", "-o", "x.pgm", "--invert"]).is_err());
+        assert!(
+            Cli::try_parse_from(["validity", "capture", "-o", "x.pgm", "--fprint", "--iso"])
+                .is_err()
+        );
+        assert!(
+            Cli::try_parse_from(["validity", "capture", "-o", "x.pgm", "-n", "--invert"]).is_ok()
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! What the commands print: text for people, or with `--json` a JSON object per line for
+//! scripts (the events while the command runs, then its result or the error)
+
+use crate::json::Json;
+use driver::DriverError;
+use std::{error::Error, fmt};
+
+/// The verification found no match, a failure with its own code
+#[derive(Debug)]
+pub struct NoMatch;
+
+impl fmt::Display for NoMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("No match")
+    }
+}
+
+impl Error for NoMatch {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    json: bool,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        Self { json }
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    /// Print the text, or the JSON object
+    pub fn emit(&self, text: impl fmt::Display, json: Json) {
+        if self.json {
+            println!("{json}");
+        } else {
+            println!("{text}");
+        }
+    }
+
+    /// Ask the user to do something, `{"prompt": ...}` in JSON
+    pub fn prompt(&self, text: &str) {
+        self.emit(text, Json::object([("prompt", text.into())]))
+    }
+
+    /// Print the error with its sources, in JSON with a code scripts can check (see [`code`])
+    pub fn error(&self, e: &(dyn Error + 'static)) {
+        let mut message = e.to_string();
+        let mut source = e.source();
+        while let Some(e) = source {
+            message += &format!(": {e}");
+            source = e.source();
+        }
+
+        if !self.json {
+            eprintln!("Error: {message}");
+            return;
+        }
+
+        let status = e
+            .downcast_ref::<DriverError>()
+            .and_then(|e| match e.root() {
+                DriverError::UsbInitFailed(status) => Some(status.code()),
+                _ => None,
+            });
+        let error = Json::object([
+            ("message", message.into()),
+            ("code", code(e).into()),
+            ("status", status.into()),
+        ]);
+        println!("{}", Json::object([("error", error)]));
+    }
+}
+
+/// What went wrong, in a word: `not_found`, `no_match`, `bootloader`, `storage_full`,
+/// `disconnected`, `transient` or `failed`
+fn code(e: &(dyn Error + 'static)) -> &'static str {
+    if e.is::<NoMatch>() {
+        return "no_match";
+    }
+    let Some(e) = e.downcast_ref::<DriverError>() else {
+        return "failed";
+    };
+
+    match e.root() {
+        DriverError::GetDeviceNotFound | DriverError::GetDeviceFoundUnsupported => "not_found",
+        DriverError::Bootloader => "bootloader",
+        DriverError::StorageFull { .. } => "storage_full",
+        _ if e.is_fatal() => "disconnected",
+        _ if e.is_transient() => "transient",
+        _ => "failed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use driver::proto::StatusCode;
+
+    #[test]
+    fn codes() {
+        assert_eq!(code(&NoMatch), "no_match");
+        assert_eq!(code(&fmt::Error), "failed");
+        assert_eq!(code(&DriverError::GetDeviceNotFound), "not_found");
+        assert_eq!(code(&DriverError::Bootloader), "bootloader");
+        assert_eq!(
+            code(&DriverError::UsbInitFailed(StatusCode::SignatureFailed)),
+            "disconnected"
+        );
+        assert_eq!(code(&DriverError::CaptureIncomplete(1, 2)), "transient");
+        assert_eq!(
+            code(&DriverError::UsbInitFailed(StatusCode::NotFound)),
+            "failed"
+        );
+    }
+
+    #[test]
+    fn codes_of_commands() {
+        // The error of the command counts, not the command around it
+        let e = DriverError::CommandFailed {
+            opcode: 0x01,
+            cmd: vec![0x01],
+            resp: vec![],
+            source: Box::new(DriverError::Bootloader),
+        };
+        assert_eq!(code(&e), "bootloader");
+    }
+}
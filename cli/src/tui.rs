@@ -0,0 +1,186 @@
+//! The `enroll --tui` wizard: a few lines redrawn in place after every touch, with the progress,
+//! the quality of the last sample and where to put the finger next. Plain ANSI escapes, any
+//! terminal emulator will do.
+
+use driver::enroll::{EnrollEvent, EnrollProgress, Finger, RejectReason, TemplateId};
+use std::io::{self, IsTerminal, Write};
+
+const BAR_WIDTH: usize = 30;
+
+/// Lines drawn by [`Wizard::draw`], moved up over to redraw
+const LINES: usize = 6;
+
+/// Where to touch next, cycled through while the samples are accepted so the whole finger is
+/// covered
+const PLACEMENTS: [&str; 5] = [
+    "Place the center of your finger flat on the sensor",
+    "Now the tip of your finger",
+    "Now roll it slightly to the left edge",
+    "Now roll it slightly to the right edge",
+    "Now the lower part, closer to the first joint",
+];
+
+pub struct Wizard {
+    finger: u8,
+    prev: Option<EnrollProgress>,
+    last: Option<(EnrollProgress, EnrollEvent)>,
+    accepted: usize,
+    drawn: bool,
+}
+
+impl Wizard {
+    /// Fails if the output is not a terminal, the escapes would end up in a file
+    pub fn new(finger: u8) -> io::Result<Self> {
+        if !io::stdout().is_terminal() {
+            return Err(io::Error::other("--tui needs a terminal"));
+        }
+        Ok(Self {
+            finger,
+            prev: None,
+            last: None,
+            accepted: 0,
+            drawn: false,
+        })
+    }
+
+    /// Record the sample and redraw
+    pub fn update(&mut self, progress: EnrollProgress) {
+        let event = EnrollEvent::from_progress(self.prev.as_ref(), &progress);
+        if let EnrollEvent::SampleAccepted { .. } = event {
+            self.accepted += 1;
+        }
+        self.last = Some((progress, event));
+        self.prev = Some(progress);
+        self.draw();
+    }
+
+    /// Redraw a last time with the template
+    pub fn finish(&mut self, TemplateId(id): TemplateId) {
+        self.draw_lines([
+            self.title(),
+            String::new(),
+            format!("  Coverage  {}", bar(100)),
+            format!("  Samples   {} taken", self.samples()),
+            String::new(),
+            format!("  \x1b[1;32mDone\x1b[0m, stored as template {id}"),
+        ]);
+    }
+
+    /// Draw the progress up to now, call it once before the first touch
+    pub fn draw(&mut self) {
+        let (last, hint) = match self.last {
+            None => ("waiting for the first touch".to_string(), PLACEMENTS[0]),
+            Some((p, EnrollEvent::SampleAccepted { .. })) => (
+                format!("quality {}%  \x1b[32maccepted\x1b[0m", p.quality),
+                PLACEMENTS[self.accepted % PLACEMENTS.len()],
+            ),
+            Some((p, EnrollEvent::SampleRejected { reason })) => (
+                format!("quality {}%  \x1b[33mrejected\x1b[0m", p.quality),
+                match reason {
+                    RejectReason::LowQuality => {
+                        "Press flatter and hold still, wipe the finger if too dry or wet"
+                    }
+                    RejectReason::NoNewArea => "Same area as before, shift your finger a bit",
+                },
+            ),
+            Some((_, EnrollEvent::Completed { .. })) => (String::new(), ""),
+        };
+
+        let coverage = self.prev.map_or(0, |p| p.coverage);
+        let remaining = self
+            .prev
+            .map_or(String::new(), |p| format!(", ~{} remaining", p.remaining));
+
+        self.draw_lines([
+            self.title(),
+            String::new(),
+            format!("  Coverage  {}", bar(coverage)),
+            format!("  Samples   {} taken{remaining}", self.samples()),
+            format!("  Last      {last}"),
+            format!("  Next      \x1b[1m{hint}\x1b[0m"),
+        ]);
+    }
+
+    fn title(&self) -> String {
+        match Finger::from_id(self.finger) {
+            Some(finger) => format!("Enrolling finger {} ({})", self.finger, name(finger)),
+            None => format!("Enrolling finger {}", self.finger),
+        }
+    }
+
+    fn samples(&self) -> u32 {
+        self.prev.map_or(0, |p| p.sample)
+    }
+
+    fn draw_lines(&mut self, lines: [String; LINES]) {
+        let mut out = io::stdout().lock();
+        if self.drawn {
+            let _ = write!(out, "\x1b[{LINES}A");
+        }
+        for line in lines {
+            // Clear what was left of the previous line
+            let _ = writeln!(out, "\x1b[2K{line}");
+        }
+        let _ = out.flush();
+        self.drawn = true;
+    }
+}
+
+/// `[#####-----]  50%`
+fn bar(percent: u16) -> String {
+    let percent = percent.min(100) as usize;
+    let filled = percent * BAR_WIDTH / 100;
+    format!(
+        "[{}{}] {percent:3}%",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled)
+    )
+}
+
+/// `RightIndex` as `right index`
+fn name(finger: Finger) -> String {
+    let mut res = String::new();
+    for c in format!("{finger:?}").chars() {
+        if c.is_uppercase() && !res.is_empty() {
+            res.push(' ');
+        }
+        res.push(c.to_ascii_lowercase());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bars() {
+        assert_eq!(bar(0), format!("[{}]   0%", "-".repeat(BAR_WIDTH)));
+        assert_eq!(
+            bar(50),
+            format!("[{}{}]  50%", "#".repeat(15), "-".repeat(15))
+        );
+        // Capped
+        assert_eq!(bar(150), format!("[{}] 100%", "#".repeat(BAR_WIDTH)));
+    }
+
+    #[test]
+    fn finger_names() {
+        assert_eq!(name(Finger::RightIndex), "right index");
+        assert_eq!(name(Finger::LeftThumb), "left thumb");
+    }
+
+    #[test]
+    fn titles() {
+        let wizard = |finger| Wizard {
+            finger,
+            prev: None,
+            last: None,
+            accepted: 0,
+            drawn: false,
+        };
+        assert_eq!(wizard(7).title(), "Enrolling finger 7 (right index)");
+        assert_eq!(wizard(0).title(), "Enrolling finger 0");
+        assert_eq!(wizard(0).samples(), 0);
+    }
+}
@@ -0,0 +1,303 @@
+//! The configuration file of the daemon (TOML), read at start and again on `SIGHUP`. Every key
+//! is optional, the flags given to the daemon take precedence:
+//!
+//! ```toml
+//! # The device to use (BUS:ADDR or its serial number), the first supported one otherwise
+//! device = "1:4"
+//! serial = "0123456789ab"
+//!
+//! # Directory with a file per user listing their templates
+//! templates = "/var/lib/validity/templates"
+//!
+//! # error, warn, info or debug
+//! log_level = "info"
+//!
+//! # Serve the metrics on http://127.0.0.1:9477/metrics, disabled by default
+//! metrics = "127.0.0.1:9477"
+//!
+//! # In seconds
+//! [timeouts]
+//! touch = 30
+//! capture = 5
+//! enroll = 10
+//! fast = 1
+//! flash = 15
+//!
+//! # After this many failed verifications in a row, the user can't verify for a while
+//! [lockout]
+//! max_failures = 5
+//! duration = 60
+//! ```
+
+use crate::log::Level;
+use driver::{metadata, timeouts::Timeouts};
+use std::{
+    error::Error,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use toml_edit::{Document, Item, Table, TomlError};
+
+/// Read if it exists, unless another one is given with `--config`
+pub const DEFAULT_PATH: &str = "/etc/validity/validityd.toml";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub device: Option<(u8, u8)>,
+    pub serial: Option<String>,
+    pub templates: PathBuf,
+    pub log_level: Level,
+
+    /// Where to serve the metrics, see [`crate::metrics`]
+    pub metrics: Option<SocketAddr>,
+    pub timeouts: Timeouts,
+    pub lockout: Lockout,
+}
+
+/// How failed verifications are limited, per user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lockout {
+    /// Failures in a row before the user is locked out, 0 to never lock them out
+    pub max_failures: u32,
+
+    /// How long the user is locked out
+    pub duration: Duration,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            duration: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            device: None,
+            serial: None,
+            templates: metadata::DEFAULT_DIR.into(),
+            log_level: Level::Info,
+            metrics: None,
+            timeouts: Timeouts::default(),
+            lockout: Lockout::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, TomlError),
+
+    /// Unknown key, or a value of the wrong type or out of range
+    Invalid(String, &'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, _) => write!(f, "Couldn't read {}", path.display()),
+            Self::Parse(path, _) => write!(f, "Couldn't parse {}", path.display()),
+            Self::Invalid(key, reason) => write!(f, "Invalid `{key}`: {reason}"),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, e) => Some(e),
+            Self::Parse(_, e) => Some(e),
+            Self::Invalid(..) => None,
+        }
+    }
+}
+
+impl Config {
+    /// Read the file, the defaults if it does not exist and is not `required`
+    pub fn load(path: &Path, required: bool) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(text).map_err(|e| match e {
+                ConfigError::Parse(_, e) => ConfigError::Parse(path.into(), e),
+                e => e,
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Self::default()),
+            Err(e) => Err(ConfigError::Io(path.into(), e)),
+        }
+    }
+
+    pub fn parse(text: String) -> Result<Self, ConfigError> {
+        let doc = Document::parse(text).map_err(|e| ConfigError::Parse(PathBuf::new(), e))?;
+        let mut res = Self::default();
+
+        for (key, item) in doc.iter() {
+            match key {
+                "device" => {
+                    let s = string(key, item)?;
+                    res.device = Some(
+                        parse_busaddr(s)
+                            .map_err(|_| ConfigError::Invalid(key.into(), "expected BUS:ADDR"))?,
+                    );
+                }
+                "serial" => res.serial = Some(string(key, item)?.into()),
+                "templates" => res.templates = string(key, item)?.into(),
+                "log_level" => {
+                    res.log_level = string(key, item)?.parse().map_err(|_| {
+                        ConfigError::Invalid(key.into(), "expected error, warn, info or debug")
+                    })?
+                }
+                "metrics" => {
+                    res.metrics = Some(string(key, item)?.parse().map_err(|_| {
+                        ConfigError::Invalid(key.into(), "expected an address (IP:PORT)")
+                    })?)
+                }
+                "timeouts" => {
+                    for (name, item) in table(key, item)? {
+                        let key = format!("timeouts.{name}");
+                        let timeout = match name {
+                            "touch" => &mut res.timeouts.touch,
+                            "capture" => &mut res.timeouts.capture,
+                            "enroll" => &mut res.timeouts.enroll,
+                            "fast" => &mut res.timeouts.fast,
+                            "flash" => &mut res.timeouts.flash,
+                            _ => return Err(ConfigError::Invalid(key, "unknown key")),
+                        };
+                        *timeout = seconds(&key, item)?;
+                    }
+                }
+                "lockout" => {
+                    for (name, item) in table(key, item)? {
+                        let key = format!("lockout.{name}");
+                        match name {
+                            "max_failures" => {
+                                res.lockout.max_failures = item
+                                    .as_integer()
+                                    .and_then(|n| u32::try_from(n).ok())
+                                    .ok_or(ConfigError::Invalid(
+                                        key,
+                                        "expected a positive integer",
+                                    ))?
+                            }
+                            "duration" => res.lockout.duration = seconds(&key, item)?,
+                            _ => return Err(ConfigError::Invalid(key, "unknown key")),
+                        }
+                    }
+                }
+                _ => return Err(ConfigError::Invalid(key.into(), "unknown key")),
+            }
+        }
+
+        if res.device.is_some() && res.serial.is_some() {
+            return Err(ConfigError::Invalid(
+                "serial".into(),
+                "can't be given with `device`",
+            ));
+        }
+        Ok(res)
+    }
+}
+
+fn string<'a>(key: &str, item: &'a Item) -> Result<&'a str, ConfigError> {
+    item.as_str()
+        .ok_or(ConfigError::Invalid(key.into(), "expected a string"))
+}
+
+fn table<'a>(key: &str, item: &'a Item) -> Result<&'a Table, ConfigError> {
+    item.as_table()
+        .ok_or(ConfigError::Invalid(key.into(), "expected a table"))
+}
+
+/// An integer or a float, in seconds. At least a millisecond, the USB transfers would wait
+/// forever with less.
+fn seconds(key: &str, item: &Item) -> Result<Duration, ConfigError> {
+    item.as_float()
+        .or_else(|| item.as_integer().map(|n| n as f64))
+        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+        .filter(|d| *d >= Duration::from_millis(1))
+        .ok_or(ConfigError::Invalid(
+            key.into(),
+            "expected a number of seconds, at least 0.001",
+        ))
+}
+
+pub fn parse_busaddr(s: &str) -> Result<(u8, u8), String> {
+    let (bus, addr) = s.split_once(':').ok_or("expected BUS:ADDR")?;
+    let bus = bus.parse().map_err(|_| "invalid bus number")?;
+    let addr = addr.parse().map_err(|_| "invalid address")?;
+    Ok((bus, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        Config::parse(text.into())
+    }
+
+    /// The key the configuration was rejected for
+    fn invalid(text: &str) -> String {
+        match parse(text) {
+            Err(ConfigError::Invalid(key, _)) => key,
+            res => panic!("not rejected: {res:?}"),
+        }
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn values() {
+        let config = parse(
+            r#"
+            device = "1:4"
+            log_level = "debug"
+
+            [timeouts]
+            touch = 10
+            fast = 0.5
+
+            [lockout]
+            max_failures = 3
+            duration = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.device, Some((1, 4)));
+        assert_eq!(config.timeouts.touch, Duration::from_secs(10));
+        assert_eq!(config.timeouts.fast, Duration::from_millis(500));
+        assert_eq!(config.timeouts.flash, Timeouts::default().flash);
+        assert_eq!(config.lockout.max_failures, 3);
+        assert_eq!(config.lockout.duration, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn timeouts() {
+        assert_eq!(
+            parse("timeouts.touch = 0.001").unwrap().timeouts.touch,
+            Duration::from_millis(1)
+        );
+        assert_eq!(invalid("timeouts.touch = 0"), "timeouts.touch");
+        assert_eq!(invalid("timeouts.fast = 0.0001"), "timeouts.fast");
+        assert_eq!(invalid("timeouts.enroll = -1"), "timeouts.enroll");
+        assert_eq!(invalid("timeouts.flash = \"1s\""), "timeouts.flash");
+        assert_eq!(invalid("lockout.duration = 0"), "lockout.duration");
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(parse("timeouts = ["), Err(ConfigError::Parse(..))));
+        assert_eq!(invalid("device = \"1-4\""), "device");
+        assert_eq!(invalid("lockout.max_failures = -1"), "lockout.max_failures");
+        assert_eq!(invalid("timeouts.other = 1"), "timeouts.other");
+        assert_eq!(invalid("other = 1"), "other");
+        assert_eq!(invalid("device = \"1:4\"\nserial = \"x\""), "serial");
+    }
+}
@@ -0,0 +1,82 @@
+//! Messages of the daemon, written to stderr (the journal when run by systemd) if their level is
+//! enabled. The level can be changed while running, see [`set_level`].
+
+use std::{
+    env, fmt,
+    str::FromStr,
+    sync::{
+        OnceLock,
+        atomic::{AtomicU8, Ordering},
+    },
+};
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// The prefix understood by the journal (see `sd-daemon(3)`), only written when stderr is
+    /// connected to it
+    fn prefix(self) -> &'static str {
+        static JOURNAL: OnceLock<bool> = OnceLock::new();
+        if !*JOURNAL.get_or_init(|| env::var_os("JOURNAL_STREAM").is_some()) {
+            return "";
+        }
+
+        match self {
+            Self::Error => "<3>",
+            Self::Warn => "<4>",
+            Self::Info => "<6>",
+            Self::Debug => "<7>",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Only write the messages of this level or more important
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log(level: Level, msg: fmt::Arguments<'_>) {
+    if level as u8 <= LEVEL.load(Ordering::Relaxed) {
+        eprintln!("{}{msg}", level.prefix());
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+
+pub(crate) use {debug, error, info, warning};
@@ -0,0 +1,241 @@
+//! Daemon owning the fingerprint sensor, desktop clients enroll and verify fingers over D-Bus so
+//! they don't fight over the USB handle. It runs on the system bus as [`BUS_NAME`], the policy in
+//! `io.github.karelantonio.Validity.conf` has to be installed in `/usr/share/dbus-1/system.d`.
+//!
+//! Every user can only see and verify the templates they enrolled, listed in the same files used
+//! by the PAM module (`/var/lib/validity/templates/<user>`), root can see all of them.
+//!
+//! The settings are read from [`config::DEFAULT_PATH`] (see [`config`]), and again when the
+//! daemon gets `SIGHUP`.
+//!
+//! Under systemd (`validityd.service`) it is started on demand by D-Bus activation, reports when
+//! it is ready (see [`systemd`]) and runs as the unprivileged `validity` user, given access to
+//! the sensors by the udev rules. Started otherwise as root, `--user` drops the privileges once
+//! the device is open.
+
+mod config;
+mod log;
+mod metrics;
+mod service;
+mod systemd;
+mod users;
+
+use clap::Parser;
+use config::{Config, parse_busaddr};
+use driver::{
+    DriverError,
+    shared::SharedDevice,
+    usb::{OpenOptions, OpenedUsbDevice, UsbDevice},
+};
+use log::{error, info, warning};
+use metrics::Metrics;
+use service::Service;
+use std::{error::Error, net::SocketAddr, path::PathBuf, process::ExitCode};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{SignalKind, signal},
+    task,
+};
+use zbus::{Connection, connection};
+
+/// Well known name of the daemon
+const BUS_NAME: &str = "io.github.karelantonio.Validity";
+
+/// Where the device is exported, see [`Service`]
+const OBJECT_PATH: &str = "/io/github/karelantonio/Validity/Device";
+
+#[derive(Parser)]
+#[command(name = "validityd", about = "Share the fingerprint sensor over D-Bus")]
+struct Cli {
+    /// Configuration file, see the `config` module docs
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Use the device at BUS:ADDR instead of the first supported one
+    #[arg(short, long, value_parser = parse_busaddr)]
+    device: Option<(u8, u8)>,
+
+    /// Use the device with this serial number (see `validity devices`), stable across reboots
+    #[arg(short, long, conflicts_with = "device")]
+    serial: Option<String>,
+
+    /// Directory with a file per user listing their templates
+    #[arg(long)]
+    templates: Option<PathBuf>,
+
+    /// Serve the metrics (Prometheus) on http://ADDR/metrics
+    #[arg(long, value_name = "ADDR")]
+    metrics: Option<SocketAddr>,
+
+    /// Run as this user once the device is open, it needs access to the sensor to reconnect it
+    /// (after a suspend)
+    #[arg(short, long)]
+    user: Option<String>,
+
+    /// Use the session bus instead of the system one (for testing)
+    #[arg(long)]
+    session: bool,
+}
+
+impl Cli {
+    /// Read the configuration file, the flags take precedence
+    fn config(&self) -> Result<Config, Box<dyn Error>> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path, true)?,
+            None => Config::load(config::DEFAULT_PATH.as_ref(), false)?,
+        };
+
+        if self.device.is_some() || self.serial.is_some() {
+            config.device = self.device;
+            config.serial = self.serial.clone();
+        }
+        if let Some(templates) = &self.templates {
+            config.templates = templates.clone();
+        }
+        if self.metrics.is_some() {
+            config.metrics = self.metrics;
+        }
+        Ok(config)
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let res = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|rt| rt.block_on(run(cli)));
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Error: {}", chain(e.as_ref()));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let mut config = cli.config()?;
+    log::set_level(config.log_level);
+
+    let metrics = Metrics::new();
+    let mut usb = open(&config)?;
+    usb.on_command(Some(metrics.observer()));
+    let dev = SharedDevice::new(usb);
+
+    // Bound before dropping the privileges, the port may be a privileged one
+    if let Some(addr) = config.metrics {
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(metrics.clone().serve(listener));
+        info!("Serving the metrics on http://{addr}/metrics");
+    }
+
+    if let Some(user) = &cli.user {
+        users::drop_privileges(user)?;
+        info!("Running as {user}");
+    }
+    let service = Service::new(dev.clone(), &config, metrics);
+
+    let builder = if cli.session {
+        connection::Builder::session()?
+    } else {
+        connection::Builder::system()?
+    };
+    let conn = builder
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, service)?
+        .build()
+        .await?;
+    info!("Serving {BUS_NAME}");
+    systemd::ready();
+
+    // The requests are handled by the connection until the daemon is killed
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        systemd::reloading();
+        match reload(&cli, &conn, &dev, &config).await {
+            Ok(new) => {
+                info!("Configuration reloaded");
+                config = new;
+            }
+            // Keep running with the old one
+            Err(e) => {
+                let e = chain(e.as_ref());
+                error!("Couldn't reload the configuration: {e}");
+                systemd::status(&format!("Reload failed: {e}"));
+            }
+        }
+        systemd::ready();
+    }
+    Ok(())
+}
+
+/// Read the configuration again and apply it, the device is opened again if another one is
+/// selected. Waits for the methods being called to finish.
+async fn reload(
+    cli: &Cli,
+    conn: &Connection,
+    dev: &SharedDevice,
+    current: &Config,
+) -> Result<Config, Box<dyn Error>> {
+    let config = cli.config()?;
+    if config.metrics != current.metrics {
+        warning!("The metrics address only changes when restarted");
+    }
+
+    let mut new_dev = None;
+    if (config.device, &config.serial) != (current.device, &current.serial) {
+        let dev = dev.clone();
+        let config = config.clone();
+        // Waits for the device, like the methods
+        new_dev = task::spawn_blocking(move || -> Result<_, DriverError> {
+            let usb = find(&config)?;
+            // The same device selected another way, it can't be opened twice
+            if usb.0 == dev.lock().hnd.device() {
+                return Ok(None);
+            }
+            usb.open_with(OpenOptions::new().timeouts(config.timeouts))
+                .map(Some)
+        })
+        .await??;
+    }
+
+    let iface = conn
+        .object_server()
+        .interface::<_, Service>(OBJECT_PATH)
+        .await?;
+    iface.get_mut().await.reload(new_dev, &config).await?;
+    log::set_level(config.log_level);
+    Ok(config)
+}
+
+/// The selected device, the first supported one if none was
+fn find(config: &Config) -> Result<UsbDevice, DriverError> {
+    Ok(match (config.device, &config.serial) {
+        (Some((bus, addr)), _) => driver::get_device(bus, addr)?,
+        (None, Some(serial)) => driver::get_device_by_serial(serial)?,
+        (None, None) => driver::list_supported_devices()?
+            .into_iter()
+            .next()
+            .ok_or(DriverError::GetDeviceNotFound)?,
+    })
+}
+
+/// Open and initialize the selected device
+fn open(config: &Config) -> Result<OpenedUsbDevice, DriverError> {
+    find(config)?.open_with(OpenOptions::new().timeouts(config.timeouts))
+}
+
+/// The error and its sources
+fn chain(e: &dyn Error) -> String {
+    let mut msg = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        msg += &format!(": {e}");
+        source = e.source();
+    }
+    msg
+}
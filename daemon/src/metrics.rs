@@ -0,0 +1,294 @@
+//! Counters and histograms of the daemon, served in the Prometheus text format on
+//! `http://<addr>/metrics` when enabled (`--metrics` or `metrics` in the configuration). Only
+//! `GET /metrics` is answered, the server is meant for a scraper on a trusted network.
+
+use crate::log::warning;
+use driver::{DriverError, usb::CommandObserver};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+/// Upper bounds of the histogram buckets, in seconds: the commands take milliseconds, waiting
+/// for a finger takes seconds
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// The longest request read, the headers are ignored anyway
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// The connection is closed if the request did not arrive by then
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Result of a verification, see [`Metrics::verify`]
+#[derive(Debug, Clone, Copy)]
+pub enum VerifyResult {
+    Match,
+    NoMatch,
+    Error,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Not cumulative, summed when rendered
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&b| secs <= b) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    /// Its lines in the Prometheus text format, `labels` are the ones of this series
+    fn render(&self, res: &mut String, name: &str, labels: &str) {
+        let mut total = 0;
+        for (bound, n) in BUCKETS.iter().zip(self.buckets) {
+            total += n;
+            let _ = writeln!(res, "{name}_bucket{{{labels},le=\"{bound}\"}} {total}");
+        }
+        let _ = writeln!(res, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(res, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(res, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    verify: [AtomicU64; 3],
+    enrollments: AtomicU64,
+
+    /// Per libusb error
+    usb_errors: Mutex<BTreeMap<String, u64>>,
+
+    /// Per D-Bus method
+    durations: Mutex<BTreeMap<&'static str, Histogram>>,
+
+    /// Per opcode, see [`Self::observer`]
+    commands: Mutex<BTreeMap<u8, Histogram>>,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    pub fn verify(&self, res: VerifyResult) {
+        self.verify[res as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn enrolled(&self) {
+        self.enrollments.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the error if it came from libusb
+    pub fn error(&self, e: &DriverError) {
+        let Some(e) = e.usb_error() else {
+            return;
+        };
+        let mut errors = self
+            .usb_errors
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *errors.entry(format!("{e:?}")).or_default() += 1;
+    }
+
+    /// How long the method took with the device locked, waiting for the finger included
+    pub fn duration(&self, method: &'static str, duration: Duration) {
+        self.durations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(method)
+            .or_default()
+            .observe(duration);
+    }
+
+    /// How long the sensor took to answer the command
+    pub fn command(&self, opcode: u8, duration: Duration) {
+        self.commands
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(opcode)
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Records the commands of a device, see [`driver::usb::OpenedUsbDevice::on_command`]
+    pub fn observer(self: &Arc<Self>) -> CommandObserver {
+        let metrics = self.clone();
+        Arc::new(move |opcode, duration| metrics.command(opcode, duration))
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut res = String::new();
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+        res += "# HELP validity_verify_total Verifications, by result\n";
+        res += "# TYPE validity_verify_total counter\n";
+        for (res_name, counter) in ["match", "no_match", "error"].iter().zip(&self.verify) {
+            let _ = writeln!(
+                res,
+                "validity_verify_total{{result=\"{res_name}\"}} {}",
+                load(counter)
+            );
+        }
+
+        res += "# HELP validity_enrollments_total Fingers enrolled\n";
+        res += "# TYPE validity_enrollments_total counter\n";
+        let _ = writeln!(
+            res,
+            "validity_enrollments_total {}",
+            load(&self.enrollments)
+        );
+
+        res += "# HELP validity_usb_errors_total Errors of the USB transfers, by libusb error\n";
+        res += "# TYPE validity_usb_errors_total counter\n";
+        for (error, n) in self
+            .usb_errors
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            let _ = writeln!(res, "validity_usb_errors_total{{error=\"{error}\"}} {n}");
+        }
+
+        res += "# HELP validity_method_duration_seconds Time taken by the D-Bus methods, waiting \
+                for the finger included\n";
+        res += "# TYPE validity_method_duration_seconds histogram\n";
+        for (method, h) in self
+            .durations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            h.render(
+                &mut res,
+                "validity_method_duration_seconds",
+                &format!("method=\"{method}\""),
+            );
+        }
+
+        res += "# HELP validity_command_duration_seconds Time taken by the sensor to answer the \
+                commands, by opcode\n";
+        res += "# TYPE validity_command_duration_seconds histogram\n";
+        for (opcode, h) in self
+            .commands
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            h.render(
+                &mut res,
+                "validity_command_duration_seconds",
+                &format!("opcode=\"0x{opcode:02x}\""),
+            );
+        }
+
+        res
+    }
+
+    /// Answer the scrapers until the daemon stops
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warning!("Couldn't accept a metrics connection: {e}");
+                    continue;
+                }
+            };
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = metrics.answer(stream).await {
+                    warning!("Metrics request failed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Read the request line and the headers, answer and close the connection
+    async fn answer(&self, mut stream: TcpStream) -> io::Result<()> {
+        let req = time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no request received"))??;
+
+        let (status, body) = if req.starts_with(b"GET /metrics ") {
+            ("200 OK", self.render())
+        } else {
+            ("404 Not Found", String::new())
+        };
+        let resp = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(resp.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+/// Read until the end of the headers (or the connection is closed)
+async fn read_request(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut req = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") && req.len() < MAX_REQUEST {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        req.extend(&buf[..n]);
+    }
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        metrics.verify(VerifyResult::Match);
+        metrics.command(0x01, Duration::from_micros(800));
+        metrics.command(0x01, Duration::from_millis(20));
+        metrics.duration("verify", Duration::from_secs(3));
+
+        let text = metrics.render();
+        assert!(text.contains("validity_verify_total{result=\"match\"} 1\n"));
+        assert!(text.contains(
+            "validity_command_duration_seconds_bucket{opcode=\"0x01\",le=\"0.001\"} 1\n"
+        ));
+        assert!(
+            text.contains(
+                "validity_command_duration_seconds_bucket{opcode=\"0x01\",le=\"0.05\"} 2\n"
+            )
+        );
+        assert!(text.contains("validity_command_duration_seconds_count{opcode=\"0x01\"} 2\n"));
+        assert!(
+            text.contains(
+                "validity_method_duration_seconds_bucket{method=\"verify\",le=\"2.5\"} 0\n"
+            )
+        );
+        assert!(text.contains(
+            "validity_method_duration_seconds_bucket{method=\"verify\",le=\"+Inf\"} 1\n"
+        ));
+    }
+}
@@ -0,0 +1,334 @@
+//! The `io.github.karelantonio.Validity.Device1` interface
+
+use crate::{
+    config::{Config, Lockout},
+    log::{debug, info, warning},
+    metrics::{Metrics, VerifyResult},
+    users::{self, ROOT_UID},
+};
+use driver::{
+    DriverError,
+    cancel::CancelToken,
+    enroll::{Enroll, EnrollProgress, TemplateId},
+    identify::Identify,
+    metadata::MetadataStore,
+    shared::SharedDevice,
+    storage::Storage,
+    usb::OpenedUsbDevice,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+use tokio::{
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+use zbus::{Connection, fdo, interface, message::Header, object_server::SignalEmitter};
+
+/// The user calling a method
+struct Caller {
+    uid: u32,
+    name: String,
+}
+
+impl Caller {
+    /// Whether the user may use the template
+    fn owns(&self, store: &MetadataStore, template: TemplateId) -> bool {
+        self.uid == ROOT_UID || store.templates(&self.name).contains(&template)
+    }
+}
+
+/// Failed verifications of a user, see [`Lockout`]
+#[derive(Debug, Default)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// The shared device, every method locks it while talking to the sensor
+pub struct Service {
+    dev: SharedDevice,
+    store: MetadataStore,
+    lockout: Lockout,
+
+    /// Per user id
+    failures: Mutex<HashMap<u32, Failures>>,
+    metrics: Arc<Metrics>,
+
+    /// Id of the user of the operation running, the one who may cancel it
+    running: Arc<Mutex<Option<u32>>>,
+}
+
+impl Service {
+    pub fn new(dev: SharedDevice, config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            dev,
+            store: MetadataStore::new(&config.templates),
+            lockout: config.lockout,
+            failures: Mutex::new(HashMap::new()),
+            metrics,
+            running: Arc::default(),
+        }
+    }
+
+    /// Apply a new configuration, the device is replaced if given (once the operation using it
+    /// finished). The failures counted up to now are kept.
+    pub async fn reload(
+        &mut self,
+        dev: Option<OpenedUsbDevice>,
+        config: &Config,
+    ) -> Result<(), task::JoinError> {
+        let shared = self.dev.clone();
+        let timeouts = config.timeouts;
+        let observer = self.metrics.observer();
+        task::spawn_blocking(move || {
+            let mut current = shared.lock();
+            if let Some(mut dev) = dev {
+                dev.on_command(Some(observer));
+                *current = dev;
+            }
+            current.timeouts = timeouts;
+        })
+        .await?;
+
+        self.store = MetadataStore::new(&config.templates);
+        self.lockout = config.lockout;
+        Ok(())
+    }
+
+    /// Fail if the user is locked out
+    fn check_lockout(&self, caller: &Caller) -> fdo::Result<()> {
+        let failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        match failures.get(&caller.uid).and_then(|f| f.locked_until) {
+            Some(until) if until > Instant::now() => Err(fdo::Error::AccessDenied(format!(
+                "Too many failed attempts, try again in {}s",
+                (until - Instant::now()).as_secs() + 1
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count the result of a verification, a match resets the count
+    fn record_attempt(&self, caller: &Caller, matched: bool) {
+        debug!("{} verification: matched {matched}", caller.name);
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        if matched {
+            failures.remove(&caller.uid);
+            return;
+        }
+
+        let f = failures.entry(caller.uid).or_default();
+        f.count += 1;
+        if self.lockout.max_failures > 0 && f.count >= self.lockout.max_failures {
+            warning!(
+                "{} failed {} verifications, locked out for {:?}",
+                caller.name,
+                f.count,
+                self.lockout.duration
+            );
+            f.count = 0;
+            f.locked_until = Some(Instant::now() + self.lockout.duration);
+        }
+    }
+
+    /// Run the operation of the caller in a blocking thread with the device locked, reconnecting
+    /// it if it was lost (after a suspend). It can be stopped with `Cancel`, the token is
+    /// cancelled then. The errors and the time taken are recorded for the method.
+    fn spawn<R, F>(
+        &self,
+        method: &'static str,
+        caller: &Caller,
+        mut op: F,
+    ) -> JoinHandle<Result<R, DriverError>>
+    where
+        R: Send + 'static,
+        F: FnMut(&OpenedUsbDevice, &CancelToken) -> Result<R, DriverError> + Send + 'static,
+    {
+        let dev = self.dev.clone();
+        let metrics = self.metrics.clone();
+        let running = self.running.clone();
+        let uid = caller.uid;
+        task::spawn_blocking(move || {
+            dev.with_cancel(|dev, cancel| {
+                *running.lock().unwrap_or_else(PoisonError::into_inner) = Some(uid);
+                let start = Instant::now();
+                let res = dev.recovering(|dev| {
+                    let res = op(dev, cancel);
+                    if let Err(e) = &res {
+                        metrics.error(e);
+                    }
+                    res
+                });
+                metrics.duration(method, start.elapsed());
+                *running.lock().unwrap_or_else(PoisonError::into_inner) = None;
+                res
+            })
+        })
+    }
+}
+
+#[interface(name = "io.github.karelantonio.Validity.Device1")]
+impl Service {
+    /// Enroll a new finger for the caller (in their namespace, see
+    /// [`MetadataStore::allocate_namespace`]), the user has to touch the sensor several times (an
+    /// `EnrollProgress` signal is sent after each one). Returns the id of the new template.
+    async fn enroll(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+        finger: u8,
+    ) -> fdo::Result<u16> {
+        let caller = caller(conn, &hdr).await?;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let namespace = self
+            .store
+            .allocate_namespace(&caller.name)
+            .map_err(failed)?;
+        let task = self.spawn("enroll", &caller, move |dev, cancel| {
+            dev.enroll_in_with(namespace, finger, cancel, |p| {
+                let _ = tx.send(p);
+            })
+        });
+
+        // Ends once the enrollment finished and the sender was dropped
+        while let Some(p) = rx.recv().await {
+            let EnrollProgress {
+                sample,
+                remaining,
+                quality,
+                coverage,
+            } = p;
+            let _ = Self::enroll_progress(&emitter, sample, remaining, quality, coverage).await;
+        }
+
+        let id = join(task).await?;
+        self.store.add(&caller.name, id).map_err(failed)?;
+        self.metrics.enrolled();
+        info!("{} enrolled template {}", caller.name, id.0);
+        Ok(id.0)
+    }
+
+    /// Scan a finger and check it matches one of the caller's templates. After too many
+    /// failures in a row the caller is locked out for a while (see [`Lockout`]).
+    #[zbus(out_args("matched", "template"))]
+    async fn verify(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] hdr: Header<'_>,
+    ) -> fdo::Result<(bool, u16)> {
+        let caller = caller(conn, &hdr).await?;
+        self.check_lockout(&caller)?;
+
+        // Only the caller's fingers can match, unless they were enrolled before the namespaces
+        let namespace = self.store.namespace(&caller.name);
+        let task = self.spawn("verify", &caller, move |dev, cancel| match namespace {
+            Some(ns) if !dev.list_templates_in(ns)?.is_empty() => dev.identify_in_with(ns, cancel),
+            _ => dev.identify_with(cancel),
+        });
+        let res = join(task)
+            .await
+            .inspect_err(|_| self.metrics.verify(VerifyResult::Error))?;
+        let res = match res {
+            Some(m) if caller.owns(&self.store, m.template) => (true, m.template.0),
+            _ => (false, 0),
+        };
+        self.record_attempt(&caller, res.0);
+        self.metrics.verify(if res.0 {
+            VerifyResult::Match
+        } else {
+            VerifyResult::NoMatch
+        });
+        Ok(res)
+    }
+
+    /// The templates of the caller (every one for root): template id and finger id
+    async fn list_templates(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] hdr: Header<'_>,
+    ) -> fdo::Result<Vec<(u16, u8)>> {
+        let caller = caller(conn, &hdr).await?;
+
+        let task = self.spawn("list_templates", &caller, |dev, _| dev.list_templates());
+        let templates = join(task).await?;
+        Ok(templates
+            .into_iter()
+            .filter(|t| caller.owns(&self.store, t.id))
+            .map(|t| (t.id.0, t.finger_id))
+            .collect())
+    }
+
+    /// Stop the enrollment or verification running, if it is the caller's (any for root). The
+    /// operations waiting for the device are cancelled too, they fail right away.
+    async fn cancel(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] hdr: Header<'_>,
+    ) -> fdo::Result<()> {
+        let caller = caller(conn, &hdr).await?;
+        let running = *self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        match running {
+            None => return Ok(()),
+            Some(uid) if uid != caller.uid && caller.uid != ROOT_UID => {
+                return Err(fdo::Error::AccessDenied(
+                    "The operation running is not yours".into(),
+                ));
+            }
+            Some(_) => {}
+        }
+
+        // The operations leave the sensor idle once cancelled, nothing else to send
+        let dev = self.dev.clone();
+        join(task::spawn_blocking(move || dev.abort(|_| Ok(())))).await?;
+        info!("{} cancelled the operation running", caller.name);
+        Ok(())
+    }
+
+    /// Sent after every enrollment sample
+    #[zbus(signal)]
+    async fn enroll_progress(
+        emitter: &SignalEmitter<'_>,
+        sample: u32,
+        remaining: u16,
+        quality: u16,
+        coverage: u16,
+    ) -> zbus::Result<()>;
+}
+
+/// Ask the bus who sent the message
+async fn caller(conn: &Connection, hdr: &Header<'_>) -> fdo::Result<Caller> {
+    let sender = hdr
+        .sender()
+        .ok_or_else(|| fdo::Error::AccessDenied("Unknown sender".into()))?;
+
+    let uid = fdo::DBusProxy::new(conn)
+        .await?
+        .get_connection_unix_user(sender.clone().into())
+        .await?;
+    let name = users::user_name(uid)
+        .ok_or_else(|| fdo::Error::AccessDenied(format!("Unknown user {uid}")))?;
+
+    Ok(Caller { uid, name })
+}
+
+/// Wait for the blocking operation
+async fn join<R>(task: JoinHandle<Result<R, DriverError>>) -> fdo::Result<R> {
+    task.await.map_err(failed)?.map_err(failed)
+}
+
+/// The error and its sources, as a D-Bus error
+fn failed(e: impl Error) -> fdo::Error {
+    let mut msg = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        msg.push_str(": ");
+        msg.push_str(&e.to_string());
+        source = e.source();
+    }
+    fdo::Error::Failed(msg)
+}
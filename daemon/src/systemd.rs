@@ -0,0 +1,59 @@
+//! The readiness protocol of systemd (see `sd_notify(3)`), the unit is `Type=notify-reload`: the
+//! daemon says when it is serving and when it reloads its configuration. Nothing is sent when
+//! not started by systemd.
+
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+};
+
+/// The daemon owns its name and handles the requests
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// The configuration is being read again, [`ready`] has to follow
+pub fn reloading() {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is valid, the clock always exists
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    let usec = ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000;
+
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={usec}"));
+}
+
+/// A line shown by `systemctl status`
+pub fn status(status: &str) {
+    notify(&format!("STATUS={status}"));
+}
+
+/// Best effort, the daemon works the same if systemd does not get it
+fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let _ = send(&path, state);
+}
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let sock = UnixDatagram::unbound()?;
+
+    // Abstract sockets start with `@`
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    sock.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! The users calling the daemon, as found in the password database
+
+use std::{
+    ffi::{CStr, CString, c_char},
+    io,
+    mem::MaybeUninit,
+    ptr,
+};
+
+/// The root user, allowed to see every template
+pub const ROOT_UID: u32 = 0;
+
+/// The name of the user with this id, from the password database
+pub fn user_name(uid: u32) -> Option<String> {
+    let mut pwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut buf = vec![0 as c_char; 4096];
+    let mut res = ptr::null_mut();
+
+    // SAFETY: Every pointer is valid for the given sizes, `res` is only set on success
+    let rc =
+        unsafe { libc::getpwuid_r(uid, pwd.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut res) };
+    if rc != 0 || res.is_null() {
+        return None;
+    }
+
+    // SAFETY: On success `pw_name` points to a string in `buf`
+    let name = unsafe { CStr::from_ptr((*res).pw_name) };
+    name.to_str().ok().map(String::from)
+}
+
+/// Run as this user from now on, with its groups (the sensors have to be accessible to one of
+/// them, see the udev rules). Only root can do it.
+pub fn drop_privileges(name: &str) -> io::Result<()> {
+    let c_name = CString::new(name).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let mut pwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut buf = vec![0 as c_char; 4096];
+    let mut res = ptr::null_mut();
+
+    // SAFETY: Every pointer is valid for the given sizes, `res` is only set on success
+    let rc = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            pwd.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut res,
+        )
+    };
+    if rc != 0 || res.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown user {name}"),
+        ));
+    }
+    // SAFETY: Set on success
+    let (uid, gid) = unsafe { ((*res).pw_uid, (*res).pw_gid) };
+
+    // The groups first, they can't be changed once the user is
+    // SAFETY: Plain calls, `c_name` outlives them
+    unsafe {
+        if libc::initgroups(c_name.as_ptr(), gid) != 0
+            || libc::setgid(gid) != 0
+            || libc::setuid(uid) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
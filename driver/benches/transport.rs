@@ -0,0 +1,45 @@
+//! Overhead of the protocol layer on top of the transport, with a mocked device answering
+//! instantly. Run with `cargo bench -p driver`, real devices are measured by `validity bench`.
+
+use driver::{SUPPORTED, bench, proto::Command, transport::MockTransport};
+
+const ITERATIONS: u32 = 10_000;
+
+fn main() {
+    let version = Command::GetVersion.to_bytes();
+    let mut dev = MockTransport::new(&SUPPORTED[0]);
+    for _ in 0..ITERATIONS {
+        dev = dev.expect(&version, &[0u8; 13]);
+    }
+    report("round trip", bench::round_trip(&dev, ITERATIONS));
+
+    for size in bench::BULK_SIZES {
+        let cmd = Command::ReadFlash {
+            partition: 1,
+            addr: 0,
+            size,
+        }
+        .to_bytes();
+        let resp = vec![0u8; 2 + size as usize];
+
+        let mut dev = MockTransport::new(&SUPPORTED[0]);
+        for _ in 0..ITERATIONS {
+            dev = dev.expect(&cmd, &resp);
+        }
+        report(
+            &format!("bulk read {size}"),
+            bench::bulk_read(&dev, 1, size, ITERATIONS),
+        );
+    }
+}
+
+fn report(name: &str, res: Result<bench::BenchResult, driver::DriverError>) {
+    match res {
+        Ok(res) => println!(
+            "{name:>16}: {:>10.2?}/iter {:>10.1} MiB/s",
+            res.latency(),
+            res.throughput() / (1024.0 * 1024.0)
+        ),
+        Err(e) => println!("{name:>16}: failed: {e}"),
+    }
+}
@@ -0,0 +1,292 @@
+//! The finger minutiae record of ANSI INCITS 378-2004, used by many enterprise systems. Like the
+//! ISO one (see [`crate::iso`]) with a CBEFF product identifier in the header, a length of 2
+//! bytes (or 0 and 4 bytes for big records) and the angles in units of 2 degrees.
+
+use crate::{
+    DriverError,
+    enroll::Finger,
+    iso::{Impression, read_minutia, write_minutia},
+    minutiae::Template,
+};
+
+/// Every record starts with this
+const RECORD_MAGIC: &[u8] = b"FMR\0 20\0";
+
+/// Angles are in units of 2 degrees
+const ANGLE_STEPS: u32 = 180;
+
+/// The views of a finger are numbered with 4 bits
+const MAX_VIEWS: usize = 16;
+
+/// Host templates as ANSI INCITS 378 records, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct AnsiExport {
+    product: u32,
+    finger: Option<Finger>,
+    impression: Impression,
+    resolution: u16,
+    quality: u8,
+}
+
+impl AnsiExport {
+    /// An unknown finger pressed on a 500 dpi sensor, quality not reported and no product
+    pub fn new() -> Self {
+        Self {
+            product: 0,
+            finger: None,
+            impression: Impression::LivePlain,
+            resolution: 197,
+            quality: 0,
+        }
+    }
+
+    /// The CBEFF product identifier: the owner of the format (registered with IBIA) and its type
+    pub fn product(mut self, owner: u16, kind: u16) -> Self {
+        self.product = (owner as u32) << 16 | kind as u32;
+        self
+    }
+
+    pub fn finger(mut self, finger: Finger) -> Self {
+        self.finger = Some(finger);
+        self
+    }
+
+    pub fn impression(mut self, impression: Impression) -> Self {
+        self.impression = impression;
+        self
+    }
+
+    /// Resolution of the sensor, in dots per inch (the record has pixels per cm)
+    pub fn resolution_dpi(mut self, dpi: u16) -> Self {
+        self.resolution = (dpi as f32 / 2.54).round() as u16;
+        self
+    }
+
+    /// Quality of the finger (1-100, see [`crate::quality`]), 0 if not known
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = quality.min(100);
+        self
+    }
+
+    /// Write the samples (the template of [`crate::matcher::MinutiaeMatcher`]) as a record, a
+    /// view each. Only the first 16 samples and 255 minutiae of each fit.
+    pub fn to_bytes(&self, samples: &[Template]) -> Vec<u8> {
+        let samples = &samples[..samples.len().min(MAX_VIEWS)];
+        let width = samples.iter().map(|s| s.width).max().unwrap_or(0);
+        let height = samples.iter().map(|s| s.height).max().unwrap_or(0);
+
+        let mut body = self.product.to_be_bytes().to_vec();
+        // No compliance, unknown device
+        body.extend([0; 2]);
+        body.extend(width.to_be_bytes());
+        body.extend(height.to_be_bytes());
+        body.extend(self.resolution.to_be_bytes());
+        body.extend(self.resolution.to_be_bytes());
+        body.push(samples.len() as u8);
+        body.push(0);
+
+        for (view, sample) in samples.iter().enumerate() {
+            let minutiae = &sample.minutiae[..sample.minutiae.len().min(u8::MAX as usize)];
+
+            body.push(self.finger.map_or(0, Finger::iso_position));
+            body.push((view as u8) << 4 | self.impression as u8);
+            body.push(self.quality);
+            body.push(minutiae.len() as u8);
+            for m in minutiae {
+                write_minutia(&mut body, m, ANGLE_STEPS);
+            }
+            // No extended data
+            body.extend([0; 2]);
+        }
+
+        let mut res = RECORD_MAGIC.to_vec();
+        let len = RECORD_MAGIC.len() + 2 + body.len();
+        match u16::try_from(len) {
+            Ok(len) => res.extend(len.to_be_bytes()),
+            Err(_) => {
+                res.extend([0; 2]);
+                res.extend((len as u32 + 4).to_be_bytes());
+            }
+        }
+        res.extend(body);
+        res
+    }
+}
+
+impl Default for AnsiExport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A finger view of a record
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnsiView {
+    /// `None` if unknown or not a single finger
+    pub finger: Option<Finger>,
+
+    /// `None` if not scanned by a live sensor
+    pub impression: Option<Impression>,
+
+    /// 1-100, 0 if not reported
+    pub quality: u8,
+
+    /// The minutiae, the ones of type "other" are left out, they have no equivalent here
+    pub template: Template,
+}
+
+/// Parse an ANSI INCITS 378 record, the extended data of the views is skipped
+pub fn parse(data: &[u8]) -> Result<Vec<AnsiView>, DriverError> {
+    let mut data = data
+        .strip_prefix(RECORD_MAGIC)
+        .ok_or(DriverError::HostTemplateInvalid)?;
+    let total = data.len() + RECORD_MAGIC.len();
+    let mut take = |n: usize| -> Result<&[u8], DriverError> {
+        let (head, rest) = data
+            .split_at_checked(n)
+            .ok_or(DriverError::HostTemplateInvalid)?;
+        data = rest;
+        Ok(head)
+    };
+    let u16_be = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+
+    let len = match u16_be(take(2)?) {
+        0 => u32::from_be_bytes(take(4)?.try_into().unwrap()) as usize,
+        len => len as usize,
+    };
+    if len != total {
+        return Err(DriverError::HostTemplateInvalid);
+    }
+
+    // The product and the capture device
+    take(6)?;
+    let header = take(10)?;
+    let (width, height) = (u16_be(&header[0..2]), u16_be(&header[2..4]));
+    let views = header[8];
+
+    let mut res = Vec::with_capacity(views as usize);
+    for _ in 0..views {
+        let head = take(4)?;
+
+        let mut minutiae = Vec::with_capacity(head[3] as usize);
+        for _ in 0..head[3] {
+            let m = take(6)?.try_into().unwrap();
+            minutiae.extend(read_minutia(m, ANGLE_STEPS)?);
+        }
+        let extended = u16_be(take(2)?);
+        take(extended as usize)?;
+
+        res.push(AnsiView {
+            finger: Finger::from_iso_position(head[0]),
+            impression: Impression::from_code(head[1] & 0x0f),
+            quality: head[2],
+            template: Template {
+                width,
+                height,
+                minutiae,
+            },
+        });
+    }
+
+    if !data.is_empty() {
+        return Err(DriverError::HostTemplateInvalid);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minutiae::{Minutia, MinutiaKind};
+    use core::f32::consts::TAU;
+
+    fn samples() -> Vec<Template> {
+        let minutia = |x, angle, kind| Minutia {
+            x,
+            y: 20,
+            angle,
+            kind,
+        };
+        vec![
+            Template {
+                width: 64,
+                height: 48,
+                minutiae: vec![
+                    minutia(3, 1.0, MinutiaKind::Ending),
+                    minutia(40, 4.0, MinutiaKind::Bifurcation),
+                ],
+            },
+            Template {
+                width: 64,
+                height: 48,
+                minutiae: vec![],
+            },
+        ]
+    }
+
+    fn export() -> Vec<u8> {
+        AnsiExport::new()
+            .product(0x0033, 0x0101)
+            .finger(Finger::LeftThumb)
+            .quality(60)
+            .to_bytes(&samples())
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = export();
+        assert_eq!(data[8..10], (data.len() as u16).to_be_bytes());
+        assert_eq!(data[10..14], [0x00, 0x33, 0x01, 0x01]);
+
+        let views = parse(&data).unwrap();
+        assert_eq!(views.len(), 2);
+        for (view, sample) in views.iter().zip(samples()) {
+            assert_eq!(view.finger, Some(Finger::LeftThumb));
+            assert_eq!(view.impression, Some(Impression::LivePlain));
+            assert_eq!(view.quality, 60);
+            assert_eq!(view.template.minutiae.len(), sample.minutiae.len());
+
+            for (a, b) in view.template.minutiae.iter().zip(&sample.minutiae) {
+                assert_eq!((a.x, a.y, a.kind), (b.x, b.y, b.kind));
+                // Stored in units of 2 degrees
+                let diff = (a.angle - b.angle).abs();
+                assert!(diff.min(TAU - diff) <= TAU / ANGLE_STEPS as f32);
+            }
+        }
+    }
+
+    #[test]
+    fn long_length() {
+        // The same record, with the length of the big ones
+        let data = export();
+        let mut long = RECORD_MAGIC.to_vec();
+        long.extend([0, 0]);
+        long.extend((data.len() as u32 + 4).to_be_bytes());
+        long.extend(&data[10..]);
+
+        assert_eq!(parse(&long).unwrap(), parse(&data).unwrap());
+    }
+
+    #[test]
+    fn invalid() {
+        let data = export();
+        assert!(parse(&data[1..]).is_err());
+        // Cut, the length does not match
+        assert!(parse(&data[..data.len() - 1]).is_err());
+
+        // Trailing bytes, counted in the length
+        let mut trailing = data.clone();
+        trailing.push(0);
+        let len = trailing.len() as u16;
+        trailing[8..10].copy_from_slice(&len.to_be_bytes());
+        assert!(matches!(
+            parse(&trailing),
+            Err(DriverError::HostTemplateInvalid)
+        ));
+
+        // More views than there are
+        let mut views = data;
+        views[24] = 3;
+        assert!(parse(&views).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+//! Backup of what the host needs to use the enrolled templates again after a reinstall. The
+//! templates stay in the flash of the sensor, but without the pairing (see [`crate::pairing`])
+//! the sensor can't be talked to and without the [`MetadataStore`] nobody owns them. The
+//! firmwares don't let the template data be read back, so the backup holds the pairing and the
+//! slot map: every template with its finger, namespace and owner.
+//!
+//! The file is: magic, pairing length (u16, 0 if none) and pairing data (like the pairing file),
+//! template count (u16) and the templates, each one with: id (u16), namespace (u16), finger id
+//! (u8), owner length (u8) and owner (empty if none).
+
+use crate::{
+    DriverError,
+    enroll::TemplateId,
+    metadata::MetadataStore,
+    pairing::{decode_pairing, encode_pairing, save_pairing, write_private},
+    secure::SessionParams,
+    storage::{Storage, TemplateInfo, TemplateOrigin},
+    transport::Transport,
+};
+use std::{fs, path::Path};
+
+/// Every backup file starts with this
+const FILE_MAGIC: &[u8] = b"VSBACK01";
+
+/// A template in the backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupEntry {
+    pub info: TemplateInfo,
+
+    /// The user in the [`MetadataStore`], if any
+    pub owner: Option<String>,
+}
+
+/// What [`Backup::import_templates`] restored
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Whether the pairing was in the backup and was saved
+    pub pairing: bool,
+
+    /// Templates found on the device, given back to their owner
+    pub restored: Vec<TemplateId>,
+
+    /// Templates of the backup the device does not have anymore (or with another finger)
+    pub missing: Vec<TemplateId>,
+}
+
+/// Backup and restore of the templates, implemented for every [`Transport`]
+pub trait Backup: Transport {
+    /// Save the pairing (if given) and every template on the device (but the ones enrolled from
+    /// Windows) with its owner in the store. The file is only readable by its owner, it contains
+    /// the host key. Returns the number of templates saved.
+    fn export_templates(
+        &self,
+        path: impl AsRef<Path>,
+        store: &MetadataStore,
+        pairing: Option<&SessionParams>,
+    ) -> Result<usize, DriverError> {
+        let entries: Vec<_> = self
+            .list_templates()?
+            .into_iter()
+            // Those are for Windows to back up
+            .filter(|info| info.origin == TemplateOrigin::Host)
+            .map(|info| BackupEntry {
+                owner: store.owner(info.id),
+                info,
+            })
+            .collect();
+
+        let data = encode_backup(pairing, &entries)?;
+        write_private(path, &data).map_err(DriverError::BackupIo)?;
+        Ok(entries.len())
+    }
+
+    /// Restore a backup made with [`Self::export_templates`]: the templates still on the device
+    /// are recorded again for their owner in the store, and the pairing is saved to
+    /// `pairing_path` (if given, and in the backup)
+    fn import_templates(
+        &self,
+        path: impl AsRef<Path>,
+        store: &MetadataStore,
+        pairing_path: Option<&Path>,
+    ) -> Result<ImportReport, DriverError> {
+        let data = fs::read(path).map_err(DriverError::BackupIo)?;
+        let (pairing, entries) = decode_backup(&data)?;
+        let on_device = self.list_templates()?;
+        let mut report = ImportReport::default();
+
+        if let (Some(params), Some(path)) = (&pairing, pairing_path) {
+            save_pairing(params, path)?;
+            report.pairing = true;
+        }
+
+        for entry in entries {
+            let id = entry.info.id;
+            if !on_device.contains(&entry.info) {
+                report.missing.push(id);
+                continue;
+            }
+
+            if let Some(owner) = &entry.owner
+                && store.owner(id).as_ref() != Some(owner)
+            {
+                store.remove(id)?;
+                store.add(owner, id)?;
+            }
+            report.restored.push(id);
+        }
+
+        Ok(report)
+    }
+}
+
+impl<T: Transport + ?Sized> Backup for T {}
+
+fn encode_backup(
+    pairing: Option<&SessionParams>,
+    entries: &[BackupEntry],
+) -> Result<Vec<u8>, DriverError> {
+    let pairing = pairing.map(encode_pairing).transpose()?.unwrap_or_default();
+
+    let mut data = FILE_MAGIC.to_vec();
+    data.extend((pairing.len() as u16).to_le_bytes());
+    data.extend(pairing);
+    data.extend((entries.len() as u16).to_le_bytes());
+
+    for entry in entries {
+        // Longer names are not valid user names anyway
+        let owner = entry.owner.as_deref().unwrap_or_default().as_bytes();
+        let owner = &owner[..owner.len().min(u8::MAX as usize)];
+
+        data.extend(entry.info.id.0.to_le_bytes());
+        data.extend(entry.info.user.to_le_bytes());
+        data.push(entry.info.finger_id);
+        data.push(owner.len() as u8);
+        data.extend(owner);
+    }
+    Ok(data)
+}
+
+fn decode_backup(data: &[u8]) -> Result<(Option<SessionParams>, Vec<BackupEntry>), DriverError> {
+    let mut data = data
+        .strip_prefix(FILE_MAGIC)
+        .ok_or(DriverError::BackupInvalid)?;
+    let mut take = |n: usize| -> Result<&[u8], DriverError> {
+        let (head, rest) = data.split_at_checked(n).ok_or(DriverError::BackupInvalid)?;
+        data = rest;
+        Ok(head)
+    };
+    let u16_le = |b: &[u8]| u16::from_le_bytes([b[0], b[1]]);
+
+    let pairing_len = u16_le(take(2)?) as usize;
+    let pairing = match take(pairing_len)? {
+        [] => None,
+        pairing => Some(decode_pairing(pairing)?),
+    };
+
+    let count = u16_le(take(2)?);
+    let entries = (0..count)
+        .map(|_| {
+            let head = take(6)?;
+            let owner = take(head[5] as usize)?;
+            let owner =
+                String::from_utf8(owner.to_vec()).map_err(|_| DriverError::BackupInvalid)?;
+
+            Ok(BackupEntry {
+                info: TemplateInfo {
+                    id: TemplateId(u16_le(&head[0..2])),
+                    user: u16_le(&head[2..4]),
+                    finger_id: head[4],
+                    origin: TemplateOrigin::Host,
+                },
+                owner: (!owner.is_empty()).then_some(owner),
+            })
+        })
+        .collect::<Result<_, DriverError>>()?;
+
+    if !data.is_empty() {
+        return Err(DriverError::BackupInvalid);
+    }
+    Ok((pairing, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hostkey::HostKey, proto::Command, transport::MockTransport};
+    use p256::SecretKey;
+    use rand_core::OsRng;
+    use std::path::PathBuf;
+
+    fn entry(id: u16, owner: Option<&str>) -> BackupEntry {
+        BackupEntry {
+            info: TemplateInfo {
+                id: TemplateId(id),
+                user: 2,
+                finger_id: 7,
+                origin: TemplateOrigin::Host,
+            },
+            owner: owner.map(str::to_string),
+        }
+    }
+
+    fn params() -> SessionParams {
+        SessionParams {
+            host_key: HostKey::generate(),
+            host_cert: vec![1, 2, 3],
+            device_key: SecretKey::random(&mut OsRng).public_key(),
+        }
+    }
+
+    /// A device with the templates, the Windows one has flags 1
+    fn device(ids: &[u16], windows: u16) -> MockTransport {
+        let mut resp = vec![0, 0];
+        resp.extend((ids.len() as u16 + 1).to_le_bytes());
+        for &id in ids {
+            resp.extend(id.to_le_bytes());
+            resp.extend([2, 0, 7, 0]);
+        }
+        resp.extend(windows.to_le_bytes());
+        resp.extend([0, 0, 1, 1]);
+        MockTransport::new(&crate::SUPPORTED[0]).expect(&Command::ListRecords.to_bytes(), &resp)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("validity-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let entries = [entry(1, Some("alice")), entry(4, None)];
+        let params = params();
+        let data = encode_backup(Some(&params), &entries).unwrap();
+
+        let (pairing, decoded) = decode_backup(&data).unwrap();
+        let pairing = pairing.unwrap();
+        assert_eq!(pairing.host_cert, params.host_cert);
+        assert_eq!(pairing.device_key, params.device_key);
+        assert_eq!(pairing.host_key.public_key(), params.host_key.public_key());
+        assert_eq!(decoded, entries);
+
+        let data = encode_backup(None, &[]).unwrap();
+        assert_eq!(data.len(), FILE_MAGIC.len() + 4);
+        assert!(matches!(decode_backup(&data), Ok((None, e)) if e.is_empty()));
+    }
+
+    #[test]
+    fn invalid() {
+        let data = encode_backup(None, &[entry(1, Some("alice"))]).unwrap();
+        assert!(decode_backup(&data[1..]).is_err());
+        assert!(decode_backup(&data[..data.len() - 1]).is_err());
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(matches!(
+            decode_backup(&trailing),
+            Err(DriverError::BackupInvalid)
+        ));
+
+        let mut not_utf8 = data;
+        *not_utf8.last_mut().unwrap() = 0xff;
+        assert!(decode_backup(&not_utf8).is_err());
+    }
+
+    #[test]
+    fn export_and_import() {
+        let dir = temp_dir("backup");
+        let file = dir.join("backup");
+        let store = MetadataStore::new(dir.join("old"));
+        store.add("alice", TemplateId(1)).unwrap();
+        store.add("bob", TemplateId(2)).unwrap();
+
+        let dev = device(&[1, 2], 9);
+        assert_eq!(
+            dev.export_templates(&file, &store, Some(&params()))
+                .unwrap(),
+            2
+        );
+        assert!(dev.is_done());
+
+        // Template 2 was deleted since
+        let store = MetadataStore::new(dir.join("new"));
+        let pairing = dir.join("pairing");
+        let report = device(&[1], 9)
+            .import_templates(&file, &store, Some(&pairing))
+            .unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                pairing: true,
+                restored: vec![TemplateId(1)],
+                missing: vec![TemplateId(2)],
+            }
+        );
+        assert_eq!(store.owner(TemplateId(1)).as_deref(), Some("alice"));
+        assert_eq!(store.owner(TemplateId(2)), None);
+        assert!(pairing.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
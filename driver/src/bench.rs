@@ -0,0 +1,82 @@
+//! Measure the transport: how long a command takes to be answered and how fast big responses are
+//! read, used by `validity bench` and the benches of this crate to catch regressions.
+
+use crate::{
+    DriverError,
+    proto::{Command, Response},
+    transport::Transport,
+};
+use core::time::Duration;
+use std::time::Instant;
+
+/// Sizes of the reads measured by default, in bytes (the flash is read in chunks of 4 KiB)
+pub const BULK_SIZES: [u32; 4] = [512, 1024, 4096, 8192];
+
+/// See [`round_trip`] and [`bulk_read`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchResult {
+    pub iterations: u32,
+    pub elapsed: Duration,
+
+    /// Bytes read in total (without the status)
+    pub bytes: usize,
+}
+
+impl BenchResult {
+    /// Mean time of each iteration
+    pub fn latency(&self) -> Duration {
+        self.elapsed / self.iterations.max(1)
+    }
+
+    /// Bytes read per second
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Send the smallest command (the version query) `iterations` times
+pub fn round_trip<T: Transport + ?Sized>(
+    dev: &T,
+    iterations: u32,
+) -> Result<BenchResult, DriverError> {
+    let mut buf = [0u8; 1024];
+    measure(iterations, || {
+        Ok(dev.run(&Command::GetVersion, &mut buf)?.rest().len())
+    })
+}
+
+/// Read `size` bytes from the start of the flash partition `iterations` times, in a single
+/// command each (see [`Transport::run_vec`])
+pub fn bulk_read<T: Transport + ?Sized>(
+    dev: &T,
+    partition: u8,
+    size: u32,
+    iterations: u32,
+) -> Result<BenchResult, DriverError> {
+    let cmd = Command::ReadFlash {
+        partition,
+        addr: 0,
+        size,
+    };
+    measure(iterations, || {
+        let resp = dev.run_vec(&cmd)?;
+        Ok(Response::parse(&resp)?.rest().len())
+    })
+}
+
+fn measure<F>(iterations: u32, mut op: F) -> Result<BenchResult, DriverError>
+where
+    F: FnMut() -> Result<usize, DriverError>,
+{
+    let mut bytes = 0;
+    let start = Instant::now();
+    for _ in 0..iterations {
+        bytes += op()?;
+    }
+
+    Ok(BenchResult {
+        iterations,
+        elapsed: start.elapsed(),
+        bytes,
+    })
+}
@@ -0,0 +1,321 @@
+//! Every sensor is calibrated at the factory: the gain of each line and the pixels known to be
+//! dead are stored in their own flash partition. Without them the images are too noisy to match,
+//! so a broken (or erased) calibration has to be replaced with [`Calibrate::write_calibration`].
+
+use crate::{
+    DriverError,
+    capture::Frame,
+    flash::{FLASH_CHUNK, Flash},
+    proto::Response,
+    transport::Transport,
+};
+
+/// Partition where the calibration data lives
+pub const CALIBRATION_PARTITION: u8 = 6;
+
+/// The only known version of the calibration format
+const CALIBRATION_VERSION: u16 = 1;
+
+/// The calibration data of a sensor, see [`Calibrate::read_calibration`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Calibration {
+    /// Size of the image the calibration is for
+    pub width: u16,
+    pub height: u16,
+
+    /// Gain applied to each line of the image
+    pub gains: Vec<u8>,
+
+    /// Coordinates (x, y) of the pixels that always read the same value
+    pub dead_pixels: Vec<(u16, u16)>,
+}
+
+impl Calibration {
+    /// Parse the calibration data, the format is: version (u16), width (u16), height (u16), gain
+    /// count (u16), the gains (u8), dead pixel count (u16) and the dead pixels, each one with: x
+    /// (u16) and y (u16). The rest of the partition is ignored.
+    pub fn parse(blob: &[u8]) -> Result<Self, DriverError> {
+        let mut data = Response::raw(blob);
+        let res = Self::parse_fields(&mut data).ok_or(DriverError::CalibrationInvalid)?;
+        if res.width == 0 {
+            return Err(DriverError::CalibrationInvalid);
+        }
+
+        let fits = |(x, y): &(u16, u16)| *x < res.width && *y < res.height;
+        if !res.dead_pixels.iter().all(fits) {
+            return Err(DriverError::CalibrationInvalid);
+        }
+
+        Ok(res)
+    }
+
+    fn parse_fields(data: &mut Response<'_>) -> Option<Self> {
+        if data.u16()? != CALIBRATION_VERSION {
+            return None;
+        }
+
+        let width = data.u16()?;
+        let height = data.u16()?;
+        let count = data.u16()?;
+        let gains = data.bytes(count as usize)?.to_vec();
+
+        let count = data.u16()?;
+        let dead_pixels = (0..count)
+            .map(|_| Some((data.u16()?, data.u16()?)))
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            width,
+            height,
+            gains,
+            dead_pixels,
+        })
+    }
+
+    /// The dead pixels, to repair the frames with (see [`DeadPixelMap::repair`])
+    pub fn dead_pixel_map(&self) -> DeadPixelMap {
+        let mut dead = vec![false; self.width as usize * self.height as usize];
+        for &(x, y) in &self.dead_pixels {
+            if x < self.width && y < self.height {
+                dead[y as usize * self.width as usize + x as usize] = true;
+            }
+        }
+
+        DeadPixelMap {
+            width: self.width,
+            height: self.height,
+            dead,
+        }
+    }
+
+    /// Serialize the calibration data, as stored in the flash. Fails with
+    /// [`DriverError::CalibrationInvalid`] if there are too many gains or dead pixels for their
+    /// count (u16).
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DriverError> {
+        let count = |n: usize| u16::try_from(n).map_err(|_| DriverError::CalibrationInvalid);
+
+        let mut res = Vec::new();
+        res.extend(CALIBRATION_VERSION.to_le_bytes());
+        res.extend(self.width.to_le_bytes());
+        res.extend(self.height.to_le_bytes());
+        res.extend(count(self.gains.len())?.to_le_bytes());
+        res.extend(&self.gains);
+        res.extend(count(self.dead_pixels.len())?.to_le_bytes());
+        for (x, y) in &self.dead_pixels {
+            res.extend(x.to_le_bytes());
+            res.extend(y.to_le_bytes());
+        }
+        Ok(res)
+    }
+}
+
+/// Where the dead pixels are, see [`Calibration::dead_pixel_map`]. Some units have whole columns
+/// of them, they break the stitching and the matching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadPixelMap {
+    width: u16,
+    height: u16,
+
+    /// A pixel after the other, row by row
+    dead: Vec<bool>,
+}
+
+impl DeadPixelMap {
+    /// Number of dead pixels
+    pub fn count(&self) -> usize {
+        self.dead.iter().filter(|d| **d).count()
+    }
+
+    /// Replace the dead pixels with the values interpolated from the closest good ones in their
+    /// row (the dead columns are then filled too). Frames of another width or depth than 8 or 16
+    /// bits are left alone, frames taller than the sensor (stitched) repeat its rows.
+    pub fn repair(&self, frame: &mut Frame) {
+        let width = frame.width as usize;
+        let bytes = match frame.bpp {
+            8 => 1,
+            16 => 2,
+            _ => return,
+        };
+        if frame.width != self.width || width == 0 || self.height == 0 {
+            return;
+        }
+
+        let rows = frame.data.chunks_exact_mut(width * bytes);
+        for (y, row) in rows.take(frame.height as usize).enumerate() {
+            let dead = &self.dead[(y % self.height as usize) * width..][..width];
+            if !dead.contains(&true) {
+                continue;
+            }
+
+            let get = |row: &[u8], x: usize| match bytes {
+                1 => row[x] as f32,
+                _ => u16::from_le_bytes([row[2 * x], row[2 * x + 1]]) as f32,
+            };
+            for x in (0..width).filter(|&x| dead[x]) {
+                let left = (0..x).rev().find(|&i| !dead[i]);
+                let right = (x + 1..width).find(|&i| !dead[i]);
+                let value = match (left, right) {
+                    (Some(l), Some(r)) => {
+                        let t = (x - l) as f32 / (r - l) as f32;
+                        get(row, l) + (get(row, r) - get(row, l)) * t
+                    }
+                    (Some(i), None) | (None, Some(i)) => get(row, i),
+                    // The whole row is dead
+                    (None, None) => continue,
+                };
+
+                match bytes {
+                    1 => row[x] = value.round() as u8,
+                    _ => {
+                        row[2 * x..2 * x + 2].copy_from_slice(&(value.round() as u16).to_le_bytes())
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Calibration read and write, implemented for every [`Transport`]
+pub trait Calibrate: Flash {
+    /// Read the whole calibration partition, as is
+    fn read_calibration_blob(&self) -> Result<Vec<u8>, DriverError> {
+        let size = self.calibration_size()?;
+        self.read_flash(CALIBRATION_PARTITION, 0, size)
+    }
+
+    /// Read and parse the calibration data
+    fn read_calibration(&self) -> Result<Calibration, DriverError> {
+        Calibration::parse(&self.read_calibration_blob()?)
+    }
+
+    /// Replace the calibration data, it is read back to check it was written correctly
+    fn write_calibration(&self, calib: &Calibration) -> Result<(), DriverError> {
+        let blob = calib.to_bytes()?;
+        if blob.len() > self.calibration_size()? {
+            return Err(DriverError::CalibrationInvalid);
+        }
+
+        self.erase_flash(CALIBRATION_PARTITION)?;
+        for (i, chunk) in blob.chunks(FLASH_CHUNK).enumerate() {
+            self.write_flash(CALIBRATION_PARTITION, (i * FLASH_CHUNK) as u32, chunk)?;
+        }
+
+        if self.read_flash(CALIBRATION_PARTITION, 0, blob.len())? != blob {
+            return Err(DriverError::CalibrationVerifyFailed);
+        }
+        Ok(())
+    }
+
+    /// Size of the calibration partition
+    fn calibration_size(&self) -> Result<usize, DriverError> {
+        self.partition_table()?
+            .find(CALIBRATION_PARTITION)
+            .map(|p| p.size as usize)
+            .ok_or(DriverError::CalibrationMissing)
+    }
+}
+
+impl<T: Transport + ?Sized> Calibrate for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration() -> Calibration {
+        Calibration {
+            width: 4,
+            height: 2,
+            gains: vec![1, 2],
+            dead_pixels: vec![(1, 0), (3, 1)],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let calib = calibration();
+        let mut blob = calib.to_bytes().unwrap();
+        // The rest of the partition
+        blob.extend([0xff; 16]);
+        assert_eq!(Calibration::parse(&blob).unwrap(), calib);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut calib = calibration();
+        calib.dead_pixels.push((4, 0));
+        let blob = calib.to_bytes().unwrap();
+        assert!(Calibration::parse(&blob).is_err());
+
+        let mut calib = calibration();
+        calib.width = 0;
+        calib.dead_pixels.clear();
+        let blob = calib.to_bytes().unwrap();
+        assert!(Calibration::parse(&blob).is_err());
+
+        let blob = calibration().to_bytes().unwrap();
+        assert!(Calibration::parse(&blob[..blob.len() - 1]).is_err());
+
+        let mut calib = calibration();
+        calib.gains = vec![0; 0x10000];
+        assert!(calib.to_bytes().is_err());
+    }
+
+    #[test]
+    fn repair() {
+        let map = calibration().dead_pixel_map();
+        assert_eq!(map.count(), 2);
+
+        let mut frame = Frame {
+            width: 4,
+            height: 4,
+            bpp: 8,
+            data: vec![
+                10, 0, 30, 40, //
+                10, 20, 30, 0, //
+                10, 0, 50, 40, //
+                10, 20, 30, 0, //
+            ],
+        };
+        map.repair(&mut frame);
+        // Interpolated between the neighbours, the last column copies its left one, the
+        // stitched rows repeat the map
+        assert_eq!(
+            frame.data,
+            [
+                10, 20, 30, 40, 10, 20, 30, 30, 10, 30, 50, 40, 10, 20, 30, 30
+            ]
+        );
+    }
+
+    #[test]
+    fn repair_16_bits() {
+        let map = calibration().dead_pixel_map();
+        let mut frame = Frame {
+            width: 4,
+            height: 1,
+            bpp: 16,
+            data: [1000u16, 0, 3000, 4000]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect(),
+        };
+        map.repair(&mut frame);
+        assert_eq!(frame.data[2..4], 2000u16.to_le_bytes());
+    }
+
+    #[test]
+    fn repair_empty() {
+        let map = DeadPixelMap {
+            width: 0,
+            height: 1,
+            dead: vec![],
+        };
+        let mut frame = Frame {
+            width: 0,
+            height: 1,
+            bpp: 8,
+            data: vec![],
+        };
+        map.repair(&mut frame);
+    }
+}
@@ -0,0 +1,112 @@
+//! Enrolling, capturing or writing the flash can block for many seconds. A [`CancelToken`] is
+//! checked between the USB transfers of those operations, so another thread (a UI) can abort
+//! them, they fail with [`DriverError::Cancelled`].
+
+use crate::{
+    DriverError,
+    timeouts::time_left,
+    transport::{INT_SCAN_COMPLETE, Transport},
+};
+use core::time::Duration;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+/// How often the token is checked while waiting for a finger
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Cancels an operation from another thread, clones share the same state
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the operations using this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [`Self::cancel`] was called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Fail if the operation was cancelled, nothing to check without a token
+pub(crate) fn check(cancel: Option<&CancelToken>) -> Result<(), DriverError> {
+    match cancel {
+        Some(cancel) if cancel.is_cancelled() => Err(DriverError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// Like [`Transport::wait_scan`], but the interrupt is read in short slices to check the token
+pub(crate) fn wait_scan<T: Transport + ?Sized>(
+    dev: &T,
+    cancel: Option<&CancelToken>,
+) -> Result<(), DriverError> {
+    let Some(cancel) = cancel else {
+        return dev.wait_scan();
+    };
+
+    let deadline = Instant::now() + dev.touch_timeout();
+    let mut int = [0u8; 64];
+
+    loop {
+        check(Some(cancel))?;
+
+        let Some(left) = time_left(deadline) else {
+            return Err(DriverError::UsbReadInterrupt(rusb::Error::Timeout));
+        };
+
+        match dev.wait_int(&mut int, left.min(POLL_INTERVAL)) {
+            Ok(len) if len > 0 && int[0] == INT_SCAN_COMPLETE => return Ok(()),
+            Ok(_) | Err(DriverError::UsbReadInterrupt(rusb::Error::Timeout)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn wait_scan_cancelled() {
+        let dev = MockTransport::new(&crate::SUPPORTED[0]).interrupt(&[INT_SCAN_COMPLETE]);
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        assert!(matches!(
+            wait_scan(&dev, Some(&cancel)),
+            Err(DriverError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn wait_scan_deadline() {
+        // Less than a millisecond left would be an endless wait for libusb
+        let dev = MockTransport::new(&crate::SUPPORTED[0])
+            .with_touch_timeout(Duration::from_micros(500))
+            .interrupt(&[INT_SCAN_COMPLETE]);
+        assert!(matches!(
+            wait_scan(&dev, Some(&CancelToken::new())),
+            Err(DriverError::UsbReadInterrupt(rusb::Error::Timeout))
+        ));
+    }
+
+    #[test]
+    fn wait_scan_complete() {
+        let dev = MockTransport::new(&crate::SUPPORTED[0])
+            .interrupt(&[0x00])
+            .interrupt(&[INT_SCAN_COMPLETE]);
+        wait_scan(&dev, Some(&CancelToken::new())).unwrap();
+    }
+}
@@ -0,0 +1,482 @@
+use crate::{
+    DriverError,
+    cancel::{self, CancelToken},
+    integrity,
+    normalize::{Background, Normalize},
+    pool::BufferPool,
+    proto::{Command, Response, responses::FrameHeader},
+    quirks::SensorType,
+    sink::FrameSink,
+    stitch::Stitcher,
+    timeouts::time_left,
+    trace::warning,
+    transport::{INT_FINGER_DOWN, INT_FINGER_UP, INT_SCAN_COMPLETE, Transport},
+};
+use core::time::Duration;
+use std::{
+    io::{self, Write},
+    time::Instant,
+};
+
+pub use crate::proto::CaptureMode;
+
+/// Size of each bulk read
+pub(crate) const CHUNK_SIZE: usize = 1024 * 16;
+
+/// A raw image captured by the sensor
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub width: u16,
+    pub height: u16,
+
+    /// Bits per pixel
+    pub bpp: u8,
+
+    /// The pixels, row by row
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Write the frame as a binary PGM, only 8 and 16 bits per pixel are supported
+    pub fn write_pgm<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let maxval = match self.bpp {
+            8 => u8::MAX as u16,
+            16 => u16::MAX,
+            bpp => return Err(unsupported_bpp(bpp)),
+        };
+
+        write!(out, "P5\n{} {}\n{maxval}\n", self.width, self.height)?;
+        out.write_all(&self.big_endian())
+    }
+
+    /// Write the frame as a grayscale PNG, only 8 and 16 bits per pixel are supported
+    #[cfg(feature = "png")]
+    pub fn write_png<W: Write>(&self, out: W) -> io::Result<()> {
+        let depth = match self.bpp {
+            8 => png::BitDepth::Eight,
+            16 => png::BitDepth::Sixteen,
+            bpp => return Err(unsupported_bpp(bpp)),
+        };
+
+        let mut enc = png::Encoder::new(out, self.width as u32, self.height as u32);
+        enc.set_color(png::ColorType::Grayscale);
+        enc.set_depth(depth);
+        enc.write_header()?.write_image_data(&self.big_endian())?;
+        Ok(())
+    }
+
+    /// The pixels, with 16 bit ones swapped to big endian as image formats want them
+    pub(crate) fn big_endian(&self) -> Vec<u8> {
+        match self.bpp {
+            16 => self
+                .data
+                .chunks_exact(2)
+                .flat_map(|px| [px[1], px[0]])
+                .collect(),
+            _ => self.data.clone(),
+        }
+    }
+}
+
+fn unsupported_bpp(bpp: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{bpp} bits per pixel are not supported"),
+    )
+}
+
+/// How the finger was scanned, in the records of the standards (see [`crate::iso_image`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Impression {
+    /// Pressed on a sensor
+    LivePlain = 0,
+
+    /// Swiped over a line sensor
+    LiveSwipe = 8,
+}
+
+impl Impression {
+    /// The impression type of a record, `None` for the ones not made by a sensor like these
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::LivePlain),
+            8 => Some(Self::LiveSwipe),
+            _ => None,
+        }
+    }
+}
+
+impl From<SensorType> for Impression {
+    fn from(sensor: SensorType) -> Self {
+        match sensor {
+            SensorType::Press => Self::LivePlain,
+            SensorType::Swipe => Self::LiveSwipe,
+        }
+    }
+}
+
+/// Result of [`Capture::wait_for_finger`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerWait {
+    /// A finger touched the sensor
+    Detected,
+
+    /// Nobody touched the sensor in time
+    TimedOut,
+}
+
+/// Capture of raw images, implemented for every [`Transport`]
+pub trait Capture: Transport {
+    /// Wait for a finger and read the raw image
+    /// On swipe sensors the frames read while the finger moves are stitched, see [`Stitcher`].
+    fn capture(&self) -> Result<Frame, DriverError> {
+        capture(self, None)
+    }
+
+    /// Like [`Self::capture`], but stops waiting for the finger once the token is cancelled. The
+    /// capture is stopped on every error, so the sensor is left idle.
+    fn capture_with(&self, cancel: &CancelToken) -> Result<Frame, DriverError> {
+        capture(self, Some(cancel))
+    }
+
+    /// Like [`Self::capture`], but the frame is turned into a clean 8 bits image, see
+    /// [`Normalize`]
+    fn capture_normalized(&self, opts: &Normalize) -> Result<Frame, DriverError> {
+        Ok(opts.apply(&self.capture()?))
+    }
+
+    /// Read `frames` frames right away, without waiting for a finger, and average them into the
+    /// background subtracted by [`Normalize::background`]. Nobody should touch the sensor.
+    fn capture_background(&self, frames: u32) -> Result<Background, DriverError> {
+        let mut res = Vec::new();
+        let armed = IdleGuard::new(self, Command::CaptureStop);
+        for _ in 0..frames.max(1) {
+            arm_capture(self, CaptureMode::Image)?;
+            res.push(self.read_frame()?);
+        }
+        armed.disarm();
+        self.run(&Command::CaptureStop, &mut [0u8; 64])?;
+
+        Background::from_frames(&res).ok_or(DriverError::CaptureInvalidResponse)
+    }
+
+    /// Arm the sensor and wait until a finger touches it, at most `timeout`. Once detected the
+    /// scan goes on, its frame can be read with [`Self::read_frame`], otherwise the capture is
+    /// stopped.
+    fn wait_for_finger(&self, timeout: Duration) -> Result<FingerWait, DriverError> {
+        arm_capture(self, CaptureMode::Image)?;
+        let armed = IdleGuard::new(self, Command::CaptureStop);
+
+        let deadline = Instant::now() + timeout;
+        let mut int = [0u8; 64];
+        loop {
+            let Some(left) = time_left(deadline) else {
+                return Ok(FingerWait::TimedOut);
+            };
+
+            match self.wait_int(&mut int, left) {
+                Ok(len) if len > 0 && matches!(int[0], INT_FINGER_DOWN | INT_SCAN_COMPLETE) => {
+                    armed.disarm();
+                    return Ok(FingerWait::Detected);
+                }
+                Ok(_) => {}
+                Err(DriverError::UsbReadInterrupt(rusb::Error::Timeout)) => {
+                    return Ok(FingerWait::TimedOut);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The frames scanned while a finger is on the sensor, the stream ends once it is lifted.
+    /// The sensor is armed on the first call to `next` and disarmed when the stream ends (or is
+    /// dropped), waiting for the finger times out like [`Self::capture`].
+    fn stream_frames(&self) -> FrameStream<'_, Self> {
+        FrameStream {
+            dev: self,
+            started: false,
+            armed: false,
+        }
+    }
+
+    /// Push the frames of [`Self::stream_frames`] into the sink until the finger is lifted (or
+    /// the sink wants no more), returns how many were pushed
+    fn stream_into<S: FrameSink>(&self, sink: &mut S) -> Result<usize, DriverError> {
+        let mut count = 0;
+        for frame in self.stream_frames() {
+            count += 1;
+            if !sink.push(&frame?) {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Read the last captured frame, the data may be split in several bulk reads. The dead pixels
+    /// are repaired if the transport knows them, see [`Transport::dead_pixels`].
+    fn read_frame(&self) -> Result<Frame, DriverError> {
+        let mut frame = Frame {
+            width: 0,
+            height: 0,
+            bpp: 0,
+            data: Vec::new(),
+        };
+        self.read_frame_into(&mut frame)?;
+        Ok(frame)
+    }
+
+    /// Like [`Self::read_frame`], but the frame is read into `frame`, reusing the memory of its
+    /// data (for continuous capture)
+    fn read_frame_into(&self, frame: &mut Frame) -> Result<(), DriverError> {
+        let mut buf = BufferPool::global().get(CHUNK_SIZE);
+        let mut resp = self.run(&Command::ReadFrame, &mut buf)?;
+        let (width, height, bpp, total) =
+            parse_header(&mut resp).ok_or(DriverError::CaptureInvalidResponse)?;
+
+        let data = &mut frame.data;
+        data.clear();
+        // The size comes from the device, grown as the data arrives
+        data.reserve(total.min(CHUNK_SIZE));
+        integrity::append(data, resp.rest(), total).map_err(DriverError::Integrity)?;
+
+        while data.len() < total {
+            let len = self.read(&mut buf)?;
+            if len == 0 {
+                return Err(DriverError::CaptureIncomplete(data.len(), total));
+            }
+            integrity::append(data, &buf[..len], total).map_err(DriverError::Integrity)?;
+        }
+
+        frame.width = width;
+        frame.height = height;
+        frame.bpp = bpp;
+        if let Some(map) = self.dead_pixels() {
+            map.repair(frame);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Capture for T {}
+
+/// Frames read while a finger is on the sensor, see [`Capture::stream_frames`]
+#[derive(Debug)]
+pub struct FrameStream<'a, T: Transport + ?Sized> {
+    dev: &'a T,
+    started: bool,
+
+    /// Not disarmed yet
+    armed: bool,
+}
+
+impl<T: Transport + ?Sized> FrameStream<'_, T> {
+    /// Wait for the next scan, `None` once the finger is lifted
+    fn next_frame(&self) -> Result<Option<Frame>, DriverError> {
+        let mut int = [0u8; 64];
+        loop {
+            let len = self.dev.wait_int(&mut int, self.dev.touch_timeout())?;
+            match int[..len].first().copied() {
+                Some(INT_SCAN_COMPLETE) => {
+                    let frame = self.dev.read_frame()?;
+                    // Swipe sensors keep scanning, the others scan once per arm
+                    if self.dev.quirks().sensor_type == SensorType::Press {
+                        arm_capture(self.dev, CaptureMode::Image)?;
+                    }
+                    return Ok(Some(frame));
+                }
+                Some(INT_FINGER_UP) => return Ok(None),
+                _ => (),
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        self.armed = false;
+        if let Err(_e) = self.dev.run(&Command::CaptureStop, &mut [0u8; 64]) {
+            warning!(error = %_e, "could not stop the capture");
+        }
+    }
+}
+
+impl<T: Transport + ?Sized> Iterator for FrameStream<'_, T> {
+    type Item = Result<Frame, DriverError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            if let Err(e) = arm_capture(self.dev, CaptureMode::Image) {
+                return Some(Err(e));
+            }
+            self.armed = true;
+        }
+
+        if !self.armed {
+            return None;
+        }
+
+        match self.next_frame() {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => {
+                self.stop();
+                None
+            }
+            Err(e) => {
+                self.stop();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<T: Transport + ?Sized> Drop for FrameStream<'_, T> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.stop();
+        }
+    }
+}
+
+fn capture<T: Transport + ?Sized>(
+    dev: &T,
+    cancel: Option<&CancelToken>,
+) -> Result<Frame, DriverError> {
+    arm_capture(dev, CaptureMode::Image)?;
+    let armed = IdleGuard::new(dev, Command::CaptureStop);
+    let frame = scan(dev, cancel)?;
+    armed.disarm();
+    Ok(frame)
+}
+
+/// Wait for the finger and read the frame, the sensor is armed already
+fn scan<T: Transport + ?Sized>(
+    dev: &T,
+    cancel: Option<&CancelToken>,
+) -> Result<Frame, DriverError> {
+    cancel::wait_scan(dev, cancel)?;
+    cancel::check(cancel)?;
+
+    if dev.quirks().sensor_type == SensorType::Press {
+        return dev.read_frame();
+    }
+
+    // Swipe sensors send a frame for every few lines, until the finger leaves
+    let mut stitcher = Stitcher::new();
+    let mut frame = dev.read_frame()?;
+    stitcher.push(&frame)?;
+
+    let mut int = [0u8; 64];
+    loop {
+        cancel::check(cancel)?;
+        let len = dev.wait_int(&mut int, dev.touch_timeout())?;
+        match int[..len].first().copied() {
+            Some(INT_SCAN_COMPLETE) => {
+                dev.read_frame_into(&mut frame)?;
+                stitcher.push(&frame)?;
+            }
+            Some(INT_FINGER_UP) => return Ok(stitcher.finish()),
+            _ => (),
+        }
+    }
+}
+
+/// Sends the command once dropped, unless disarmed: the sensor is left idle after an error (or
+/// a cancellation) instead of waiting for a finger
+pub(crate) struct IdleGuard<'a, T: Transport + ?Sized> {
+    dev: &'a T,
+    stop: Option<Command<'static>>,
+}
+
+impl<'a, T: Transport + ?Sized> IdleGuard<'a, T> {
+    pub(crate) fn new(dev: &'a T, stop: Command<'static>) -> Self {
+        Self {
+            dev,
+            stop: Some(stop),
+        }
+    }
+
+    /// The operation finished, nothing to send
+    pub(crate) fn disarm(mut self) {
+        self.stop = None;
+    }
+}
+
+impl<T: Transport + ?Sized> Drop for IdleGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            // Best effort, the error that got us here is what matters to the caller
+            if let Err(_e) = self.dev.run(&stop, &mut [0u8; 64]) {
+                warning!(error = %_e, "could not leave the sensor idle");
+            }
+        }
+    }
+}
+
+/// Arm the sensor for the next scan
+pub(crate) fn arm_capture<T: Transport + ?Sized>(
+    dev: &T,
+    mode: CaptureMode,
+) -> Result<(), DriverError> {
+    dev.run(&Command::CaptureStart(mode), &mut [0u8; 64])?;
+    Ok(())
+}
+
+/// The header sent before the frame data (see [`FrameHeader`]), its size must match
+/// the size of the image
+pub(crate) fn parse_header(resp: &mut Response<'_>) -> Option<(u16, u16, u8, usize)> {
+    let FrameHeader {
+        width,
+        height,
+        bpp,
+        size,
+    } = FrameHeader::parse(resp)?;
+
+    let expected = (width as usize * height as usize * bpp as usize).div_ceil(8);
+    (size as usize == expected).then_some((width, height, bpp, size as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    /// A device expecting the capture to be armed
+    fn armed() -> MockTransport {
+        MockTransport::new(&crate::SUPPORTED[0]).expect(
+            &Command::CaptureStart(CaptureMode::Image).to_bytes(),
+            &[0, 0],
+        )
+    }
+
+    #[test]
+    fn finger_detected() {
+        let dev = armed().interrupt(&[INT_FINGER_DOWN]);
+        assert_eq!(
+            dev.wait_for_finger(Duration::from_secs(1)).unwrap(),
+            FingerWait::Detected
+        );
+        assert!(dev.is_done());
+    }
+
+    #[test]
+    fn finger_timed_out() {
+        let dev = armed().expect(&Command::CaptureStop.to_bytes(), &[0, 0]);
+        assert_eq!(
+            dev.wait_for_finger(Duration::from_secs(1)).unwrap(),
+            FingerWait::TimedOut
+        );
+        assert!(dev.is_done());
+    }
+
+    #[test]
+    fn finger_deadline() {
+        let dev = armed()
+            .expect(&Command::CaptureStop.to_bytes(), &[0, 0])
+            .interrupt(&[INT_FINGER_DOWN]);
+        assert_eq!(
+            dev.wait_for_finger(Duration::from_micros(500)).unwrap(),
+            FingerWait::TimedOut
+        );
+        assert!(dev.is_done());
+    }
+}
@@ -0,0 +1,366 @@
+use crate::{
+    DriverError,
+    cancel::{self, CancelToken},
+    capture::{CaptureMode, IdleGuard, arm_capture},
+    metadata::MetadataStore,
+    proto::{Command, Response, StatusCode, responses},
+    storage::{Storage, UserNamespace},
+    transport::Transport,
+};
+
+/// Give up after this many samples
+const MAX_SAMPLES: u32 = 32;
+
+/// Samples with a lower quality (as reported by the sensor) are reported as rejected
+const MIN_SAMPLE_QUALITY: u16 = 25;
+
+/// The ID of a template stored on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TemplateId(pub u16);
+
+/// Fingers, in the order used by fprintd (the id stored with the templates)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum Finger {
+    LeftThumb = 1,
+    LeftIndex,
+    LeftMiddle,
+    LeftRing,
+    LeftLittle,
+    RightThumb,
+    RightIndex,
+    RightMiddle,
+    RightRing,
+    RightLittle,
+}
+
+impl Finger {
+    const ALL: [Self; 10] = [
+        Self::LeftThumb,
+        Self::LeftIndex,
+        Self::LeftMiddle,
+        Self::LeftRing,
+        Self::LeftLittle,
+        Self::RightThumb,
+        Self::RightIndex,
+        Self::RightMiddle,
+        Self::RightRing,
+        Self::RightLittle,
+    ];
+
+    /// The finger stored in the templates, see [`Enroll::enroll`]
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get((id as usize).checked_sub(1)?).copied()
+    }
+
+    /// The finger position of the ISO/IEC 19794 and ANSI records: the right hand first, from the
+    /// thumb
+    pub fn iso_position(self) -> u8 {
+        match self as u8 {
+            id @ 1..=5 => id + 5,
+            id => id - 5,
+        }
+    }
+
+    /// The finger at the position, `None` if unknown (0) or not a single finger
+    pub fn from_iso_position(position: u8) -> Option<Self> {
+        match position {
+            1..=5 => Self::from_id(position + 5),
+            6..=10 => Self::from_id(position - 5),
+            _ => None,
+        }
+    }
+}
+
+/// Progress report, sent after every sample
+#[derive(Debug, Clone, Copy)]
+pub struct EnrollProgress {
+    /// The number of the sample (starting at 1)
+    pub sample: u32,
+
+    /// Remaining samples, as estimated by the sensor
+    pub remaining: u16,
+
+    /// Quality of the sample (0-100)
+    pub quality: u16,
+
+    /// Percentage of the finger covered up to now (0-100)
+    pub coverage: u16,
+}
+
+/// Why a sample did not help the enrollment, see [`EnrollEvent::SampleRejected`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The image was bad (finger too dry, wet or moved), touch again
+    LowQuality,
+
+    /// The same area of the finger was scanned again, move the finger slightly
+    NoNewArea,
+}
+
+/// What happened during an enrollment, see [`Enroll::enroll_events`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnrollEvent {
+    /// The sample was used, `remaining` more are needed (as estimated by the sensor)
+    SampleAccepted { remaining: u16 },
+
+    /// The sample was not used, the user should touch the sensor again
+    SampleRejected { reason: RejectReason },
+
+    /// The template was stored on the device
+    Completed { template_id: TemplateId },
+}
+
+impl EnrollEvent {
+    /// Classify a sample, comparing it with the previous one. The sensor does not say whether
+    /// it used a sample: it did if less samples remain or the coverage grew. `prev` is the
+    /// progress of the previous sample, if any.
+    pub fn from_progress(prev: Option<&EnrollProgress>, progress: &EnrollProgress) -> Self {
+        let remaining = progress.remaining;
+        if progress.quality < MIN_SAMPLE_QUALITY {
+            return Self::SampleRejected {
+                reason: RejectReason::LowQuality,
+            };
+        }
+
+        match prev {
+            Some(prev) if remaining >= prev.remaining && progress.coverage <= prev.coverage => {
+                Self::SampleRejected {
+                    reason: RejectReason::NoNewArea,
+                }
+            }
+            _ => Self::SampleAccepted { remaining },
+        }
+    }
+}
+
+/// Enrollment of new fingers, implemented for every [`Transport`]
+pub trait Enroll: Transport {
+    /// Enroll a new finger, the user should touch the sensor several times (`progress_cb` will be
+    /// called after each touch). Returns the id of the template stored on the device.
+    fn enroll<F>(&self, finger_id: u8, progress_cb: F) -> Result<TemplateId, DriverError>
+    where
+        F: FnMut(EnrollProgress),
+    {
+        enroll(
+            self,
+            UserNamespace::SHARED,
+            finger_id,
+            None,
+            false,
+            progress_cb,
+        )
+    }
+
+    /// Like [`Self::enroll`], but the template is stored in the user's namespace, see
+    /// [`crate::identify::Identify::identify_in`]
+    fn enroll_in<F>(
+        &self,
+        namespace: UserNamespace,
+        finger_id: u8,
+        progress_cb: F,
+    ) -> Result<TemplateId, DriverError>
+    where
+        F: FnMut(EnrollProgress),
+    {
+        enroll(self, namespace, finger_id, None, false, progress_cb)
+    }
+
+    /// Like [`Self::enroll_in`], but stops once the token is cancelled, see [`Self::enroll_with`]
+    fn enroll_in_with<F>(
+        &self,
+        namespace: UserNamespace,
+        finger_id: u8,
+        cancel: &CancelToken,
+        progress_cb: F,
+    ) -> Result<TemplateId, DriverError>
+    where
+        F: FnMut(EnrollProgress),
+    {
+        enroll(self, namespace, finger_id, Some(cancel), false, progress_cb)
+    }
+
+    /// Like [`Self::enroll_in`], but when there is no room left the templates enrolled from
+    /// Windows are deleted until the new one fits, see [`Storage::reclaim_windows_slot`]
+    fn enroll_reclaiming<F>(
+        &self,
+        namespace: UserNamespace,
+        finger_id: u8,
+        progress_cb: F,
+    ) -> Result<TemplateId, DriverError>
+    where
+        F: FnMut(EnrollProgress),
+    {
+        enroll(self, namespace, finger_id, None, true, progress_cb)
+    }
+
+    /// Like [`Self::enroll`], but stops once the token is cancelled, the enrollment is ended so
+    /// the device goes back to idle
+    fn enroll_with<F>(
+        &self,
+        finger_id: u8,
+        cancel: &CancelToken,
+        progress_cb: F,
+    ) -> Result<TemplateId, DriverError>
+    where
+        F: FnMut(EnrollProgress),
+    {
+        enroll(
+            self,
+            UserNamespace::SHARED,
+            finger_id,
+            Some(cancel),
+            false,
+            progress_cb,
+        )
+    }
+
+    /// Like [`Self::enroll`], but reports what happened with each sample (and the end of the
+    /// enrollment) as an [`EnrollEvent`], so the user can get hints. To send the events to a
+    /// channel pass `|ev| { let _ = tx.send(ev); }`.
+    fn enroll_events<F>(&self, finger_id: u8, mut event_cb: F) -> Result<TemplateId, DriverError>
+    where
+        F: FnMut(EnrollEvent),
+    {
+        let mut prev = None;
+        let template_id = self.enroll(finger_id, |p| {
+            event_cb(EnrollEvent::from_progress(prev.as_ref(), &p));
+            prev = Some(p);
+        })?;
+
+        event_cb(EnrollEvent::Completed { template_id });
+        Ok(template_id)
+    }
+
+    /// Enroll the finger for the user, in their namespace (see
+    /// [`MetadataStore::allocate_namespace`]). The template is recorded in the store so matches
+    /// can be mapped back to the user (see [`MetadataStore::owner`]).
+    fn enroll_for<F>(
+        &self,
+        finger: Finger,
+        user: &str,
+        store: &MetadataStore,
+        progress_cb: F,
+    ) -> Result<TemplateId, DriverError>
+    where
+        F: FnMut(EnrollProgress),
+    {
+        let namespace = store.allocate_namespace(user)?;
+        let id = self.enroll_in(namespace, finger as u8, progress_cb)?;
+        store.add(user, id)?;
+        Ok(id)
+    }
+}
+
+impl<T: Transport + ?Sized> Enroll for T {}
+
+fn enroll<T, F>(
+    dev: &T,
+    namespace: UserNamespace,
+    finger_id: u8,
+    cancel: Option<&CancelToken>,
+    reclaim: bool,
+    mut progress_cb: F,
+) -> Result<TemplateId, DriverError>
+where
+    T: Transport + ?Sized,
+    F: FnMut(EnrollProgress),
+{
+    let mut buf = vec![0u8; 1024 * 8];
+    dev.run(&Command::Enroll(true), &mut buf)?;
+
+    // Ends the enrollment if the samples can't be collected
+    let enrolling = IdleGuard::new(dev, Command::Enroll(false));
+    let template = collect_samples(dev, cancel, &mut progress_cb, &mut buf)?;
+    enrolling.disarm();
+
+    dev.run(&Command::Enroll(false), &mut buf)?;
+
+    let template = template.ok_or(DriverError::EnrollIncomplete(MAX_SAMPLES))?;
+
+    let cmd = Command::NewFinger {
+        user: namespace.0,
+        finger_id,
+        template: &template,
+    };
+    loop {
+        match dev.run(&cmd, &mut buf) {
+            Ok(mut resp) => {
+                let new = responses::NewRecord::parse(&mut resp)
+                    .ok_or(DriverError::EnrollInvalidResponse)?;
+                return Ok(TemplateId(new.record));
+            }
+            Err(e)
+                if matches!(
+                    e.root(),
+                    DriverError::UsbInitFailed(StatusCode::StorageFull)
+                ) =>
+            {
+                // The template is kept by the host, it can be stored again once there is room
+                if reclaim && dev.reclaim_windows_slot()?.is_some() {
+                    continue;
+                }
+                let windows = dev.list_windows_templates()?.len();
+                return Err(DriverError::StorageFull { windows });
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Scan samples until the sensor sends the template id, `None` if it never did
+fn collect_samples<T, F>(
+    dev: &T,
+    cancel: Option<&CancelToken>,
+    progress_cb: &mut F,
+    buf: &mut [u8],
+) -> Result<Option<Vec<u8>>, DriverError>
+where
+    T: Transport + ?Sized,
+    F: FnMut(EnrollProgress),
+{
+    let mut key = 0u32;
+
+    for sample in 1..=MAX_SAMPLES {
+        cancel::check(cancel)?;
+        arm_capture(dev, CaptureMode::Enroll)?;
+        let armed = IdleGuard::new(dev, Command::CaptureStop);
+        cancel::wait_scan(dev, cancel)?;
+        armed.disarm();
+
+        let mut resp = dev.run(&Command::EnrollUpdateStart { key }, buf)?;
+        key = responses::EnrollKey::parse(&mut resp)
+            .ok_or(DriverError::EnrollInvalidResponse)?
+            .key;
+
+        let resp = dev.run(&Command::EnrollUpdate { key }, buf)?;
+        let (progress, tid) =
+            parse_update(resp, sample).ok_or(DriverError::EnrollInvalidResponse)?;
+
+        progress_cb(progress);
+
+        if let Some(tid) = tid {
+            return Ok(Some(tid.to_vec()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// See [`responses::EnrollUpdate`], the template id follows (only once the enrollment finished)
+fn parse_update(mut resp: Response<'_>, sample: u32) -> Option<(EnrollProgress, Option<&[u8]>)> {
+    let update = responses::EnrollUpdate::parse(&mut resp)?;
+    let progress = EnrollProgress {
+        sample,
+        quality: update.quality,
+        coverage: update.coverage,
+        remaining: update.remaining,
+    };
+
+    let tid_len = update.template_size as usize;
+    let tid = resp.bytes(tid_len)?;
+
+    Some((progress, (tid_len > 0).then_some(tid)))
+}
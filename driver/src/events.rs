@@ -0,0 +1,435 @@
+//! The sensor tells when a finger touches it (and leaves) through the interrupt endpoint, a
+//! [`FingerListener`] reads it in the background so nobody has to poll. Sensors only used for
+//! images can be watched instead, see [`PresenceDetector`] and [`FingerListener::spawn_polling`].
+//!
+//! A finger that bounces on the sensor sends several events, and a user failing to verify would
+//! keep the sensor busy: [`Debounced`] filters the events with a [`Debouncer`].
+
+use crate::{
+    DriverError,
+    capture::{Capture, CaptureMode, Frame, arm_capture},
+    quality::quality,
+    trace::debug,
+    transport::{INT_FINGER_DOWN, INT_FINGER_UP, Transport},
+};
+use core::time::Duration;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+/// How often the listener checks if it should stop
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Coverage (see [`quality`]) from which a finger is on the sensor
+const PRESENT_COVERAGE: u8 = 25;
+
+/// Coverage under which the finger was removed, lower than [`PRESENT_COVERAGE`] so a frame at
+/// the limit does not toggle the state back and forth
+const ABSENT_COVERAGE: u8 = 10;
+
+type EventSender = Sender<Result<FingerEvent, DriverError>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerEvent {
+    /// A finger touched the sensor
+    Down,
+
+    /// The finger was removed
+    Up,
+}
+
+/// Tells when a finger lands and lifts from the frames, for sensors read without the interrupts:
+/// the ridges make the covered part of the frame vary
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PresenceDetector {
+    present: bool,
+}
+
+impl PresenceDetector {
+    /// A detector starting without a finger
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a finger was on the sensor in the last frame
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// Look at the next frame, returns an event if the finger landed or lifted
+    pub fn push(&mut self, frame: &Frame) -> Option<FingerEvent> {
+        let coverage = quality(frame).coverage;
+
+        match self.present {
+            false if coverage >= PRESENT_COVERAGE => {
+                self.present = true;
+                Some(FingerEvent::Down)
+            }
+            true if coverage < ABSENT_COVERAGE => {
+                self.present = false;
+                Some(FingerEvent::Up)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How the finger events are filtered, see [`Debouncer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Debounce {
+    settle: Duration,
+    max_failures: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Debounce {
+    /// Touches within 300ms are the same one, after 3 failures in a row the touches are ignored
+    /// for 1s, doubled after every other failure up to 30s
+    pub fn new() -> Self {
+        Self {
+            settle: Duration::from_millis(300),
+            max_failures: 3,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+
+    /// A touch this soon after the previous one is the finger bouncing, it is ignored
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Failed attempts in a row before the touches are ignored for a while, 0 to never ignore
+    /// them
+    pub fn max_failures(mut self, max: u32) -> Self {
+        self.max_failures = max;
+        self
+    }
+
+    /// How long the touches are ignored after the first failure over the limit, doubled after
+    /// every other one up to `max`
+    pub fn backoff(mut self, backoff: Duration, max: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max;
+        self
+    }
+}
+
+impl Default for Debounce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Turns the raw finger events into one [`FingerEvent::Down`] per touch, and holds them back
+/// while the user is throttled after failing too many times (see [`Debounce`]). The caller
+/// reports the result of each attempt with [`Self::record`].
+#[derive(Debug, Clone)]
+pub struct Debouncer {
+    opts: Debounce,
+    down: bool,
+    last_down: Option<Instant>,
+    failures: u32,
+    throttled_until: Option<Instant>,
+}
+
+impl Debouncer {
+    pub fn new(opts: Debounce) -> Self {
+        Self {
+            opts,
+            down: false,
+            last_down: None,
+            failures: 0,
+            throttled_until: None,
+        }
+    }
+
+    /// Filter the event, `None` if it is a bounce, a repeated one or the user is throttled
+    pub fn push(&mut self, ev: FingerEvent) -> Option<FingerEvent> {
+        let now = Instant::now();
+
+        match ev {
+            FingerEvent::Down if self.down => None,
+            FingerEvent::Down => {
+                let bounce = self
+                    .last_down
+                    .is_some_and(|t| now.duration_since(t) < self.opts.settle);
+                if bounce || self.throttled_for().is_some() {
+                    return None;
+                }
+                self.down = true;
+                self.last_down = Some(now);
+                Some(ev)
+            }
+            FingerEvent::Up if self.down => {
+                self.down = false;
+                Some(ev)
+            }
+            FingerEvent::Up => None,
+        }
+    }
+
+    /// The result of the attempt started by the last touch, a success resets the failures
+    pub fn record(&mut self, success: bool) {
+        if success {
+            self.failures = 0;
+            self.throttled_until = None;
+            return;
+        }
+
+        self.failures += 1;
+        let max = self.opts.max_failures;
+        if max == 0 || self.failures < max {
+            return;
+        }
+
+        let backoff = self
+            .opts
+            .backoff
+            .saturating_mul(1 << (self.failures - max).min(16))
+            .min(self.opts.max_backoff);
+        debug!(failures = self.failures, ?backoff, "throttling the touches");
+        self.throttled_until = Some(Instant::now() + backoff);
+    }
+
+    /// How long the touches are still ignored, if they are
+    pub fn throttled_for(&self) -> Option<Duration> {
+        let left = self
+            .throttled_until?
+            .checked_duration_since(Instant::now())?;
+        (!left.is_zero()).then_some(left)
+    }
+}
+
+/// A [`FingerListener`] only giving the events let through by its [`Debouncer`], see
+/// [`FingerListener::debounce`]
+pub struct Debounced {
+    listener: FingerListener,
+    debouncer: Debouncer,
+}
+
+impl Debounced {
+    /// Wait for the next event, `None` if the listener stopped
+    pub fn recv(&mut self) -> Option<Result<FingerEvent, DriverError>> {
+        loop {
+            match self.listener.recv()? {
+                Ok(ev) => {
+                    if let Some(ev) = self.debouncer.push(ev) {
+                        return Some(Ok(ev));
+                    }
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// Wait for the next event, at most `timeout`
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<FingerEvent>, DriverError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.listener.recv_timeout(left)? {
+                Some(ev) => {
+                    if let Some(ev) = self.debouncer.push(ev) {
+                        return Ok(Some(ev));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// See [`Debouncer::record`]
+    pub fn record(&mut self, success: bool) {
+        self.debouncer.record(success);
+    }
+
+    pub fn debouncer(&self) -> &Debouncer {
+        &self.debouncer
+    }
+
+    /// The listener back, without the filter
+    pub fn into_inner(self) -> FingerListener {
+        self.listener
+    }
+}
+
+/// Reads the interrupt endpoint in a background thread, stopped when dropped
+pub struct FingerListener {
+    rx: Receiver<Result<FingerEvent, DriverError>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FingerListener {
+    /// Start listening, the device can still be used to send commands
+    pub fn spawn<T>(dev: Arc<T>) -> Self
+    where
+        T: Transport + Send + Sync + 'static,
+    {
+        Self::start(move |tx, stop| {
+            let mut buf = [0u8; 64];
+
+            while !stop.load(Ordering::Relaxed) {
+                let ev = match dev.wait_int(&mut buf, POLL_INTERVAL) {
+                    Ok(0) => continue,
+                    Ok(_) => match buf[0] {
+                        INT_FINGER_DOWN => FingerEvent::Down,
+                        INT_FINGER_UP => FingerEvent::Up,
+                        _ => continue,
+                    },
+                    Err(DriverError::UsbReadInterrupt(rusb::Error::Timeout)) => continue,
+                    Err(e) => {
+                        // The device is probably gone, nothing else to do
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                };
+
+                if tx.send(Ok(ev)).is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    /// Like [`Self::spawn`], but the events come from a frame read every `interval` (see
+    /// [`PresenceDetector`]), for sensors used without the interrupts. The device is busy
+    /// reading frames, other commands wait longer.
+    pub fn spawn_polling<T>(dev: Arc<T>, interval: Duration) -> Self
+    where
+        T: Transport + Send + Sync + 'static,
+    {
+        Self::start(move |tx, stop| {
+            let mut detector = PresenceDetector::new();
+            let mut frame = Frame {
+                width: 0,
+                height: 0,
+                bpp: 0,
+                data: Vec::new(),
+            };
+
+            while !stop.load(Ordering::Relaxed) {
+                let read = arm_capture(&*dev, CaptureMode::Image)
+                    .and_then(|_| dev.read_frame_into(&mut frame));
+                if let Err(e) = read {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+
+                if let Some(ev) = detector.push(&frame)
+                    && tx.send(Ok(ev)).is_err()
+                {
+                    return;
+                }
+                thread::sleep(interval);
+            }
+        })
+    }
+
+    /// Run the body in the background thread, it should return once `stop` is set
+    fn start<F>(body: F) -> Self
+    where
+        F: FnOnce(EventSender, Arc<AtomicBool>) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        let thread = thread::spawn(move || body(tx, stop_thread));
+
+        Self {
+            rx,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Wait for the next event, `None` if the listener stopped
+    pub fn recv(&self) -> Option<Result<FingerEvent, DriverError>> {
+        self.rx.recv().ok()
+    }
+
+    /// Wait for the next event, at most `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<FingerEvent>, DriverError> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(ev) => ev.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(DriverError::ListenerStopped),
+        }
+    }
+
+    /// Filter the events, see [`Debouncer`]
+    pub fn debounce(self, opts: Debounce) -> Debounced {
+        Debounced {
+            listener: self,
+            debouncer: Debouncer::new(opts),
+        }
+    }
+
+    /// Get the next event if there is one, without waiting
+    pub fn try_recv(&self) -> Option<Result<FingerEvent, DriverError>> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Stop the background thread and wait for it
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for FingerListener {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debounce_touches() {
+        let mut d = Debouncer::new(Debounce::new().settle(Duration::from_secs(3600)));
+        assert_eq!(d.push(FingerEvent::Down), Some(FingerEvent::Down));
+        assert_eq!(d.push(FingerEvent::Down), None);
+        assert_eq!(d.push(FingerEvent::Up), Some(FingerEvent::Up));
+        assert_eq!(d.push(FingerEvent::Up), None);
+
+        // Bouncing
+        assert_eq!(d.push(FingerEvent::Down), None);
+    }
+
+    #[test]
+    fn throttle_failures() {
+        let opts = Debounce::new()
+            .settle(Duration::ZERO)
+            .max_failures(2)
+            .backoff(Duration::from_secs(3600), Duration::from_secs(7200));
+        let mut d = Debouncer::new(opts);
+
+        d.record(false);
+        assert_eq!(d.throttled_for(), None);
+        d.record(false);
+        assert!(d.throttled_for().is_some());
+        assert_eq!(d.push(FingerEvent::Down), None);
+
+        d.record(true);
+        assert_eq!(d.throttled_for(), None);
+        assert_eq!(d.push(FingerEvent::Down), Some(FingerEvent::Down));
+    }
+}
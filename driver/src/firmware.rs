@@ -0,0 +1,238 @@
+//! The firmware extension (the `.xpfwext` files shipped with the windows driver) is stored in its
+//! own flash partition, the sensor checks its signature at boot and refuses to work without it.
+//! When the check fails (after an interrupted upload, for example) the sensor stays in its
+//! bootloader, see [`DeviceState`].
+
+use crate::{
+    DriverError,
+    cancel::{self, CancelToken},
+    flash::{FLASH_CHUNK, Flash},
+    info::{DeviceInfo, Info},
+    proto::{Command, StatusCode},
+    trace::debug,
+    transport::Transport,
+};
+use sha2::{Digest, Sha256};
+
+/// Partition where the firmware extension lives
+pub const FIRMWARE_PARTITION: u8 = 2;
+
+/// Size of the RSA signature at the end of the firmware
+const SIGNATURE_SIZE: usize = 0x100;
+
+/// The firmware files start with a text header ended with this byte
+const HEADER_END: u8 = 0x1a;
+
+/// A parsed firmware extension
+#[derive(Debug, Clone)]
+pub struct Firmware {
+    /// What gets written to [`FIRMWARE_PARTITION`]
+    pub payload: Vec<u8>,
+
+    /// Signature of the payload, checked by the sensor
+    pub signature: Vec<u8>,
+
+    /// The hardware the firmware is for (`HWID` in the header), if the header says
+    pub hardware_id: Option<u8>,
+
+    /// SHA-256 of the payload (`SHA256` in the header), if the header has it
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl Firmware {
+    /// Parse the contents of a firmware file: a text header (`KEY=value` lines, the unknown ones
+    /// are ignored), the payload and the signature
+    pub fn parse(blob: &[u8]) -> Result<Self, DriverError> {
+        let start = blob
+            .iter()
+            .position(|b| *b == HEADER_END)
+            .ok_or(DriverError::FirmwareInvalid)?;
+
+        let body = &blob[start + 1..];
+        if body.len() <= SIGNATURE_SIZE {
+            return Err(DriverError::FirmwareInvalid);
+        }
+
+        let (payload, signature) = body.split_at(body.len() - SIGNATURE_SIZE);
+        let mut fw = Self {
+            payload: payload.to_vec(),
+            signature: signature.to_vec(),
+            hardware_id: None,
+            sha256: None,
+        };
+
+        let header = String::from_utf8_lossy(&blob[..start]);
+        for (key, value) in header.lines().filter_map(|l| l.split_once('=')) {
+            let value = value.trim();
+            match key.trim().to_ascii_uppercase().as_str() {
+                "HWID" => {
+                    let id = value.trim_start_matches("0x");
+                    fw.hardware_id =
+                        Some(u8::from_str_radix(id, 16).map_err(|_| DriverError::FirmwareInvalid)?);
+                }
+                "SHA256" => fw.sha256 = Some(parse_hash(value)?),
+                _ => {}
+            }
+        }
+
+        Ok(fw)
+    }
+
+    /// Check the firmware before flashing it: the header has the hash and the hardware, the
+    /// payload matches the hash, the firmware is for this hardware and the signature is not
+    /// blank (all `0x00` or `0xff`). The signature itself is not checked, only the sensor can.
+    pub fn verify(&self, info: &DeviceInfo) -> Result<(), DriverError> {
+        let hash = self
+            .sha256
+            .ok_or(DriverError::FirmwareHeaderMissing("SHA256"))?;
+        let firmware = self
+            .hardware_id
+            .ok_or(DriverError::FirmwareHeaderMissing("HWID"))?;
+
+        if Sha256::digest(&self.payload)[..] != hash {
+            return Err(DriverError::FirmwareHashMismatch);
+        }
+
+        if firmware != info.hardware_id {
+            return Err(DriverError::FirmwareIncompatible {
+                firmware,
+                device: info.hardware_id,
+            });
+        }
+
+        // Erased or zeroed, nothing the sensor would accept
+        if self.signature.iter().all(|b| *b == 0x00) || self.signature.iter().all(|b| *b == 0xff) {
+            return Err(DriverError::FirmwareBlankSignature);
+        }
+        Ok(())
+    }
+}
+
+fn parse_hash(hex: &str) -> Result<[u8; 32], DriverError> {
+    let mut res = [0u8; 32];
+    if hex.len() != res.len() * 2 || !hex.is_ascii() {
+        return Err(DriverError::FirmwareInvalid);
+    }
+
+    for (i, b) in res.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| DriverError::FirmwareInvalid)?;
+    }
+    Ok(res)
+}
+
+/// What the sensor is running, see [`FirmwareUpdate::device_state`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeviceState {
+    /// The firmware extension booted, every command is available
+    Firmware,
+
+    /// Only the ROM is running: the firmware is missing or its signature is wrong. The sensor
+    /// answers [`Command::GetVersion`], the flash commands and [`Command::Reboot`], the firmware
+    /// has to be uploaded again (see [`FirmwareUpdate::recover`]).
+    Bootloader,
+}
+
+impl DeviceState {
+    /// The state told by a failed command, `None` if the error is not about the firmware
+    pub fn from_error(e: &DriverError) -> Option<Self> {
+        match e.root() {
+            DriverError::UsbInitFailed(StatusCode::NoFirmware | StatusCode::SignatureFailed)
+            | DriverError::Bootloader => Some(Self::Bootloader),
+            _ => None,
+        }
+    }
+}
+
+/// Firmware upload, implemented for every [`Transport`]
+pub trait FirmwareUpdate: Flash {
+    /// Check the firmware is for this device (see [`Firmware::verify`]), write it to the sensor,
+    /// check it was written correctly and reboot
+    fn upload_firmware(&self, fw: &Firmware) -> Result<(), DriverError> {
+        upload_firmware(self, fw, false, None)
+    }
+
+    /// Like [`Self::upload_firmware`], but stops between chunks once the token is cancelled. The
+    /// partition is left incomplete and the sensor won't boot it, the upload has to be retried.
+    fn upload_firmware_with(&self, fw: &Firmware, cancel: &CancelToken) -> Result<(), DriverError> {
+        upload_firmware(self, fw, false, Some(cancel))
+    }
+
+    /// Like [`Self::upload_firmware`], without checking the firmware first. A firmware for
+    /// another hardware may leave the sensor unusable.
+    fn upload_firmware_forced(&self, fw: &Firmware) -> Result<(), DriverError> {
+        upload_firmware(self, fw, true, None)
+    }
+
+    /// Reboot the sensor, the device will disconnect and enumerate again
+    fn reboot(&self) -> Result<(), DriverError> {
+        self.run(&Command::Reboot, &mut [0u8; 64])?;
+        Ok(())
+    }
+
+    /// Whether the firmware extension booted, works in both states (open the device without the
+    /// init sequence, see [`crate::usb::OpenOptions::init`])
+    fn device_state(&self) -> Result<DeviceState, DriverError> {
+        let cmd = Command::GetFirmwareInfo {
+            partition: FIRMWARE_PARTITION,
+        };
+        match self.run(&cmd, &mut [0u8; 1024]) {
+            Ok(_) => Ok(DeviceState::Firmware),
+            Err(e) => DeviceState::from_error(&e).ok_or(e),
+        }
+    }
+
+    /// Bring a sensor stuck in the bootloader back: flash the firmware again if given (it reboots
+    /// afterwards), otherwise just reboot it, in case the firmware is fine and the boot failed.
+    /// Nothing is done if the firmware is running, returns the state found.
+    fn recover(&self, fw: Option<&Firmware>) -> Result<DeviceState, DriverError> {
+        let state = self.device_state()?;
+        debug!(?state, "recovering");
+
+        if state == DeviceState::Bootloader {
+            match fw {
+                Some(fw) => self.upload_firmware(fw)?,
+                None => self.reboot()?,
+            }
+        }
+        Ok(state)
+    }
+}
+
+impl<T: Transport + ?Sized> FirmwareUpdate for T {}
+
+fn upload_firmware<T: Transport + ?Sized>(
+    dev: &T,
+    fw: &Firmware,
+    force: bool,
+    cancel: Option<&CancelToken>,
+) -> Result<(), DriverError> {
+    if !force {
+        fw.verify(&dev.device_info()?)?;
+    }
+
+    cancel::check(cancel)?;
+    dev.erase_flash(FIRMWARE_PARTITION)?;
+
+    for (i, chunk) in fw.payload.chunks(FLASH_CHUNK).enumerate() {
+        cancel::check(cancel)?;
+        dev.write_flash(FIRMWARE_PARTITION, (i * FLASH_CHUNK) as u32, chunk)?;
+    }
+
+    let cmd = Command::WriteSignature {
+        partition: FIRMWARE_PARTITION,
+        signature: &fw.signature,
+    };
+    dev.run(&cmd, &mut [0u8; 64])?;
+
+    let written = match cancel {
+        Some(cancel) => dev.read_flash_with(FIRMWARE_PARTITION, 0, fw.payload.len(), cancel)?,
+        None => dev.read_flash(FIRMWARE_PARTITION, 0, fw.payload.len())?,
+    };
+    if written != fw.payload {
+        return Err(DriverError::FirmwareVerifyFailed);
+    }
+
+    dev.reboot()
+}
@@ -0,0 +1,281 @@
+//! The flash of the sensor is split in partitions (firmware, calibration data, finger records,
+//! ...), their layout is read with [`Flash::partition_table`] and their contents with
+//! [`Flash::read_flash`].
+
+use crate::{
+    DriverError,
+    cancel::{self, CancelToken},
+    firmware::FIRMWARE_PARTITION,
+    integrity::{self, IntegrityError},
+    proto::{Command, Response, StatusCode, responses::FlashHeader},
+    transport::Transport,
+};
+
+/// Size of the flash writes and reads
+pub(crate) const FLASH_CHUNK: usize = 0x1000;
+
+/// A partition of the flash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    pub id: u8,
+
+    /// What the partition is used for, as reported by the sensor
+    pub kind: u8,
+
+    /// Access level needed to write the partition
+    pub access: u16,
+
+    /// Start of the partition, in bytes from the start of the flash
+    pub offset: u32,
+
+    /// Size of the partition, in bytes
+    pub size: u32,
+}
+
+/// Version of the firmware stored in [`FIRMWARE_PARTITION`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+
+    /// Number of modules in the firmware
+    pub modules: u16,
+
+    /// Unix timestamp of the build
+    pub build_time: u32,
+}
+
+/// Layout of the flash, see [`Flash::partition_table`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionTable {
+    /// JEDEC id of the flash chip
+    pub jedec_id: [u16; 2],
+
+    /// Size of an erase block, in bytes
+    pub block_size: u16,
+
+    /// Number of erase blocks
+    pub blocks: u16,
+
+    pub partitions: Vec<Partition>,
+
+    /// Version of the firmware, `None` if it was not uploaded yet
+    pub firmware: Option<FirmwareVersion>,
+}
+
+impl PartitionTable {
+    /// Total size of the flash, in bytes
+    pub fn flash_size(&self) -> u32 {
+        self.block_size as u32 * self.blocks as u32
+    }
+
+    /// Find a partition by its id
+    pub fn find(&self, id: u8) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.id == id)
+    }
+}
+
+/// Flash access, implemented for every [`Transport`]
+pub trait Flash: Transport {
+    /// Read the partition table and the version of the firmware
+    fn partition_table(&self) -> Result<PartitionTable, DriverError> {
+        let mut buf = vec![0u8; 1024];
+        let resp = self.run(&Command::GetFlashInfo, &mut buf)?;
+        let mut table = parse_flash_info(resp).ok_or(DriverError::FlashInvalidResponse)?;
+
+        let cmd = Command::GetFirmwareInfo {
+            partition: FIRMWARE_PARTITION,
+        };
+        table.firmware = match self.run(&cmd, &mut buf) {
+            Ok(resp) => Some(parse_firmware_info(resp).ok_or(DriverError::FlashInvalidResponse)?),
+            Err(e) if matches!(e.root(), DriverError::UsbInitFailed(StatusCode::NoFirmware)) => {
+                None
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(table)
+    }
+
+    /// Erase the whole partition
+    fn erase_flash(&self, partition: u8) -> Result<(), DriverError> {
+        self.run(&Command::EraseFlash { partition }, &mut [0u8; 64])?;
+        Ok(())
+    }
+
+    /// Write the data at the given address (relative to the partition start)
+    fn write_flash(&self, partition: u8, addr: u32, data: &[u8]) -> Result<(), DriverError> {
+        let cmd = Command::WriteFlash {
+            partition,
+            addr,
+            data,
+        };
+        self.run(&cmd, &mut [0u8; 64])?;
+        Ok(())
+    }
+
+    /// Read `size` bytes at the given address (relative to the partition start)
+    fn read_flash(&self, partition: u8, addr: u32, size: usize) -> Result<Vec<u8>, DriverError> {
+        read_flash(self, partition, addr, size, None)
+    }
+
+    /// Like [`Self::read_flash`], but stops between chunks once the token is cancelled
+    fn read_flash_with(
+        &self,
+        partition: u8,
+        addr: u32,
+        size: usize,
+        cancel: &CancelToken,
+    ) -> Result<Vec<u8>, DriverError> {
+        read_flash(self, partition, addr, size, Some(cancel))
+    }
+}
+
+impl<T: Transport + ?Sized> Flash for T {}
+
+fn read_flash<T: Transport + ?Sized>(
+    dev: &T,
+    partition: u8,
+    addr: u32,
+    size: usize,
+    cancel: Option<&CancelToken>,
+) -> Result<Vec<u8>, DriverError> {
+    let mut res = Vec::with_capacity(size);
+
+    while res.len() < size {
+        cancel::check(cancel)?;
+
+        let chunk = (size - res.len()).min(FLASH_CHUNK);
+        let cmd = Command::ReadFlash {
+            partition,
+            addr: addr + res.len() as u32,
+            size: chunk as u32,
+        };
+
+        let buf = dev.run_vec(&cmd)?;
+        let mut resp = Response::parse(&buf)?;
+        let index = res.len() / FLASH_CHUNK;
+        let FlashHeader { size: got, crc } =
+            FlashHeader::parse(&mut resp).ok_or(DriverError::FlashInvalidResponse)?;
+        if got as usize != chunk {
+            return Err(DriverError::Integrity(IntegrityError::Length {
+                index,
+                got: got as usize,
+                expected: chunk,
+            }));
+        }
+        let data = resp.bytes(chunk).ok_or(DriverError::FlashInvalidResponse)?;
+        integrity::check_crc(index, data, crc).map_err(DriverError::Integrity)?;
+
+        res.extend(data);
+    }
+
+    Ok(res)
+}
+
+/// The response to [`Command::GetFlashInfo`] has the format: JEDEC id (2 x u16), blocks (u16),
+/// unknown (u16), block size (u16), unknown (u16), partition count (u16) and the partitions, each
+/// one with: id (u8), kind (u8), access level (u16), offset (u32) and size (u32)
+fn parse_flash_info(mut resp: Response<'_>) -> Option<PartitionTable> {
+    let jedec_id = [resp.u16()?, resp.u16()?];
+    let blocks = resp.u16()?;
+    resp.u16()?;
+    let block_size = resp.u16()?;
+    resp.u16()?;
+    let count = resp.u16()?;
+
+    let partitions = (0..count)
+        .map(|_| {
+            Some(Partition {
+                id: resp.u8()?,
+                kind: resp.u8()?,
+                access: resp.u16()?,
+                offset: resp.u32()?,
+                size: resp.u32()?,
+            })
+        })
+        .collect::<Option<_>>()?;
+
+    Some(PartitionTable {
+        jedec_id,
+        block_size,
+        blocks,
+        partitions,
+        firmware: None,
+    })
+}
+
+/// The response to [`Command::GetFirmwareInfo`] starts with: major (u16), minor (u16), module
+/// count (u16) and build time (u32), followed by the modules (ignored)
+fn parse_firmware_info(mut resp: Response<'_>) -> Option<FirmwareVersion> {
+    Some(FirmwareVersion {
+        major: resp.u16()?,
+        minor: resp.u16()?,
+        modules: resp.u16()?,
+        build_time: resp.u32()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    const DATA: &[u8] = b"123456789";
+
+    /// CRC-16/CCITT-FALSE of [`DATA`]
+    const DATA_CRC: u16 = 0x29b1;
+
+    /// A device answering a single read of [`DATA`] with the given size and CRC
+    fn device(size: u32, crc: u16, data: &[u8]) -> MockTransport {
+        let cmd = Command::ReadFlash {
+            partition: 1,
+            addr: 0x100,
+            size: DATA.len() as u32,
+        };
+        let mut resp = vec![0, 0];
+        resp.extend(size.to_le_bytes());
+        resp.extend(crc.to_le_bytes());
+        resp.extend(data);
+        MockTransport::new(&crate::SUPPORTED[0]).expect(&cmd.to_bytes(), &resp)
+    }
+
+    #[test]
+    fn read() {
+        let dev = device(DATA.len() as u32, DATA_CRC, DATA);
+        assert_eq!(dev.read_flash(1, 0x100, DATA.len()).unwrap(), DATA);
+        assert!(dev.is_done());
+    }
+
+    #[test]
+    fn read_without_crc() {
+        let dev = device(DATA.len() as u32, 0, DATA);
+        assert_eq!(dev.read_flash(1, 0x100, DATA.len()).unwrap(), DATA);
+    }
+
+    #[test]
+    fn read_bad_crc() {
+        let dev = device(DATA.len() as u32, DATA_CRC ^ 1, DATA);
+        assert!(matches!(
+            dev.read_flash(1, 0x100, DATA.len()).unwrap_err(),
+            DriverError::Integrity(IntegrityError::Checksum {
+                index: 0,
+                got: DATA_CRC,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn read_bad_length() {
+        let dev = device(DATA.len() as u32 + 1, DATA_CRC, b"1234567890");
+        assert!(matches!(
+            dev.read_flash(1, 0x100, DATA.len()).unwrap_err(),
+            DriverError::Integrity(IntegrityError::Length {
+                index: 0,
+                got: 10,
+                expected: 9
+            })
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+//! The driver as seen by fprintd: fingers are identified by their names, enrollment and
+//! verification report the same results fprintd sends over D-Bus (`enroll-stage-passed`,
+//! `verify-match`, ...) and prints are described as `fprintd/<user>/<finger>`. The images and
+//! host templates can be exported for libfprint, see [`export`].
+
+pub mod export;
+
+pub use crate::enroll::Finger;
+
+use crate::{
+    DriverError,
+    enroll::{Enroll, TemplateId},
+    identify::Identify,
+    quirks::SensorType,
+    storage::{Storage, TemplateOrigin},
+    transport::Transport,
+};
+
+const FINGERS: [(Finger, &str); 10] = [
+    (Finger::LeftThumb, "left-thumb"),
+    (Finger::LeftIndex, "left-index-finger"),
+    (Finger::LeftMiddle, "left-middle-finger"),
+    (Finger::LeftRing, "left-ring-finger"),
+    (Finger::LeftLittle, "left-little-finger"),
+    (Finger::RightThumb, "right-thumb"),
+    (Finger::RightIndex, "right-index-finger"),
+    (Finger::RightMiddle, "right-middle-finger"),
+    (Finger::RightRing, "right-ring-finger"),
+    (Finger::RightLittle, "right-little-finger"),
+];
+
+impl Finger {
+    /// The name used by fprintd
+    pub fn name(self) -> &'static str {
+        FINGERS[self as usize - 1].1
+    }
+
+    /// Parse a name used by fprintd
+    pub fn from_name(name: &str) -> Option<Self> {
+        FINGERS.iter().find(|(_, n)| *n == name).map(|(f, _)| *f)
+    }
+}
+
+/// Results sent by fprintd in the `EnrollStatus` signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnrollStatus {
+    Completed,
+    Failed,
+    StagePassed,
+    RetryScan,
+    DataFull,
+    Disconnected,
+    UnknownError,
+}
+
+impl EnrollStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Completed => "enroll-completed",
+            Self::Failed => "enroll-failed",
+            Self::StagePassed => "enroll-stage-passed",
+            Self::RetryScan => "enroll-retry-scan",
+            Self::DataFull => "enroll-data-full",
+            Self::Disconnected => "enroll-disconnected",
+            Self::UnknownError => "enroll-unknown-error",
+        }
+    }
+
+    /// Whether fprintd considers the enrollment over
+    pub fn is_done(self) -> bool {
+        !matches!(self, Self::StagePassed | Self::RetryScan)
+    }
+}
+
+/// Results sent by fprintd in the `VerifyStatus` signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyStatus {
+    Match,
+    NoMatch,
+    RetryScan,
+    Disconnected,
+    UnknownError,
+}
+
+impl VerifyStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Match => "verify-match",
+            Self::NoMatch => "verify-no-match",
+            Self::RetryScan => "verify-retry-scan",
+            Self::Disconnected => "verify-disconnected",
+            Self::UnknownError => "verify-unknown-error",
+        }
+    }
+}
+
+/// Quality below which a sample is reported as `enroll-retry-scan`
+const MIN_QUALITY: u16 = 30;
+
+/// A device with the interface fprintd expects
+pub struct FprintDevice<T: Transport> {
+    dev: T,
+}
+
+impl<T: Transport> FprintDevice<T> {
+    /// Wrap an already initialized device
+    pub fn new(dev: T) -> Self {
+        Self { dev }
+    }
+
+    /// The `name` property of the device
+    pub fn name(&self) -> String {
+        format!("Validity Sensors {:04x}", self.dev.quirks().pid)
+    }
+
+    /// The `scan-type` property of the device
+    pub fn scan_type(&self) -> &'static str {
+        match self.dev.quirks().sensor_type {
+            SensorType::Press => "press",
+            SensorType::Swipe => "swipe",
+        }
+    }
+
+    /// Enroll the finger, `status_cb` gets every `EnrollStatus` signal (the last one is the
+    /// result, see [`EnrollStatus::is_done`])
+    pub fn enroll<F>(&self, finger: Finger, mut status_cb: F) -> Option<TemplateId>
+    where
+        F: FnMut(EnrollStatus),
+    {
+        let res = self.dev.enroll(finger as u8, |p| {
+            status_cb(if p.quality < MIN_QUALITY {
+                EnrollStatus::RetryScan
+            } else {
+                EnrollStatus::StagePassed
+            })
+        });
+
+        match res {
+            Ok(id) => {
+                status_cb(EnrollStatus::Completed);
+                Some(id)
+            }
+            Err(e) => {
+                status_cb(match e.root() {
+                    DriverError::EnrollIncomplete(_) => EnrollStatus::Failed,
+                    e if is_disconnected(e) => EnrollStatus::Disconnected,
+                    _ => EnrollStatus::UnknownError,
+                });
+                None
+            }
+        }
+    }
+
+    /// Verify the finger, matching any print of it
+    pub fn verify(&self, finger: Finger) -> VerifyStatus {
+        match self.dev.identify() {
+            Ok(Some(m)) if m.finger_id == finger as u8 => VerifyStatus::Match,
+            Ok(_) => VerifyStatus::NoMatch,
+            Err(e) => verify_error(&e),
+        }
+    }
+
+    /// Identify the finger among every enrolled print
+    pub fn identify(&self) -> (VerifyStatus, Option<Finger>) {
+        match self.dev.identify() {
+            Ok(Some(m)) => (VerifyStatus::Match, Finger::from_id(m.finger_id)),
+            Ok(None) => (VerifyStatus::NoMatch, None),
+            Err(e) => (verify_error(&e), None),
+        }
+    }
+
+    /// The fingers with a print stored on the device (`ListEnrolledFingers`), the ones enrolled
+    /// from Windows are left out
+    pub fn list_enrolled_fingers(&self) -> Result<Vec<Finger>, DriverError> {
+        let mut res: Vec<_> = self
+            .dev
+            .list_templates()?
+            .into_iter()
+            .filter(|t| t.origin == TemplateOrigin::Host)
+            .filter_map(|t| Finger::from_id(t.finger_id))
+            .collect();
+        res.sort_by_key(|f| *f as u8);
+        res.dedup();
+        Ok(res)
+    }
+
+    /// Delete every print of the finger (`DeleteEnrolledFinger`), but the ones enrolled from
+    /// Windows
+    pub fn delete_enrolled_finger(&self, finger: Finger) -> Result<(), DriverError> {
+        for t in self.dev.list_templates()? {
+            if t.finger_id == finger as u8 && t.origin == TemplateOrigin::Host {
+                self.dev.delete_template(t.id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The underlying device
+    pub fn device(&self) -> &T {
+        &self.dev
+    }
+}
+
+/// How fprintd names the prints it stores
+pub fn print_description(user: &str, finger: Finger) -> String {
+    format!("fprintd/{user}/{}", finger.name())
+}
+
+fn is_disconnected(e: &DriverError) -> bool {
+    matches!(
+        e.root(),
+        DriverError::UsbWrite(rusb::Error::NoDevice)
+            | DriverError::UsbReadResponse(rusb::Error::NoDevice)
+            | DriverError::UsbReadInterrupt(rusb::Error::NoDevice)
+    )
+}
+
+fn verify_error(e: &DriverError) -> VerifyStatus {
+    match e.root() {
+        DriverError::MatchInvalidResponse => VerifyStatus::RetryScan,
+        e if is_disconnected(e) => VerifyStatus::Disconnected,
+        _ => VerifyStatus::UnknownError,
+    }
+}
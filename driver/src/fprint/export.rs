@@ -0,0 +1,226 @@
+//! Images and prints in the formats of libfprint, to compare this driver with the tools built on
+//! it. Images are 8 bits per pixel PGM (what its `img-capture` example writes and the image
+//! drivers are tested with). Prints are what `fp_print_serialize` writes: `FP1` and a little
+//! endian GVariant `(issbymsmsia{sv}v)`: type, driver, device id, stored on the device, finger,
+//! user name, description, enrollment date (julian day), an empty dictionary and the data. Host
+//! templates are exported as NBIS prints, whose data is `a(aiaiai)`: the x, y and angle columns
+//! of the minutiae of every sample.
+
+use crate::capture::Frame;
+use std::io::{self, Write};
+#[cfg(feature = "minutiae")]
+use {
+    super::{Finger, print_description},
+    crate::minutiae::{Minutia, Template},
+    core::f32::consts::TAU,
+    std::time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The name of the driver in the prints, libfprint only uses the prints of its own driver
+pub const DRIVER: &str = "validity";
+
+/// Every serialized print starts with this
+#[cfg(feature = "minutiae")]
+const PRINT_MAGIC: &[u8] = b"FP1";
+
+/// `FPI_PRINT_NBIS`, prints made of minutiae
+#[cfg(feature = "minutiae")]
+const PRINT_NBIS: i32 = 2;
+
+/// `g_date_get_julian` of 1970-01-01
+#[cfg(feature = "minutiae")]
+const UNIX_EPOCH_JULIAN: u64 = 719_163;
+
+/// Write the frame as the PGM libfprint reads: 8 bits per pixel, 16 bits frames are stretched
+/// from their darkest to their lightest pixel
+pub fn write_image<W: Write>(frame: &Frame, out: W) -> io::Result<()> {
+    if frame.bpp != 16 {
+        // 8 bits already, the rest fail
+        return frame.write_pgm(out);
+    }
+
+    let pixels: Vec<_> = frame
+        .data
+        .chunks_exact(2)
+        .map(|px| u16::from_le_bytes([px[0], px[1]]))
+        .collect();
+    let min = pixels.iter().copied().min().unwrap_or(0) as u32;
+    let range = (pixels.iter().copied().max().unwrap_or(0) as u32 - min).max(1);
+
+    Frame {
+        bpp: 8,
+        data: pixels
+            .iter()
+            .map(|&p| ((p as u32 - min) * 255 / range) as u8)
+            .collect(),
+        ..*frame
+    }
+    .write_pgm(out)
+}
+
+/// A host template as a libfprint print, see the [module docs](self)
+#[cfg(feature = "minutiae")]
+#[derive(Debug, Clone)]
+pub struct PrintExport {
+    device_id: String,
+    finger: Finger,
+    username: Option<String>,
+    enroll_date: Option<u32>,
+}
+
+#[cfg(feature = "minutiae")]
+impl PrintExport {
+    /// `device_id` is the `device-id` of the libfprint device, the print is only used with it
+    pub fn new(device_id: impl Into<String>, finger: Finger) -> Self {
+        Self {
+            device_id: device_id.into(),
+            finger,
+            username: None,
+            enroll_date: None,
+        }
+    }
+
+    /// The user the print was enrolled for, also used for the fprintd description
+    pub fn username(mut self, name: impl Into<String>) -> Self {
+        self.username = Some(name.into());
+        self
+    }
+
+    /// When the print was enrolled, unset by default
+    pub fn enrolled_at(mut self, time: SystemTime) -> Self {
+        let days = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / 86_400);
+        self.enroll_date = Some((UNIX_EPOCH_JULIAN + days) as u32);
+        self
+    }
+
+    /// Serialize the samples (the template of [`crate::matcher::MinutiaeMatcher`]) as a print
+    pub fn to_bytes(&self, samples: &[Template]) -> Vec<u8> {
+        let description = self
+            .username
+            .as_deref()
+            .map(|user| print_description(user, self.finger));
+        let date = self.enroll_date.map_or(i32::MIN, |d| d as i32);
+        let xyt = samples.iter().map(xyt).collect::<Vec<_>>();
+
+        let mut print = Container::default();
+        print.fixed(4, &PRINT_NBIS.to_le_bytes());
+        print.var(1, &string(DRIVER));
+        print.var(1, &string(&self.device_id));
+        print.fixed(1, &[0]);
+        print.fixed(1, &[self.finger as u8]);
+        print.var(1, &maybe(self.username.as_deref().map(string)));
+        print.var(1, &maybe(description.as_deref().map(string)));
+        print.fixed(4, &date.to_le_bytes());
+        print.var(8, &[]);
+        print.last(8, &variant(&array(4, &xyt), "a(aiaiai)"));
+
+        let mut res = PRINT_MAGIC.to_vec();
+        res.extend(print.finish(true));
+        res
+    }
+}
+
+/// The `(aiaiai)` of a sample, in the coordinates of NIST: the origin at the bottom left and the
+/// angles in degrees, counterclockwise
+#[cfg(feature = "minutiae")]
+fn xyt(sample: &Template) -> Vec<u8> {
+    let column = |f: &dyn Fn(&Minutia) -> i32| {
+        sample
+            .minutiae
+            .iter()
+            .flat_map(|m| f(m).to_le_bytes())
+            .collect::<Vec<_>>()
+    };
+    let height = sample.height as i32;
+
+    let mut res = Container::default();
+    res.var(4, &column(&|m| m.x as i32));
+    res.var(4, &column(&|m| height - m.y as i32));
+    res.last(
+        4,
+        &column(&|m| ((-m.angle).rem_euclid(TAU).to_degrees().round() as i32) % 360),
+    );
+    res.finish(true)
+}
+
+/// The GVariant serialization of a tuple or of an array of variable size elements: the items
+/// aligned, followed by where each variable size one ends
+#[cfg(feature = "minutiae")]
+#[derive(Default)]
+struct Container {
+    data: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+#[cfg(feature = "minutiae")]
+impl Container {
+    fn fixed(&mut self, align: usize, item: &[u8]) {
+        self.data.resize(self.data.len().next_multiple_of(align), 0);
+        self.data.extend(item);
+    }
+
+    fn var(&mut self, align: usize, item: &[u8]) {
+        self.fixed(align, item);
+        self.ends.push(self.data.len());
+    }
+
+    /// The last item of a tuple, its end is not written
+    fn last(&mut self, align: usize, item: &[u8]) {
+        self.fixed(align, item);
+    }
+
+    /// The tuples have their offsets in reverse order, the arrays in order
+    fn finish(mut self, tuple: bool) -> Vec<u8> {
+        let size = [1, 2, 4, 8]
+            .into_iter()
+            .find(|&size| {
+                let total = self.data.len() + size * self.ends.len();
+                size == 8 || total < 1 << (size * 8)
+            })
+            .unwrap_or(8);
+
+        if tuple {
+            self.ends.reverse();
+        }
+        for end in self.ends {
+            self.data.extend(&end.to_le_bytes()[..size]);
+        }
+        self.data
+    }
+}
+
+#[cfg(feature = "minutiae")]
+fn string(s: &str) -> Vec<u8> {
+    let mut res = s.as_bytes().to_vec();
+    res.push(0);
+    res
+}
+
+/// A maybe of a variable size type: empty for nothing
+#[cfg(feature = "minutiae")]
+fn maybe(item: Option<Vec<u8>>) -> Vec<u8> {
+    item.map(|mut item| {
+        item.push(0);
+        item
+    })
+    .unwrap_or_default()
+}
+
+#[cfg(feature = "minutiae")]
+fn array(align: usize, items: &[Vec<u8>]) -> Vec<u8> {
+    let mut res = Container::default();
+    for item in items {
+        res.var(align, item);
+    }
+    res.finish(false)
+}
+
+#[cfg(feature = "minutiae")]
+fn variant(item: &[u8], ty: &str) -> Vec<u8> {
+    let mut res = item.to_vec();
+    res.push(0);
+    res.extend(ty.as_bytes());
+    res
+}
@@ -0,0 +1,149 @@
+//! The private key of the host, used when pairing (see [`crate::pairing`]) and in the handshake
+//! of every session (see [`crate::secure`]). Usually it is kept in memory and saved with the
+//! pairing data, with the `tpm` feature it can be generated inside the TPM2 instead and never
+//! leave it (see [`TpmKeyStore::resident`](crate::keystore::tpm::TpmKeyStore::resident)). A key
+//! can also be kept on its own as a [`HostIdentity`], to pair several times with the same one.
+
+#[cfg(feature = "tpm")]
+use crate::keystore::tpm::TpmKey;
+use crate::{DriverError, pairing::write_private};
+use p256::{
+    PublicKey, SecretKey,
+    ecdsa::{DerSignature, SigningKey, signature::Signer},
+    elliptic_curve::sec1::ToEncodedPoint,
+    pkcs8::{DecodePrivateKey, LineEnding},
+};
+use rand_core::OsRng;
+use std::{fs, path::Path};
+
+/// Type and curve of the keys in the certificate (secp256r1)
+const CERT_HEADER: &[u8] = &[0x17, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00];
+
+/// The key of the host, see [`crate::secure::SessionParams`]
+#[derive(Clone)]
+pub enum HostKey {
+    /// Kept in memory, saved with the rest of the pairing data
+    Software(SecretKey),
+
+    /// Generated by the TPM2, only usable through it
+    #[cfg(feature = "tpm")]
+    Tpm(TpmKey),
+}
+
+impl HostKey {
+    /// A new random key, kept in memory
+    pub fn generate() -> Self {
+        Self::Software(SecretKey::random(&mut OsRng))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            Self::Software(key) => key.public_key(),
+            #[cfg(feature = "tpm")]
+            Self::Tpm(key) => key.public_key(),
+        }
+    }
+
+    /// The private key, `None` if it can't leave the TPM
+    pub fn secret(&self) -> Option<&SecretKey> {
+        match self {
+            Self::Software(key) => Some(key),
+            #[cfg(feature = "tpm")]
+            Self::Tpm(_) => None,
+        }
+    }
+
+    /// The x coordinate of the ECDH shared point
+    pub(crate) fn diffie_hellman(&self, peer: &PublicKey) -> Result<[u8; 32], DriverError> {
+        match self {
+            Self::Software(key) => {
+                let shared = p256::ecdh::diffie_hellman(key.to_nonzero_scalar(), peer.as_affine());
+                Ok((*shared.raw_secret_bytes()).into())
+            }
+            #[cfg(feature = "tpm")]
+            Self::Tpm(key) => key.diffie_hellman(peer),
+        }
+    }
+
+    /// ECDSA signature (DER) of the SHA-256 of the message
+    pub(crate) fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, DriverError> {
+        match self {
+            Self::Software(key) => {
+                let sig: DerSignature = SigningKey::from(key).sign(msg);
+                Ok(sig.as_bytes().to_vec())
+            }
+            #[cfg(feature = "tpm")]
+            Self::Tpm(key) => key.sign(msg),
+        }
+    }
+}
+
+impl From<SecretKey> for HostKey {
+    fn from(key: SecretKey) -> Self {
+        Self::Software(key)
+    }
+}
+
+/// The private key is left out
+impl core::fmt::Debug for HostKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Software(_) => f.write_str("Software(..)"),
+            #[cfg(feature = "tpm")]
+            Self::Tpm(key) => f.debug_tuple("Tpm").field(key).finish(),
+        }
+    }
+}
+
+/// The key of the host and the certificate sent to the sensor when pairing (see
+/// [`crate::pairing::Pair::pair_identity`]). The certificate is just a header and the
+/// coordinates of the public key, so only the key is saved.
+///
+/// The file is the key in PEM: SEC1 (`EC PRIVATE KEY`) when saved, PKCS#8 (`PRIVATE KEY`) is
+/// accepted too. Those are the formats of `openssl ecparam -genkey` and of the python
+/// `cryptography` package used by python-validity, so the keys can be moved between both.
+#[derive(Debug, Clone)]
+pub struct HostIdentity {
+    pub key: HostKey,
+}
+
+impl HostIdentity {
+    /// A new random identity, kept in memory
+    pub fn generate() -> Self {
+        Self {
+            key: HostKey::generate(),
+        }
+    }
+
+    /// Load the identity saved with [`Self::save`] (or by python-validity)
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, DriverError> {
+        let pem = fs::read_to_string(path).map_err(DriverError::HostIdentityIo)?;
+        let key = SecretKey::from_sec1_pem(&pem)
+            .or_else(|_| SecretKey::from_pkcs8_pem(&pem))
+            .map_err(|_| DriverError::HostIdentityInvalid)?;
+        Ok(Self { key: key.into() })
+    }
+
+    /// Save the key, the file is only readable by its owner. Fails for keys kept by the TPM.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), DriverError> {
+        let key = self.key.secret().ok_or(DriverError::HostKeyNotExportable)?;
+        let pem = key
+            .to_sec1_pem(LineEnding::LF)
+            .map_err(|_| DriverError::HostIdentityInvalid)?;
+        write_private(path, pem.as_bytes()).map_err(DriverError::HostIdentityIo)
+    }
+
+    /// The certificate of the host, as sent to the sensor
+    pub fn certificate(&self) -> Vec<u8> {
+        let point = self.key.public_key().to_encoded_point(false);
+        let mut res = CERT_HEADER.to_vec();
+        res.extend(&point.as_bytes()[1..]);
+        res
+    }
+}
+
+impl From<HostKey> for HostIdentity {
+    fn from(key: HostKey) -> Self {
+        Self { key }
+    }
+}
@@ -0,0 +1,181 @@
+//! Sensors disappear on suspend and come back on resume (or when a dock is attached), a
+//! [`DeviceWatcher`] reports them as they arrive and leave, see [`crate::watch_devices`].
+
+use crate::{DriverError, quirks::DeviceQuirks, usb::UsbDevice};
+use core::time::Duration;
+use rusb::{Device, GlobalContext, Hotplug, HotplugBuilder, Registration, UsbContext};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+};
+
+/// How often the watcher checks if it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub enum DeviceEvent {
+    /// A supported sensor was plugged (or was already there when the watcher started)
+    Arrived(UsbDevice),
+
+    /// The sensor at the given bus and address was removed
+    Left { bus: u8, addr: u8 },
+}
+
+/// Handles the libusb hotplug events in a background thread, stopped when dropped. Iterating it
+/// waits for the next event.
+pub struct DeviceWatcher {
+    rx: Receiver<DeviceEvent>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DeviceWatcher {
+    /// Register the hotplug callback and start handling its events
+    pub(crate) fn spawn() -> Result<Self, DriverError> {
+        let (tx, rx) = mpsc::channel();
+        // Not filtered by vendor, devices of any vendor can be registered
+        let registration: Registration<GlobalContext> = HotplugBuilder::new()
+            .enumerate(true)
+            .register(GlobalContext::default(), Box::new(Callback(tx)))
+            .map_err(DriverError::HotplugRegister)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+
+        let thread = thread::spawn(move || {
+            let ctx = GlobalContext::default();
+            while !stop_thread.load(Ordering::Relaxed) {
+                // Errors are not fatal, the next call may work
+                let _ = ctx.handle_events(Some(POLL_INTERVAL));
+            }
+            drop(registration);
+        });
+
+        Ok(Self {
+            rx,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Wait for the next event, at most `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<DeviceEvent>, DriverError> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(ev) => Ok(Some(ev)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(DriverError::ListenerStopped),
+        }
+    }
+
+    /// Get the next event if there is one, without waiting
+    pub fn try_recv(&self) -> Option<DeviceEvent> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Stop the background thread and wait for it
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Iterator for DeviceWatcher {
+    type Item = DeviceEvent;
+
+    fn next(&mut self) -> Option<DeviceEvent> {
+        self.rx.recv().ok()
+    }
+}
+
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// Called by libusb from the watcher thread
+struct Callback(Sender<DeviceEvent>);
+
+impl Callback {
+    fn quirks(dev: &Device<GlobalContext>) -> Option<&'static DeviceQuirks> {
+        let desc = dev.device_descriptor().ok()?;
+        DeviceQuirks::find(desc.vendor_id(), desc.product_id())
+    }
+}
+
+impl Hotplug<GlobalContext> for Callback {
+    fn device_arrived(&mut self, dev: Device<GlobalContext>) {
+        if let Some(quirks) = Self::quirks(&dev) {
+            let _ = self.0.send(DeviceEvent::Arrived(UsbDevice(dev, quirks)));
+        }
+    }
+
+    fn device_left(&mut self, dev: Device<GlobalContext>) {
+        if Self::quirks(&dev).is_some() {
+            let _ = self.0.send(DeviceEvent::Left {
+                bus: dev.bus_number(),
+                addr: dev.address(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A watcher fed by the sender, without a libusb thread
+    fn watcher() -> (Sender<DeviceEvent>, DeviceWatcher) {
+        let (tx, rx) = mpsc::channel();
+        let watcher = DeviceWatcher {
+            rx,
+            stop: Arc::default(),
+            thread: None,
+        };
+        (tx, watcher)
+    }
+
+    #[test]
+    fn events() {
+        let (tx, mut watcher) = watcher();
+        assert!(watcher.try_recv().is_none());
+        assert!(matches!(
+            watcher.recv_timeout(Duration::from_millis(1)),
+            Ok(None)
+        ));
+
+        tx.send(DeviceEvent::Left { bus: 1, addr: 2 }).unwrap();
+        tx.send(DeviceEvent::Left { bus: 1, addr: 3 }).unwrap();
+        assert!(matches!(
+            watcher.try_recv(),
+            Some(DeviceEvent::Left { bus: 1, addr: 2 })
+        ));
+        assert!(matches!(
+            watcher.next(),
+            Some(DeviceEvent::Left { bus: 1, addr: 3 })
+        ));
+    }
+
+    #[test]
+    fn stopped() {
+        let (tx, mut watcher) = watcher();
+        drop(tx);
+        assert!(matches!(
+            watcher.recv_timeout(Duration::from_millis(1)),
+            Err(DriverError::ListenerStopped)
+        ));
+        assert!(watcher.next().is_none());
+
+        watcher.stop();
+    }
+}
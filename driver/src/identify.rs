@@ -0,0 +1,126 @@
+use crate::{
+    DriverError,
+    cancel::{self, CancelToken},
+    capture::{CaptureMode, IdleGuard, arm_capture},
+    enroll::{Finger, TemplateId},
+    proto::{Command, Response, opcodes, responses},
+    storage::{Storage, UserNamespace},
+    transport::Transport,
+};
+
+/// Match against every template stored in the device
+const ANY_TEMPLATE: u16 = opcodes::ANY_RECORD;
+
+/// A successful match
+#[derive(Debug, Clone, Copy)]
+pub struct MatchResult {
+    /// The template that matched
+    pub template: TemplateId,
+
+    /// The finger id given at enrollment, see [`crate::enroll::Enroll::enroll`]
+    pub finger_id: u8,
+
+    /// How good the match was, as reported by the sensor
+    pub score: u16,
+}
+
+impl MatchResult {
+    /// The finger given at enrollment, if it is a known one
+    pub fn finger(&self) -> Option<Finger> {
+        Finger::from_id(self.finger_id)
+    }
+}
+
+/// Matching against the enrolled templates, implemented for every [`Transport`]
+pub trait Identify: Transport {
+    /// Scan a finger and find which one of the enrolled templates matches, if any
+    fn identify(&self) -> Result<Option<MatchResult>, DriverError> {
+        scan_and_match(self, &[ANY_TEMPLATE], None)
+    }
+
+    /// Like [`Self::identify`], but stops waiting for the finger once the token is cancelled
+    fn identify_with(&self, cancel: &CancelToken) -> Result<Option<MatchResult>, DriverError> {
+        scan_and_match(self, &[ANY_TEMPLATE], Some(cancel))
+    }
+
+    /// Like [`Self::identify`], but only the templates of the namespace can match. The finger is
+    /// scanned once and matched against each template, `None` without scanning if the namespace
+    /// is empty.
+    fn identify_in(&self, namespace: UserNamespace) -> Result<Option<MatchResult>, DriverError> {
+        identify_in(self, namespace, None)
+    }
+
+    /// Like [`Self::identify_in`], but stops waiting for the finger once the token is cancelled
+    fn identify_in_with(
+        &self,
+        namespace: UserNamespace,
+        cancel: &CancelToken,
+    ) -> Result<Option<MatchResult>, DriverError> {
+        identify_in(self, namespace, Some(cancel))
+    }
+
+    /// Scan a finger and check it matches the given template
+    fn verify(&self, template: TemplateId) -> Result<Option<MatchResult>, DriverError> {
+        let res = scan_and_match(self, &[template.0], None)?;
+        Ok(res.filter(|m| m.template == template))
+    }
+}
+
+impl<T: Transport + ?Sized> Identify for T {}
+
+fn identify_in<T: Transport + ?Sized>(
+    dev: &T,
+    namespace: UserNamespace,
+    cancel: Option<&CancelToken>,
+) -> Result<Option<MatchResult>, DriverError> {
+    let templates: Vec<u16> = dev
+        .list_templates_in(namespace)?
+        .iter()
+        .map(|t| t.id.0)
+        .collect();
+    if templates.is_empty() {
+        return Ok(None);
+    }
+
+    let res = scan_and_match(dev, &templates, cancel)?;
+    Ok(res.filter(|m| templates.contains(&m.template.0)))
+}
+
+/// Wait for a finger, match it against the templates in turn and read the result, until one
+/// matches
+fn scan_and_match<T: Transport + ?Sized>(
+    dev: &T,
+    templates: &[u16],
+    cancel: Option<&CancelToken>,
+) -> Result<Option<MatchResult>, DriverError> {
+    let mut buf = [0u8; 1024];
+    arm_capture(dev, CaptureMode::Identify)?;
+    let armed = IdleGuard::new(dev, Command::CaptureStop);
+    cancel::wait_scan(dev, cancel)?;
+    armed.disarm();
+
+    for &template in templates {
+        dev.run(&Command::Match(template), &mut buf)?;
+
+        // Always cleanup, even if the result could not be read
+        let res = dev
+            .run(&Command::MatchResult, &mut buf)
+            .and_then(|resp| parse_match(resp).ok_or(DriverError::MatchInvalidResponse));
+        dev.run(&Command::MatchCleanup, &mut [0u8; 64])?;
+
+        if let Some(m) = res? {
+            return Ok(Some(m));
+        }
+    }
+    Ok(None)
+}
+
+/// See [`responses::MatchResult`]
+fn parse_match(mut resp: Response<'_>) -> Option<Option<MatchResult>> {
+    let res = responses::MatchResult::parse(&mut resp)?;
+    Some(res.matched.then_some(MatchResult {
+        template: TemplateId(res.record),
+        finger_id: res.finger,
+        score: res.score,
+    }))
+}
@@ -0,0 +1,176 @@
+//! What the sensor reports about itself in the answer to [`Command::GetVersion`], the first
+//! command of the init sequence, and the internal status of [`Command::GetDiagnostics`].
+
+use crate::{
+    DriverError,
+    flash::Flash,
+    proto::{Command, Response, StatusCode, responses},
+    quirks::SensorType,
+    transport::Transport,
+};
+use core::time::Duration;
+
+/// Sent instead of the temperature by the chips without a sensor
+const NO_TEMPERATURE: i16 = i16::MIN;
+
+/// Firmware and hardware information of the sensor, see [`Info::device_info`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceInfo {
+    pub firmware_major: u8,
+    pub firmware_minor: u8,
+
+    /// Build number of the firmware
+    pub build: u32,
+
+    /// Unix timestamp of the firmware build
+    pub build_time: u32,
+
+    /// Identifies the sensor chip, the same for every device with the same product id
+    pub hardware_id: u8,
+
+    /// Serial number of the device, if it has one
+    pub serial: Option<String>,
+}
+
+/// What a device can do, see [`Info::capabilities`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Capability {
+    /// The fingers are enrolled and matched by the sensor, see [`crate::enroll::Enroll`] and
+    /// [`crate::identify::Identify`]
+    MatchOnChip,
+
+    /// Raw images can be read, see [`crate::capture::Capture`]
+    ImageCapture,
+
+    /// The sensor can store this many templates
+    StorageSlots(u16),
+
+    /// The finger is swiped over the sensor, the frames are stitched
+    SwipeSensor,
+}
+
+/// Why the sensor started last, see [`Diagnostics`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ResetReason {
+    /// Plugged in or powered on with the laptop
+    PowerOn,
+
+    /// Asked by the host (a reboot or a USB reset)
+    Software,
+
+    /// The watchdog fired, the firmware hung
+    Watchdog,
+
+    /// The supply voltage dropped, usually a flaky port or a suspend gone wrong
+    Brownout,
+
+    /// A code not decoded yet
+    Unknown(u8),
+}
+
+impl From<u8> for ResetReason {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => Self::PowerOn,
+            1 => Self::Software,
+            2 => Self::Watchdog,
+            3 => Self::Brownout,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+/// The internal status of the firmware, to triage the sensors that fail now and then, see
+/// [`Info::diagnostics`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Diagnostics {
+    /// Since the last reset
+    pub uptime: Duration,
+
+    /// Status of the last failed command, `None` if none failed since the reset, see
+    /// [`Self::last_status`]
+    pub last_error: Option<u16>,
+
+    /// Of the die in degrees Celsius, `None` if the chip can't measure it
+    pub temperature: Option<f32>,
+
+    pub reset_reason: ResetReason,
+}
+
+impl Diagnostics {
+    /// The last error, decoded
+    pub fn last_status(&self) -> Option<StatusCode> {
+        self.last_error.and_then(StatusCode::from_u16)
+    }
+}
+
+/// Device information query, implemented for every [`Transport`]
+pub trait Info: Transport {
+    /// Query the firmware version and the hardware of the sensor
+    fn device_info(&self) -> Result<DeviceInfo, DriverError> {
+        let mut buf = [0u8; 1024];
+        let resp = self.run(&Command::GetVersion, &mut buf)?;
+        let mut info = parse_version(resp).ok_or(DriverError::InfoInvalidResponse)?;
+        info.serial = self.serial_number();
+        Ok(info)
+    }
+
+    /// What the device can do, from its quirks and the firmware it runs: matching on chip (and
+    /// storing templates) needs the firmware to be uploaded
+    fn capabilities(&self) -> Result<Vec<Capability>, DriverError> {
+        let quirks = self.quirks();
+        let mut res = vec![Capability::ImageCapture];
+
+        if quirks.sensor_type == SensorType::Swipe {
+            res.push(Capability::SwipeSensor);
+        }
+
+        if quirks.match_on_chip && self.partition_table()?.firmware.is_some() {
+            res.push(Capability::MatchOnChip);
+            if quirks.storage_slots > 0 {
+                res.push(Capability::StorageSlots(quirks.storage_slots));
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// The internal status of the firmware, it also answers when the init failed
+    fn diagnostics(&self) -> Result<Diagnostics, DriverError> {
+        let mut buf = [0u8; 64];
+        let resp = self.run(&Command::GetDiagnostics, &mut buf)?;
+        parse_diagnostics(resp).ok_or(DriverError::InfoInvalidResponse)
+    }
+}
+
+impl<T: Transport + ?Sized> Info for T {}
+
+/// See [`responses::Version`]
+fn parse_version(mut resp: Response<'_>) -> Option<DeviceInfo> {
+    let version = responses::Version::parse(&mut resp)?;
+    Some(DeviceInfo {
+        build_time: version.build_time,
+        build: version.build,
+        firmware_major: version.major,
+        firmware_minor: version.minor,
+        hardware_id: version.hardware_id,
+        serial: None,
+    })
+}
+
+/// See [`responses::Diagnostics`]
+fn parse_diagnostics(mut resp: Response<'_>) -> Option<Diagnostics> {
+    let diag = responses::Diagnostics::parse(&mut resp)?;
+    Some(Diagnostics {
+        uptime: Duration::from_millis(diag.uptime_ms as u64),
+        last_error: Some(diag.last_status).filter(|&code| code != 0),
+        temperature: Some(diag.temperature)
+            .filter(|&t| t != NO_TEMPERATURE)
+            .map(|t| t as f32 / 100.0),
+        reset_reason: diag.reset_reason.into(),
+    })
+}
@@ -0,0 +1,62 @@
+//! Frames and flash dumps come in several transfers, a short, long or corrupted one must fail
+//! the read instead of ending up in a capture or a backup. The chunks of a flash read have their
+//! size and a CRC (older firmwares send `0` instead, it is then skipped), the frames only their
+//! total size.
+
+/// A multi-part transfer did not add up, see the [module docs](self)
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityError {
+    #[error("Chunk {index} has {got} bytes, expected {expected}")]
+    Length {
+        index: usize,
+        got: usize,
+        expected: usize,
+    },
+
+    #[error("Got {extra} bytes more than the {total} announced")]
+    Overrun { extra: usize, total: usize },
+
+    #[error("Chunk {index} has the checksum {got:04x}, expected {expected:04x}")]
+    Checksum {
+        index: usize,
+        got: u16,
+        expected: u16,
+    },
+}
+
+/// Append a chunk of a transfer of `total` bytes, nothing is appended if it goes past the end
+pub(crate) fn append(data: &mut Vec<u8>, chunk: &[u8], total: usize) -> Result<(), IntegrityError> {
+    let len = data.len() + chunk.len();
+    if len > total {
+        return Err(IntegrityError::Overrun {
+            extra: len - total,
+            total,
+        });
+    }
+
+    data.extend(chunk);
+    Ok(())
+}
+
+/// Check the CRC sent with a chunk, `0` if the firmware does not compute it
+pub(crate) fn check_crc(index: usize, data: &[u8], expected: u16) -> Result<(), IntegrityError> {
+    let got = crc16(data);
+    match expected == 0 || got == expected {
+        true => Ok(()),
+        false => Err(IntegrityError::Checksum {
+            index,
+            got,
+            expected,
+        }),
+    }
+}
+
+/// CRC-16/CCITT-FALSE: polynomial `0x1021`, starting at `0xffff`
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => crc << 1 ^ 0x1021,
+        })
+    })
+}
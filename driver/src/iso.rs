@@ -0,0 +1,235 @@
+//! The finger minutiae record of ISO/IEC 19794-2:2005, read by most matchers and conformance
+//! tools. The record is big endian: a header (`FMR`, version ` 20`, length, capture device,
+//! image size, resolution and number of views) and a view per sample of the finger, with its
+//! minutiae. Angles are counterclockwise in 1/256 of a turn, from the top left of the image.
+
+use crate::{
+    DriverError,
+    enroll::Finger,
+    minutiae::{Minutia, MinutiaKind, Template},
+};
+use core::f32::consts::TAU;
+
+pub use crate::capture::Impression;
+
+/// Every record starts with this
+const RECORD_MAGIC: &[u8] = b"FMR\0 20\0";
+
+/// Size of the header, before the views
+const HEADER_LEN: usize = 24;
+
+/// The views of a finger are numbered with 4 bits
+const MAX_VIEWS: usize = 16;
+
+/// Coordinates are 14 bits
+const MAX_COORD: u16 = 0x3fff;
+
+/// Host templates as ISO/IEC 19794-2 records, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct IsoExport {
+    finger: Option<Finger>,
+    impression: Impression,
+    resolution: u16,
+    quality: u8,
+}
+
+impl IsoExport {
+    /// An unknown finger pressed on a 500 dpi sensor, quality not reported
+    pub fn new() -> Self {
+        Self {
+            finger: None,
+            impression: Impression::LivePlain,
+            resolution: 197,
+            quality: 0,
+        }
+    }
+
+    pub fn finger(mut self, finger: Finger) -> Self {
+        self.finger = Some(finger);
+        self
+    }
+
+    pub fn impression(mut self, impression: Impression) -> Self {
+        self.impression = impression;
+        self
+    }
+
+    /// Resolution of the sensor, in dots per inch (the record has pixels per cm)
+    pub fn resolution_dpi(mut self, dpi: u16) -> Self {
+        self.resolution = (dpi as f32 / 2.54).round() as u16;
+        self
+    }
+
+    /// Quality of the finger (1-100, see [`crate::quality`]), 0 if not known
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = quality.min(100);
+        self
+    }
+
+    /// Write the samples (the template of [`crate::matcher::MinutiaeMatcher`]) as a record, a
+    /// view each. Only the first 16 samples and 255 minutiae of each fit.
+    pub fn to_bytes(&self, samples: &[Template]) -> Vec<u8> {
+        let samples = &samples[..samples.len().min(MAX_VIEWS)];
+        let width = samples.iter().map(|s| s.width).max().unwrap_or(0);
+        let height = samples.iter().map(|s| s.height).max().unwrap_or(0);
+
+        let mut res = RECORD_MAGIC.to_vec();
+        // The length, written once known
+        res.extend([0; 4]);
+        // No certification, unknown device
+        res.extend([0; 2]);
+        res.extend(width.to_be_bytes());
+        res.extend(height.to_be_bytes());
+        res.extend(self.resolution.to_be_bytes());
+        res.extend(self.resolution.to_be_bytes());
+        res.push(samples.len() as u8);
+        res.push(0);
+        debug_assert_eq!(res.len(), HEADER_LEN);
+
+        for (view, sample) in samples.iter().enumerate() {
+            let minutiae = &sample.minutiae[..sample.minutiae.len().min(u8::MAX as usize)];
+
+            res.push(self.finger.map_or(0, Finger::iso_position));
+            res.push((view as u8) << 4 | self.impression as u8);
+            res.push(self.quality);
+            res.push(minutiae.len() as u8);
+
+            for m in minutiae {
+                write_minutia(&mut res, m, 256);
+            }
+
+            // No extended data
+            res.extend([0; 2]);
+        }
+
+        let len = res.len() as u32;
+        res[8..12].copy_from_slice(&len.to_be_bytes());
+        res
+    }
+}
+
+impl Default for IsoExport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Type and x (u16), y (u16), angle (u8, in `steps` per turn) and quality (u8, not reported),
+/// the same in ANSI INCITS 378 records
+pub(crate) fn write_minutia(res: &mut Vec<u8>, m: &Minutia, steps: u32) {
+    let kind: u16 = match m.kind {
+        MinutiaKind::Ending => 0b01,
+        MinutiaKind::Bifurcation => 0b10,
+    };
+    // Our angles go clockwise, y grows downwards
+    let angle = ((-m.angle).rem_euclid(TAU) / TAU * steps as f32).round() as u32 % steps;
+
+    res.extend((kind << 14 | m.x.min(MAX_COORD)).to_be_bytes());
+    res.extend(m.y.min(MAX_COORD).to_be_bytes());
+    res.push(angle as u8);
+    res.push(0);
+}
+
+/// Parse a minutia written by [`write_minutia`], `None` for the ones of type "other"
+pub(crate) fn read_minutia(data: &[u8; 6], steps: u32) -> Result<Option<Minutia>, DriverError> {
+    let x = u16::from_be_bytes([data[0], data[1]]);
+    let kind = match x >> 14 {
+        0b00 => return Ok(None),
+        0b01 => MinutiaKind::Ending,
+        0b10 => MinutiaKind::Bifurcation,
+        _ => return Err(DriverError::HostTemplateInvalid),
+    };
+    let angle = data[4] as u32;
+    if angle >= steps {
+        return Err(DriverError::HostTemplateInvalid);
+    }
+
+    Ok(Some(Minutia {
+        x: x & MAX_COORD,
+        y: u16::from_be_bytes([data[2], data[3]]) & MAX_COORD,
+        angle: (-(angle as f32) / steps as f32 * TAU).rem_euclid(TAU),
+        kind,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(width: u16, minutiae: Vec<Minutia>) -> Template {
+        Template {
+            width,
+            height: 40,
+            minutiae,
+        }
+    }
+
+    fn minutia(x: u16, angle: f32, kind: MinutiaKind) -> Minutia {
+        Minutia {
+            x,
+            y: 7,
+            angle,
+            kind,
+        }
+    }
+
+    #[test]
+    fn record() {
+        let samples = [
+            template(30, vec![minutia(1, 0.0, MinutiaKind::Ending)]),
+            template(50, vec![]),
+        ];
+        let data = IsoExport::new()
+            .finger(Finger::RightIndex)
+            .impression(Impression::LiveSwipe)
+            .quality(150)
+            .to_bytes(&samples);
+
+        assert_eq!(&data[..8], RECORD_MAGIC);
+        assert_eq!(data.len(), HEADER_LEN + 4 + 6 + 2 + 4 + 2);
+        assert_eq!(data[8..12], (data.len() as u32).to_be_bytes());
+        // The biggest size, 500 dpi and two views
+        assert_eq!(data[14..24], [0, 50, 0, 40, 0, 197, 0, 197, 2, 0]);
+
+        // Right index, view 0, swiped, quality capped, one minutia
+        assert_eq!(data[24..28], [2, 8, 100, 1]);
+        // The second view has none
+        assert_eq!(data[36..40], [2, 0x18, 100, 0]);
+    }
+
+    #[test]
+    fn views_capped() {
+        let samples = vec![template(10, vec![]); 20];
+        let data = IsoExport::new().to_bytes(&samples);
+        assert_eq!(data[22], MAX_VIEWS as u8);
+        assert_eq!(data.len(), HEADER_LEN + MAX_VIEWS * 6);
+    }
+
+    #[test]
+    fn minutia_round_trip() {
+        for steps in [256, 180] {
+            for m in [
+                minutia(12, 1.0, MinutiaKind::Ending),
+                minutia(MAX_COORD, 5.0, MinutiaKind::Bifurcation),
+            ] {
+                let mut data = vec![];
+                write_minutia(&mut data, &m, steps);
+                let parsed = read_minutia(data[..].try_into().unwrap(), steps)
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!((parsed.x, parsed.y, parsed.kind), (m.x, m.y, m.kind));
+                let diff = (parsed.angle - m.angle).abs();
+                assert!(diff.min(TAU - diff) <= TAU / steps as f32);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_minutiae() {
+        // Type "other"
+        assert_eq!(read_minutia(&[0, 1, 0, 2, 0, 0], 256).unwrap(), None);
+        assert!(read_minutia(&[0xc0, 1, 0, 2, 0, 0], 256).is_err());
+        assert!(read_minutia(&[0x40, 1, 0, 2, 180, 0], 180).is_err());
+    }
+}
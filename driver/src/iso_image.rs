@@ -0,0 +1,229 @@
+//! The finger image record of ISO/IEC 19794-4:2005, to feed the frames to other biometric
+//! pipelines. The record is big endian: a header (`FIR`, version `010`, length, capture device,
+//! acquisition level, resolution, pixel depth and compression) and an image per view of the
+//! finger, uncompressed.
+
+use crate::{
+    capture::{Frame, Impression},
+    enroll::Finger,
+    quirks::DeviceQuirks,
+};
+use std::io::{self, Write};
+
+/// Every record starts with this
+const RECORD_MAGIC: &[u8] = b"FIR\x00010\x00";
+
+/// Size of the header, before the views
+const HEADER_LEN: usize = 32;
+
+/// Size of the header of every view, before its image
+const VIEW_HEADER_LEN: usize = 14;
+
+/// The resolutions are in pixels per inch
+const SCALE_PPI: u8 = 1;
+
+/// Uncompressed, a byte (or two) per pixel
+const COMPRESSION_NONE: u8 = 0;
+
+/// The quality of a view was not measured
+const QUALITY_UNKNOWN: u8 = 254;
+
+/// Frames as ISO/IEC 19794-4 records, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct IsoImageExport {
+    device_id: u16,
+    finger: Option<Finger>,
+    impression: Impression,
+    acquisition_level: u16,
+    resolution: u16,
+    quality: Option<u8>,
+}
+
+impl IsoImageExport {
+    /// An unknown finger pressed on an unknown 500 dpi device, quality not measured
+    pub fn new() -> Self {
+        Self {
+            device_id: 0,
+            finger: None,
+            impression: Impression::LivePlain,
+            acquisition_level: 31,
+            resolution: 500,
+            quality: None,
+        }
+    }
+
+    /// The device and the impression of its sensor type
+    pub fn device(self, quirks: &DeviceQuirks) -> Self {
+        self.device_id(quirks.pid)
+            .impression(quirks.sensor_type.into())
+    }
+
+    /// The capture device of the vendor, 0 if unknown
+    pub fn device_id(mut self, id: u16) -> Self {
+        self.device_id = id & 0x0fff;
+        self
+    }
+
+    pub fn finger(mut self, finger: Finger) -> Self {
+        self.finger = Some(finger);
+        self
+    }
+
+    pub fn impression(mut self, impression: Impression) -> Self {
+        self.impression = impression;
+        self
+    }
+
+    /// The setting level of the standard the images follow, 31 by default (500 ppi, 8 bits)
+    pub fn acquisition_level(mut self, level: u16) -> Self {
+        self.acquisition_level = level;
+        self
+    }
+
+    /// Resolution of the sensor, in dots per inch
+    pub fn resolution_dpi(mut self, dpi: u16) -> Self {
+        self.resolution = dpi;
+        self
+    }
+
+    /// Quality of the images (0-100, see [`crate::quality`])
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = Some(quality.min(100));
+        self
+    }
+
+    /// Write the frames as a record, a view each. They must all have the same pixel depth, of 8
+    /// or 16 bits.
+    pub fn write<W: Write>(&self, frames: &[Frame], mut out: W) -> io::Result<()> {
+        let bpp = frames.first().map_or(8, |f| f.bpp);
+        if !matches!(bpp, 8 | 16) {
+            return Err(unsupported(format!(
+                "{bpp} bits per pixel are not supported"
+            )));
+        }
+        if frames.iter().any(|f| f.bpp != bpp) {
+            return Err(unsupported("the frames have different pixel depths".into()));
+        }
+        let views = u8::try_from(frames.len())
+            .map_err(|_| unsupported(format!("{} views don't fit", frames.len())))?;
+
+        let images = frames.iter().map(Frame::big_endian).collect::<Vec<_>>();
+        let len = HEADER_LEN
+            + images
+                .iter()
+                .map(|img| VIEW_HEADER_LEN + img.len())
+                .sum::<usize>();
+
+        let mut header = RECORD_MAGIC.to_vec();
+        header.extend(&(len as u64).to_be_bytes()[2..]);
+        header.extend(self.device_id.to_be_bytes());
+        header.extend(self.acquisition_level.to_be_bytes());
+        header.push(1);
+        header.push(SCALE_PPI);
+        // The scan and the image resolutions, the same without scaling
+        for _ in 0..4 {
+            header.extend(self.resolution.to_be_bytes());
+        }
+        header.push(bpp);
+        header.push(COMPRESSION_NONE);
+        header.extend([0; 2]);
+        debug_assert_eq!(header.len(), HEADER_LEN);
+        out.write_all(&header)?;
+
+        for (i, (frame, image)) in frames.iter().zip(&images).enumerate() {
+            let mut view = ((VIEW_HEADER_LEN + image.len()) as u32)
+                .to_be_bytes()
+                .to_vec();
+            view.push(self.finger.map_or(0, Finger::iso_position));
+            view.push(views);
+            view.push(i as u8 + 1);
+            view.push(self.quality.unwrap_or(QUALITY_UNKNOWN));
+            view.push(self.impression as u8);
+            view.extend(frame.width.to_be_bytes());
+            view.extend(frame.height.to_be_bytes());
+            view.push(0);
+            out.write_all(&view)?;
+            out.write_all(image)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for IsoImageExport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn unsupported(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(bpp: u8, data: &[u8]) -> Frame {
+        Frame {
+            width: 2,
+            height: 1,
+            bpp,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn record() {
+        let frames = [frame(16, &[1, 2, 3, 4]), frame(16, &[5, 6, 7, 8])];
+        let mut data = vec![];
+        IsoImageExport::new()
+            .device_id(0xf123)
+            .finger(Finger::LeftIndex)
+            .impression(Impression::LiveSwipe)
+            .quality(80)
+            .write(&frames, &mut data)
+            .unwrap();
+
+        assert_eq!(&data[..8], RECORD_MAGIC);
+        assert_eq!(data.len(), HEADER_LEN + 2 * (VIEW_HEADER_LEN + 4));
+        assert_eq!(data[8..14], [0, 0, 0, 0, 0, data.len() as u8]);
+        // The device, level 31, one finger in ppi
+        assert_eq!(data[14..20], [0x01, 0x23, 0, 31, 1, SCALE_PPI]);
+        assert_eq!(data[20..28], [0x01, 0xf4].repeat(4)[..]);
+        assert_eq!(data[28..32], [16, COMPRESSION_NONE, 0, 0]);
+
+        // Left index, the first of two views, swiped, 2x1 pixels, then the big endian pixels
+        let view = &data[HEADER_LEN..HEADER_LEN + VIEW_HEADER_LEN + 4];
+        assert_eq!(
+            view,
+            [0, 0, 0, 18, 7, 2, 1, 80, 8, 0, 2, 0, 1, 0, 2, 1, 4, 3]
+        );
+        // The second view
+        assert_eq!(data[HEADER_LEN + 18 + 6], 2);
+        assert_eq!(data[data.len() - 4..], [6, 5, 8, 7]);
+    }
+
+    #[test]
+    fn unknown_quality() {
+        let mut data = vec![];
+        IsoImageExport::new()
+            .write(&[frame(8, &[1, 2])], &mut data)
+            .unwrap();
+        assert_eq!(
+            data[HEADER_LEN + 4..HEADER_LEN + 9],
+            [0, 1, 1, QUALITY_UNKNOWN, 0]
+        );
+        assert_eq!(data[HEADER_LEN + VIEW_HEADER_LEN..], [1, 2]);
+    }
+
+    #[test]
+    fn unsupported_frames() {
+        let export = IsoImageExport::new();
+        let err = export.write(&[frame(4, &[0])], vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        let mixed = [frame(8, &[1, 2]), frame(16, &[1, 2, 3, 4])];
+        assert!(export.write(&mixed, vec![]).is_err());
+        assert!(export.write(&vec![frame(8, &[1, 2]); 256], vec![]).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+//! Where the pairing data (see [`crate::pairing`]) is kept between runs. It contains the private
+//! key of the host, anybody able to read it can talk to the sensor as if it was this host.
+//!
+//! - [`FileKeyStore`]: a file per sensor, only readable by its owner
+//! - [`SecretServiceKeyStore`](secret_service::SecretServiceKeyStore): the freedesktop Secret
+//!   Service (GNOME Keyring, KWallet, ...), with the `secret-service` feature
+//! - [`TpmKeyStore`](tpm::TpmKeyStore): the host key is sealed by the TPM2 (or generated inside
+//!   it), with the `tpm` feature
+
+#[cfg(feature = "secret-service")]
+pub mod secret_service;
+#[cfg(feature = "tpm")]
+pub mod tpm;
+
+use crate::{
+    DriverError,
+    hostkey::HostKey,
+    pairing::{decode_pairing, encode_pairing, write_private},
+    secure::SessionParams,
+    transport::Transport,
+};
+use std::{fs, io, path::PathBuf};
+
+/// Storage of the pairing data, every sensor is identified by an id (see [`store_id`])
+pub trait KeyStore {
+    /// Load the pairing data of the sensor, `None` if it was never saved
+    fn load(&self, id: &str) -> Result<Option<SessionParams>, DriverError>;
+
+    /// Save the pairing data of the sensor, replacing the previous one
+    fn save(&self, id: &str, params: &SessionParams) -> Result<(), DriverError>;
+
+    /// Delete the pairing data of the sensor, if any
+    fn delete(&self, id: &str) -> Result<(), DriverError>;
+
+    /// A new host key to pair the sensor with, a random one kept in memory unless the store
+    /// can do better
+    fn generate_key(&self, _id: &str) -> Result<HostKey, DriverError> {
+        Ok(HostKey::generate())
+    }
+}
+
+/// The id of the sensor in a [`KeyStore`]: its serial number, or its vendor and product ids if
+/// it has none
+pub fn store_id<T: Transport + ?Sized>(dev: &T) -> String {
+    dev.serial_number().unwrap_or_else(|| {
+        let quirks = dev.quirks();
+        format!("{:04x}-{:04x}", quirks.vid, quirks.pid)
+    })
+}
+
+/// Keeps the pairing data of each sensor in its own file, in the format of
+/// [`crate::pairing::save_pairing`]
+#[derive(Debug, Clone)]
+pub struct FileKeyStore {
+    dir: PathBuf,
+}
+
+impl FileKeyStore {
+    /// Used by [`Self::default`]
+    pub const DEFAULT_DIR: &'static str = "/var/lib/validity/pairing";
+
+    /// Store the files in the given directory, created if missing
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The file of the given sensor
+    pub fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.pair", file_name(id)))
+    }
+}
+
+impl Default for FileKeyStore {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_DIR)
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn load(&self, id: &str) -> Result<Option<SessionParams>, DriverError> {
+        match fs::read(self.path(id)) {
+            Ok(data) => decode_pairing(&data).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(DriverError::KeyStoreIo(e)),
+        }
+    }
+
+    fn save(&self, id: &str, params: &SessionParams) -> Result<(), DriverError> {
+        let data = encode_pairing(params)?;
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| write_private(self.path(id), &data))
+            .map_err(DriverError::KeyStoreIo)
+    }
+
+    fn delete(&self, id: &str) -> Result<(), DriverError> {
+        match fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(DriverError::KeyStoreIo(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The id, usable as a file name
+pub(crate) fn file_name(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
@@ -0,0 +1,73 @@
+//! Pairing data stored as a secret of the freedesktop Secret Service, unlocked with the session
+//! of the user (so it is only useful for per-user applications, not for system daemons).
+
+use super::KeyStore;
+use crate::{
+    DriverError,
+    pairing::{decode_pairing, encode_pairing},
+    secure::SessionParams,
+};
+use secret_service::{EncryptionType, blocking::SecretService};
+use std::collections::HashMap;
+
+/// Attribute identifying the secrets of this driver
+const APPLICATION: &str = "validity-sens";
+
+/// Keeps the pairing data in the default collection of the Secret Service
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SecretServiceKeyStore;
+
+impl SecretServiceKeyStore {
+    fn connect() -> Result<SecretService<'static>, DriverError> {
+        SecretService::connect(EncryptionType::Dh).map_err(backend)
+    }
+}
+
+impl KeyStore for SecretServiceKeyStore {
+    fn load(&self, id: &str) -> Result<Option<SessionParams>, DriverError> {
+        let ss = Self::connect()?;
+        let found = ss.search_items(attributes(id)).map_err(backend)?;
+
+        let Some(item) = found.unlocked.first().or(found.locked.first()) else {
+            return Ok(None);
+        };
+
+        item.ensure_unlocked().map_err(backend)?;
+        decode_pairing(&item.get_secret().map_err(backend)?).map(Some)
+    }
+
+    fn save(&self, id: &str, params: &SessionParams) -> Result<(), DriverError> {
+        let ss = Self::connect()?;
+        let collection = ss.get_default_collection().map_err(backend)?;
+        collection.ensure_unlocked().map_err(backend)?;
+
+        collection
+            .create_item(
+                &format!("Fingerprint sensor pairing ({id})"),
+                attributes(id),
+                &encode_pairing(params)?,
+                true,
+                "application/octet-stream",
+            )
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), DriverError> {
+        let ss = Self::connect()?;
+        let found = ss.search_items(attributes(id)).map_err(backend)?;
+
+        for item in found.unlocked.iter().chain(&found.locked) {
+            item.delete().map_err(backend)?;
+        }
+        Ok(())
+    }
+}
+
+fn attributes(id: &str) -> HashMap<&str, &str> {
+    HashMap::from([("application", APPLICATION), ("device", id)])
+}
+
+fn backend(e: secret_service::Error) -> DriverError {
+    DriverError::KeyStoreBackend(Box::new(e))
+}
@@ -0,0 +1,421 @@
+//! The host key sealed by the TPM2, so it can only be recovered on this machine: the sealed
+//! object is useless on another one (or after the TPM is cleared). The TPM is driven with the
+//! `tpm2-tools` commands, which must be installed.
+//!
+//! Sealed objects are limited to 128 bytes, so only the host key is sealed: the public parts of
+//! the pairing (the certificate and the sensor key) are stored next to it.
+//!
+//! With [`TpmKeyStore::resident`] the host key is generated by the TPM instead and never leaves
+//! it, the ECDH and the signatures of the handshake are done by the TPM (see [`TpmKey`]).
+//!
+//! Both the sealed object and the resident key can only be used while the PCRs of the firmware
+//! and the Secure Boot state (0 and 7) have the values they had when the key was stored, after
+//! a firmware or Secure Boot update the sensor has to be paired again. The files
+//! are only readable by their owner, and every operation works in its own scratch directory.
+
+use super::{KeyStore, file_name};
+use crate::{DriverError, hostkey::HostKey, pairing::write_private, secure::SessionParams};
+use p256::{PublicKey, SecretKey, elliptic_curve::sec1::ToEncodedPoint, pkcs8::DecodePublicKey};
+use rand_core::{OsRng, RngCore};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Attributes of the keys generated by [`TpmKeyStore::generate_key`]: bound to this TPM, for
+/// both ECDH (`decrypt`) and ECDSA (`sign`), only usable with the policy
+const KEY_ATTRIBUTES: &str =
+    "fixedtpm|fixedparent|sensitivedataorigin|adminwithpolicy|sign|decrypt";
+
+/// Attributes of the sealed host key: bound to this TPM, only unsealed with the policy
+const SEALED_ATTRIBUTES: &str = "fixedtpm|fixedparent|adminwithpolicy";
+
+/// The PCRs of the policy of the stored keys: the firmware (0) and the Secure Boot state (7)
+const POLICY_PCRS: &str = "sha256:0,7";
+
+/// A directory for the files of one operation (the contexts, the inputs and outputs of the
+/// tools), only readable by its owner and removed once dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    /// A new directory inside `dir`, with a random name
+    fn new(dir: &Path) -> io::Result<Self> {
+        let mut builder = fs::DirBuilder::new();
+
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+        let path = dir.join(format!(".scratch-{:016x}", OsRng.next_u64()));
+        builder.create(&path)?;
+        Ok(Self(path))
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    /// Create the primary key in the owner hierarchy, the same every time for the same TPM
+    fn primary(&self) -> io::Result<PathBuf> {
+        let ctx = self.path("primary.ctx");
+        tpm2(&["tpm2_createprimary", "-Q", "-C", "o", "-c"], &ctx)?;
+        Ok(ctx)
+    }
+
+    /// The policy of the stored keys, see [`POLICY_PCRS`]
+    fn policy(&self) -> io::Result<PathBuf> {
+        let digest = self.path("policy.digest");
+        let cmd = [
+            "tpm2_createpolicy",
+            "-Q",
+            "--policy-pcr",
+            "-l",
+            POLICY_PCRS,
+            "-L",
+        ];
+        tpm2(&cmd, &digest)?;
+        Ok(digest)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The auth of the tools using a stored key, satisfying its policy
+fn policy_auth() -> String {
+    format!("pcr:{POLICY_PCRS}")
+}
+
+/// Keeps the sealed host key and the public parts of the pairing in a directory
+#[derive(Debug, Clone)]
+pub struct TpmKeyStore {
+    dir: PathBuf,
+    resident: bool,
+}
+
+impl TpmKeyStore {
+    /// Used by [`Self::default`]
+    pub const DEFAULT_DIR: &'static str = "/var/lib/validity/tpm";
+
+    /// Store the files in the given directory, created if missing
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            resident: false,
+        }
+    }
+
+    /// Whether new host keys (see [`KeyStore::generate_key`]) are generated inside the TPM,
+    /// instead of in memory and then sealed. The pairings saved before keep working.
+    pub fn resident(mut self, resident: bool) -> Self {
+        self.resident = resident;
+        self
+    }
+
+    fn path(&self, id: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{ext}", file_name(id)))
+    }
+
+    /// Seal the key with the primary key, under the policy
+    fn seal(&self, id: &str, key: &[u8]) -> io::Result<()> {
+        let scratch = Scratch::new(&self.dir)?;
+        let primary = scratch.primary()?;
+        let policy = scratch.policy()?;
+        Command::new("tpm2_create")
+            .args(["-Q", "-a", SEALED_ATTRIBUTES, "-C"])
+            .arg(&primary)
+            .arg("-L")
+            .arg(&policy)
+            .arg("-u")
+            .arg(scratch.path("sealed.pub"))
+            .arg("-r")
+            .arg(scratch.path("sealed.priv"))
+            .args(["-i", "-"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(key)?;
+                }
+                child.wait()
+            })
+            .and_then(check_status)?;
+        self.keep(id, &scratch, "sealed")
+    }
+
+    /// Load the sealed object and unseal the key
+    fn unseal(&self, id: &str) -> io::Result<Vec<u8>> {
+        self.with_object(id, "sealed", |_, object| {
+            tpm2(&["tpm2_unseal", "-Q", "-p", &policy_auth(), "-c"], object)
+        })
+    }
+
+    /// Move the object created in the scratch directory (`<name>.pub` and `<name>.priv`) to the
+    /// store
+    fn keep(&self, id: &str, scratch: &Scratch, name: &str) -> io::Result<()> {
+        for ext in ["pub", "priv"] {
+            let file = format!("{name}.{ext}");
+            write_private(self.path(id, &file), &fs::read(scratch.path(&file))?)?;
+        }
+        Ok(())
+    }
+
+    /// Load the object (`<name>.pub` and `<name>.priv`) and run the operation with its context
+    fn with_object<R>(
+        &self,
+        id: &str,
+        name: &str,
+        op: impl FnOnce(&Scratch, &Path) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let scratch = Scratch::new(&self.dir)?;
+        let primary = scratch.primary()?;
+        let object = scratch.path("object.ctx");
+        Command::new("tpm2_load")
+            .args(["-Q", "-C"])
+            .arg(&primary)
+            .arg("-u")
+            .arg(self.path(id, &format!("{name}.pub")))
+            .arg("-r")
+            .arg(self.path(id, &format!("{name}.priv")))
+            .arg("-c")
+            .arg(&object)
+            .status()
+            .and_then(check_status)?;
+        op(&scratch, &object)
+    }
+
+    /// Create a P-256 key in the TPM, under the policy. Its public part is saved to `key.der`.
+    fn create_key(&self, id: &str) -> io::Result<PublicKey> {
+        let scratch = Scratch::new(&self.dir)?;
+        let primary = scratch.primary()?;
+        let policy = scratch.policy()?;
+        Command::new("tpm2_create")
+            .args(["-Q", "-G", "ecc256", "-a", KEY_ATTRIBUTES, "-C"])
+            .arg(&primary)
+            .arg("-L")
+            .arg(&policy)
+            .arg("-u")
+            .arg(scratch.path("key.pub"))
+            .arg("-r")
+            .arg(scratch.path("key.priv"))
+            .status()
+            .and_then(check_status)?;
+        self.keep(id, &scratch, "key")?;
+
+        let der = self.with_object(id, "key", |scratch, object| {
+            let der = scratch.path("key.der");
+            Command::new("tpm2_readpublic")
+                .args(["-Q", "-f", "der", "-c"])
+                .arg(object)
+                .arg("-o")
+                .arg(&der)
+                .status()
+                .and_then(check_status)?;
+            fs::read(der)
+        })?;
+        let path = self.path(id, "key.der");
+        fs::write(&path, der)?;
+        read_public_key(&path)
+    }
+}
+
+impl Default for TpmKeyStore {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_DIR)
+    }
+}
+
+/// A P-256 key generated by the TPM (see [`TpmKeyStore::resident`]), the files kept can only be
+/// loaded by this TPM
+#[derive(Debug, Clone)]
+pub struct TpmKey {
+    store: TpmKeyStore,
+    id: String,
+    public: PublicKey,
+}
+
+impl TpmKey {
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    /// The x coordinate of the shared point, computed by the TPM
+    pub(crate) fn diffie_hellman(&self, peer: &PublicKey) -> Result<[u8; 32], DriverError> {
+        self.store
+            .with_object(&self.id, "key", |scratch, object| {
+                let (input, output) = (scratch.path("peer"), scratch.path("z"));
+                fs::write(&input, encode_point(peer))?;
+                Command::new("tpm2_ecdhzgen")
+                    .args(["-Q", "-p", &policy_auth(), "-c"])
+                    .arg(object)
+                    .arg("-u")
+                    .arg(&input)
+                    .arg("-o")
+                    .arg(&output)
+                    .status()
+                    .and_then(check_status)?;
+                fs::read(output)
+            })
+            .and_then(|z| {
+                decode_x(&z).ok_or_else(|| io::Error::other("invalid point from tpm2_ecdhzgen"))
+            })
+            .map_err(DriverError::HostKeyTpm)
+    }
+
+    /// ECDSA signature (DER) of the SHA-256 of the message, made by the TPM
+    pub(crate) fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, DriverError> {
+        self.store
+            .with_object(&self.id, "key", |scratch, object| {
+                let (input, output) = (scratch.path("msg"), scratch.path("sig"));
+                fs::write(&input, msg)?;
+                Command::new("tpm2_sign")
+                    .args(["-Q", "-g", "sha256", "-s", "ecdsa", "-f", "plain"])
+                    .args(["-p", &policy_auth(), "-c"])
+                    .arg(object)
+                    .arg("-o")
+                    .arg(&output)
+                    .arg(&input)
+                    .status()
+                    .and_then(check_status)?;
+                fs::read(output)
+            })
+            .map_err(DriverError::HostKeyTpm)
+    }
+}
+
+impl KeyStore for TpmKeyStore {
+    fn load(&self, id: &str) -> Result<Option<SessionParams>, DriverError> {
+        let public = match fs::read(self.path(id, "public")) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(DriverError::KeyStoreIo(e)),
+        };
+
+        let der = self.path(id, "key.der");
+        let host_key = if der.exists() {
+            HostKey::Tpm(TpmKey {
+                store: self.clone(),
+                id: id.to_string(),
+                public: read_public_key(&der).map_err(DriverError::KeyStoreIo)?,
+            })
+        } else {
+            let key = self.unseal(id).map_err(DriverError::KeyStoreIo)?;
+            SecretKey::from_slice(&key)
+                .map_err(|_| DriverError::PairingInvalid)?
+                .into()
+        };
+        let (host_cert, device_key) = decode_public(&public).ok_or(DriverError::PairingInvalid)?;
+
+        Ok(Some(SessionParams {
+            host_key,
+            host_cert,
+            device_key,
+        }))
+    }
+
+    /// A key generated by the TPM for another sensor can't be saved, it belongs to its id
+    fn save(&self, id: &str, params: &SessionParams) -> Result<(), DriverError> {
+        fs::create_dir_all(&self.dir).map_err(DriverError::KeyStoreIo)?;
+
+        match &params.host_key {
+            HostKey::Software(key) => self.seal(id, &key.to_bytes()),
+            HostKey::Tpm(key) if key.id == id && key.store.dir == self.dir => Ok(()),
+            HostKey::Tpm(_) => return Err(DriverError::HostKeyNotExportable),
+        }
+        .and_then(|_| write_private(self.path(id, "public"), &encode_public(params)))
+        .map_err(DriverError::KeyStoreIo)
+    }
+
+    fn delete(&self, id: &str) -> Result<(), DriverError> {
+        let exts = ["public", "sealed.pub", "sealed.priv"];
+        for ext in exts.into_iter().chain(["key.pub", "key.priv", "key.der"]) {
+            match fs::remove_file(self.path(id, ext)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(DriverError::KeyStoreIo(e));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn generate_key(&self, id: &str) -> Result<HostKey, DriverError> {
+        if !self.resident {
+            return Ok(HostKey::generate());
+        }
+
+        fs::create_dir_all(&self.dir).map_err(DriverError::KeyStoreIo)?;
+        let public = self.create_key(id).map_err(DriverError::HostKeyTpm)?;
+        Ok(HostKey::Tpm(TpmKey {
+            store: self.clone(),
+            id: id.to_string(),
+            public,
+        }))
+    }
+}
+
+/// Run a `tpm2-tools` command with the path as its last argument, returns its output
+fn tpm2(cmd: &[&str], path: &Path) -> io::Result<Vec<u8>> {
+    let out = Command::new(cmd[0]).args(&cmd[1..]).arg(path).output()?;
+    check_status(out.status)?;
+    Ok(out.stdout)
+}
+
+fn check_status(status: std::process::ExitStatus) -> io::Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("tpm2-tools failed: {status}")))
+    }
+}
+
+/// Certificate length (u16), the certificate and the sensor key
+fn encode_public(params: &SessionParams) -> Vec<u8> {
+    let mut data = (params.host_cert.len() as u16).to_le_bytes().to_vec();
+    data.extend(&params.host_cert);
+    data.extend(params.device_key.to_encoded_point(false).as_bytes());
+    data
+}
+
+fn decode_public(data: &[u8]) -> Option<(Vec<u8>, PublicKey)> {
+    let (len, data) = data.split_first_chunk()?;
+    let (cert, key) = data.split_at_checked(u16::from_le_bytes(*len) as usize)?;
+    Some((cert.to_vec(), PublicKey::from_sec1_bytes(key).ok()?))
+}
+
+fn read_public_key(path: &Path) -> io::Result<PublicKey> {
+    PublicKey::from_public_key_der(&fs::read(path)?)
+        .map_err(|_| io::Error::other("invalid public key from tpm2_readpublic"))
+}
+
+/// A TPM2B_ECC_POINT, as read by the tools: size (u16), x and y, each one with its size (u16),
+/// all big endian
+fn encode_point(key: &PublicKey) -> Vec<u8> {
+    let point = key.to_encoded_point(false);
+    let mut data = Vec::new();
+    for coord in [point.x(), point.y()].into_iter().flatten() {
+        data.extend((coord.len() as u16).to_be_bytes());
+        data.extend(coord);
+    }
+
+    let mut res = (data.len() as u16).to_be_bytes().to_vec();
+    res.extend(data);
+    res
+}
+
+/// The x coordinate of a TPM2B_ECC_POINT, see [`encode_point`]
+fn decode_x(data: &[u8]) -> Option<[u8; 32]> {
+    let data = data.get(2..)?;
+    let (len, data) = data.split_first_chunk()?;
+    let x = data.get(..u16::from_be_bytes(*len) as usize)?;
+
+    // The leading zeros may be left out
+    let mut res = [0u8; 32];
+    res.get_mut(32usize.checked_sub(x.len())?..)?
+        .copy_from_slice(x);
+    Some(res)
+}
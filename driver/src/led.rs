@@ -0,0 +1,44 @@
+//! The sensors with a LED ring run small scripts to drive it, the firmware keeps running the last
+//! one until another is sent.
+
+use crate::{DriverError, proto::Command, transport::Transport};
+
+/// Length of each breathing cycle, in milliseconds
+const BREATHING_PERIOD: u16 = 1500;
+
+/// What the LED should do, see [`Led::set_led`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedMode {
+    Off,
+
+    /// Fade in and out, to ask the user to touch the sensor
+    Breathing,
+
+    /// On while the sensor is armed, off once the finger was scanned
+    Capture,
+}
+
+impl LedMode {
+    /// The script run by the firmware: mode (u8), brightness (u8) and period (u16, ms)
+    fn script(self) -> [u8; 4] {
+        let (mode, brightness, period) = match self {
+            Self::Off => (0x00, 0x00, 0),
+            Self::Breathing => (0x01, 0xff, BREATHING_PERIOD),
+            Self::Capture => (0x02, 0xff, 0),
+        };
+
+        let [lo, hi] = u16::to_le_bytes(period);
+        [mode, brightness, lo, hi]
+    }
+}
+
+/// LED control, implemented for every [`Transport`]
+pub trait Led: Transport {
+    /// Change what the LED does, until the next call
+    fn set_led(&self, mode: LedMode) -> Result<(), DriverError> {
+        self.run(&Command::LedCtrl(&mode.script()), &mut [0u8; 64])?;
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Led for T {}
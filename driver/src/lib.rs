@@ -1,10 +1,76 @@
+#[cfg(feature = "minutiae")]
+pub mod ansi;
+pub mod backup;
+pub mod bench;
+pub mod calibration;
+pub mod cancel;
+pub mod capture;
+pub mod enroll;
+pub mod events;
+pub mod firmware;
+pub mod flash;
+#[cfg(feature = "fprint")]
+pub mod fprint;
+pub mod hostkey;
+pub mod hotplug;
+pub mod identify;
+pub mod info;
+pub mod integrity;
+#[cfg(feature = "minutiae")]
+pub mod iso;
+pub mod iso_image;
+pub mod keystore;
+pub mod led;
+pub mod lifecycle;
+pub mod manager;
+pub mod matcher;
+pub mod metadata;
+#[cfg(feature = "minutiae")]
+pub mod minutiae;
+pub mod normalize;
+pub mod otp;
+pub mod pairing;
+pub mod platform;
+pub mod pool;
+pub mod power;
+pub mod proto;
+pub mod quality;
+pub mod quirks;
+#[cfg(feature = "record")]
+pub mod record;
+pub mod reset;
+pub mod secure;
+pub mod self_test;
+pub mod settings;
+pub mod setup;
+pub mod shared;
+pub mod sink;
+pub mod stitch;
+pub mod storage;
+pub mod timeouts;
+mod trace;
+pub mod transport;
 pub mod usb;
+pub mod wrap;
 
+/// Every trait needed to talk to the sensor: `use driver::prelude::*;`
+pub mod prelude {
+    pub use crate::{
+        backup::Backup, calibration::Calibrate, capture::Capture, enroll::Enroll,
+        firmware::FirmwareUpdate, flash::Flash, identify::Identify, info::Info, led::Led,
+        matcher::HostMatch, otp::Otp, pairing::Pair, power::Power, reset::FactoryReset,
+        self_test::SelfTest, settings::Settings, storage::Storage, transport::Transport,
+    };
+}
+
+use hotplug::DeviceWatcher;
+use quirks::DeviceQuirks;
+use rusb::{GlobalContext, UsbContext};
 use usb::UsbDevice;
 
-/// List of supported device IDs (only one for now, as im testing it on my sensor.
-/// They have the format: (vendor, product)
-pub const SUPPORTED: &[(u16, u16)] = &[(0x138a, 0x0097)];
+/// List of supported devices and their quirks (only the 0097 was tested, as its my sensor),
+/// more can be added at runtime, see [`quirks::DeviceRegistry`]
+pub const SUPPORTED: &[DeviceQuirks] = quirks::QUIRKS;
 
 #[derive(thiserror::Error, Debug)]
 pub enum DriverError {
@@ -23,6 +89,24 @@ pub enum DriverError {
     #[error("Could not call open() on the USB device")]
     OpenDevice(#[source] rusb::Error),
 
+    #[error("Could not detach the kernel driver from the USB device")]
+    UsbDetachKernelDriver(#[source] rusb::Error),
+
+    #[error("Could not set the configuration of the USB device")]
+    UsbSetConfiguration(#[source] rusb::Error),
+
+    #[error("Could not claim the interface of the USB device")]
+    UsbClaimInterface(#[source] rusb::Error),
+
+    #[error("Could not release USB interface")]
+    UsbReleaseInterface(#[source] rusb::Error),
+
+    #[error("Hotplug is not supported on this platform")]
+    HotplugUnsupported,
+
+    #[error("Could not register the hotplug callback")]
+    HotplugRegister(#[source] rusb::Error),
+
     #[error("Error writing data to the USB device")]
     UsbWrite(#[source] rusb::Error),
 
@@ -32,22 +116,336 @@ pub enum DriverError {
     #[error("Could not read response from USB device")]
     UsbReadResponse(#[source] rusb::Error),
 
+    #[error("Could not read from the USB interrupt endpoint")]
+    UsbReadInterrupt(#[source] rusb::Error),
+
     #[error("Could not reset USB device")]
     UsbReset(#[source] rusb::Error),
 
+    #[error("Could not handle the USB events")]
+    UsbHandleEvents(#[source] rusb::Error),
+
     #[error("Device returned an invalid response")]
     UsbInitInvalid,
 
-    #[error("Failed, code: {0:04x}")]
-    UsbInitFailed(u16),
+    #[error("{0}")]
+    UsbInitFailed(proto::StatusCode),
+
+    #[error("The device is in its bootloader, the firmware has to be uploaded again")]
+    Bootloader,
+
+    #[error("Device returned an invalid enrollment response")]
+    EnrollInvalidResponse,
+
+    #[error("Enrollment did not finish after {0} samples")]
+    EnrollIncomplete(u32),
+
+    #[error("Device returned an invalid match response")]
+    MatchInvalidResponse,
+
+    #[error("Device returned an invalid frame")]
+    CaptureInvalidResponse,
+
+    #[error("Frame transfer ended early, got {0} of {1} bytes")]
+    CaptureIncomplete(usize, usize),
+
+    #[error("A multi-part transfer was corrupted")]
+    Integrity(#[source] integrity::IntegrityError),
+
+    #[error("The capture settings are out of range")]
+    CaptureSettingsInvalid,
+
+    #[error("Device returned an invalid register response")]
+    RegisterInvalidResponse,
+
+    #[error("The host template is not valid")]
+    HostTemplateInvalid,
+
+    #[error("The frame can't be stitched to the previous ones")]
+    StitchInvalidFrame,
+
+    #[error("Device returned an invalid storage response")]
+    StorageInvalidResponse,
+
+    #[error("{0} templates are still stored after erasing them")]
+    StorageNotEmpty(usize),
+
+    #[error("No room left for another template ({windows} were enrolled from Windows)")]
+    StorageFull { windows: usize },
+
+    #[error("Template {0} was not enrolled from Windows")]
+    StorageNotWindows(u16),
+
+    #[error("The firmware file is not valid")]
+    FirmwareInvalid,
+
+    #[error("The firmware read back from the device does not match the uploaded one")]
+    FirmwareVerifyFailed,
+
+    #[error("The firmware does not match the hash in its header")]
+    FirmwareHashMismatch,
+
+    #[error("The firmware signature is blank")]
+    FirmwareBlankSignature,
+
+    #[error("The firmware header has no {0} field, it can only be uploaded forced")]
+    FirmwareHeaderMissing(&'static str),
+
+    #[error("The firmware is for hardware {firmware:02x}, the device is {device:02x}")]
+    FirmwareIncompatible { firmware: u8, device: u8 },
+
+    #[error("Device returned an invalid flash response")]
+    FlashInvalidResponse,
+
+    #[error("Device returned an invalid version response")]
+    InfoInvalidResponse,
+
+    #[error("The calibration data is not valid")]
+    CalibrationInvalid,
+
+    #[error("Device returned an invalid OTP response")]
+    OtpInvalidResponse,
+
+    #[error("Device returned an invalid self-test response")]
+    SelfTestInvalidResponse,
+
+    #[error("The device has no calibration partition")]
+    CalibrationMissing,
+
+    #[error("The calibration data read back from the device does not match the written one")]
+    CalibrationVerifyFailed,
+
+    #[error("Device returned an invalid pairing response")]
+    PairingInvalidResponse,
+
+    #[error("Could not read or write the pairing file")]
+    PairingIo(#[source] std::io::Error),
+
+    #[error("The pairing file is not valid")]
+    PairingInvalid,
+
+    #[error("No pairing data was loaded")]
+    PairingMissing,
+
+    #[error("Could not read or write the key store")]
+    KeyStoreIo(#[source] std::io::Error),
+
+    #[error("The key store failed")]
+    KeyStoreBackend(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Could not read or write the host identity")]
+    HostIdentityIo(#[source] std::io::Error),
+
+    #[error("The host identity is not valid")]
+    HostIdentityInvalid,
+
+    #[error("The host key is kept by the TPM, it can't be exported")]
+    HostKeyNotExportable,
+
+    #[error("The TPM failed to use the host key")]
+    HostKeyTpm(#[source] std::io::Error),
+
+    #[error("Could not read or write the template metadata")]
+    MetadataIo(#[source] std::io::Error),
+
+    #[error("The user name {0:?} can't be used in the template metadata")]
+    MetadataInvalidUser(String),
+
+    #[error("Every user namespace is taken")]
+    MetadataNamespacesFull,
+
+    #[error("Could not read or write the backup file")]
+    BackupIo(#[source] std::io::Error),
+
+    #[error("The backup file is not valid")]
+    BackupInvalid,
+
+    #[error("The finger event listener stopped")]
+    ListenerStopped,
+
+    #[error("The operation was cancelled")]
+    Cancelled,
+
+    #[error("Response to command {cmd:02x} is too short, got {got} bytes, expected {expected}")]
+    MalformedResponse {
+        cmd: u8,
+        got: usize,
+        expected: usize,
+    },
+
+    #[error("Response to command {cmd:02x} has {field} out of range: {value}")]
+    ResponseOutOfRange {
+        cmd: u8,
+        field: &'static str,
+        value: u32,
+    },
+
+    #[error("Command {opcode:02x} failed")]
+    CommandFailed {
+        opcode: u8,
+
+        /// The whole command, as written to the device
+        cmd: Vec<u8>,
 
-    #[error("Signature validation failed, code: {0:04x}")]
-    UsbInitSignatureFailed(u16),
+        /// The response read (status included), empty if it could not be read
+        resp: Vec<u8>,
+
+        #[source]
+        source: Box<DriverError>,
+    },
+
+    #[error("Could not read the transcript")]
+    RecordIo(#[source] std::io::Error),
+
+    #[error("Could not read the sysfs attributes of the device")]
+    SysfsIo(#[source] std::io::Error),
+
+    #[error("The sysfs attributes of the device are invalid")]
+    SysfsInvalid,
+
+    #[error("Invalid transcript, line {0}")]
+    RecordInvalid(usize),
+
+    #[error("Mock transport got an unexpected command: {0:02x?}")]
+    MockUnexpectedCommand(Vec<u8>),
+
+    #[error("Device sent an invalid TLS record")]
+    TlsInvalidRecord,
+
+    #[error("Device sent an unexpected TLS record, type: {0:02x}")]
+    TlsUnexpectedRecord(u8),
+
+    #[error("Device sent an unexpected TLS handshake message, type: {0:02x}")]
+    TlsUnexpectedHandshake(u8),
+
+    #[error("Device sent a TLS alert, level: {0:02x}, description: {1:02x}")]
+    TlsAlert(u8, u8),
+
+    #[error("TLS record MAC does not match")]
+    TlsBadMac,
+
+    #[error("TLS handshake with the device failed")]
+    TlsHandshakeFailed,
+
+    #[error("Device sent an invalid wrapped message")]
+    WrapInvalid,
+
+    #[error("Wrapped message tag does not match")]
+    WrapBadTag,
+
+    #[error("Wrapped message out of sequence, expected {expected}, got {got}")]
+    WrapSequence { expected: u32, got: u32 },
+
+    #[error("The command is too long to be wrapped")]
+    WrapTooLong,
+
+    #[error("The sequence numbers ran out, the session has to be established again")]
+    WrapExhausted,
 }
 
-/// List the supported USB devices, see also: [`SUPPORTED`]
+impl DriverError {
+    /// The error without the command context, see [`Self::CommandFailed`]
+    pub fn root(&self) -> &DriverError {
+        match self {
+            Self::CommandFailed { source, .. } => source.root(),
+            e => e,
+        }
+    }
+
+    /// The command that failed, if known
+    pub fn command(&self) -> Option<&[u8]> {
+        match self {
+            Self::CommandFailed { cmd, .. } => Some(cmd),
+            _ => None,
+        }
+    }
+
+    /// The raw response to the command that failed, if it was read
+    pub fn raw_response(&self) -> Option<&[u8]> {
+        match self {
+            Self::CommandFailed { resp, .. } if !resp.is_empty() => Some(resp),
+            _ => None,
+        }
+    }
+
+    /// Whether trying again may work (timeouts, stalls, the device being busy, ...)
+    pub fn is_transient(&self) -> bool {
+        match self.root() {
+            Self::UsbWritePartial | Self::CaptureIncomplete(..) | Self::Integrity(_) => true,
+            e => matches!(
+                e.usb_error(),
+                Some(
+                    rusb::Error::Timeout
+                        | rusb::Error::Pipe
+                        | rusb::Error::Busy
+                        | rusb::Error::Interrupted
+                )
+            ),
+        }
+    }
+
+    /// Whether the device can't be used anymore, it has to be found and opened again
+    pub fn is_fatal(&self) -> bool {
+        match self.root() {
+            Self::GetDeviceNotFound
+            | Self::GetDeviceFoundUnsupported
+            | Self::OpenDevice(_)
+            | Self::UsbClaimInterface(_)
+            | Self::UsbInitFailed(proto::StatusCode::SignatureFailed) => true,
+            e => matches!(
+                e.usb_error(),
+                Some(rusb::Error::NoDevice | rusb::Error::Access)
+            ),
+        }
+    }
+
+    /// Whether the handle stopped working (usually after a suspend), the device may still be
+    /// there, see [`usb::OpenedUsbDevice::reconnect`]
+    pub fn is_disconnected(&self) -> bool {
+        matches!(
+            self.root().usb_error(),
+            Some(rusb::Error::NoDevice | rusb::Error::Io)
+        )
+    }
+
+    /// Add the command (and its response) to the error
+    pub(crate) fn in_command(self, cmd: &[u8], resp: &[u8]) -> Self {
+        Self::CommandFailed {
+            opcode: cmd.first().copied().unwrap_or(0),
+            cmd: cmd.to_vec(),
+            resp: resp.to_vec(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The libusb error behind this one (looking through [`Self::CommandFailed`]), if any
+    pub fn usb_error(&self) -> Option<rusb::Error> {
+        match self.root() {
+            Self::ListDevices(e)
+            | Self::DeviceDescription(e)
+            | Self::OpenDevice(e)
+            | Self::UsbDetachKernelDriver(e)
+            | Self::UsbSetConfiguration(e)
+            | Self::UsbClaimInterface(e)
+            | Self::UsbReleaseInterface(e)
+            | Self::HotplugRegister(e)
+            | Self::UsbWrite(e)
+            | Self::UsbReadResponse(e)
+            | Self::UsbReadInterrupt(e)
+            | Self::UsbReset(e) => Some(*e),
+            _ => None,
+        }
+    }
+}
+
+/// List the supported USB devices, see also: [`quirks::DeviceRegistry`]
 pub fn list_supported_devices() -> Result<Vec<UsbDevice>, DriverError> {
-    let devs = rusb::devices().map_err(DriverError::ListDevices)?;
+    list_supported_devices_in(&GlobalContext::default())
+}
+
+/// Like [`list_supported_devices`], but in a libusb context managed by the caller
+pub fn list_supported_devices_in<C: UsbContext>(ctx: &C) -> Result<Vec<UsbDevice<C>>, DriverError> {
+    let devs = ctx.devices().map_err(DriverError::ListDevices)?;
     let mut res = Vec::new();
 
     for dev in devs.iter() {
@@ -55,11 +453,8 @@ pub fn list_supported_devices() -> Result<Vec<UsbDevice>, DriverError> {
             .device_descriptor()
             .map_err(DriverError::DeviceDescription)?;
 
-        for (vid, pid) in SUPPORTED {
-            if desc.vendor_id() == *vid && desc.product_id() == *pid {
-                res.push(UsbDevice(dev));
-                break;
-            }
+        if let Some(quirks) = DeviceQuirks::find(desc.vendor_id(), desc.product_id()) {
+            res.push(UsbDevice(dev, quirks));
         }
     }
 
@@ -68,7 +463,16 @@ pub fn list_supported_devices() -> Result<Vec<UsbDevice>, DriverError> {
 
 /// Try to get the USB at the given bus number and address
 pub fn get_device(busnum: u8, addr: u8) -> Result<UsbDevice, DriverError> {
-    let devs = rusb::devices().map_err(DriverError::ListDevices)?;
+    get_device_in(&GlobalContext::default(), busnum, addr)
+}
+
+/// Like [`get_device`], but in a libusb context managed by the caller
+pub fn get_device_in<C: UsbContext>(
+    ctx: &C,
+    busnum: u8,
+    addr: u8,
+) -> Result<UsbDevice<C>, DriverError> {
+    let devs = ctx.devices().map_err(DriverError::ListDevices)?;
 
     for dev in devs.iter() {
         if dev.bus_number() != busnum || dev.address() != addr {
@@ -79,14 +483,58 @@ pub fn get_device(busnum: u8, addr: u8) -> Result<UsbDevice, DriverError> {
             .device_descriptor()
             .map_err(DriverError::DeviceDescription)?;
 
-        for (vid, pid) in SUPPORTED {
-            if desc.vendor_id() == *vid && desc.product_id() == *pid {
-                return Ok(UsbDevice(dev));
-            }
-        }
-
-        return Err(DriverError::GetDeviceFoundUnsupported);
+        return DeviceQuirks::find(desc.vendor_id(), desc.product_id())
+            .map(|quirks| UsbDevice(dev, quirks))
+            .ok_or(DriverError::GetDeviceFoundUnsupported);
     }
 
     Err(DriverError::GetDeviceNotFound)
 }
+
+/// Find the supported device with this serial number, unlike the bus address it does not change
+/// across reboots (every device is opened to read it)
+pub fn get_device_by_serial(serial: &str) -> Result<UsbDevice, DriverError> {
+    get_device_by_serial_in(&GlobalContext::default(), serial)
+}
+
+/// Like [`get_device_by_serial`], but in a libusb context managed by the caller
+pub fn get_device_by_serial_in<C: UsbContext>(
+    ctx: &C,
+    serial: &str,
+) -> Result<UsbDevice<C>, DriverError> {
+    list_supported_devices_in(ctx)?
+        .into_iter()
+        .find(|dev| dev.serial_number().as_deref() == Some(serial))
+        .ok_or(DriverError::GetDeviceNotFound)
+}
+
+/// Get the device at this sysfs path (like `/sys/bus/usb/devices/1-3`), the `DEVPATH` given by
+/// udev (without `/sys`) works too
+#[cfg(target_os = "linux")]
+pub fn get_device_by_syspath(path: impl AsRef<std::path::Path>) -> Result<UsbDevice, DriverError> {
+    let path = path.as_ref();
+    let path = match path.strip_prefix("/sys") {
+        Ok(_) => path.to_path_buf(),
+        Err(_) => std::path::Path::new("/sys").join(path.strip_prefix("/").unwrap_or(path)),
+    };
+
+    let attr = |name: &str| -> Result<u8, DriverError> {
+        std::fs::read_to_string(path.join(name))
+            .map_err(DriverError::SysfsIo)?
+            .trim()
+            .parse()
+            .map_err(|_| DriverError::SysfsInvalid)
+    };
+
+    get_device(attr("busnum")?, attr("devnum")?)
+}
+
+/// Watch the supported devices arriving and leaving, the ones already connected are reported
+/// first, see also: [`hotplug::DeviceEvent`]
+pub fn watch_devices() -> Result<DeviceWatcher, DriverError> {
+    if !platform::backend_capabilities().hotplug {
+        return Err(DriverError::HotplugUnsupported);
+    }
+
+    DeviceWatcher::spawn()
+}
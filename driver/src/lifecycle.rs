@@ -0,0 +1,137 @@
+//! Most commands fail with a firmware status that says little when the device was not
+//! initialized, and the TLS handshake needs the keys of a pairing. The wrappers here follow the
+//! life of a device, each step is only reachable from the previous one:
+//!
+//! [`UsbDevice`] (closed) → [`Opened`] → [`Initialized`] → [`Paired`] → [`SecureSession`]
+
+use crate::{
+    DriverError,
+    firmware::{DeviceState, Firmware, FirmwareUpdate},
+    hostkey::HostIdentity,
+    pairing::Pair,
+    secure::{SecureSession, SessionParams},
+    transport::Transport,
+    usb::{OpenOptions, OpenedUsbDevice, UsbDevice},
+};
+use core::ops::{Deref, DerefMut};
+
+/// An opened device, the init sequence was not sent: only the commands that work in the
+/// bootloader too are available
+#[derive(Debug)]
+pub struct Opened<T: Transport = OpenedUsbDevice>(T);
+
+impl Opened {
+    /// Open the device, without initializing it
+    pub fn open(dev: &UsbDevice) -> Result<Self, DriverError> {
+        Self::open_with(dev, OpenOptions::new())
+    }
+
+    /// Open the device as told by the options, the init sequence is never sent
+    pub fn open_with(dev: &UsbDevice, opts: OpenOptions) -> Result<Self, DriverError> {
+        dev.open_with(opts.init(false)).map(Self)
+    }
+}
+
+impl<T: Transport> Opened<T> {
+    /// A device opened without the init sequence
+    pub fn new(dev: T) -> Self {
+        Self(dev)
+    }
+
+    /// Send the init sequence, fails with [`DriverError::Bootloader`] if the firmware did not
+    /// boot (see [`Self::recover`])
+    pub fn init(self) -> Result<Initialized<T>, DriverError> {
+        self.0.send_init()?;
+        Ok(Initialized(self.0))
+    }
+
+    /// Whether the firmware booted, see [`FirmwareUpdate::device_state`]
+    pub fn device_state(&self) -> Result<DeviceState, DriverError> {
+        self.0.device_state()
+    }
+
+    /// See [`FirmwareUpdate::recover`], the device has to be opened again afterwards
+    pub fn recover(self, fw: Option<&Firmware>) -> Result<DeviceState, DriverError> {
+        self.0.recover(fw)
+    }
+
+    /// The device, every check is up to the caller from now on
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// An initialized device, every command not encrypted is available through [`Deref`]
+#[derive(Debug)]
+pub struct Initialized<T: Transport = OpenedUsbDevice>(T);
+
+impl<T: Transport> Initialized<T> {
+    /// Pair the host with the sensor with the given identity, see [`Pair::pair_identity`]
+    pub fn pair(self, identity: HostIdentity) -> Result<Paired<T>, DriverError> {
+        let params = self.0.pair_identity(identity)?;
+        Ok(self.with_pairing(params))
+    }
+
+    /// Use a pairing done before (see [`crate::pairing::load_pairing`])
+    pub fn with_pairing(self, params: SessionParams) -> Paired<T> {
+        Paired {
+            dev: self.0,
+            params,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: Transport> Deref for Initialized<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Transport> DerefMut for Initialized<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// An initialized device with the keys to establish a session
+#[derive(Debug)]
+pub struct Paired<T: Transport = OpenedUsbDevice> {
+    dev: T,
+    params: SessionParams,
+}
+
+impl<T: Transport> Paired<T> {
+    /// The keys of the pairing, to save them
+    pub fn params(&self) -> &SessionParams {
+        &self.params
+    }
+
+    /// Perform the handshake, see [`SecureSession::establish`]
+    pub fn establish(self) -> Result<SecureSession<T>, DriverError> {
+        SecureSession::establish(self.dev, &self.params)
+    }
+
+    pub fn into_inner(self) -> (T, SessionParams) {
+        (self.dev, self.params)
+    }
+}
+
+impl<T: Transport> Deref for Paired<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.dev
+    }
+}
+
+impl<T: Transport> DerefMut for Paired<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.dev
+    }
+}
@@ -0,0 +1,137 @@
+//! Docks and test rigs may have more than one sensor attached. A [`DeviceManager`] opens all of
+//! them and hands out [`DeviceSession`]s, every access to a device goes through its lock so
+//! several threads can share it.
+
+use crate::{
+    DriverError,
+    shared::SharedDevice,
+    usb::{OpenOptions, OpenedUsbDevice},
+};
+use core::fmt;
+use std::thread;
+
+/// Where a device is connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub bus: u8,
+    pub addr: u8,
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:03}:{:03}", self.bus, self.addr)
+    }
+}
+
+/// Shared access to one of the devices of a [`DeviceManager`], clones use the same device
+#[derive(Debug, Clone)]
+pub struct DeviceSession {
+    id: DeviceId,
+    dev: SharedDevice,
+}
+
+impl DeviceSession {
+    /// Where the device was connected when it was opened
+    pub fn id(&self) -> DeviceId {
+        self.id
+    }
+
+    /// Run the operation with the device locked, other users wait until it is done
+    pub fn with<R, F>(&self, op: F) -> Result<R, DriverError>
+    where
+        F: FnOnce(&mut OpenedUsbDevice) -> Result<R, DriverError>,
+    {
+        self.dev.with(op)
+    }
+
+    /// The device, to share it outside of the manager
+    pub fn device(&self) -> &SharedDevice {
+        &self.dev
+    }
+}
+
+/// Owns every supported device connected, see [`Self::open_all`]
+#[derive(Debug, Default)]
+pub struct DeviceManager {
+    sessions: Vec<DeviceSession>,
+}
+
+impl DeviceManager {
+    /// Open every supported device with the same options, fails if any of them can't be opened
+    pub fn open_all(opts: OpenOptions) -> Result<Self, DriverError> {
+        let sessions = crate::list_supported_devices()?
+            .into_iter()
+            .map(|dev| {
+                let id = DeviceId {
+                    bus: dev.0.bus_number(),
+                    addr: dev.0.address(),
+                };
+                let dev = dev.open_with(opts.clone())?;
+                Ok(DeviceSession {
+                    id,
+                    dev: SharedDevice::new(dev),
+                })
+            })
+            .collect::<Result<_, DriverError>>()?;
+
+        Ok(Self { sessions })
+    }
+
+    /// Where the managed devices are connected
+    pub fn ids(&self) -> impl Iterator<Item = DeviceId> + '_ {
+        self.sessions.iter().map(DeviceSession::id)
+    }
+
+    pub fn sessions(&self) -> &[DeviceSession] {
+        &self.sessions
+    }
+
+    /// The session of the device at the given bus and address, if it is managed
+    pub fn session(&self, id: DeviceId) -> Option<DeviceSession> {
+        self.sessions.iter().find(|s| s.id == id).cloned()
+    }
+
+    /// Run the operation on every device at the same time (one thread each), the results are
+    /// tagged with the device they came from
+    pub fn for_each<R, F>(&self, op: F) -> Vec<(DeviceId, Result<R, DriverError>)>
+    where
+        R: Send,
+        F: Fn(&mut OpenedUsbDevice) -> Result<R, DriverError> + Sync,
+    {
+        thread::scope(|scope| {
+            let threads = self
+                .sessions
+                .iter()
+                .map(|s| (s.id, scope.spawn(|| s.with(&op))))
+                .collect::<Vec<_>>();
+
+            threads
+                .into_iter()
+                .map(|(id, thread)| {
+                    let res = thread
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e));
+                    (id, res)
+                })
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_id() {
+        assert_eq!(DeviceId { bus: 1, addr: 23 }.to_string(), "001:023");
+    }
+
+    #[test]
+    fn no_devices() {
+        let manager = DeviceManager::default();
+        assert_eq!(manager.ids().count(), 0);
+        assert!(manager.session(DeviceId { bus: 1, addr: 2 }).is_none());
+        assert!(manager.for_each(|_| Ok(())).is_empty());
+    }
+}
@@ -0,0 +1,457 @@
+//! Matching on the host, for sensors without match-on-chip: the driver captures the frames (see
+//! [`HostMatch`]) and a [`Matcher`] turns them into templates and compares them. The built-in
+//! [`MinutiaeMatcher`] (with the `minutiae` feature) is simple, engines like SourceAFIS can be
+//! plugged in by implementing the trait.
+
+use crate::{
+    DriverError,
+    capture::{Capture, Frame},
+    enroll::EnrollProgress,
+    quality::quality,
+    transport::Transport,
+};
+
+/// Captures tried for every sample asked, bad touches are retried
+const CAPTURES_PER_SAMPLE: u32 = 3;
+
+/// How similar a frame is to a template, higher is better, see [`Matcher::threshold`]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Score(pub f32);
+
+/// A host-side fingerprint matching engine
+pub trait Matcher {
+    /// What is stored for an enrolled finger
+    type Template;
+
+    /// Build a template from several captures of the same finger
+    fn enroll_samples(&self, samples: &[Frame]) -> Result<Self::Template, DriverError>;
+
+    /// Compare the frame with the template
+    fn match_frame(&self, template: &Self::Template, frame: &Frame) -> Result<Score, DriverError>;
+
+    /// Scores at or above this are a match
+    fn threshold(&self) -> Score;
+
+    /// Serialize the template, so it can be stored
+    fn template_to_bytes(&self, template: &Self::Template) -> Vec<u8>;
+
+    /// Parse a template serialized with [`Self::template_to_bytes`]
+    fn template_from_bytes(&self, data: &[u8]) -> Result<Self::Template, DriverError>;
+}
+
+/// Enrollment and verification with a [`Matcher`], implemented for every [`Transport`]
+pub trait HostMatch: Capture {
+    /// Capture `samples` frames of the finger and build a template with them, frames of bad
+    /// quality (see [`crate::quality`]) are not used. `progress_cb` is called after every
+    /// capture, with the quality and coverage of the frame.
+    fn enroll_host<M, F>(
+        &self,
+        matcher: &M,
+        samples: u32,
+        mut progress_cb: F,
+    ) -> Result<M::Template, DriverError>
+    where
+        M: Matcher,
+        F: FnMut(EnrollProgress),
+    {
+        let max_captures = samples * CAPTURES_PER_SAMPLE;
+        let mut frames = Vec::new();
+
+        for sample in 1..=max_captures {
+            let frame = self.capture()?;
+            let q = quality(&frame);
+            if q.is_acceptable() {
+                frames.push(frame);
+            }
+
+            progress_cb(EnrollProgress {
+                sample,
+                remaining: (samples as usize - frames.len()) as u16,
+                quality: q.contrast as u16,
+                coverage: q.coverage as u16,
+            });
+
+            if frames.len() == samples as usize {
+                return matcher.enroll_samples(&frames);
+            }
+        }
+
+        Err(DriverError::EnrollIncomplete(max_captures))
+    }
+
+    /// Capture a frame and compare it with the template, returns the score if it matches
+    fn verify_host<M: Matcher>(
+        &self,
+        matcher: &M,
+        template: &M::Template,
+    ) -> Result<Option<Score>, DriverError> {
+        let frame = self.capture()?;
+        let score = matcher.match_frame(template, &frame)?;
+        Ok((score >= matcher.threshold()).then_some(score))
+    }
+}
+
+impl<T: Transport + ?Sized> HostMatch for T {}
+
+#[cfg(feature = "minutiae")]
+pub use builtin::MinutiaeMatcher;
+
+#[cfg(feature = "minutiae")]
+mod builtin {
+    use super::{Matcher, Score};
+    use crate::{
+        DriverError,
+        capture::Frame,
+        minutiae::{self, Minutia, MinutiaKind, Template},
+    };
+    use core::f32::consts::{PI, TAU};
+
+    /// Minutiae further than this (in pixels) after the alignment are not paired
+    const MAX_DISTANCE: f32 = 10.0;
+
+    /// Minutiae whose directions differ more than this (in radians) are not paired
+    const MAX_ANGLE: f32 = 0.35;
+
+    /// Matches the minutiae of the frames (see [`minutiae::extract`]): the frame is aligned
+    /// with every sample using each pair of minutiae, the alignment pairing the most minutiae
+    /// gives the score
+    #[derive(Debug, Clone, Copy)]
+    pub struct MinutiaeMatcher {
+        /// Scores (0-1) at or above this are a match
+        pub threshold: f32,
+    }
+
+    impl Default for MinutiaeMatcher {
+        fn default() -> Self {
+            Self { threshold: 0.25 }
+        }
+    }
+
+    impl Matcher for MinutiaeMatcher {
+        /// The minutiae of every sample
+        type Template = Vec<Template>;
+
+        fn enroll_samples(&self, samples: &[Frame]) -> Result<Self::Template, DriverError> {
+            if samples.is_empty() {
+                return Err(DriverError::EnrollIncomplete(0));
+            }
+            Ok(samples.iter().map(minutiae::extract).collect())
+        }
+
+        fn match_frame(
+            &self,
+            template: &Self::Template,
+            frame: &Frame,
+        ) -> Result<Score, DriverError> {
+            let probe = minutiae::extract(frame);
+            let best = template
+                .iter()
+                .map(|t| score(t, &probe))
+                .fold(0.0, f32::max);
+            Ok(Score(best))
+        }
+
+        fn threshold(&self) -> Score {
+            Score(self.threshold)
+        }
+
+        /// Count (u8) and the samples, each one with: length (u16) and the serialized minutiae
+        /// (see [`Template::to_bytes`])
+        fn template_to_bytes(&self, template: &Self::Template) -> Vec<u8> {
+            let mut res = vec![template.len() as u8];
+            for t in template {
+                let data = t.to_bytes();
+                res.extend((data.len() as u16).to_le_bytes());
+                res.extend(data);
+            }
+            res
+        }
+
+        fn template_from_bytes(&self, data: &[u8]) -> Result<Self::Template, DriverError> {
+            let (&count, mut data) = data.split_first().ok_or(DriverError::HostTemplateInvalid)?;
+
+            let mut res = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let (len, rest) = data
+                    .split_first_chunk()
+                    .ok_or(DriverError::HostTemplateInvalid)?;
+                let len = u16::from_le_bytes(*len) as usize;
+                if rest.len() < len {
+                    return Err(DriverError::HostTemplateInvalid);
+                }
+
+                let (sample, rest) = rest.split_at(len);
+                res.push(Template::from_bytes(sample)?);
+                data = rest;
+            }
+
+            if !data.is_empty() {
+                return Err(DriverError::HostTemplateInvalid);
+            }
+            Ok(res)
+        }
+    }
+
+    /// Pairs found with the best alignment, squared and divided by both minutiae counts (0-1)
+    fn score(a: &Template, b: &Template) -> f32 {
+        let (na, nb) = (a.minutiae.len(), b.minutiae.len());
+        if na == 0 || nb == 0 {
+            return 0.0;
+        }
+
+        let mut best = 0;
+        for ma in &a.minutiae {
+            for mb in b.minutiae.iter().filter(|mb| mb.kind == ma.kind) {
+                let rotation = mb.angle - ma.angle;
+                best = best.max(pairs(a, b, ma, mb, rotation));
+
+                // The direction of a bifurcation is only known up to half a turn
+                if ma.kind == MinutiaKind::Bifurcation {
+                    best = best.max(pairs(a, b, ma, mb, rotation + PI));
+                }
+            }
+        }
+
+        (best * best) as f32 / (na * nb) as f32
+    }
+
+    /// Minutiae paired once `a` is rotated around `ma` and moved over `mb`
+    fn pairs(a: &Template, b: &Template, ma: &Minutia, mb: &Minutia, rotation: f32) -> usize {
+        let (sin, cos) = rotation.sin_cos();
+        let mut used = vec![false; b.minutiae.len()];
+        let mut count = 0;
+
+        for m in &a.minutiae {
+            let (dx, dy) = (m.x as f32 - ma.x as f32, m.y as f32 - ma.y as f32);
+            let x = mb.x as f32 + dx * cos - dy * sin;
+            let y = mb.y as f32 + dx * sin + dy * cos;
+            let angle = m.angle + rotation;
+
+            let found = b.minutiae.iter().enumerate().position(|(i, n)| {
+                !used[i]
+                    && n.kind == m.kind
+                    && (n.x as f32 - x).hypot(n.y as f32 - y) <= MAX_DISTANCE
+                    && angle_diff(n.angle, angle, n.kind) <= MAX_ANGLE
+            });
+
+            if let Some(i) = found {
+                used[i] = true;
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    /// Difference between two directions, bifurcations only have an orientation (modulo π)
+    fn angle_diff(a: f32, b: f32, kind: MinutiaKind) -> f32 {
+        let period = match kind {
+            MinutiaKind::Ending => TAU,
+            MinutiaKind::Bifurcation => PI,
+        };
+        let d = (a - b).rem_euclid(period);
+        d.min(period - d)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn minutia(x: u16, y: u16, angle: f32, kind: MinutiaKind) -> Minutia {
+            Minutia { x, y, angle, kind }
+        }
+
+        fn sample() -> Template {
+            Template {
+                width: 64,
+                height: 64,
+                minutiae: vec![
+                    minutia(10, 10, 0.5, MinutiaKind::Ending),
+                    minutia(40, 12, 2.0, MinutiaKind::Bifurcation),
+                    minutia(20, 45, 4.0, MinutiaKind::Ending),
+                    minutia(50, 50, 1.0, MinutiaKind::Ending),
+                ],
+            }
+        }
+
+        #[test]
+        fn same_finger() {
+            assert_eq!(score(&sample(), &sample()), 1.0);
+
+            // Moved and turned a quarter
+            let mut moved = sample();
+            for m in &mut moved.minutiae {
+                (m.x, m.y) = (100 - m.y, m.x + 5);
+                m.angle = (m.angle + PI / 2.0).rem_euclid(TAU);
+            }
+            assert_eq!(score(&sample(), &moved), 1.0);
+        }
+
+        #[test]
+        fn other_finger() {
+            let other = Template {
+                width: 64,
+                height: 64,
+                minutiae: vec![
+                    minutia(5, 5, 0.0, MinutiaKind::Ending),
+                    minutia(60, 8, 3.0, MinutiaKind::Ending),
+                    minutia(8, 60, 5.5, MinutiaKind::Ending),
+                ],
+            };
+            // Any single minutia pairs, not more
+            assert_eq!(score(&sample(), &other), 1.0 / 12.0);
+            assert!(Score(score(&sample(), &other)) < MinutiaeMatcher::default().threshold());
+
+            let empty = Template {
+                minutiae: vec![],
+                ..other
+            };
+            assert_eq!(score(&sample(), &empty), 0.0);
+        }
+
+        #[test]
+        fn angles() {
+            assert!(angle_diff(0.1, TAU - 0.1, MinutiaKind::Ending) < 0.21);
+            assert!(angle_diff(0.0, PI, MinutiaKind::Ending) > 3.0);
+            // Half a turn is the same orientation
+            assert!(angle_diff(0.0, PI, MinutiaKind::Bifurcation) < 1e-5);
+        }
+
+        #[test]
+        fn template_bytes() {
+            let matcher = MinutiaeMatcher::default();
+            let template = vec![
+                sample(),
+                Template::from_bytes(&sample().to_bytes()).unwrap(),
+            ];
+            let mut data = matcher.template_to_bytes(&template);
+            let parsed = matcher.template_from_bytes(&data).unwrap();
+            assert_eq!(parsed.len(), 2);
+            assert_eq!(parsed[1], template[1]);
+
+            data.push(0);
+            assert!(matches!(
+                matcher.template_from_bytes(&data),
+                Err(DriverError::HostTemplateInvalid)
+            ));
+            assert!(matcher.template_from_bytes(&[1, 200, 0]).is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proto::{CaptureMode, Command},
+        transport::{INT_SCAN_COMPLETE, MockTransport},
+    };
+
+    /// Compares the first pixel of the first sample
+    struct FirstPixel;
+
+    impl Matcher for FirstPixel {
+        type Template = u8;
+
+        fn enroll_samples(&self, samples: &[Frame]) -> Result<u8, DriverError> {
+            Ok(samples[0].data[0])
+        }
+
+        fn match_frame(&self, template: &u8, frame: &Frame) -> Result<Score, DriverError> {
+            Ok(Score(if frame.data[0] == *template { 1.0 } else { 0.0 }))
+        }
+
+        fn threshold(&self) -> Score {
+            Score(0.5)
+        }
+
+        fn template_to_bytes(&self, template: &u8) -> Vec<u8> {
+            vec![*template]
+        }
+
+        fn template_from_bytes(&self, data: &[u8]) -> Result<u8, DriverError> {
+            data.first()
+                .copied()
+                .ok_or(DriverError::HostTemplateInvalid)
+        }
+    }
+
+    /// 32x8 pixels, with ridges or flat
+    fn frame(ridges: bool) -> Frame {
+        let data = (0..8)
+            .flat_map(|y| {
+                [if !ridges {
+                    240
+                } else if y % 4 < 2 {
+                    0
+                } else {
+                    255
+                }; 32]
+            })
+            .collect();
+        Frame {
+            width: 32,
+            height: 8,
+            bpp: 8,
+            data,
+        }
+    }
+
+    /// Expect a capture, answered with the frame
+    fn capture(dev: MockTransport, frame: &Frame) -> MockTransport {
+        let mut resp = vec![0, 0];
+        resp.extend(frame.width.to_le_bytes());
+        resp.extend(frame.height.to_le_bytes());
+        resp.extend([frame.bpp, 0]);
+        resp.extend((frame.data.len() as u32).to_le_bytes());
+        resp.extend(&frame.data);
+
+        dev.expect(
+            &Command::CaptureStart(CaptureMode::Image).to_bytes(),
+            &[0, 0],
+        )
+        .interrupt(&[INT_SCAN_COMPLETE])
+        .expect(&Command::ReadFrame.to_bytes(), &resp)
+    }
+
+    fn mock() -> MockTransport {
+        MockTransport::new(&crate::SUPPORTED[0])
+    }
+
+    #[test]
+    fn enroll_skips_bad_frames() {
+        let dev = capture(capture(mock(), &frame(false)), &frame(true));
+        let mut progress = vec![];
+        let template = dev
+            .enroll_host(&FirstPixel, 1, |p| progress.push(p))
+            .unwrap();
+
+        assert_eq!(template, 0);
+        assert_eq!(
+            progress.iter().map(|p| p.remaining).collect::<Vec<_>>(),
+            [1, 0]
+        );
+        assert_eq!(progress[0].coverage, 0);
+        assert_eq!(progress[1].coverage, 100);
+        assert!(dev.is_done());
+    }
+
+    #[test]
+    fn enroll_incomplete() {
+        let mut dev = mock();
+        for _ in 0..CAPTURES_PER_SAMPLE {
+            dev = capture(dev, &frame(false));
+        }
+        assert!(matches!(
+            dev.enroll_host(&FirstPixel, 1, |_| ()),
+            Err(DriverError::EnrollIncomplete(3))
+        ));
+    }
+
+    #[test]
+    fn verify() {
+        let dev = capture(capture(mock(), &frame(true)), &frame(true));
+        assert_eq!(dev.verify_host(&FirstPixel, &0).unwrap(), Some(Score(1.0)));
+        assert_eq!(dev.verify_host(&FirstPixel, &7).unwrap(), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! The sensor keeps a finger id with each template but nothing about who enrolled it, the host
+//! keeps that in a [`MetadataStore`]: a directory with a file per user listing (one per line) the
+//! ids of their templates. The PAM module reads the same files.
+//!
+//! The store also gives each user their own [`UserNamespace`] on the device, in
+//! `.namespaces/<number>` holding the user name (see [`MetadataStore::allocate_namespace`]).
+
+use crate::{
+    DriverError,
+    enroll::{Finger, TemplateId},
+    storage::UserNamespace,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Where the store is kept by default
+pub const DEFAULT_DIR: &str = "/var/lib/validity/templates";
+
+/// Directory of the namespaces, the user names can't start with a dot so it is never the file of
+/// one
+const NAMESPACES_DIR: &str = ".namespaces";
+
+/// A template and who it belongs to, see [`crate::storage::Storage::list_metadata`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TemplateMetadata {
+    pub id: TemplateId,
+
+    /// The finger stored with the template, `None` if the id is not a known finger
+    pub finger: Option<Finger>,
+
+    /// The user given at enrollment, `None` if the template is not in the store
+    pub user: Option<String>,
+}
+
+/// The user of every template, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct MetadataStore {
+    dir: PathBuf,
+}
+
+impl MetadataStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The templates of the user, empty if there are none (or the name is not valid, see
+    /// [`Self::add`])
+    pub fn templates(&self, user: &str) -> Vec<TemplateId> {
+        self.path(user).map(|p| read_ids(&p)).unwrap_or_default()
+    }
+
+    /// The user the template was enrolled for
+    pub fn owner(&self, id: TemplateId) -> Option<String> {
+        fs::read_dir(&self.dir)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .find(|e| read_ids(&e.path()).contains(&id))
+            .and_then(|e| e.file_name().into_string().ok())
+    }
+
+    /// Record the template as enrolled for the user, fails with
+    /// [`DriverError::MetadataInvalidUser`] if the name is empty, starts with a dot or has a `/`
+    /// or `..`
+    pub fn add(&self, user: &str, id: TemplateId) -> Result<(), DriverError> {
+        let path = self.path(user)?;
+        fs::create_dir_all(&self.dir).map_err(DriverError::MetadataIo)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| writeln!(f, "{}", id.0))
+            .map_err(DriverError::MetadataIo)
+    }
+
+    /// Forget the template (once deleted from the device)
+    pub fn remove(&self, id: TemplateId) -> Result<(), DriverError> {
+        let Some(user) = self.owner(id) else {
+            return Ok(());
+        };
+
+        let path = self.path(&user)?;
+        let rest: String = read_ids(&path)
+            .into_iter()
+            .filter(|t| *t != id)
+            .map(|t| format!("{}\n", t.0))
+            .collect();
+        fs::write(path, rest).map_err(DriverError::MetadataIo)
+    }
+
+    /// The namespace given to the user, `None` if they were never given one (see
+    /// [`Self::allocate_namespace`])
+    pub fn namespace(&self, user: &str) -> Option<UserNamespace> {
+        self.path(user).ok()?;
+        fs::read_dir(self.dir.join(NAMESPACES_DIR))
+            .ok()?
+            .filter_map(|e| e.ok())
+            .filter(|e| fs::read_to_string(e.path()).is_ok_and(|u| u == user))
+            .filter_map(|e| e.file_name().to_str()?.parse().ok())
+            // Two enrollments of the user at once may both allocate one
+            .min()
+            .map(UserNamespace)
+    }
+
+    /// The namespace of the user, the first free one is allocated if they have none yet. Each
+    /// number is taken by creating its file (failing if it exists), so two users never share one.
+    pub fn allocate_namespace(&self, user: &str) -> Result<UserNamespace, DriverError> {
+        self.path(user)?;
+        if let Some(namespace) = self.namespace(user) {
+            return Ok(namespace);
+        }
+
+        let dir = self.dir.join(NAMESPACES_DIR);
+        fs::create_dir_all(&dir).map_err(DriverError::MetadataIo)?;
+        // Neither the shared namespace nor the "any" value of the match command
+        for n in 1..0xffffu16 {
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dir.join(n.to_string()));
+            match file {
+                Ok(mut f) => {
+                    f.write_all(user.as_bytes())
+                        .map_err(DriverError::MetadataIo)?;
+                    return Ok(UserNamespace(n));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(DriverError::MetadataIo(e)),
+            }
+        }
+        Err(DriverError::MetadataNamespacesFull)
+    }
+
+    fn path(&self, user: &str) -> Result<PathBuf, DriverError> {
+        // Never leave the directory, nor use the file of another user (or the namespaces)
+        if user.is_empty() || user.starts_with('.') || user.contains('/') || user.contains("..") {
+            return Err(DriverError::MetadataInvalidUser(user.to_string()));
+        }
+        Ok(self.dir.join(user))
+    }
+}
+
+impl Default for MetadataStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_DIR)
+    }
+}
+
+/// The template ids in the file, empty if it can't be read
+fn read_ids(path: &Path) -> Vec<TemplateId> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.trim().parse().ok().map(TemplateId))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_users() {
+        let store = MetadataStore::new("/nonexistent/validity");
+        for user in ["", "..", ".namespaces", "x/alice", "/alice", "a..b"] {
+            assert!(matches!(
+                store.add(user, TemplateId(1)),
+                Err(DriverError::MetadataInvalidUser(u)) if u == user
+            ));
+            assert!(store.templates(user).is_empty());
+        }
+    }
+
+    #[test]
+    fn namespaces() {
+        let dir = std::env::temp_dir().join(format!("validity-ns-{}", std::process::id()));
+        let store = MetadataStore::new(&dir);
+
+        assert_eq!(store.namespace("alice"), None);
+        let alice = store.allocate_namespace("alice").unwrap();
+        let bob = store.allocate_namespace("bob").unwrap();
+        assert_ne!(alice, bob);
+        assert_ne!(alice, UserNamespace::SHARED);
+        assert_eq!(store.allocate_namespace("alice").unwrap(), alice);
+        assert_eq!(store.namespace("alice"), Some(alice));
+        assert_eq!(store.namespace("bob"), Some(bob));
+
+        // The namespaces are not a user
+        store.add("alice", TemplateId(3)).unwrap();
+        assert_eq!(store.owner(TemplateId(3)).as_deref(), Some("alice"));
+        assert_eq!(store.owner(TemplateId(1)), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
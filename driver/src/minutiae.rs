@@ -0,0 +1,443 @@
+//! Some sensors (or firmwares) only stream images, without match-on-chip. For those the
+//! minutiae (the points where ridges end or split) are extracted on the host into a [`Template`]:
+//! the ridges are separated from the valleys block by block, thinned to a single pixel and every
+//! pixel is classified by how many ridges cross it.
+
+use crate::{
+    DriverError,
+    capture::Frame,
+    quality::{self, MIN_RIDGE_STD},
+};
+use core::f32::consts::{PI, TAU};
+
+/// Side of the blocks used for the foreground mask and the binarization, in pixels
+const BLOCK: usize = 8;
+
+/// Minutiae closer than this (in pixels) are noise (broken ridges, pores, ...)
+const MIN_DISTANCE: f32 = 6.0;
+
+/// Pixels followed along the ridge to find the direction of an ending
+const TRACE_STEPS: usize = 6;
+
+/// Every serialized template starts with this
+const TEMPLATE_MAGIC: &[u8] = b"VSMT01";
+
+/// The neighbours of a pixel, clockwise starting from the one above
+const NEIGHBOURS: [(isize, isize); 8] = [
+    (0, -1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MinutiaKind {
+    /// A ridge ends
+    Ending,
+
+    /// A ridge splits in two
+    Bifurcation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Minutia {
+    pub x: u16,
+    pub y: u16,
+
+    /// Direction of the ridge in radians (0-2π), in image coordinates (y grows downwards)
+    pub angle: f32,
+
+    pub kind: MinutiaKind,
+}
+
+/// The minutiae of a frame, see [`extract`]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Template {
+    /// Size of the frame the minutiae were extracted from
+    pub width: u16,
+    pub height: u16,
+
+    pub minutiae: Vec<Minutia>,
+}
+
+impl Template {
+    /// Serialize the template: magic, width (u16), height (u16), count (u16) and the minutiae,
+    /// each one with: x (u16), y (u16), angle (u8, in 1/256 of a turn) and kind (u8)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = TEMPLATE_MAGIC.to_vec();
+        res.extend(self.width.to_le_bytes());
+        res.extend(self.height.to_le_bytes());
+        res.extend((self.minutiae.len() as u16).to_le_bytes());
+
+        for m in &self.minutiae {
+            res.extend(m.x.to_le_bytes());
+            res.extend(m.y.to_le_bytes());
+            res.push((m.angle.rem_euclid(TAU) / TAU * 256.0) as u8);
+            res.push(m.kind as u8);
+        }
+        res
+    }
+
+    /// Parse a template serialized with [`Self::to_bytes`]
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DriverError> {
+        let data = data
+            .strip_prefix(TEMPLATE_MAGIC)
+            .ok_or(DriverError::HostTemplateInvalid)?;
+        let u16_at = |i: usize| Some(u16::from_le_bytes([*data.get(i)?, *data.get(i + 1)?]));
+
+        let (Some(width), Some(height), Some(count)) = (u16_at(0), u16_at(2), u16_at(4)) else {
+            return Err(DriverError::HostTemplateInvalid);
+        };
+        let entries = &data[6..];
+        if entries.len() != count as usize * 6 {
+            return Err(DriverError::HostTemplateInvalid);
+        }
+
+        let minutiae = entries
+            .chunks_exact(6)
+            .map(|e| {
+                Ok(Minutia {
+                    x: u16::from_le_bytes([e[0], e[1]]),
+                    y: u16::from_le_bytes([e[2], e[3]]),
+                    angle: e[4] as f32 / 256.0 * TAU,
+                    kind: match e[5] {
+                        0 => MinutiaKind::Ending,
+                        1 => MinutiaKind::Bifurcation,
+                        _ => return Err(DriverError::HostTemplateInvalid),
+                    },
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            width,
+            height,
+            minutiae,
+        })
+    }
+}
+
+/// Extract the minutiae of the frame, only 8 and 16 bits per pixel are supported (the rest have
+/// none)
+pub fn extract(frame: &Frame) -> Template {
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let mut tmpl = Template {
+        width: frame.width,
+        height: frame.height,
+        minutiae: Vec::new(),
+    };
+    let Some(pixels) = quality::pixels(frame).filter(|_| width > 2 && height > 2) else {
+        return tmpl;
+    };
+
+    let mask = Mask::new(&pixels, width, height);
+    let mut ridges = binarize(&pixels, &mask, width);
+    thin(&mut ridges, width, height);
+
+    let mut found = Vec::new();
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            if !ridges[y * width + x] || !mask.is_inner(x, y) {
+                continue;
+            }
+
+            let n = neighbours(&ridges, width, x, y);
+            let crossings = (0..8).filter(|&i| n[i] != n[(i + 1) % 8]).count() / 2;
+            let (kind, angle) = match crossings {
+                1 => (MinutiaKind::Ending, trace(&ridges, width, x, y)),
+                3 => (
+                    MinutiaKind::Bifurcation,
+                    orientation(&pixels, width, height, x, y),
+                ),
+                _ => continue,
+            };
+
+            found.push(Minutia {
+                x: x as u16,
+                y: y as u16,
+                angle: angle.rem_euclid(TAU),
+                kind,
+            });
+        }
+    }
+
+    // Minutiae close to each other come in pairs from the same defect, both are dropped
+    tmpl.minutiae = found
+        .iter()
+        .filter(|m| {
+            !found
+                .iter()
+                .any(|o| !core::ptr::eq(*m, o) && distance(m, o) < MIN_DISTANCE)
+        })
+        .copied()
+        .collect();
+    tmpl
+}
+
+fn distance(a: &Minutia, b: &Minutia) -> f32 {
+    let dx = a.x as f32 - b.x as f32;
+    let dy = a.y as f32 - b.y as f32;
+    (dx * dx + dy * dy).sqrt()
+}
+
+/// The blocks with ridges (the finger) and their mean values
+struct Mask {
+    cols: usize,
+    rows: usize,
+    means: Vec<f32>,
+    finger: Vec<bool>,
+}
+
+impl Mask {
+    fn new(pixels: &[f32], width: usize, height: usize) -> Self {
+        let (cols, rows) = (width / BLOCK, height / BLOCK);
+        let mut means = Vec::with_capacity(cols * rows);
+        let mut finger = Vec::with_capacity(cols * rows);
+
+        for by in 0..rows {
+            for bx in 0..cols {
+                let values = (0..BLOCK).flat_map(|y| {
+                    let start = (by * BLOCK + y) * width + bx * BLOCK;
+                    pixels[start..start + BLOCK].iter().copied()
+                });
+                let block = quality::block_stats(values);
+                means.push(block.mean);
+                finger.push(block.std >= MIN_RIDGE_STD);
+            }
+        }
+
+        Self {
+            cols,
+            rows,
+            means,
+            finger,
+        }
+    }
+
+    /// The block of the pixel, if the frame is not cut there
+    fn block(&self, x: usize, y: usize) -> Option<usize> {
+        let (bx, by) = (x / BLOCK, y / BLOCK);
+        (bx < self.cols && by < self.rows).then_some(by * self.cols + bx)
+    }
+
+    /// Whether the pixel and the blocks around it are on the finger, minutiae at its edge are
+    /// just ridges cut by the border
+    fn is_inner(&self, x: usize, y: usize) -> bool {
+        let (bx, by) = ((x / BLOCK) as isize, (y / BLOCK) as isize);
+        (-1..=1).all(|dy| {
+            (-1..=1).all(|dx| {
+                let (cx, cy) = (bx + dx, by + dy);
+                cx >= 0
+                    && cy >= 0
+                    && (cx as usize) < self.cols
+                    && (cy as usize) < self.rows
+                    && self.finger[cy as usize * self.cols + cx as usize]
+            })
+        })
+    }
+}
+
+/// Ridges are the pixels darker than the mean of their block, only on the finger
+fn binarize(pixels: &[f32], mask: &Mask, width: usize) -> Vec<bool> {
+    pixels
+        .iter()
+        .enumerate()
+        .map(|(i, &p)| {
+            mask.block(i % width, i / width)
+                .is_some_and(|b| mask.finger[b] && p < mask.means[b])
+        })
+        .collect()
+}
+
+fn neighbours(img: &[bool], width: usize, x: usize, y: usize) -> [bool; 8] {
+    NEIGHBOURS.map(|(dx, dy)| img[(y as isize + dy) as usize * width + (x as isize + dx) as usize])
+}
+
+/// Zhang-Suen thinning, the ridges end up one pixel wide
+fn thin(img: &mut [bool], width: usize, height: usize) {
+    loop {
+        let mut changed = false;
+
+        for step in 0..2 {
+            let mut remove = Vec::new();
+
+            for y in 1..height.saturating_sub(1) {
+                for x in 1..width.saturating_sub(1) {
+                    if !img[y * width + x] {
+                        continue;
+                    }
+
+                    let n = neighbours(img, width, x, y);
+                    let set = n.iter().filter(|p| **p).count();
+                    let rises = (0..8).filter(|&i| !n[i] && n[(i + 1) % 8]).count();
+                    if !(2..=6).contains(&set) || rises != 1 {
+                        continue;
+                    }
+
+                    let [up, _, right, _, down, _, left, _] = n;
+                    let keep = match step {
+                        0 => right && down && (up || left),
+                        _ => up && left && (right || down),
+                    };
+                    if !keep {
+                        remove.push(y * width + x);
+                    }
+                }
+            }
+
+            changed |= !remove.is_empty();
+            for i in remove {
+                img[i] = false;
+            }
+        }
+
+        if !changed {
+            return;
+        }
+    }
+}
+
+/// Direction of a ridge ending: from the ridge towards the end
+fn trace(img: &[bool], width: usize, x: usize, y: usize) -> f32 {
+    let height = img.len() / width;
+    let (mut cx, mut cy) = (x, y);
+    let mut prev = (x, y);
+
+    for _ in 0..TRACE_STEPS {
+        let next = NEIGHBOURS.iter().find_map(|(dx, dy)| {
+            let nx = cx.checked_add_signed(*dx).filter(|nx| *nx < width)?;
+            let ny = cy.checked_add_signed(*dy).filter(|ny| *ny < height)?;
+            (img[ny * width + nx] && (nx, ny) != prev && (nx, ny) != (x, y)).then_some((nx, ny))
+        });
+
+        let Some(next) = next else { break };
+        prev = (cx, cy);
+        (cx, cy) = next;
+    }
+
+    (y as f32 - cy as f32).atan2(x as f32 - cx as f32)
+}
+
+/// Direction of the ridges around the pixel (0-π), from the gradients of a block around it
+fn orientation(pixels: &[f32], width: usize, height: usize, x: usize, y: usize) -> f32 {
+    let (mut vx, mut vy) = (0.0, 0.0);
+
+    for py in y.saturating_sub(BLOCK).max(1)..(y + BLOCK).min(height - 1) {
+        for px in x.saturating_sub(BLOCK).max(1)..(x + BLOCK).min(width - 1) {
+            let gx = pixels[py * width + px + 1] - pixels[py * width + px - 1];
+            let gy = pixels[(py + 1) * width + px] - pixels[(py - 1) * width + px];
+            vx += 2.0 * gx * gy;
+            vy += gx * gx - gy * gy;
+        }
+    }
+
+    // The ridges run across the gradient
+    0.5 * vx.atan2(vy) + PI / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Vertical ridges 3 pixels wide every 6, the one at x 30-32 ends at y 32 if told
+    fn ridges(ending: bool) -> Frame {
+        let data = (0..64)
+            .flat_map(|y| {
+                (0..64).map(
+                    move |x| match x % 6 < 3 && !(ending && x / 6 == 5 && y >= 32) {
+                        true => 30,
+                        false => 220,
+                    },
+                )
+            })
+            .collect();
+        Frame {
+            width: 64,
+            height: 64,
+            bpp: 8,
+            data,
+        }
+    }
+
+    #[test]
+    fn straight_ridges() {
+        let tmpl = extract(&ridges(false));
+        assert_eq!((tmpl.width, tmpl.height), (64, 64));
+        assert_eq!(tmpl.minutiae, []);
+    }
+
+    #[test]
+    fn ridge_ending() {
+        let tmpl = extract(&ridges(true));
+        let [m] = tmpl.minutiae[..] else {
+            panic!("expected one minutia, got {:?}", tmpl.minutiae);
+        };
+        assert_eq!(m.kind, MinutiaKind::Ending);
+        assert_eq!(m.x, 31);
+        assert!(m.y.abs_diff(32) <= 3);
+        // Towards the end, downwards
+        assert!((m.angle - PI / 2.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let tmpl = Template {
+            width: 64,
+            height: 48,
+            minutiae: vec![
+                Minutia {
+                    x: 10,
+                    y: 20,
+                    angle: PI,
+                    kind: MinutiaKind::Ending,
+                },
+                Minutia {
+                    x: 30,
+                    y: 5,
+                    angle: 1.0,
+                    kind: MinutiaKind::Bifurcation,
+                },
+            ],
+        };
+
+        let parsed = Template::from_bytes(&tmpl.to_bytes()).unwrap();
+        assert_eq!((parsed.width, parsed.height), (64, 48));
+        for (a, b) in tmpl.minutiae.iter().zip(&parsed.minutiae) {
+            assert_eq!((a.x, a.y, a.kind), (b.x, b.y, b.kind));
+            // Stored in 1/256 of a turn
+            assert!((a.angle - b.angle).abs() < TAU / 256.0);
+        }
+        assert_eq!(parsed.minutiae.len(), 2);
+    }
+
+    #[test]
+    fn invalid_templates() {
+        let mut data = Template {
+            width: 8,
+            height: 8,
+            minutiae: vec![Minutia {
+                x: 1,
+                y: 2,
+                angle: 0.0,
+                kind: MinutiaKind::Ending,
+            }],
+        }
+        .to_bytes();
+        assert!(Template::from_bytes(&data).is_ok());
+
+        assert!(Template::from_bytes(&data[1..]).is_err());
+        assert!(Template::from_bytes(&data[..data.len() - 1]).is_err());
+        *data.last_mut().unwrap() = 2;
+        assert!(matches!(
+            Template::from_bytes(&data),
+            Err(DriverError::HostTemplateInvalid)
+        ));
+    }
+}
@@ -0,0 +1,226 @@
+//! The raw frames are hard to look at: every pixel has its own offset, the values only use a
+//! small part of their range and the 16 bits ones are not shown by most viewers. [`Normalize`]
+//! turns them into clean 8 bits images: the background (frames without a finger, see
+//! [`crate::capture::Capture::capture_background`]) is subtracted, the histogram is stretched and
+//! the image optionally inverted.
+
+use crate::{capture::Frame, quality};
+
+/// Part of the darkest and of the lightest pixels clipped by the stretch, so a few stuck pixels
+/// don't flatten the rest
+const CLIP: f32 = 0.01;
+
+/// What the sensor reads without a finger, averaged over several frames
+#[derive(Debug, Clone, PartialEq)]
+pub struct Background {
+    width: u16,
+    height: u16,
+
+    /// Relative to the maximum value, like the frames in [`quality`]
+    pixels: Vec<f32>,
+}
+
+impl Background {
+    /// Average the frames, `None` if there are none, their sizes differ or their pixel depth is
+    /// not 8 or 16 bits
+    pub fn from_frames(frames: &[Frame]) -> Option<Self> {
+        let first = frames.first()?;
+        let (width, height) = (first.width, first.height);
+        let count = width as usize * height as usize;
+        let mut sum = vec![0.0; count];
+
+        for frame in frames {
+            if (frame.width, frame.height) != (width, height) {
+                return None;
+            }
+            for (s, p) in sum.iter_mut().zip(quality::pixels(frame)?) {
+                *s += p;
+            }
+        }
+
+        let n = frames.len() as f32;
+        Some(Self {
+            width,
+            height,
+            pixels: sum.into_iter().map(|s| s / n).collect(),
+        })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+}
+
+/// How the frames are turned into 8 bits images, see the [module docs](self)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Normalize {
+    background: Option<Background>,
+    stretch: bool,
+    invert: bool,
+}
+
+impl Normalize {
+    /// Only stretch the histogram
+    pub fn new() -> Self {
+        Self {
+            background: None,
+            stretch: true,
+            invert: false,
+        }
+    }
+
+    /// Subtract the background first, it is skipped for frames of another width. Frames taller
+    /// than it (stitched from a swipe sensor) repeat its lines.
+    pub fn background(mut self, background: Background) -> Self {
+        self.background = Some(background);
+        self
+    }
+
+    /// Map the pixels between the darkest and the lightest ones (but the 1% at both ends) to the
+    /// whole 0-255 range, enabled by default. Otherwise the values are just scaled to 8 bits.
+    pub fn stretch(mut self, stretch: bool) -> Self {
+        self.stretch = stretch;
+        self
+    }
+
+    /// Swap the dark and light pixels, for tools expecting the ridges the other way
+    pub fn invert(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+
+    /// The normalized frame, 8 bits per pixel. Frames of other depths than 8 or 16 bits are
+    /// returned as they are.
+    pub fn apply(&self, frame: &Frame) -> Frame {
+        let Some(mut pixels) = quality::pixels(frame) else {
+            return frame.clone();
+        };
+        let width = frame.width as usize;
+        pixels.truncate(width * frame.height as usize);
+
+        if let Some(bg) = self
+            .background
+            .as_ref()
+            .filter(|bg| bg.width == frame.width)
+        {
+            let bg_len = bg.pixels.len();
+            for (i, p) in pixels.iter_mut().enumerate() {
+                *p -= bg.pixels[i % bg_len];
+            }
+        }
+
+        let (low, high) = match self.stretch {
+            true => percentiles(&pixels),
+            false => (0.0, 1.0),
+        };
+        let range = (high - low).max(f32::EPSILON);
+
+        let data = pixels
+            .iter()
+            .map(|&p| {
+                let v = ((p - low) / range).clamp(0.0, 1.0);
+                let v = if self.invert { 1.0 - v } else { v };
+                (v * u8::MAX as f32).round() as u8
+            })
+            .collect();
+
+        Frame {
+            width: frame.width,
+            height: frame.height,
+            bpp: 8,
+            data,
+        }
+    }
+}
+
+impl Default for Normalize {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The values under which [`CLIP`] of the pixels are, and over which they are
+fn percentiles(pixels: &[f32]) -> (f32, f32) {
+    if pixels.is_empty() {
+        return (0.0, 1.0);
+    }
+
+    let mut sorted = pixels.to_vec();
+    let last = sorted.len() - 1;
+    let clip = (last as f32 * CLIP) as usize;
+
+    let (_, &mut low, _) = sorted.select_nth_unstable_by(clip, f32::total_cmp);
+    let (_, &mut high, _) = sorted.select_nth_unstable_by(last - clip, f32::total_cmp);
+    (low, high)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u16, data: &[u8]) -> Frame {
+        Frame {
+            width,
+            height: (data.len() / width as usize) as u16,
+            bpp: 8,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn stretch() {
+        let raw = frame(2, &[100, 110, 120, 130]);
+        assert_eq!(Normalize::new().apply(&raw).data, [0, 85, 170, 255]);
+        assert_eq!(
+            Normalize::new().invert(true).apply(&raw).data,
+            [255, 170, 85, 0]
+        );
+    }
+
+    #[test]
+    fn scale_16_bits() {
+        let raw = Frame {
+            width: 3,
+            height: 1,
+            bpp: 16,
+            data: vec![0, 0, 0x00, 0x80, 0xff, 0xff],
+        };
+        let img = Normalize::new().stretch(false).apply(&raw);
+        assert_eq!((img.bpp, img.width, img.height), (8, 3, 1));
+        assert_eq!(img.data, [0, 128, 255]);
+    }
+
+    #[test]
+    fn subtract_background() {
+        let bg = Background::from_frames(&[frame(2, &[10, 50]), frame(2, &[30, 70])]).unwrap();
+        assert_eq!((bg.width(), bg.height()), (2, 1));
+
+        // Taller than the background, its line is repeated
+        let normalize = Normalize::new().stretch(false).background(bg);
+        assert_eq!(
+            normalize.apply(&frame(2, &[20, 60, 120, 160])).data,
+            [0, 0, 100, 100]
+        );
+        // Another width, not subtracted
+        assert_eq!(normalize.apply(&frame(1, &[20])).data, [20]);
+    }
+
+    #[test]
+    fn invalid_backgrounds() {
+        assert_eq!(Background::from_frames(&[]), None);
+        assert_eq!(
+            Background::from_frames(&[frame(2, &[1, 2]), frame(1, &[1, 2])]),
+            None
+        );
+
+        let mut deep = frame(2, &[1, 2]);
+        deep.bpp = 4;
+        assert_eq!(Background::from_frames(&[deep.clone()]), None);
+        // Returned as it is
+        assert_eq!(Normalize::new().apply(&deep).data, [1, 2]);
+    }
+}
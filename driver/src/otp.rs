@@ -0,0 +1,75 @@
+//! The one-time programmable memory (OTP, or EEPROM on older chips) is written at the factory
+//! with what identifies the unit. When a sensor fails the init with a signature error, comparing
+//! it with what the firmware reports (see [`DeviceInfo`]) tells whether the unit or the firmware
+//! is the odd one.
+
+use crate::{
+    DriverError,
+    info::DeviceInfo,
+    proto::{Command, Response},
+    transport::Transport,
+};
+
+/// Size of the identification block, at the start of the OTP
+const INFO_SIZE: u16 = 12;
+
+/// Bit of the flags set once the factory calibration was done
+const FLAG_CALIBRATED: u16 = 1 << 0;
+
+/// The identification block of the OTP, see [`Otp::otp_info`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OtpInfo {
+    /// Unique for every die
+    pub chip_id: [u8; 8],
+
+    /// Identifies the sensor chip, should be the one reported by the firmware
+    pub hardware_id: u8,
+
+    /// Revision of the chip
+    pub revision: u8,
+
+    /// Whether the sensor was calibrated at the factory, see [`crate::calibration`]
+    pub calibrated: bool,
+}
+
+impl OtpInfo {
+    /// Whether the firmware reports the same chip, a mismatch means the firmware is for another
+    /// model
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        self.hardware_id == info.hardware_id
+    }
+}
+
+/// OTP reads, implemented for every [`Transport`]
+pub trait Otp: Transport {
+    /// Read `size` bytes at `addr`
+    fn read_otp(&self, addr: u16, size: u16) -> Result<Vec<u8>, DriverError> {
+        let mut buf = vec![0u8; size as usize + 2];
+        let resp = self.run(&Command::ReadOtp { addr, size }, &mut buf)?;
+        let data = resp.rest();
+        if data.len() != size as usize {
+            return Err(DriverError::OtpInvalidResponse);
+        }
+        Ok(data.to_vec())
+    }
+
+    /// Read the identification block
+    fn otp_info(&self) -> Result<OtpInfo, DriverError> {
+        let data = self.read_otp(0, INFO_SIZE)?;
+        parse_info(Response::raw(&data)).ok_or(DriverError::OtpInvalidResponse)
+    }
+}
+
+impl<T: Transport + ?Sized> Otp for T {}
+
+/// The identification block has the format: chip id (8 bytes), hardware id (u8), revision (u8)
+/// and flags (u16)
+fn parse_info(mut data: Response<'_>) -> Option<OtpInfo> {
+    Some(OtpInfo {
+        chip_id: data.bytes(8)?.try_into().ok()?,
+        hardware_id: data.u8()?,
+        revision: data.u8()?,
+        calibrated: data.u16()? & FLAG_CALIBRATED != 0,
+    })
+}
@@ -0,0 +1,159 @@
+//! Before a [`SecureSession`](crate::secure::SecureSession) can be established the host has to
+//! give the sensor its certificate, the sensor answers with its own public key. Both keys are
+//! needed for every session afterwards, so they are stored in a file (see [`load_pairing`]) or in a
+//! [`KeyStore`].
+
+use crate::{
+    DriverError, hostkey::HostIdentity, keystore::KeyStore, proto::Command, secure::SessionParams,
+    transport::Transport,
+};
+use p256::{PublicKey, SecretKey, elliptic_curve::sec1::ToEncodedPoint};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Every pairing file starts with this
+const FILE_MAGIC: &[u8] = b"VSPAIR01";
+
+/// Size of a SEC1 uncompressed point
+const POINT_SIZE: usize = 65;
+
+/// Pairing of the host with the sensor, implemented for every [`Transport`]
+pub trait Pair: Transport {
+    /// Pair the host with the sensor, a new key is generated for the host. The pairing data is
+    /// saved to `path` so it can be loaded later with [`load_pairing`].
+    fn pair(&self, path: impl AsRef<Path>) -> Result<SessionParams, DriverError> {
+        let params = exchange_keys(self, HostIdentity::generate())?;
+        save_pairing(&params, path)?;
+        Ok(params)
+    }
+
+    /// Same as [`Self::pair`], but the host key is generated by the key store (see
+    /// [`KeyStore::generate_key`]) and the pairing data is saved to it
+    fn pair_with_store(
+        &self,
+        store: &impl KeyStore,
+        id: &str,
+    ) -> Result<SessionParams, DriverError> {
+        let params = exchange_keys(self, store.generate_key(id)?.into())?;
+        store.save(id, &params)?;
+        Ok(params)
+    }
+
+    /// Pair with an existing identity (see [`HostIdentity::load`]), nothing is saved
+    fn pair_identity(&self, identity: HostIdentity) -> Result<SessionParams, DriverError> {
+        exchange_keys(self, identity)
+    }
+}
+
+impl<T: Transport + ?Sized> Pair for T {}
+
+/// Send the certificate of the host, the sensor answers with its key
+fn exchange_keys<T: Transport + ?Sized>(
+    dev: &T,
+    identity: HostIdentity,
+) -> Result<SessionParams, DriverError> {
+    let host_cert = identity.certificate();
+
+    // Key length (u16) and the public key of the sensor
+    let mut buf = [0u8; 1024];
+    let mut resp = dev.run(&Command::Pair(&host_cert), &mut buf)?;
+    let key = resp
+        .u16()
+        .and_then(|len| resp.bytes(len as usize))
+        .ok_or(DriverError::PairingInvalidResponse)?;
+
+    let device_key =
+        PublicKey::from_sec1_bytes(key).map_err(|_| DriverError::PairingInvalidResponse)?;
+
+    Ok(SessionParams {
+        host_key: identity.key,
+        host_cert,
+        device_key,
+    })
+}
+
+/// Load the pairing data saved by [`Pair::pair`]
+pub fn load_pairing(path: impl AsRef<Path>) -> Result<SessionParams, DriverError> {
+    decode_pairing(&fs::read(path).map_err(DriverError::PairingIo)?)
+}
+
+/// Save the pairing data, the file is only readable by its owner (it contains the host key)
+pub fn save_pairing(params: &SessionParams, path: impl AsRef<Path>) -> Result<(), DriverError> {
+    write_private(path, &encode_pairing(params)?).map_err(DriverError::PairingIo)
+}
+
+/// Decode the pairing data: magic, host key (32), certificate length (u16) and certificate,
+/// sensor key
+pub(crate) fn decode_pairing(data: &[u8]) -> Result<SessionParams, DriverError> {
+    let data = data
+        .strip_prefix(FILE_MAGIC)
+        .ok_or(DriverError::PairingInvalid)?;
+    if data.len() < 32 + 2 {
+        return Err(DriverError::PairingInvalid);
+    }
+
+    let (key, data) = data.split_at(32);
+    let host_key = SecretKey::from_slice(key).map_err(|_| DriverError::PairingInvalid)?;
+
+    let cert_len = u16::from_le_bytes([data[0], data[1]]) as usize;
+    let data = &data[2..];
+    if data.len() != cert_len + POINT_SIZE {
+        return Err(DriverError::PairingInvalid);
+    }
+
+    let (host_cert, device_key) = data.split_at(cert_len);
+    let device_key =
+        PublicKey::from_sec1_bytes(device_key).map_err(|_| DriverError::PairingInvalid)?;
+
+    Ok(SessionParams {
+        host_key: host_key.into(),
+        host_cert: host_cert.to_vec(),
+        device_key,
+    })
+}
+
+/// Encode the pairing data, see [`decode_pairing`]. Fails if the host key can't leave the TPM.
+pub(crate) fn encode_pairing(params: &SessionParams) -> Result<Vec<u8>, DriverError> {
+    let key = params
+        .host_key
+        .secret()
+        .ok_or(DriverError::HostKeyNotExportable)?;
+
+    let mut data = FILE_MAGIC.to_vec();
+    data.extend(key.to_bytes());
+    data.extend((params.host_cert.len() as u16).to_le_bytes());
+    data.extend(&params.host_cert);
+    data.extend(params.device_key.to_encoded_point(false).as_bytes());
+    Ok(data)
+}
+
+/// Write a file only readable by its owner
+pub(crate) fn write_private(path: impl AsRef<Path>, data: &[u8]) -> io::Result<()> {
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+
+    opts.open(path).and_then(|mut f| f.write_all(data))
+}
+
+/// Serialized as the contents of a pairing file, see [`load_pairing`]
+#[cfg(feature = "serde")]
+impl serde::Serialize for SessionParams {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let data = encode_pairing(self).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&data)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for SessionParams {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = <Vec<u8>>::deserialize(deserializer)?;
+        decode_pairing(&data).map_err(serde::de::Error::custom)
+    }
+}
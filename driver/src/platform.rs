@@ -0,0 +1,42 @@
+//! Not every libusb backend can do the same things. With the `windows` feature the driver
+//! assumes the sensor is bound to WinUSB: there is no kernel driver to detach, there is no
+//! hotplug support and resetting the device re-enumerates it (so the handle becomes useless),
+//! the endpoints are cleared instead.
+
+/// What the USB backend can do on this platform, see [`backend_capabilities`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackendCapabilities {
+    /// A kernel driver bound to the sensor can be detached
+    pub detach_kernel_driver: bool,
+
+    /// The device can be reset without losing the handle
+    pub device_reset: bool,
+
+    /// Devices arriving and leaving can be watched
+    pub hotplug: bool,
+
+    /// The interrupt endpoint can be read (finger events)
+    pub interrupt_transfers: bool,
+}
+
+/// Probe what the USB backend supports
+#[cfg(feature = "windows")]
+pub fn backend_capabilities() -> BackendCapabilities {
+    BackendCapabilities {
+        detach_kernel_driver: false,
+        device_reset: false,
+        hotplug: false,
+        interrupt_transfers: true,
+    }
+}
+
+/// Probe what the USB backend supports
+#[cfg(not(feature = "windows"))]
+pub fn backend_capabilities() -> BackendCapabilities {
+    BackendCapabilities {
+        detach_kernel_driver: rusb::supports_detach_kernel_driver(),
+        device_reset: true,
+        hotplug: rusb::has_hotplug(),
+        interrupt_transfers: true,
+    }
+}
@@ -0,0 +1,85 @@
+//! Reading frames or big responses needs buffers of several KiB for every bulk read. They are
+//! taken from a [`BufferPool`] and given back once dropped, so continuous capture does not
+//! allocate for every transfer.
+
+use core::ops::{Deref, DerefMut};
+use std::sync::{Mutex, PoisonError};
+
+/// Buffers kept by a pool at most, the rest are freed
+const MAX_POOLED: usize = 8;
+
+/// The pool used by the driver, see [`BufferPool::global`]
+static GLOBAL: BufferPool = BufferPool::new();
+
+/// Free buffers, shared between threads
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub const fn new() -> Self {
+        Self {
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The pool used for the bulk transfers of the driver
+    pub fn global() -> &'static Self {
+        &GLOBAL
+    }
+
+    /// A buffer of `size` bytes, its contents are not cleared
+    pub fn get(&self, size: usize) -> PooledBuffer<'_> {
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut buf = free
+            .iter()
+            .position(|b| b.capacity() >= size)
+            .map(|i| free.swap_remove(i))
+            .unwrap_or_default();
+        drop(free);
+
+        buf.resize(size, 0);
+        PooledBuffer { buf, pool: self }
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        if free.len() < MAX_POOLED {
+            free.push(buf);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A buffer given back to its pool when dropped
+#[derive(Debug)]
+pub struct PooledBuffer<'a> {
+    buf: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(core::mem::take(&mut self.buf));
+    }
+}
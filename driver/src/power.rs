@@ -0,0 +1,19 @@
+//! The sensor stays fully powered after a command, laptops on battery should put it to sleep
+//! between authentications. In [`PowerState::WakeOnFinger`] it also wakes up by itself on touch,
+//! so a [`FingerListener`](crate::events::FingerListener) still gets the events.
+
+use crate::{DriverError, proto::Command, transport::Transport};
+
+pub use crate::proto::PowerState;
+
+/// Power management, implemented for every [`Transport`]
+pub trait Power: Transport {
+    /// Change the power state, until the next call. Scanning or enrolling needs
+    /// [`PowerState::Active`] again (or a touch in [`PowerState::WakeOnFinger`]).
+    fn set_power_state(&self, state: PowerState) -> Result<(), DriverError> {
+        self.run(&Command::SetPowerState(state), &mut [0u8; 64])?;
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Power for T {}
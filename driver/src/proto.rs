@@ -0,0 +1,14 @@
+//! The protocol encoding, shared with the `validity-proto` crate (which is `no_std`)
+
+pub use validity_proto::*;
+
+use crate::DriverError;
+
+impl From<StatusError> for DriverError {
+    fn from(e: StatusError) -> Self {
+        match e {
+            StatusError::Missing => Self::UsbInitInvalid,
+            StatusError::Failed(status) => Self::UsbInitFailed(status),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Quality of a captured [`Frame`], so enrollment flows on the host can ask for another touch
+//! like the firmware of the match-on-chip sensors does. The frame is split in blocks: ridges make
+//! the pixels of a block vary, blocks without the finger are flat and bright (the sensor reads
+//! high values where nothing touches it), and flat dark blocks are a smudge (a wet or pressed
+//! finger, or dirt on the sensor).
+
+use crate::capture::Frame;
+
+/// Side of the blocks, in pixels
+const BLOCK: usize = 8;
+
+/// Standard deviation (relative to the maximum value) above which a block has ridges
+pub(crate) const MIN_RIDGE_STD: f32 = 0.04;
+
+/// How much darker than the background a flat block has to be to count as a smudge
+const MIN_SMUDGE_DIFF: f32 = 0.15;
+
+/// Thresholds of [`QualityReport::is_acceptable`]
+const MIN_COVERAGE: u8 = 50;
+const MIN_CONTRAST: u8 = 20;
+const MAX_SMUDGE: u8 = 30;
+
+/// See [`quality`], every field is a percentage (0-100)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QualityReport {
+    /// Part of the frame covered by the finger (ridges or smudges)
+    pub coverage: u8,
+
+    /// Difference between ridges and valleys in the covered part
+    pub contrast: u8,
+
+    /// Part of the covered area without visible ridges
+    pub smudge: u8,
+}
+
+impl QualityReport {
+    /// Whether the frame is good enough, otherwise the user should touch the sensor again
+    pub fn is_acceptable(&self) -> bool {
+        self.coverage >= MIN_COVERAGE && self.contrast >= MIN_CONTRAST && self.smudge <= MAX_SMUDGE
+    }
+}
+
+/// Statistics of a block, relative to the maximum pixel value
+pub(crate) struct Block {
+    pub(crate) mean: f32,
+    pub(crate) std: f32,
+}
+
+/// Score the frame, only 8 and 16 bits per pixel are supported (the rest score 0)
+pub fn quality(frame: &Frame) -> QualityReport {
+    let Some(pixels) = pixels(frame) else {
+        return QualityReport::default();
+    };
+
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let blocks: Vec<Block> = (0..height / BLOCK)
+        .flat_map(|by| (0..width / BLOCK).map(move |bx| (bx, by)))
+        .map(|(bx, by)| {
+            let values = (0..BLOCK).flat_map(|y| {
+                let start = (by * BLOCK + y) * width + bx * BLOCK;
+                pixels[start..start + BLOCK].iter().copied()
+            });
+            block_stats(values)
+        })
+        .collect();
+
+    if blocks.is_empty() {
+        return QualityReport::default();
+    }
+
+    let (ridges, flat): (Vec<_>, Vec<_>) = blocks.iter().partition(|b| b.std >= MIN_RIDGE_STD);
+
+    // The flat blocks are the background, except the ones much darker than it
+    let background = flat.iter().map(|b| b.mean).fold(0.0, f32::max);
+    let smudges = flat
+        .iter()
+        .filter(|b| background - b.mean >= MIN_SMUDGE_DIFF)
+        .count();
+
+    let covered = ridges.len() + smudges;
+    let contrast = match ridges.len() {
+        0 => 0.0,
+        // Ridges and valleys are about two standard deviations away from each other
+        n => ridges.iter().map(|b| b.std * 2.0).sum::<f32>() / n as f32,
+    };
+
+    QualityReport {
+        coverage: percent(covered as f32 / blocks.len() as f32),
+        contrast: percent(contrast),
+        smudge: match covered {
+            0 => 0,
+            n => percent(smudges as f32 / n as f32),
+        },
+    }
+}
+
+/// The pixels, relative to the maximum value
+pub(crate) fn pixels(frame: &Frame) -> Option<Vec<f32>> {
+    let count = frame.width as usize * frame.height as usize;
+    let pixels: Vec<f32> = match frame.bpp {
+        8 => frame
+            .data
+            .iter()
+            .map(|&p| p as f32 / u8::MAX as f32)
+            .collect(),
+        16 => frame
+            .data
+            .chunks_exact(2)
+            .map(|p| u16::from_le_bytes([p[0], p[1]]) as f32 / u16::MAX as f32)
+            .collect(),
+        _ => return None,
+    };
+
+    (pixels.len() >= count).then_some(pixels)
+}
+
+pub(crate) fn block_stats(values: impl Iterator<Item = f32> + Clone) -> Block {
+    let n = (BLOCK * BLOCK) as f32;
+    let mean = values.clone().sum::<f32>() / n;
+    let var = values.map(|v| (v - mean) * (v - mean)).sum::<f32>() / n;
+    Block {
+        mean,
+        std: var.sqrt(),
+    }
+}
+
+fn percent(v: f32) -> u8 {
+    (v * 100.0).round().clamp(0.0, 100.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocks of 8x8 pixels side by side: `Some(v)` flat with the value, `None` ridges
+    fn frame(blocks: &[Option<u8>]) -> Frame {
+        let width = blocks.len() * BLOCK;
+        let data = (0..BLOCK)
+            .flat_map(|y| {
+                blocks.iter().flat_map(move |b| match b {
+                    Some(v) => [*v; BLOCK],
+                    None => [if y % 4 < 2 { 0 } else { 255 }; BLOCK],
+                })
+            })
+            .collect();
+        Frame {
+            width: width as u16,
+            height: BLOCK as u16,
+            bpp: 8,
+            data,
+        }
+    }
+
+    #[test]
+    fn no_finger() {
+        let report = quality(&frame(&[Some(240); 4]));
+        assert_eq!(report, QualityReport::default());
+        assert!(!report.is_acceptable());
+    }
+
+    #[test]
+    fn ridges() {
+        let report = quality(&frame(&[None; 4]));
+        assert_eq!(
+            report,
+            QualityReport {
+                coverage: 100,
+                contrast: 100,
+                smudge: 0
+            }
+        );
+        assert!(report.is_acceptable());
+    }
+
+    #[test]
+    fn smudged() {
+        // Ridges, ridges, the background and a smudge
+        let report = quality(&frame(&[None, None, Some(240), Some(60)]));
+        assert_eq!(
+            report,
+            QualityReport {
+                coverage: 75,
+                contrast: 100,
+                smudge: 33
+            }
+        );
+        assert!(!report.is_acceptable());
+    }
+
+    #[test]
+    fn unsupported() {
+        let frame = Frame {
+            bpp: 4,
+            ..frame(&[None])
+        };
+        assert_eq!(quality(&frame), QualityReport::default());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::proto::Command;
+use std::sync::{LazyLock, PoisonError, RwLock};
+
+/// The global registry, see [`DeviceRegistry::global`]
+static REGISTRY: LazyLock<DeviceRegistry> = LazyLock::new(DeviceRegistry::new);
+
+/// The way the sensor reads the finger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorType {
+    /// The whole finger is placed on the sensor at once
+    Press,
+
+    /// The finger is swiped over a narrow sensor, line by line
+    Swipe,
+}
+
+/// Protocol parameters that change between the different sensor models
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceQuirks {
+    /// USB vendor ID
+    pub vid: u16,
+
+    /// USB product ID
+    pub pid: u16,
+
+    /// The commands sent (in order) by [`crate::transport::Transport::send_init`]. Every
+    /// supported model uses the opcodes of [`Command::opcode`] (the ones of the 009x sensors),
+    /// the older models using others are not known well enough to be supported.
+    pub init_sequence: &'static [Command<'static>],
+
+    /// Bulk OUT endpoint, where the commands are written. The endpoints are only used if the
+    /// descriptors don't have them, see [`crate::usb::Endpoints`]
+    pub ep_out: u8,
+
+    /// Bulk IN endpoint, where the responses are read
+    pub ep_in: u8,
+
+    /// Interrupt endpoint, used by the sensor to signal events
+    pub ep_int: u8,
+
+    /// The configuration selected when opening the device
+    pub configuration: u8,
+
+    /// The interface claimed when opening the device
+    pub interface: u8,
+
+    pub sensor_type: SensorType,
+
+    /// Whether the firmware can enroll and match fingers by itself (once it was uploaded)
+    pub match_on_chip: bool,
+
+    /// How many templates the sensor can store, `0` if it can't
+    pub storage_slots: u16,
+}
+
+impl DeviceQuirks {
+    /// Find the quirks of the given device in the [global registry](DeviceRegistry::global)
+    pub fn find(vid: u16, pid: u16) -> Option<&'static DeviceQuirks> {
+        DeviceRegistry::global().find(vid, pid)
+    }
+
+    /// The same quirks for another device, to try an unlisted model like a known one
+    pub fn with_ids(self, vid: u16, pid: u16) -> Self {
+        Self { vid, pid, ..self }
+    }
+}
+
+/// The devices the driver talks to: [`crate::SUPPORTED`] and the ones registered at runtime.
+/// The global one is used to list and open the devices.
+#[derive(Debug)]
+pub struct DeviceRegistry {
+    devices: RwLock<Vec<&'static DeviceQuirks>>,
+}
+
+impl DeviceRegistry {
+    /// A registry with the default devices, see [`crate::SUPPORTED`]
+    pub fn new() -> Self {
+        Self {
+            devices: RwLock::new(QUIRKS.iter().collect()),
+        }
+    }
+
+    /// The registry used by [`crate::list_supported_devices`], [`crate::get_device`], ...
+    pub fn global() -> &'static Self {
+        &REGISTRY
+    }
+
+    /// Add a device, replacing the quirks of the same vendor and product IDs. The quirks are
+    /// leaked (they are referenced by the opened devices), register each model once. To try a
+    /// model like a known one, see [`DeviceQuirks::with_ids`].
+    pub fn register(&self, quirks: DeviceQuirks) {
+        let quirks: &'static DeviceQuirks = Box::leak(Box::new(quirks));
+        let mut devices = self.devices.write().unwrap_or_else(PoisonError::into_inner);
+        devices.retain(|q| (q.vid, q.pid) != (quirks.vid, quirks.pid));
+        devices.push(quirks);
+    }
+
+    /// Find the quirks of the given device
+    pub fn find(&self, vid: u16, pid: u16) -> Option<&'static DeviceQuirks> {
+        self.devices
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|q| q.vid == vid && q.pid == pid)
+            .copied()
+    }
+
+    /// Every registered device
+    pub fn devices(&self) -> Vec<&'static DeviceQuirks> {
+        self.devices
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Init sequence of the 009x sensors
+const INIT_DEFAULT: &[Command<'static>] = &[Command::GetVersion, Command::Init];
+
+/// Helper to build the entries of the table, as they only differ on a few fields
+pub(crate) const fn validity(
+    pid: u16,
+    init_sequence: &'static [Command<'static>],
+    sensor_type: SensorType,
+) -> DeviceQuirks {
+    DeviceQuirks {
+        vid: 0x138a,
+        pid,
+        init_sequence,
+        ep_out: 0x01,
+        ep_in: 0x81,
+        ep_int: 0x83,
+        configuration: 1,
+        interface: 0,
+        sensor_type,
+        match_on_chip: true,
+        // A record per finger, like fprintd allows
+        storage_slots: 10,
+    }
+}
+
+pub(crate) const QUIRKS: &[DeviceQuirks] = &[
+    validity(0x0090, INIT_DEFAULT, SensorType::Press),
+    validity(0x0094, INIT_DEFAULT, SensorType::Press),
+    validity(0x0095, INIT_DEFAULT, SensorType::Swipe),
+    validity(0x0097, INIT_DEFAULT, SensorType::Press),
+    validity(0x0098, INIT_DEFAULT, SensorType::Swipe),
+    validity(0x009a, INIT_DEFAULT, SensorType::Press),
+];
@@ -0,0 +1,222 @@
+//! Transcripts of the USB traffic, for reverse engineering and for replaying real captures in
+//! tests. A [`Recorder`] wraps a transport and logs every transfer, [`MockTransport::from_transcript`]
+//! turns the log back into a device.
+//!
+//! The transcript is a text file with a transfer per line: seconds since the recording started,
+//! direction (`out`, `in` or `int`), endpoint and the data in hex, for example:
+//!
+//! ```text
+//! # validity-sens transcript 138a:0097
+//! 0.000112 out 01 01
+//! 0.001374 in 81 0000f0b05e54a4...
+//! ```
+//!
+//! Lines starting with `#` are comments, failed transfers are logged as comments too.
+
+use crate::{
+    DriverError,
+    calibration::DeadPixelMap,
+    quirks::DeviceQuirks,
+    trace::Hex,
+    transport::{MockTransport, Transport},
+};
+use core::{fmt, time::Duration};
+use std::{
+    cell::RefCell,
+    io::{BufRead, Write},
+    time::Instant,
+};
+
+/// Direction of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    Out,
+    In,
+    Int,
+}
+
+impl fmt::Display for Dir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Out => "out",
+            Self::In => "in",
+            Self::Int => "int",
+        })
+    }
+}
+
+/// Logs every transfer of the inner transport to `W`. Errors writing the log are ignored, they
+/// never break the communication with the device.
+#[derive(Debug)]
+pub struct Recorder<T: Transport, W: Write> {
+    inner: T,
+    out: RefCell<W>,
+    start: Instant,
+}
+
+impl<T: Transport, W: Write> Recorder<T, W> {
+    pub fn new(inner: T, mut out: W) -> Self {
+        let quirks = inner.quirks();
+        let _ = writeln!(
+            out,
+            "# validity-sens transcript {:04x}:{:04x}",
+            quirks.vid, quirks.pid
+        );
+
+        Self {
+            inner,
+            out: RefCell::new(out),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Stop recording, returns the transport and the log
+    pub fn into_inner(self) -> (T, W) {
+        (self.inner, self.out.into_inner())
+    }
+
+    fn log(&self, dir: Dir, data: &[u8]) {
+        let ep = self.endpoint(dir);
+        let time = self.start.elapsed().as_secs_f64();
+        let _ = writeln!(
+            self.out.borrow_mut(),
+            "{time:.6} {dir} {ep:02x} {}",
+            Hex(data)
+        );
+    }
+
+    fn log_err(&self, dir: Dir, err: &DriverError) {
+        let ep = self.endpoint(dir);
+        let _ = writeln!(self.out.borrow_mut(), "# {dir} {ep:02x} failed: {err}");
+    }
+
+    /// Log the result of the transfer, `len` bytes of `data` were transferred
+    fn record(
+        &self,
+        dir: Dir,
+        data: &[u8],
+        res: Result<usize, DriverError>,
+    ) -> Result<usize, DriverError> {
+        match &res {
+            Ok(len) => self.log(dir, &data[..(*len).min(data.len())]),
+            Err(e) => self.log_err(dir, e),
+        }
+        res
+    }
+
+    fn endpoint(&self, dir: Dir) -> u8 {
+        let quirks = self.inner.quirks();
+        match dir {
+            Dir::Out => quirks.ep_out,
+            Dir::In => quirks.ep_in,
+            Dir::Int => quirks.ep_int,
+        }
+    }
+}
+
+impl<T: Transport, W: Write> Transport for Recorder<T, W> {
+    fn quirks(&self) -> &'static DeviceQuirks {
+        self.inner.quirks()
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, DriverError> {
+        self.record(Dir::Out, data, self.inner.write(data))
+    }
+
+    fn read(&self, out: &mut [u8]) -> Result<usize, DriverError> {
+        let res = self.inner.read(out);
+        self.record(Dir::In, out, res)
+    }
+
+    fn wait_int(&self, out: &mut [u8], timeout: Duration) -> Result<usize, DriverError> {
+        let res = self.inner.wait_int(out, timeout);
+        self.record(Dir::Int, out, res)
+    }
+
+    fn reset(&mut self) -> Result<(), DriverError> {
+        self.inner.reset()
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        self.inner.serial_number()
+    }
+
+    fn touch_timeout(&self) -> Duration {
+        self.inner.touch_timeout()
+    }
+
+    fn dead_pixels(&self) -> Option<&DeadPixelMap> {
+        self.inner.dead_pixels()
+    }
+
+    /// Uses the command of the inner transport (keeping its timeouts and retries), only the
+    /// final command and response are logged
+    fn cmd(&self, data: &[u8], out: &mut [u8]) -> Result<usize, DriverError> {
+        match self.inner.cmd(data, out) {
+            Ok(len) => {
+                self.log(Dir::Out, data);
+                self.log(Dir::In, &out[..len]);
+                Ok(len)
+            }
+            Err(e) => {
+                self.log(Dir::Out, data);
+                self.log_err(Dir::In, &e);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl MockTransport {
+    /// A mock replaying the transcript written by a [`Recorder`]: the commands have to be sent
+    /// in the same order and get the recorded responses
+    pub fn from_transcript(
+        quirks: &'static DeviceQuirks,
+        transcript: impl BufRead,
+    ) -> Result<Self, DriverError> {
+        let mut mock = Self::new(quirks);
+        let mut has_cmd = false;
+
+        for (i, line) in transcript.lines().enumerate() {
+            let line = line.map_err(DriverError::RecordIo)?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || DriverError::RecordInvalid(i + 1);
+            let mut fields = line.split_ascii_whitespace().skip(1);
+            let (Some(dir), Some(_ep)) = (fields.next(), fields.next()) else {
+                return Err(invalid());
+            };
+            let data = parse_hex(fields.next().unwrap_or("")).ok_or_else(invalid)?;
+
+            mock = match dir {
+                "out" => {
+                    has_cmd = true;
+                    mock.expect_only(&data)
+                }
+                "in" if has_cmd => mock.then(&data),
+                "int" => mock.interrupt(&data),
+                _ => return Err(invalid()),
+            };
+        }
+
+        Ok(mock)
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,32 @@
+//! A factory reset erases everything stored on the sensor: the pairing with the host, the
+//! finger records and the firmware extension. It is the way out when the sensor was paired by
+//! another OS (or tool) and refuses to talk to this driver, but it can't be undone, so it needs a
+//! [`ResetToken`].
+
+use crate::{DriverError, proto::Command, transport::Transport};
+
+/// Proof the caller really wants to erase the sensor, see [`ResetToken::confirm`]
+#[derive(Debug)]
+pub struct ResetToken(());
+
+impl ResetToken {
+    /// The text to pass to [`Self::confirm`]
+    pub const CONFIRMATION: &'static str = "erase the pairing and every template";
+
+    /// Get a token, only if `text` is [`Self::CONFIRMATION`] (usually typed by the user)
+    pub fn confirm(text: &str) -> Option<Self> {
+        (text.trim() == Self::CONFIRMATION).then_some(Self(()))
+    }
+}
+
+/// Factory reset, implemented for every [`Transport`]
+pub trait FactoryReset: Transport {
+    /// Erase the pairing data and the templates, the sensor reboots afterwards (so the device
+    /// has to be found and opened again) and needs the firmware extension to be uploaded again
+    fn factory_reset(&self, _token: ResetToken) -> Result<(), DriverError> {
+        self.run(&Command::FactoryReset, &mut [0u8; 64])?;
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> FactoryReset for T {}
@@ -0,0 +1,634 @@
+//! The sensors talk a (slightly modified) TLS 1.2 over the bulk endpoints, every record sent by
+//! the host is prefixed with [`TLS_PREFIX`]. The cipher suite is always
+//! ECDH_ECDSA_WITH_AES_256_CBC_SHA: the pre-master secret comes from the (static) host key and
+//! the sensor key, data is MAC'd with HMAC-SHA256 (not the SHA-1 of the suite) and then
+//! encrypted with AES-256-CBC.
+
+#[cfg(feature = "trace")]
+use crate::trace::Hex;
+use crate::{
+    DriverError, hostkey::HostKey, trace::trace, transport::Transport, usb::OpenedUsbDevice,
+};
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::NoPadding};
+use hmac::{Hmac, Mac};
+use p256::{PublicKey, elliptic_curve::sec1::ToEncodedPoint};
+use rand_core::{OsRng, RngCore};
+use rusb::UsbContext;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+
+type HmacSha256 = Hmac<Sha256>;
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+
+/// Every TLS message sent to the sensor starts with this
+pub const TLS_PREFIX: &[u8] = &[0x44, 0x00, 0x00, 0x00];
+
+const TLS_VERSION: &[u8] = &[0x03, 0x03];
+
+const CONTENT_CHANGE_CIPHER: u8 = 0x14;
+const CONTENT_ALERT: u8 = 0x15;
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const CONTENT_APP_DATA: u8 = 0x17;
+
+const HS_CLIENT_HELLO: u8 = 0x01;
+const HS_SERVER_HELLO: u8 = 0x02;
+const HS_CERTIFICATE: u8 = 0x0b;
+const HS_CERTIFICATE_REQUEST: u8 = 0x0d;
+const HS_SERVER_HELLO_DONE: u8 = 0x0e;
+const HS_CERTIFICATE_VERIFY: u8 = 0x0f;
+const HS_CLIENT_KEY_EXCHANGE: u8 = 0x10;
+const HS_FINISHED: u8 = 0x14;
+
+/// TLS_ECDH_ECDSA_WITH_AES_256_CBC_SHA, the only one the sensor picks (it MACs with SHA256
+/// anyway, see above)
+const CIPHER_SUITES: &[u8] = &[0xc0, 0x05];
+
+/// Sequence number of the first application record, the Finished messages are the first ones
+/// (0) of each direction
+const FIRST_APP_SEQ: u64 = 1;
+
+/// Size of the buffer used to read the responses
+const RESPONSE_SIZE: usize = 1024 * 64;
+
+/// The keys needed to start a session, these are the result of pairing the host with the sensor
+#[derive(Clone)]
+pub struct SessionParams {
+    /// Private key of the host
+    pub host_key: HostKey,
+
+    /// The certificate of the host, as accepted by the sensor
+    pub host_cert: Vec<u8>,
+
+    /// Public (ECDH) key of the sensor
+    pub device_key: PublicKey,
+}
+
+/// The host key is left out
+impl core::fmt::Debug for SessionParams {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SessionParams")
+            .field("host_cert", &self.host_cert)
+            .field("device_key", &self.device_key)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The keys derived from the master secret
+struct SessionKeys {
+    sign: [u8; 0x20],
+    validation: [u8; 0x20],
+    encryption: [u8; 0x20],
+    decryption: [u8; 0x20],
+}
+
+/// An encrypted session with the sensor, see [`Self::establish`]
+pub struct SecureSession<T: Transport = OpenedUsbDevice> {
+    dev: T,
+    keys: SessionKeys,
+
+    /// Kept to do the handshake again, see [`Self::reconnect`]
+    params: SessionParams,
+
+    /// Sequence numbers of the next records sent and received, part of their MAC
+    tx_seq: Cell<u64>,
+    rx_seq: Cell<u64>,
+}
+
+impl<T: Transport> SecureSession<T> {
+    /// Perform the handshake with the sensor (the device should already be initialized with
+    /// [`Transport::send_init`])
+    pub fn establish(dev: T, params: &SessionParams) -> Result<Self, DriverError> {
+        let keys = handshake(&dev, params)?;
+        Ok(Self {
+            dev,
+            keys,
+            params: params.clone(),
+            tx_seq: Cell::new(FIRST_APP_SEQ),
+            rx_seq: Cell::new(FIRST_APP_SEQ),
+        })
+    }
+
+    /// Encrypt the command, send it to the device and decrypt the response
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(level = "debug", skip_all, fields(opcode = data.first()))
+    )]
+    pub fn cmd_secure(&self, data: &[u8]) -> Result<Vec<u8>, DriverError> {
+        trace!(data = %Hex(data), "secure command");
+        let seq = self.tx_seq.get();
+        self.tx_seq.set(seq + 1);
+        let mut msg = TLS_PREFIX.to_vec();
+        msg.extend(record(
+            CONTENT_APP_DATA,
+            &encrypt(&self.keys, seq, CONTENT_APP_DATA, data),
+        ));
+
+        let resp = self.dev.cmd_vec(&msg)?;
+
+        let mut res = Vec::new();
+        for (kind, body) in parse_records(&resp)? {
+            match kind {
+                CONTENT_APP_DATA => {
+                    // A record replayed was MAC'd with an older number
+                    let seq = self.rx_seq.get();
+                    res.extend(decrypt(&self.keys, seq, kind, body)?);
+                    self.rx_seq.set(seq + 1);
+                }
+                CONTENT_ALERT => return Err(alert(body)),
+                _ => return Err(DriverError::TlsUnexpectedRecord(kind)),
+            }
+        }
+
+        trace!(data = %Hex(&res), "secure response");
+        Ok(res)
+    }
+
+    /// The underlying device
+    pub fn device(&self) -> &T {
+        &self.dev
+    }
+
+    /// Drop the session and get the device back
+    pub fn into_inner(self) -> T {
+        self.dev
+    }
+}
+
+impl<C: UsbContext> SecureSession<OpenedUsbDevice<C>> {
+    /// Reconnect the device (see [`OpenedUsbDevice::reconnect`]) and start a new session
+    pub fn reconnect(&mut self) -> Result<(), DriverError> {
+        self.dev.reconnect()?;
+        self.keys = handshake(&self.dev, &self.params)?;
+        self.tx_seq.set(FIRST_APP_SEQ);
+        self.rx_seq.set(FIRST_APP_SEQ);
+        Ok(())
+    }
+
+    /// Run the operation, if the device was lost (see [`DriverError::is_disconnected`]) reconnect
+    /// and run it once more
+    pub fn recovering<R, F>(&mut self, mut op: F) -> Result<R, DriverError>
+    where
+        F: FnMut(&Self) -> Result<R, DriverError>,
+    {
+        match op(self) {
+            Err(e) if e.is_disconnected() => {
+                self.reconnect()?;
+                op(self)
+            }
+            res => res,
+        }
+    }
+}
+
+/// Do the handshake, returns the keys of the new session
+#[cfg_attr(feature = "trace", tracing::instrument(level = "debug", skip_all))]
+fn handshake<T: Transport>(dev: &T, params: &SessionParams) -> Result<SessionKeys, DriverError> {
+    let mut hs = Handshake::new(params);
+    let mut resp = vec![0u8; RESPONSE_SIZE];
+
+    // Client Hello
+    let hello = hs.client_hello();
+    let len = dev.cmd(&hello, &mut resp)?;
+
+    // Server Hello (+ Certificate Request + Server Hello Done)
+    for (kind, body) in parse_records(&resp[..len])? {
+        match kind {
+            CONTENT_HANDSHAKE => hs.server_handshake(body)?,
+            CONTENT_ALERT => return Err(alert(body)),
+            _ => return Err(DriverError::TlsUnexpectedRecord(kind)),
+        }
+    }
+
+    // Certificate, Key Exchange, Certificate Verify, Change Cipher Spec and Finished
+    let keys = hs.keys()?;
+    let flight = hs.client_finish(&keys)?;
+    let len = dev.cmd(&flight, &mut resp)?;
+
+    // Change Cipher Spec + (encrypted) Finished
+    let mut changed = false;
+    let mut finished = false;
+    for (kind, body) in parse_records(&resp[..len])? {
+        match kind {
+            CONTENT_CHANGE_CIPHER => changed = true,
+            CONTENT_HANDSHAKE if changed => {
+                let body = decrypt(&keys, 0, CONTENT_HANDSHAKE, body)?;
+                hs.server_finished(&body)?;
+                finished = true;
+            }
+            CONTENT_ALERT => return Err(alert(body)),
+            _ => return Err(DriverError::TlsUnexpectedRecord(kind)),
+        }
+    }
+
+    if !finished {
+        return Err(DriverError::TlsHandshakeFailed);
+    }
+
+    Ok(keys)
+}
+
+/// State kept during the handshake
+struct Handshake<'a> {
+    params: &'a SessionParams,
+    client_random: [u8; 32],
+    server_random: Option<[u8; 32]>,
+    done: bool,
+
+    /// Every handshake message, used for the signature and the Finished messages
+    messages: Vec<u8>,
+    master: [u8; 48],
+}
+
+impl<'a> Handshake<'a> {
+    fn new(params: &'a SessionParams) -> Self {
+        let mut client_random = [0u8; 32];
+        OsRng.fill_bytes(&mut client_random);
+
+        Self {
+            params,
+            client_random,
+            server_random: None,
+            done: false,
+            messages: Vec::new(),
+            master: [0u8; 48],
+        }
+    }
+
+    /// Append the handshake message to the transcript and return it
+    fn message(&mut self, kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut msg = vec![kind];
+        msg.extend(&u24(body.len()));
+        msg.extend(body);
+        self.messages.extend(&msg);
+        msg
+    }
+
+    fn client_hello(&mut self) -> Vec<u8> {
+        let mut body = TLS_VERSION.to_vec();
+        body.extend(self.client_random);
+        // No session id
+        body.push(0);
+        body.extend((CIPHER_SUITES.len() as u16).to_be_bytes());
+        body.extend(CIPHER_SUITES);
+        // Only the null compression
+        body.extend([0x01, 0x00]);
+        // Extensions: ec_point_formats (uncompressed)
+        body.extend([0x00, 0x06, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]);
+
+        let msg = self.message(HS_CLIENT_HELLO, &body);
+        let mut res = TLS_PREFIX.to_vec();
+        res.extend(record(CONTENT_HANDSHAKE, &msg));
+        res
+    }
+
+    /// Handle the handshake messages in a record sent by the sensor
+    fn server_handshake(&mut self, mut data: &[u8]) -> Result<(), DriverError> {
+        while !data.is_empty() {
+            if data.len() < 4 {
+                return Err(DriverError::TlsInvalidRecord);
+            }
+
+            let len = read_u24(&data[1..4]);
+            if data.len() < 4 + len {
+                return Err(DriverError::TlsInvalidRecord);
+            }
+
+            let (msg, rest) = data.split_at(4 + len);
+            let body = &msg[4..];
+
+            match msg[0] {
+                HS_SERVER_HELLO => {
+                    // version (2) + random (32)
+                    if body.len() < 34 {
+                        return Err(DriverError::TlsInvalidRecord);
+                    }
+                    let mut random = [0u8; 32];
+                    random.copy_from_slice(&body[2..34]);
+                    self.server_random = Some(random);
+                }
+                // We always send our certificate, so there is nothing to do here
+                HS_CERTIFICATE_REQUEST => (),
+                HS_SERVER_HELLO_DONE => self.done = true,
+                kind => return Err(DriverError::TlsUnexpectedHandshake(kind)),
+            }
+
+            self.messages.extend(msg);
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Derive the master secret and the session keys
+    fn keys(&mut self) -> Result<SessionKeys, DriverError> {
+        let server_random = match self.server_random {
+            Some(r) if self.done => r,
+            _ => return Err(DriverError::TlsHandshakeFailed),
+        };
+
+        let shared = self
+            .params
+            .host_key
+            .diffie_hellman(&self.params.device_key)?;
+
+        let seed = [&self.client_random[..], &server_random[..]].concat();
+        prf(&shared, b"master secret", &seed, &mut self.master);
+
+        let seed = [&server_random[..], &self.client_random[..]].concat();
+        let mut block = [0u8; 0x80];
+        prf(&self.master, b"key expansion", &seed, &mut block);
+
+        let mut keys = SessionKeys {
+            sign: [0u8; 0x20],
+            validation: [0u8; 0x20],
+            encryption: [0u8; 0x20],
+            decryption: [0u8; 0x20],
+        };
+        keys.sign.copy_from_slice(&block[0x00..0x20]);
+        keys.validation.copy_from_slice(&block[0x20..0x40]);
+        keys.encryption.copy_from_slice(&block[0x40..0x60]);
+        keys.decryption.copy_from_slice(&block[0x60..0x80]);
+        Ok(keys)
+    }
+
+    fn client_finish(&mut self, keys: &SessionKeys) -> Result<Vec<u8>, DriverError> {
+        // Certificate
+        let cert = &self.params.host_cert;
+        let mut body = u24(cert.len() + 3).to_vec();
+        body.extend(u24(cert.len()));
+        body.extend(cert);
+        let mut hs = self.message(HS_CERTIFICATE, &body);
+
+        // Client Key Exchange, our (uncompressed) public key
+        let point = self.params.host_key.public_key().to_encoded_point(false);
+        let mut body = vec![point.len() as u8];
+        body.extend(point.as_bytes());
+        hs.extend(self.message(HS_CLIENT_KEY_EXCHANGE, &body));
+
+        // Certificate Verify, sign everything sent up to this point
+        let sig = self.params.host_key.sign(&self.messages)?;
+        // ecdsa_secp256r1_sha256
+        let mut body = vec![0x04, 0x03];
+        body.extend((sig.len() as u16).to_be_bytes());
+        body.extend(&sig);
+        hs.extend(self.message(HS_CERTIFICATE_VERIFY, &body));
+
+        // Finished
+        let hash = Sha256::digest(&self.messages);
+        let mut verify = [0u8; 12];
+        prf(&self.master, b"client finished", &hash, &mut verify);
+        let finished = self.message(HS_FINISHED, &verify);
+
+        let mut res = TLS_PREFIX.to_vec();
+        res.extend(record(CONTENT_HANDSHAKE, &hs));
+        res.extend(record(CONTENT_CHANGE_CIPHER, &[0x01]));
+        res.extend(record(
+            CONTENT_HANDSHAKE,
+            &encrypt(keys, 0, CONTENT_HANDSHAKE, &finished),
+        ));
+        Ok(res)
+    }
+
+    /// Check the Finished message sent by the sensor (already decrypted)
+    fn server_finished(&mut self, msg: &[u8]) -> Result<(), DriverError> {
+        if msg.len() != 4 + 12 || msg[0] != HS_FINISHED {
+            return Err(DriverError::TlsInvalidRecord);
+        }
+
+        let hash = Sha256::digest(&self.messages);
+        let mut verify = [0u8; 12];
+        prf(&self.master, b"server finished", &hash, &mut verify);
+
+        if msg[4..] != verify {
+            return Err(DriverError::TlsHandshakeFailed);
+        }
+
+        self.messages.extend(msg);
+        Ok(())
+    }
+}
+
+/// The TLS 1.2 PRF (P_SHA256)
+fn prf(secret: &[u8], label: &[u8], seed: &[u8], out: &mut [u8]) {
+    let seed = [label, seed].concat();
+    let hmac = |data: &[&[u8]]| {
+        // SAFETY: HMAC accepts keys of any size
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        for d in data {
+            mac.update(d);
+        }
+        mac.finalize().into_bytes()
+    };
+
+    let mut a = hmac(&[&seed]);
+    for chunk in out.chunks_mut(32) {
+        let block = hmac(&[&a, &seed]);
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        a = hmac(&[&a]);
+    }
+}
+
+fn u24(n: usize) -> [u8; 3] {
+    let b = (n as u32).to_be_bytes();
+    [b[1], b[2], b[3]]
+}
+
+fn read_u24(b: &[u8]) -> usize {
+    u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize
+}
+
+/// Build a TLS record
+fn record(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut res = vec![kind];
+    res.extend(TLS_VERSION);
+    res.extend((data.len() as u16).to_be_bytes());
+    res.extend(data);
+    res
+}
+
+/// Split the response in records, returns (content type, body)
+fn parse_records(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, DriverError> {
+    let mut res = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < 5 || data[1..3] != *TLS_VERSION {
+            return Err(DriverError::TlsInvalidRecord);
+        }
+
+        let len = u16::from_be_bytes([data[3], data[4]]) as usize;
+        if data.len() < 5 + len {
+            return Err(DriverError::TlsInvalidRecord);
+        }
+
+        res.push((data[0], &data[5..5 + len]));
+        data = &data[5 + len..];
+    }
+
+    Ok(res)
+}
+
+fn alert(body: &[u8]) -> DriverError {
+    match body {
+        [level, desc, ..] => DriverError::TlsAlert(*level, *desc),
+        _ => DriverError::TlsInvalidRecord,
+    }
+}
+
+/// The MAC covers the sequence number, the record header (with the plaintext length) and the
+/// plaintext
+fn mac(key: &[u8], seq: u64, kind: u8, data: &[u8]) -> HmacSha256 {
+    // SAFETY: HMAC accepts keys of any size
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(&seq.to_be_bytes());
+    mac.update(&[kind]);
+    mac.update(TLS_VERSION);
+    mac.update(&(data.len() as u16).to_be_bytes());
+    mac.update(data);
+    mac
+}
+
+/// MAC then encrypt, the result is: IV + AES(data + MAC + padding)
+fn encrypt(keys: &SessionKeys, seq: u64, kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut iv = [0u8; 16];
+    OsRng.fill_bytes(&mut iv);
+
+    let mut buf = data.to_vec();
+    buf.extend(mac(&keys.sign, seq, kind, data).finalize().into_bytes());
+
+    // TLS padding: n+1 bytes with the value n
+    let pad = 16 - (buf.len() % 16);
+    buf.extend(std::iter::repeat_n((pad - 1) as u8, pad));
+
+    let len = buf.len();
+    // SAFETY: The buffer was padded to the block size
+    Aes256CbcEnc::new(&keys.encryption.into(), &iv.into())
+        .encrypt_padded_mut::<NoPadding>(&mut buf, len)
+        .unwrap();
+
+    let mut res = iv.to_vec();
+    res.extend(buf);
+    res
+}
+
+/// Decrypt and check the MAC of the record body
+fn decrypt(keys: &SessionKeys, seq: u64, kind: u8, data: &[u8]) -> Result<Vec<u8>, DriverError> {
+    // IV + at least one block
+    if data.len() < 32 || !data.len().is_multiple_of(16) {
+        return Err(DriverError::TlsInvalidRecord);
+    }
+
+    let (iv, data) = data.split_at(16);
+    let mut buf = data.to_vec();
+    let iv: [u8; 16] = iv.try_into().map_err(|_| DriverError::TlsInvalidRecord)?;
+
+    Aes256CbcDec::new(&keys.decryption.into(), &iv.into())
+        .decrypt_padded_mut::<NoPadding>(&mut buf)
+        .map_err(|_| DriverError::TlsInvalidRecord)?;
+
+    // Remove the padding and the MAC
+    let pad = *buf.last().unwrap_or(&0) as usize + 1;
+    if buf.len() < pad + 32 {
+        return Err(DriverError::TlsInvalidRecord);
+    }
+    // Every padding byte holds the padding length, checked along with the MAC so a bad padding
+    // can't be told apart from a bad MAC
+    let padding_ok = buf[buf.len() - pad..]
+        .iter()
+        .all(|b| *b as usize == pad - 1);
+    buf.truncate(buf.len() - pad);
+    let tag = buf.split_off(buf.len() - 32);
+
+    let mac_ok = mac(&keys.validation, seq, kind, &buf)
+        .verify_slice(&tag)
+        .is_ok();
+    if !(padding_ok & mac_ok) {
+        return Err(DriverError::TlsBadMac);
+    }
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The same keys both ways, so the records can be decrypted by the one encrypting them
+    fn keys() -> SessionKeys {
+        SessionKeys {
+            sign: [1; 0x20],
+            validation: [1; 0x20],
+            encryption: [2; 0x20],
+            decryption: [2; 0x20],
+        }
+    }
+
+    /// Encrypt the data with its MAC and the given padding
+    fn record(data: &[u8], padding: &[u8]) -> Vec<u8> {
+        let keys = keys();
+        let iv = [3u8; 16];
+        let mut buf = data.to_vec();
+        buf.extend(
+            mac(&keys.sign, FIRST_APP_SEQ, CONTENT_APP_DATA, data)
+                .finalize()
+                .into_bytes(),
+        );
+        buf.extend(padding);
+
+        let len = buf.len();
+        Aes256CbcEnc::new(&keys.encryption.into(), &iv.into())
+            .encrypt_padded_mut::<NoPadding>(&mut buf, len)
+            .unwrap();
+        let mut res = iv.to_vec();
+        res.extend(buf);
+        res
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = b"some data";
+        let rec = encrypt(&keys(), FIRST_APP_SEQ, CONTENT_APP_DATA, data);
+        assert_eq!(
+            decrypt(&keys(), FIRST_APP_SEQ, CONTENT_APP_DATA, &rec).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn good_padding() {
+        let rec = record(b"0123456789", &[5; 6]);
+        assert_eq!(
+            decrypt(&keys(), FIRST_APP_SEQ, CONTENT_APP_DATA, &rec).unwrap(),
+            b"0123456789"
+        );
+    }
+
+    #[test]
+    fn bad_padding() {
+        let rec = record(b"0123456789", &[0, 0, 0, 0, 0, 5]);
+        assert!(matches!(
+            decrypt(&keys(), FIRST_APP_SEQ, CONTENT_APP_DATA, &rec),
+            Err(DriverError::TlsBadMac)
+        ));
+    }
+
+    #[test]
+    fn bad_mac() {
+        let mut rec = encrypt(&keys(), FIRST_APP_SEQ, CONTENT_APP_DATA, b"some data");
+        rec[16] ^= 1;
+        assert!(matches!(
+            decrypt(&keys(), FIRST_APP_SEQ, CONTENT_APP_DATA, &rec),
+            Err(DriverError::TlsBadMac)
+        ));
+    }
+
+    #[test]
+    fn replayed() {
+        let rec = encrypt(&keys(), FIRST_APP_SEQ, CONTENT_APP_DATA, b"some data");
+        decrypt(&keys(), FIRST_APP_SEQ, CONTENT_APP_DATA, &rec).unwrap();
+        assert!(matches!(
+            decrypt(&keys(), FIRST_APP_SEQ + 1, CONTENT_APP_DATA, &rec),
+            Err(DriverError::TlsBadMac)
+        ));
+    }
+}
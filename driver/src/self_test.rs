@@ -0,0 +1,62 @@
+//! The firmware checks its own hardware on request: the analog to digital converter, the flash
+//! and the pixel array. A sensor failing them won't be fixed by the driver, it is worth knowing
+//! before chasing protocol errors.
+
+use crate::{
+    DriverError,
+    proto::{Command, Response, responses},
+    transport::Transport,
+};
+
+/// Bits of the failed tests, in the first byte of the response
+const FAILED_ADC: u8 = 1 << 0;
+const FAILED_FLASH: u8 = 1 << 1;
+const FAILED_ARRAY: u8 = 1 << 2;
+
+/// The result of the diagnostics, see [`SelfTest::self_test`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SelfTestReport {
+    /// The analog to digital converter reads the reference voltages right
+    pub adc: bool,
+
+    /// The flash reads back what was written to its scratch area
+    pub flash: bool,
+
+    /// The pixel array has no more dead pixels than the firmware tolerates
+    pub sensor_array: bool,
+
+    /// The dead pixels found in the array
+    pub dead_pixels: u16,
+}
+
+impl SelfTestReport {
+    /// Whether every test passed
+    pub fn passed(&self) -> bool {
+        self.adc && self.flash && self.sensor_array
+    }
+}
+
+/// Hardware diagnostics, implemented for every [`Transport`]
+pub trait SelfTest: Transport {
+    /// Run the diagnostics, the sensor must not be capturing. It takes about a second.
+    fn self_test(&self) -> Result<SelfTestReport, DriverError> {
+        let mut buf = [0u8; 64];
+        let resp = self.run(&Command::SelfTest, &mut buf)?;
+        parse_report(resp).ok_or(DriverError::SelfTestInvalidResponse)
+    }
+}
+
+impl<T: Transport + ?Sized> SelfTest for T {}
+
+/// See [`responses::SelfTest`]
+fn parse_report(mut resp: Response<'_>) -> Option<SelfTestReport> {
+    let report = responses::SelfTest::parse(&mut resp)?;
+    let failed = report.failed;
+    Some(SelfTestReport {
+        adc: failed & FAILED_ADC == 0,
+        flash: failed & FAILED_FLASH == 0,
+        sensor_array: failed & FAILED_ARRAY == 0,
+        dead_pixels: report.dead_pixels,
+    })
+}
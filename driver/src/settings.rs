@@ -0,0 +1,142 @@
+//! The firmware picks a gain and an integration time that suit most fingers, dry or faint ones
+//! come out pale and lose their ridges. Both are hardware registers, [`Settings`] reads and
+//! writes them. They are back to the defaults after a reset or a power cycle, read them first to
+//! restore them.
+
+use crate::{
+    DriverError,
+    proto::{Command, responses},
+    transport::Transport,
+};
+use core::time::Duration;
+
+/// Register of the analog gain of the pixel amplifiers
+const REG_GAIN: u32 = 0x8000_2040;
+
+/// Register of the integration time, in microseconds
+const REG_INTEGRATION: u32 = 0x8000_2044;
+
+/// Only the low bits of the gain register are used, higher steps are ignored by the sensor
+const MAX_GAIN: u8 = 7;
+
+/// How the sensor reads the pixels, see the [module docs](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CaptureSettings {
+    /// Amplification of the pixels, from 0 to 7, each step roughly doubles it. Raise it for
+    /// faint fingers.
+    pub gain: u8,
+
+    /// How long the pixels collect charge, up to 65 ms. Longer times help with dry fingers but
+    /// blur the moving ones on swipe sensors.
+    pub integration_time: Duration,
+}
+
+impl CaptureSettings {
+    /// Whether the sensor accepts these values
+    pub fn is_valid(&self) -> bool {
+        self.gain <= MAX_GAIN
+            && !self.integration_time.is_zero()
+            && self.integration_time.as_micros() <= u16::MAX as u128
+    }
+}
+
+/// Capture settings, implemented for every [`Transport`]
+pub trait Settings: Transport {
+    /// Read a 32 bits hardware register
+    fn read_register(&self, addr: u32) -> Result<u32, DriverError> {
+        let mut buf = [0u8; 16];
+        let mut resp = self.run(&Command::ReadRegister { addr }, &mut buf)?;
+        responses::Register::parse(&mut resp)
+            .map(|reg| reg.value)
+            .ok_or(DriverError::RegisterInvalidResponse)
+    }
+
+    /// Write a 32 bits hardware register, a wrong value can hang the sensor until it is reset
+    fn write_register(&self, addr: u32, value: u32) -> Result<(), DriverError> {
+        self.run(&Command::WriteRegister { addr, value }, &mut [0u8; 16])?;
+        Ok(())
+    }
+
+    /// The settings in use, the firmware defaults unless changed
+    fn capture_settings(&self) -> Result<CaptureSettings, DriverError> {
+        let gain = self.read_register(REG_GAIN)?;
+        let integration = self.read_register(REG_INTEGRATION)?;
+
+        Ok(CaptureSettings {
+            gain: (gain & MAX_GAIN as u32) as u8,
+            integration_time: Duration::from_micros((integration & 0xffff) as u64),
+        })
+    }
+
+    /// Use these settings for the next captures, see [`CaptureSettings::is_valid`]
+    fn set_capture_settings(&self, settings: &CaptureSettings) -> Result<(), DriverError> {
+        if !settings.is_valid() {
+            return Err(DriverError::CaptureSettingsInvalid);
+        }
+
+        self.write_register(REG_GAIN, settings.gain as u32)?;
+        self.write_register(
+            REG_INTEGRATION,
+            settings.integration_time.as_micros() as u32,
+        )
+    }
+}
+
+impl<T: Transport + ?Sized> Settings for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    fn settings(gain: u8, micros: u64) -> CaptureSettings {
+        CaptureSettings {
+            gain,
+            integration_time: Duration::from_micros(micros),
+        }
+    }
+
+    fn read(addr: u32) -> Vec<u8> {
+        Command::ReadRegister { addr }.to_bytes()
+    }
+
+    fn write(addr: u32, value: u32) -> Vec<u8> {
+        Command::WriteRegister { addr, value }.to_bytes()
+    }
+
+    #[test]
+    fn valid() {
+        assert!(settings(0, 1).is_valid());
+        assert!(settings(MAX_GAIN, 65_535).is_valid());
+        assert!(!settings(MAX_GAIN + 1, 1000).is_valid());
+        assert!(!settings(3, 0).is_valid());
+        assert!(!settings(3, 65_536).is_valid());
+    }
+
+    #[test]
+    fn read_settings() {
+        // The unused bits are left out
+        let dev = MockTransport::new(&crate::SUPPORTED[0])
+            .expect(&read(REG_GAIN), &[0, 0, 0x0b, 0, 0, 0])
+            .expect(&read(REG_INTEGRATION), &[0, 0, 0xe8, 0x03, 1, 0]);
+        assert_eq!(dev.capture_settings().unwrap(), settings(3, 1000));
+        assert!(dev.is_done());
+    }
+
+    #[test]
+    fn write_settings() {
+        let dev = MockTransport::new(&crate::SUPPORTED[0])
+            .expect(&write(REG_GAIN, 5), &[0, 0])
+            .expect(&write(REG_INTEGRATION, 2000), &[0, 0]);
+        dev.set_capture_settings(&settings(5, 2000)).unwrap();
+        assert!(dev.is_done());
+
+        // Nothing is written
+        let dev = MockTransport::new(&crate::SUPPORTED[0]);
+        assert!(matches!(
+            dev.set_capture_settings(&settings(9, 2000)),
+            Err(DriverError::CaptureSettingsInvalid)
+        ));
+    }
+}
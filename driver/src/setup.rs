@@ -0,0 +1,281 @@
+//! First-time setup of a sensor that was never provisioned (or was factory reset): upload the
+//! firmware extension, check the calibration and pair with the host, in one go. This is what the
+//! python validity-sensors-tools do before the sensor can be used.
+//!
+//! Before that the user needs access to the device: see [`generate_udev_rules`] and
+//! [`check_permissions`].
+
+use crate::{
+    DriverError,
+    calibration::{Calibrate, Calibration},
+    firmware::{DeviceState, Firmware, FirmwareUpdate},
+    pairing::Pair,
+    quirks::DeviceRegistry,
+    secure::SessionParams,
+    trace::debug,
+    usb::{OpenedUsbDevice, UsbDevice},
+};
+use rusb::UsbContext;
+use std::{fmt, io, path::PathBuf};
+
+/// Group given access to the sensors by the udev rules, for the daemons (the users logged in
+/// get it through `uaccess`)
+pub const UDEV_GROUP: &str = "plugdev";
+
+/// A step of [`OpenedUsbDevice::initialize_device`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupStep {
+    /// Upload the firmware extension and reboot
+    Firmware,
+
+    /// Check the calibration data, replace it if broken
+    Calibration,
+
+    /// Pair the host with the sensor
+    Pairing,
+}
+
+/// Progress of [`OpenedUsbDevice::initialize_device`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupEvent {
+    /// The step is starting
+    Started(SetupStep),
+
+    /// The step was done
+    Finished(SetupStep),
+
+    /// Nothing to do for the step (the firmware was running, the calibration is fine, ...)
+    Skipped(SetupStep),
+}
+
+/// What [`OpenedUsbDevice::initialize_device`] should do
+#[derive(Debug, Clone, Default)]
+pub struct SetupOptions {
+    firmware: Option<Firmware>,
+    calibration: Option<Calibration>,
+    pairing: Option<PathBuf>,
+}
+
+impl SetupOptions {
+    /// Nothing to upload and no pairing, only the checks are done
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Upload this firmware if the sensor is in its bootloader
+    pub fn firmware(mut self, fw: Firmware) -> Self {
+        self.firmware = Some(fw);
+        self
+    }
+
+    /// Write this calibration if the one of the sensor is not valid
+    pub fn calibration(mut self, calib: Calibration) -> Self {
+        self.calibration = Some(calib);
+        self
+    }
+
+    /// Pair with the sensor and save the pairing data to this file
+    pub fn pairing(mut self, path: impl Into<PathBuf>) -> Self {
+        self.pairing = Some(path.into());
+        self
+    }
+}
+
+impl<C: UsbContext> OpenedUsbDevice<C> {
+    /// Provision the sensor as told by the options, `progress_cb` is called at every step. The
+    /// device is reconnected after the firmware upload (the sensor reboots), returns the pairing
+    /// data if it paired.
+    ///
+    /// A sensor in its bootloader without a firmware to upload, or with a broken calibration and
+    /// none to replace it, fails with [`DriverError::Bootloader`] or
+    /// [`DriverError::CalibrationInvalid`].
+    pub fn initialize_device<F>(
+        &mut self,
+        opts: &SetupOptions,
+        mut progress_cb: F,
+    ) -> Result<Option<SessionParams>, DriverError>
+    where
+        F: FnMut(SetupEvent),
+    {
+        let state = self.device_state()?;
+        debug!(?state, "initializing");
+
+        match (state, &opts.firmware) {
+            (DeviceState::Firmware, _) => progress_cb(SetupEvent::Skipped(SetupStep::Firmware)),
+            (DeviceState::Bootloader, None) => return Err(DriverError::Bootloader),
+            (DeviceState::Bootloader, Some(fw)) => {
+                progress_cb(SetupEvent::Started(SetupStep::Firmware));
+                self.upload_firmware(fw)?;
+                self.reconnect()?;
+                progress_cb(SetupEvent::Finished(SetupStep::Firmware));
+            }
+        }
+
+        match self.read_calibration() {
+            Ok(_) => progress_cb(SetupEvent::Skipped(SetupStep::Calibration)),
+            Err(DriverError::CalibrationInvalid) => {
+                let calib = opts
+                    .calibration
+                    .as_ref()
+                    .ok_or(DriverError::CalibrationInvalid)?;
+                progress_cb(SetupEvent::Started(SetupStep::Calibration));
+                self.write_calibration(calib)?;
+                progress_cb(SetupEvent::Finished(SetupStep::Calibration));
+            }
+            Err(e) => return Err(e),
+        }
+
+        let Some(path) = &opts.pairing else {
+            progress_cb(SetupEvent::Skipped(SetupStep::Pairing));
+            return Ok(None);
+        };
+        progress_cb(SetupEvent::Started(SetupStep::Pairing));
+        let params = self.pair(path)?;
+        progress_cb(SetupEvent::Finished(SetupStep::Pairing));
+
+        Ok(Some(params))
+    }
+}
+
+/// udev rules giving access to every registered sensor (see [`DeviceRegistry`]), to the user
+/// logged in on the seat and to the [`UDEV_GROUP`]. To be installed in
+/// `/etc/udev/rules.d/60-validity.rules`, the sensors have to be plugged again (or
+/// `udevadm trigger`) for them to apply.
+pub fn generate_udev_rules() -> String {
+    let mut res = String::from("# Validity fingerprint sensors\n");
+    let mut seen = Vec::new();
+
+    for quirks in DeviceRegistry::global().devices() {
+        let ids = (quirks.vid, quirks.pid);
+        if seen.contains(&ids) {
+            continue;
+        }
+        seen.push(ids);
+
+        res += &format!(
+            "SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", \
+             MODE=\"0660\", GROUP=\"{UDEV_GROUP}\", TAG+=\"uaccess\"\n",
+            ids.0, ids.1,
+        );
+    }
+    res
+}
+
+/// Whether the current user can open a device, see [`check_permissions`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Permission {
+    /// The device can be opened
+    Granted,
+
+    /// There is no device node (`/dev` not mounted, or in a container without it)
+    NoNode,
+
+    /// Only root can open it, there is no udev rule for the device (or the user is not logged in
+    /// on the seat, `uaccess` only applies to local sessions)
+    NoRule { mode: u32 },
+
+    /// Only the group can open it and the user is not in it. Once added, the user has to log in
+    /// again.
+    NotInGroup { gid: u32, name: Option<String> },
+
+    /// Something else denied it (SELinux, AppArmor, a read only `/dev`, ...)
+    Denied(io::ErrorKind),
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Granted => f.write_str("the device can be opened"),
+            Self::NoNode => f.write_str("the device node does not exist, is /dev mounted?"),
+            Self::NoRule { mode } => {
+                write!(
+                    f,
+                    "only root can open it (mode {mode:o}), no udev rule gives access"
+                )
+            }
+            Self::NotInGroup { gid, name } => {
+                let name = name.clone().unwrap_or_else(|| gid.to_string());
+                write!(
+                    f,
+                    "the user is not in the `{name}` group, add it and log in again"
+                )
+            }
+            Self::Denied(kind) => write!(f, "denied by something else: {kind}"),
+        }
+    }
+}
+
+/// The node libusb opens for the device, `/dev/bus/usb/BUS/ADDR`
+pub fn device_node<C: UsbContext>(dev: &UsbDevice<C>) -> PathBuf {
+    PathBuf::from(format!(
+        "/dev/bus/usb/{:03}/{:03}",
+        dev.0.bus_number(),
+        dev.0.address()
+    ))
+}
+
+/// Check whether the current user can open the device, and why not. The node is opened like
+/// libusb does, the device is not claimed.
+#[cfg(target_os = "linux")]
+pub fn check_permissions<C: UsbContext>(dev: &UsbDevice<C>) -> Permission {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = device_node(dev);
+    let err = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+    {
+        Ok(_) => return Permission::Granted,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Permission::NoNode,
+        Err(e) if e.kind() != io::ErrorKind::PermissionDenied => {
+            return Permission::Denied(e.kind());
+        }
+        Err(e) => e,
+    };
+
+    let Ok(meta) = std::fs::metadata(&path) else {
+        return Permission::Denied(err.kind());
+    };
+    let mode = meta.mode() & 0o777;
+    debug!(
+        ?path,
+        mode,
+        uid = meta.uid(),
+        gid = meta.gid(),
+        "permission denied"
+    );
+
+    let groups = groups().unwrap_or_default();
+    match (meta.gid(), mode & 0o060 == 0o060) {
+        (gid, true) if gid != 0 && !groups.contains(&gid) => Permission::NotInGroup {
+            gid,
+            name: group_name(gid),
+        },
+        (_, _) if mode & 0o006 != 0o006 => Permission::NoRule { mode },
+        _ => Permission::Denied(err.kind()),
+    }
+}
+
+/// The supplementary groups of the process, from `/proc/self/status`
+#[cfg(target_os = "linux")]
+fn groups() -> Option<Vec<u32>> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find_map(|l| l.strip_prefix("Groups:"))?;
+    Some(
+        line.split_whitespace()
+            .filter_map(|id| id.parse().ok())
+            .collect(),
+    )
+}
+
+/// The name of the group, from `/etc/group`
+#[cfg(target_os = "linux")]
+fn group_name(gid: u32) -> Option<String> {
+    let groups = std::fs::read_to_string("/etc/group").ok()?;
+    groups.lines().find_map(|l| {
+        let mut fields = l.split(':');
+        let name = fields.next()?;
+        (fields.nth(1)?.parse() == Ok(gid)).then(|| name.to_string())
+    })
+}
@@ -0,0 +1,338 @@
+//! The sensor has a single USB handle and can't interleave the exchanges of two commands. A
+//! [`SharedDevice`] keeps it behind a lock, so services can use it from several threads (or
+//! blocking tasks) and a command is always answered before the next one is sent.
+//!
+//! The users wait their turn in a queue, where [`SharedDevice::abort`] goes first: it cancels
+//! the operation running and the ones queued with [`SharedDevice::with_cancel`], so stopping an
+//! enrollment does not wait for the frames already asked for.
+
+use crate::{DriverError, cancel::CancelToken, usb::OpenedUsbDevice};
+use core::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// A device shared between threads, clones use the same device
+#[derive(Debug)]
+pub struct SharedDevice<T = OpenedUsbDevice> {
+    inner: Arc<Inner<T>>,
+}
+
+#[derive(Debug)]
+struct Inner<T> {
+    dev: Mutex<T>,
+    queue: Mutex<Queue>,
+
+    /// Signaled when the device is free
+    turn: Condvar,
+}
+
+/// Who is using the device and who is waiting for it
+#[derive(Debug, Default)]
+struct Queue {
+    busy: bool,
+
+    /// Aborts waiting, they go before everything else
+    aborts: usize,
+
+    /// Incremented by every abort, the operations queued before one are cancelled
+    generation: u64,
+
+    /// Token of the operation running, if it can be cancelled
+    running: Option<CancelToken>,
+}
+
+impl<T> SharedDevice<T> {
+    pub fn new(dev: T) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                dev: Mutex::new(dev),
+                queue: Mutex::default(),
+                turn: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Wait for the turn and lock the device until the guard is dropped, to run several
+    /// commands without other users sending theirs in between
+    pub fn lock(&self) -> DeviceGuard<'_, T> {
+        let queue = self.queue();
+        let mut queue = self.wait(queue, |q| q.busy || q.aborts > 0);
+        queue.busy = true;
+        drop(queue);
+        self.guard()
+    }
+
+    /// Run the operation with the device locked, other users wait until it is done
+    pub fn with<R, F>(&self, op: F) -> Result<R, DriverError>
+    where
+        F: FnOnce(&mut T) -> Result<R, DriverError>,
+    {
+        op(&mut self.lock())
+    }
+
+    /// Like [`Self::with`], but an [`Self::abort`] cancels the token given to the operation
+    /// (see [`CancelToken`]), or fails with [`DriverError::Cancelled`] if it was still queued
+    pub fn with_cancel<R, F>(&self, op: F) -> Result<R, DriverError>
+    where
+        F: FnOnce(&mut T, &CancelToken) -> Result<R, DriverError>,
+    {
+        let mut queue = self.queue();
+        let generation = queue.generation;
+        queue = self.wait(queue, |q| {
+            (q.busy || q.aborts > 0) && q.generation == generation
+        });
+        if queue.generation != generation {
+            return Err(DriverError::Cancelled);
+        }
+
+        let cancel = CancelToken::new();
+        queue.busy = true;
+        queue.running = Some(cancel.clone());
+        drop(queue);
+        op(&mut self.guard(), &cancel)
+    }
+
+    /// Cancel the operation running and the queued ones (see [`Self::with_cancel`]), then run
+    /// this one before any other, usually to stop the sensor
+    pub fn abort<R, F>(&self, op: F) -> Result<R, DriverError>
+    where
+        F: FnOnce(&mut T) -> Result<R, DriverError>,
+    {
+        let mut queue = self.queue();
+        queue.aborts += 1;
+        queue.generation += 1;
+        if let Some(cancel) = queue.running.take() {
+            cancel.cancel();
+        }
+        // The cancelled ones leave the queue
+        self.inner.turn.notify_all();
+
+        queue = self.wait(queue, |q| q.busy);
+        queue.aborts -= 1;
+        queue.busy = true;
+        drop(queue);
+        op(&mut self.guard())
+    }
+
+    /// Replace the device (once reconnected or another one selected), returns the old one
+    pub fn replace(&self, dev: T) -> T {
+        std::mem::replace(&mut self.lock(), dev)
+    }
+
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.inner
+            .queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wait<'q>(
+        &self,
+        queue: MutexGuard<'q, Queue>,
+        cond: impl FnMut(&mut Queue) -> bool,
+    ) -> MutexGuard<'q, Queue> {
+        self.inner
+            .turn
+            .wait_while(queue, cond)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the device, once it is our turn
+    fn guard(&self) -> DeviceGuard<'_, T> {
+        DeviceGuard {
+            // A panic in another user does not leave the device in a worse state than an error
+            dev: self
+                .inner
+                .dev
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+            shared: self,
+        }
+    }
+}
+
+// Not derived, it would require `T: Clone`
+impl<T> Clone for SharedDevice<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Default> Default for SharedDevice<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for SharedDevice<T> {
+    fn from(dev: T) -> Self {
+        Self::new(dev)
+    }
+}
+
+/// The device locked by [`SharedDevice::lock`], the next user gets its turn once dropped
+#[derive(Debug)]
+pub struct DeviceGuard<'a, T> {
+    dev: MutexGuard<'a, T>,
+    shared: &'a SharedDevice<T>,
+}
+
+impl<T> Deref for DeviceGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.dev
+    }
+}
+
+impl<T> DerefMut for DeviceGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.dev
+    }
+}
+
+impl<T> Drop for DeviceGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue();
+        queue.busy = false;
+        queue.running = None;
+        self.shared.inner.turn.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn one_at_a_time() {
+        let dev = SharedDevice::new(0u32);
+        let inside = Arc::new(AtomicBool::new(false));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let dev = dev.clone();
+                let inside = inside.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        dev.with(|count| {
+                            assert!(!inside.swap(true, Ordering::SeqCst));
+                            thread::sleep(Duration::from_micros(100));
+                            *count += 1;
+                            inside.store(false, Ordering::SeqCst);
+                            Ok(())
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(dev.with(|count| Ok(*count)).unwrap(), 80);
+    }
+
+    #[test]
+    fn panic_frees_the_device() {
+        let dev = SharedDevice::new(0u32);
+
+        let other = dev.clone();
+        let res =
+            thread::spawn(move || other.with(|_| -> Result<(), DriverError> { panic!() })).join();
+        assert!(res.is_err());
+
+        assert_eq!(
+            dev.with(|count| {
+                *count += 1;
+                Ok(*count)
+            })
+            .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn replace() {
+        let dev = SharedDevice::new(1u32);
+        assert_eq!(dev.replace(2), 1);
+        assert_eq!(dev.with(|v| Ok(*v)).unwrap(), 2);
+    }
+
+    /// Run an operation on another thread which waits until it is cancelled, once it started
+    fn run_until_cancelled(dev: &SharedDevice<u32>) -> thread::JoinHandle<Result<(), DriverError>> {
+        let (started, wait) = std::sync::mpsc::channel();
+        let dev = dev.clone();
+        let t = thread::spawn(move || {
+            dev.with_cancel(|_, cancel| {
+                started.send(()).unwrap();
+                while !cancel.is_cancelled() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(DriverError::Cancelled)
+            })
+        });
+        wait.recv().unwrap();
+        t
+    }
+
+    #[test]
+    fn abort_running() {
+        let dev = SharedDevice::new(0u32);
+        let running = run_until_cancelled(&dev);
+
+        dev.abort(|v| {
+            *v = 1;
+            Ok(())
+        })
+        .unwrap();
+        assert!(matches!(
+            running.join().unwrap(),
+            Err(DriverError::Cancelled)
+        ));
+        assert_eq!(dev.with(|v| Ok(*v)).unwrap(), 1);
+    }
+
+    #[test]
+    fn abort_queued() {
+        let dev = SharedDevice::new(0u32);
+        let running = run_until_cancelled(&dev);
+
+        let other = dev.clone();
+        let queued = thread::spawn(move || {
+            other.with_cancel(|v, _| {
+                *v = 2;
+                Ok(())
+            })
+        });
+        // Give it time to queue
+        thread::sleep(Duration::from_millis(50));
+
+        dev.abort(|v| {
+            *v = 1;
+            Ok(())
+        })
+        .unwrap();
+        assert!(running.join().unwrap().is_err());
+        assert!(matches!(
+            queued.join().unwrap(),
+            Err(DriverError::Cancelled)
+        ));
+        assert_eq!(dev.with(|v| Ok(*v)).unwrap(), 1);
+
+        // The ones queued afterwards run
+        dev.with_cancel(|v, _| {
+            *v = 3;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(dev.with(|v| Ok(*v)).unwrap(), 3);
+    }
+}
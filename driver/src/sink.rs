@@ -0,0 +1,42 @@
+//! Where [`Capture::stream_into`](crate::capture::Capture::stream_into) pushes the frames, for a
+//! live preview: the sink must return quickly, the next frame is read once it does. GUIs can use
+//! a [`ChannelSink`] and render the frames in their own thread.
+
+use crate::capture::Frame;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// Receives the frames of a streaming capture
+pub trait FrameSink {
+    /// Take the next frame, returns `false` once no more frames are wanted (the capture stops)
+    fn push(&mut self, frame: &Frame) -> bool;
+}
+
+impl<F: FnMut(&Frame) -> bool> FrameSink for F {
+    fn push(&mut self, frame: &Frame) -> bool {
+        self(frame)
+    }
+}
+
+/// Sends the frames to a channel without blocking: when the receiver is behind the frames are
+/// dropped, a preview only needs the last ones. The capture stops once the receiver is dropped.
+#[derive(Debug, Clone)]
+pub struct ChannelSink {
+    tx: SyncSender<Frame>,
+}
+
+impl ChannelSink {
+    /// A sink and the receiver of its frames, at most `capacity` frames wait in the channel
+    pub fn new(capacity: usize) -> (Self, Receiver<Frame>) {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        (Self { tx }, rx)
+    }
+}
+
+impl FrameSink for ChannelSink {
+    fn push(&mut self, frame: &Frame) -> bool {
+        match self.tx.try_send(frame.clone()) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
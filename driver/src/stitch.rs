@@ -0,0 +1,175 @@
+//! Swipe sensors (see [`SensorType::Swipe`](crate::quirks::SensorType)) only see a few lines of
+//! the finger at a time, every frame overlaps the previous one by as many lines as the finger
+//! moved less than the frame height. The [`Stitcher`] finds that overlap by comparing the first
+//! lines of the new frame with the last lines of the image built up to now.
+
+use crate::{DriverError, capture::Frame};
+
+/// Mean difference per pixel (relative to the maximum value) above which the frames are
+/// considered not to overlap at all (the finger moved faster than a frame)
+const MAX_DIFF: f32 = 0.1;
+
+/// Builds a full image from consecutive line frames, see the [module docs](self)
+#[derive(Debug, Clone, Default)]
+pub struct Stitcher {
+    width: u16,
+    bpp: u8,
+
+    /// Lines added up to now
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl Stitcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether no frame was added yet
+    pub fn is_empty(&self) -> bool {
+        self.height == 0
+    }
+
+    /// Add the next frame, every frame must have the same width and bits per pixel as the first
+    /// one. Returns the amount of new lines.
+    pub fn push(&mut self, frame: &Frame) -> Result<usize, DriverError> {
+        let row_len = row_len(frame.width, frame.bpp);
+        if !matches!(frame.bpp, 8 | 16) || frame.data.len() != row_len * frame.height as usize {
+            return Err(DriverError::StitchInvalidFrame);
+        }
+
+        if self.is_empty() {
+            self.width = frame.width;
+            self.bpp = frame.bpp;
+        } else if (frame.width, frame.bpp) != (self.width, self.bpp) {
+            return Err(DriverError::StitchInvalidFrame);
+        }
+
+        let overlap = self.overlap(frame);
+        self.data.extend(&frame.data[overlap * row_len..]);
+
+        let added = frame.height as usize - overlap;
+        self.height += added;
+        Ok(added)
+    }
+
+    /// The image built up to now
+    pub fn finish(self) -> Frame {
+        Frame {
+            width: self.width,
+            height: self.height as u16,
+            bpp: self.bpp,
+            data: self.data,
+        }
+    }
+
+    /// How many of the first lines of the frame are already at the end of the image
+    fn overlap(&self, frame: &Frame) -> usize {
+        let row_len = row_len(self.width, self.bpp);
+        let max = self.height.min(frame.height as usize);
+
+        let best = (1..=max)
+            .map(|lines| {
+                let tail = &self.data[self.data.len() - lines * row_len..];
+                let head = &frame.data[..lines * row_len];
+                (lines, self.diff(tail, head))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+
+        match best {
+            Some((lines, diff)) if diff <= MAX_DIFF => lines,
+            _ => 0,
+        }
+    }
+
+    /// Mean difference per pixel, relative to the maximum value
+    fn diff(&self, a: &[u8], b: &[u8]) -> f32 {
+        let (sum, count, max) = match self.bpp {
+            16 => {
+                let px = |c: &[u8]| u16::from_le_bytes([c[0], c[1]]) as i32;
+                let sum: u64 = a
+                    .chunks_exact(2)
+                    .zip(b.chunks_exact(2))
+                    .map(|(a, b)| px(a).abs_diff(px(b)) as u64)
+                    .sum();
+                (sum, a.len() / 2, u16::MAX as f32)
+            }
+            _ => {
+                let sum: u64 = a.iter().zip(b).map(|(a, b)| a.abs_diff(*b) as u64).sum();
+                (sum, a.len(), u8::MAX as f32)
+            }
+        };
+
+        sum as f32 / count.max(1) as f32 / max
+    }
+}
+
+/// Size of a line, in bytes
+fn row_len(width: u16, bpp: u8) -> usize {
+    (width as usize * bpp as usize).div_ceil(8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A finger of 16 lines of 4 pixels, each line different
+    fn finger() -> Vec<u8> {
+        (0..16u8)
+            .flat_map(|y| (0..4u8).map(move |x| y * 15 + x * 3))
+            .collect()
+    }
+
+    /// Lines `from..to` of the finger
+    fn lines(from: usize, to: usize) -> Frame {
+        Frame {
+            width: 4,
+            height: (to - from) as u16,
+            bpp: 8,
+            data: finger()[from * 4..to * 4].to_vec(),
+        }
+    }
+
+    #[test]
+    fn overlapping() {
+        let mut stitcher = Stitcher::new();
+        assert_eq!(stitcher.push(&lines(0, 6)).unwrap(), 6);
+        assert_eq!(stitcher.push(&lines(4, 10)).unwrap(), 4);
+        assert_eq!(stitcher.push(&lines(8, 14)).unwrap(), 4);
+        assert_eq!(stitcher.push(&lines(10, 16)).unwrap(), 2);
+
+        let image = stitcher.finish();
+        assert_eq!((image.width, image.height, image.bpp), (4, 16, 8));
+        assert_eq!(image.data, finger());
+    }
+
+    #[test]
+    fn not_overlapping() {
+        let mut stitcher = Stitcher::new();
+        stitcher.push(&lines(0, 4)).unwrap();
+        // Moved faster than a frame
+        assert_eq!(stitcher.push(&lines(10, 14)).unwrap(), 4);
+        assert_eq!(stitcher.finish().height, 8);
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut stitcher = Stitcher::new();
+        let mut short = lines(0, 4);
+        short.data.pop();
+        assert!(matches!(
+            stitcher.push(&short),
+            Err(DriverError::StitchInvalidFrame)
+        ));
+
+        stitcher.push(&lines(0, 4)).unwrap();
+        let wider = Frame {
+            width: 2,
+            ..lines(0, 2)
+        };
+        assert!(matches!(
+            stitcher.push(&wider),
+            Err(DriverError::StitchInvalidFrame)
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{
+    DriverError,
+    enroll::{Finger, TemplateId},
+    metadata::{MetadataStore, TemplateMetadata},
+    proto::{Command, Response, StatusCode},
+    trace::debug,
+    transport::Transport,
+};
+
+/// Set in the flags of the records enrolled by the Windows driver, see [`TemplateOrigin`]
+const RECORD_WINDOWS: u8 = 0x01;
+
+/// A template stored on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TemplateInfo {
+    pub id: TemplateId,
+
+    /// The finger id given at enrollment, see [`crate::enroll::Enroll::enroll`]
+    pub finger_id: u8,
+
+    /// The namespace of the user owning the template, see [`UserNamespace`]
+    pub user: u16,
+
+    pub origin: TemplateOrigin,
+}
+
+/// Who enrolled a template. On dual boot machines the Windows driver (WinBio) stores its
+/// templates next to ours, they take slots and can't be used here.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TemplateOrigin {
+    /// This driver (or any other one talking to the sensor from the host)
+    #[default]
+    Host,
+
+    /// The Windows driver, deleting the template breaks the login on Windows
+    Windows,
+}
+
+impl TemplateInfo {
+    /// The finger given at enrollment, if it is a known one
+    pub fn finger(&self) -> Option<Finger> {
+        Finger::from_id(self.finger_id)
+    }
+
+    pub fn namespace(&self) -> UserNamespace {
+        UserNamespace(self.user)
+    }
+}
+
+/// The user a template is stored under on the device, so the matches can be restricted to the
+/// templates of one user (see [`crate::identify::Identify::identify_in`]) and a finger never
+/// matches the template of somebody else. Each user gets their own from the [`MetadataStore`]
+/// (see [`MetadataStore::allocate_namespace`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UserNamespace(pub u16);
+
+impl UserNamespace {
+    /// The templates enrolled without a user, see [`crate::enroll::Enroll::enroll`]
+    pub const SHARED: Self = Self(0);
+}
+
+/// Management of the templates stored on the device, implemented for every [`Transport`]
+pub trait Storage: Transport {
+    /// List the templates stored on the device
+    fn list_templates(&self) -> Result<Vec<TemplateInfo>, DriverError> {
+        let mut buf = vec![0u8; 1024 * 4];
+        let resp = self.run(&Command::ListRecords, &mut buf)?;
+        parse_records(resp).ok_or(DriverError::StorageInvalidResponse)
+    }
+
+    /// List the templates stored in the namespace
+    fn list_templates_in(
+        &self,
+        namespace: UserNamespace,
+    ) -> Result<Vec<TemplateInfo>, DriverError> {
+        let mut res = self.list_templates()?;
+        res.retain(|t| t.namespace() == namespace);
+        Ok(res)
+    }
+
+    /// List the templates enrolled from Windows, see [`TemplateOrigin::Windows`]
+    fn list_windows_templates(&self) -> Result<Vec<TemplateInfo>, DriverError> {
+        let mut res = self.list_templates()?;
+        res.retain(|t| t.origin == TemplateOrigin::Windows);
+        Ok(res)
+    }
+
+    /// Get the information of a single template, if it exists
+    fn template_info(&self, id: TemplateId) -> Result<Option<TemplateInfo>, DriverError> {
+        Ok(self.list_templates()?.into_iter().find(|t| t.id == id))
+    }
+
+    /// Delete the given template from the device
+    fn delete_template(&self, id: TemplateId) -> Result<(), DriverError> {
+        self.run(&Command::DeleteRecord(id.0), &mut [0u8; 64])?;
+        Ok(())
+    }
+
+    /// Delete a template enrolled from Windows, fails without deleting anything if it was
+    /// enrolled from the host
+    fn delete_windows_template(&self, id: TemplateId) -> Result<(), DriverError> {
+        match self.template_info(id)? {
+            Some(t) if t.origin != TemplateOrigin::Windows => {
+                Err(DriverError::StorageNotWindows(id.0))
+            }
+            _ => self.delete_template(id),
+        }
+    }
+
+    /// Make room for a new template by deleting one enrolled from Windows (the last one), returns
+    /// it or `None` if there was none
+    fn reclaim_windows_slot(&self) -> Result<Option<TemplateInfo>, DriverError> {
+        let Some(tmpl) = self.list_windows_templates()?.pop() else {
+            return Ok(None);
+        };
+        debug!(id = tmpl.id.0, "deleting a template enrolled from Windows");
+        self.delete_template(tmpl.id)?;
+        Ok(Some(tmpl))
+    }
+
+    /// List the templates stored on the device with their finger and the user they were enrolled
+    /// for, see [`crate::enroll::Enroll::enroll_for`]
+    fn list_metadata(&self, store: &MetadataStore) -> Result<Vec<TemplateMetadata>, DriverError> {
+        Ok(self
+            .list_templates()?
+            .into_iter()
+            .map(|t| TemplateMetadata {
+                id: t.id,
+                finger: t.finger(),
+                user: store.owner(t.id),
+            })
+            .collect())
+    }
+
+    /// Delete every template stored on the device
+    fn delete_all_templates(&self) -> Result<(), DriverError> {
+        for tmpl in self.list_templates()? {
+            self.delete_template(tmpl.id)?;
+        }
+        Ok(())
+    }
+
+    /// Delete every template stored in the namespace (when the user is removed)
+    fn delete_namespace(&self, namespace: UserNamespace) -> Result<(), DriverError> {
+        for tmpl in self.list_templates_in(namespace)? {
+            self.delete_template(tmpl.id)?;
+        }
+        Ok(())
+    }
+
+    /// Wipe the storage of the templates (before handing the machine to somebody else) and
+    /// check nothing is left. Firmwares without the wipe command delete the templates one by one
+    /// instead, see [`Self::delete_all_templates`].
+    fn erase_all_templates(&self) -> Result<(), DriverError> {
+        match self.run(&Command::WipeRecords, &mut [0u8; 64]) {
+            Ok(_) => {}
+            Err(e) if matches!(e.root(), DriverError::UsbInitFailed(StatusCode::Unknown(_))) => {
+                debug!(error = %e, "wipe not supported, deleting the templates");
+                self.delete_all_templates()?;
+            }
+            Err(e) => return Err(e),
+        }
+
+        match self.list_templates()?.len() {
+            0 => Ok(()),
+            left => Err(DriverError::StorageNotEmpty(left)),
+        }
+    }
+}
+
+impl<T: Transport + ?Sized> Storage for T {}
+
+/// The response to [`Command::ListRecords`] has the format: count (u16) and the entries, each
+/// one with: id (u16), user (u16), finger id (u8) and flags (u8, see [`RECORD_WINDOWS`])
+fn parse_records(mut resp: Response<'_>) -> Option<Vec<TemplateInfo>> {
+    let count = resp.u16()?;
+
+    (0..count)
+        .map(|_| {
+            let id = TemplateId(resp.u16()?);
+            let user = resp.u16()?;
+            let finger_id = resp.u8()?;
+            let origin = match resp.u8()? & RECORD_WINDOWS {
+                0 => TemplateOrigin::Host,
+                _ => TemplateOrigin::Windows,
+            };
+            Some(TemplateInfo {
+                id,
+                finger_id,
+                user,
+                origin,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,138 @@
+//! Init commands are answered in about a millisecond, but erasing the flash or merging an
+//! enrollment sample can take seconds. Commands are classified by their opcode and each class
+//! gets its own timeout, transfers failing with a timeout, a stall or a busy device are retried.
+
+use crate::proto::opcodes::Opcode;
+use core::time::Duration;
+use std::time::Instant;
+
+/// Kind of command, decides which timeout is used, see [`Timeouts`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandClass {
+    /// Init, info and most other commands
+    Fast,
+
+    /// Arming the sensor, reading frames and matching
+    Capture,
+
+    /// Flash erase/read/write, firmware upload, reboot and factory reset
+    Flash,
+
+    /// Enrollment steps
+    Enroll,
+}
+
+impl CommandClass {
+    /// Classify a command by its first byte (the opcode)
+    pub fn of(cmd: &[u8]) -> Self {
+        match cmd.first().copied().and_then(Opcode::from_u8) {
+            Some(
+                Opcode::Reboot
+                | Opcode::FactoryReset
+                | Opcode::EraseFlash
+                | Opcode::ReadFlash
+                | Opcode::WriteFlash
+                | Opcode::WriteSignature,
+            ) => Self::Flash,
+            Some(
+                Opcode::NewFinger
+                | Opcode::EnrollUpdateStart
+                | Opcode::Enroll
+                | Opcode::EnrollUpdate,
+            ) => Self::Enroll,
+            Some(
+                Opcode::CaptureStart | Opcode::ReadFrame | Opcode::Match | Opcode::MatchResult,
+            ) => Self::Capture,
+            _ => Self::Fast,
+        }
+    }
+}
+
+/// The time left before the deadline, `None` once it is under a millisecond: rusb sends a
+/// shorter timeout as 0, which libusb takes as waiting forever
+pub(crate) fn time_left(deadline: Instant) -> Option<Duration> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    (left >= Duration::from_millis(1)).then_some(left)
+}
+
+/// Timeout of the USB transfers, per [`CommandClass`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub fast: Duration,
+    pub capture: Duration,
+    pub flash: Duration,
+    pub enroll: Duration,
+
+    /// How long to wait for the user to touch the sensor
+    pub touch: Duration,
+}
+
+impl Timeouts {
+    pub fn get(&self, class: CommandClass) -> Duration {
+        match class {
+            CommandClass::Fast => self.fast,
+            CommandClass::Capture => self.capture,
+            CommandClass::Flash => self.flash,
+            CommandClass::Enroll => self.enroll,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            fast: Duration::from_secs(1),
+            capture: Duration::from_secs(5),
+            flash: Duration::from_secs(15),
+            enroll: Duration::from_secs(10),
+            touch: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How to retry the bulk transfers failing with a transient error, see [`Self::is_retriable`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, `0` disables them
+    pub max_retries: u32,
+
+    /// Wait before the first retry
+    pub backoff: Duration,
+
+    /// The wait is multiplied by this after every retry
+    pub backoff_factor: u32,
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub const NONE: Self = Self {
+        max_retries: 0,
+        backoff: Duration::ZERO,
+        backoff_factor: 1,
+    };
+
+    /// How long to wait before the given retry (starting at 0)
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(self.backoff_factor.saturating_pow(retry))
+    }
+
+    /// Whether the error is worth retrying: a timeout, a stall (once the endpoints are cleared)
+    /// or the device being busy, all seen on flaky hubs
+    pub fn is_retriable(err: &rusb::Error) -> bool {
+        matches!(
+            err,
+            rusb::Error::Timeout | rusb::Error::Pipe | rusb::Error::Busy
+        )
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            backoff: Duration::from_millis(50),
+            backoff_factor: 2,
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Logging of the USB transfers and of the protocol steps with `tracing`, only with the `trace`
+//! feature: without it the macros below expand to nothing. The transfers are hexdumped at the
+//! `TRACE` level, commands and init steps are logged at `DEBUG`.
+
+/// `tracing::trace!`, if enabled
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "trace")]
+        tracing::trace!($($arg)*);
+    };
+}
+
+/// `tracing::debug!`, if enabled
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "trace")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+/// `tracing::warn!`, if enabled
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "trace")]
+        tracing::warn!($($arg)*);
+    };
+}
+
+pub(crate) use {debug, trace, warning};
+
+/// Formats the bytes as hex, without separators
+#[cfg(any(feature = "trace", feature = "record"))]
+pub(crate) struct Hex<'a>(pub &'a [u8]);
+
+#[cfg(any(feature = "trace", feature = "record"))]
+impl core::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
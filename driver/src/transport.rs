@@ -0,0 +1,371 @@
+//! Everything the protocol needs from the device: writing commands, reading the responses and
+//! the interrupts. Implemented by [`OpenedUsbDevice`](crate::usb::OpenedUsbDevice) and by
+//! [`MockTransport`], which replays canned responses so the protocol code can run without a
+//! sensor.
+
+use crate::{
+    DriverError,
+    calibration::DeadPixelMap,
+    firmware::DeviceState,
+    pool::BufferPool,
+    proto::{Command, MalformedResponse, Response},
+    quirks::DeviceQuirks,
+    timeouts::Timeouts,
+    trace::debug,
+};
+use core::time::Duration;
+use std::{cell::RefCell, collections::VecDeque};
+
+pub(crate) use crate::proto::opcodes::{INT_FINGER_DOWN, INT_FINGER_UP, INT_SCAN_COMPLETE};
+
+/// Size of each bulk read of [`Transport::cmd_vec`]
+const READ_CHUNK: usize = 1024 * 16;
+
+/// Check the response has the fields of the command, see [`Command::check_response`]
+pub(crate) fn check_response<'b>(
+    cmd: &Command<'_>,
+    resp: Response<'b>,
+) -> Result<Response<'b>, DriverError> {
+    cmd.check_response(resp.rest()).map_err(|e| match e {
+        MalformedResponse::Short { got, expected } => DriverError::MalformedResponse {
+            cmd: cmd.opcode(),
+            got,
+            expected,
+        },
+        MalformedResponse::OutOfRange { field, value } => DriverError::ResponseOutOfRange {
+            cmd: cmd.opcode(),
+            field,
+            value,
+        },
+    })?;
+    Ok(resp)
+}
+
+pub trait Transport {
+    /// The quirks of the device behind this transport
+    fn quirks(&self) -> &'static DeviceQuirks;
+
+    /// Write a command, returns the amount of bytes written
+    fn write(&self, data: &[u8]) -> Result<usize, DriverError>;
+
+    /// Read (part of) a response, returns the amount of bytes read
+    fn read(&self, out: &mut [u8]) -> Result<usize, DriverError>;
+
+    /// Wait for an event in the interrupt endpoint, returns the amount of bytes read
+    fn wait_int(&self, out: &mut [u8], timeout: Duration) -> Result<usize, DriverError>;
+
+    /// Reset the device
+    fn reset(&mut self) -> Result<(), DriverError>;
+
+    /// Serial number of the device, if the transport knows it
+    fn serial_number(&self) -> Option<String> {
+        None
+    }
+
+    /// How long to wait for the user to touch the sensor
+    fn touch_timeout(&self) -> Duration {
+        Timeouts::default().touch
+    }
+
+    /// The dead pixels repaired in every frame read, if known (see
+    /// [`crate::usb::OpenOptions::repair_dead_pixels`])
+    fn dead_pixels(&self) -> Option<&DeadPixelMap> {
+        None
+    }
+
+    /// Send a command to the device and wait for a reply (usually 1ms)
+    fn cmd(&self, data: &[u8], out: &mut [u8]) -> Result<usize, DriverError> {
+        let wrlen = self.write(data)?;
+
+        if data.len() != wrlen {
+            return Err(DriverError::UsbWritePartial);
+        }
+
+        self.read(out)
+    }
+
+    /// Like [`Self::cmd`], but keeps reading until the whole response arrived, for the ones too
+    /// big for a single bulk read (flash dumps, frames). The device ends a transfer with a read
+    /// shorter than the buffer (or an empty one).
+    fn cmd_vec(&self, data: &[u8]) -> Result<Vec<u8>, DriverError> {
+        let mut buf = BufferPool::global().get(READ_CHUNK);
+        let mut len = self.cmd(data, &mut buf)?;
+        let mut res = buf[..len].to_vec();
+
+        while len == buf.len() {
+            len = match self.read(&mut buf) {
+                Ok(len) => len,
+                // Some devices don't send the empty read when the response fills the buffer
+                Err(DriverError::UsbReadResponse(rusb::Error::Timeout)) => break,
+                Err(e) => return Err(e),
+            };
+            res.extend(&buf[..len]);
+        }
+
+        Ok(res)
+    }
+
+    /// Send the init messages and check the answer, fails with [`DriverError::Bootloader`] if
+    /// the firmware did not boot
+    #[cfg_attr(feature = "trace", tracing::instrument(level = "debug", skip_all))]
+    fn send_init(&self) -> Result<(), DriverError> {
+        let mut buf = [0u8; 1024];
+        for cmd in self.quirks().init_sequence {
+            debug!(?cmd, "init step");
+            self.run(cmd, &mut buf)
+                .map_err(|e| match DeviceState::from_error(&e) {
+                    Some(DeviceState::Bootloader) => DriverError::Bootloader,
+                    _ => e,
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Run the command and check the status code, the response is read into `buf`
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(level = "debug", skip_all, fields(opcode = cmd.opcode()))
+    )]
+    fn run<'b>(&self, cmd: &Command<'_>, buf: &'b mut [u8]) -> Result<Response<'b>, DriverError> {
+        let bytes = cmd.to_bytes();
+        let len = self
+            .cmd(&bytes, buf)
+            .map_err(|e| e.in_command(&bytes, &[]))?;
+        let buf = &buf[..len];
+        Response::parse(buf)
+            .map_err(DriverError::from)
+            .and_then(|resp| check_response(cmd, resp))
+            .map_err(|e| e.in_command(&bytes, buf))
+            .inspect_err(|_e| {
+                debug!(error = %_e, "command failed");
+            })
+    }
+
+    /// Like [`Self::run`], but reads the whole response with [`Self::cmd_vec`], it is returned
+    /// with the status (parse it again with [`Response::parse`])
+    fn run_vec(&self, cmd: &Command<'_>) -> Result<Vec<u8>, DriverError> {
+        let bytes = cmd.to_bytes();
+        let resp = self
+            .cmd_vec(&bytes)
+            .map_err(|e| e.in_command(&bytes, &[]))?;
+        Response::parse(&resp)
+            .map_err(DriverError::from)
+            .and_then(|parsed| check_response(cmd, parsed))
+            .map_err(|e| e.in_command(&bytes, &resp))?;
+        Ok(resp)
+    }
+
+    /// Run the command and check the status code (the first two bytes of the response)
+    fn run_and_check(&self, cmd: &[u8], resp: &mut [u8]) -> Result<usize, DriverError> {
+        let res = self.cmd(cmd, resp).map_err(|e| e.in_command(cmd, &[]))?;
+        Response::parse(&resp[..res])
+            .map_err(|e| DriverError::from(e).in_command(cmd, &resp[..res]))?;
+        Ok(res)
+    }
+
+    /// Wait until the sensor says the finger was scanned
+    fn wait_scan(&self) -> Result<(), DriverError> {
+        let mut int = [0u8; 64];
+        loop {
+            let len = self.wait_int(&mut int, self.touch_timeout())?;
+            if len > 0 && int[0] == INT_SCAN_COMPLETE {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// An expected command and its responses
+type Exchange = (Vec<u8>, Vec<Vec<u8>>);
+
+/// A fake device which expects the given commands (in order) and answers with the given
+/// responses. Interrupts are returned in order too, once there are no more the reads time out.
+#[derive(Debug)]
+pub struct MockTransport {
+    quirks: &'static DeviceQuirks,
+
+    exchanges: RefCell<VecDeque<Exchange>>,
+    interrupts: RefCell<VecDeque<Vec<u8>>>,
+
+    /// Responses of the written commands, not read yet
+    pending: RefCell<VecDeque<Vec<u8>>>,
+
+    touch_timeout: Duration,
+}
+
+impl MockTransport {
+    /// A mock of the device with the given quirks, see [`crate::SUPPORTED`]
+    pub fn new(quirks: &'static DeviceQuirks) -> Self {
+        Self {
+            quirks,
+            exchanges: RefCell::default(),
+            interrupts: RefCell::default(),
+            pending: RefCell::default(),
+            touch_timeout: Timeouts::default().touch,
+        }
+    }
+
+    /// Wait this long for a finger, see [`Transport::touch_timeout`]
+    pub fn with_touch_timeout(mut self, timeout: Duration) -> Self {
+        self.touch_timeout = timeout;
+        self
+    }
+
+    /// Expect the command and answer with the response
+    pub fn expect(self, cmd: &[u8], resp: &[u8]) -> Self {
+        self.exchanges
+            .borrow_mut()
+            .push_back((cmd.to_vec(), vec![resp.to_vec()]));
+        self
+    }
+
+    /// Expect the command, its responses are added with [`Self::then`]
+    #[cfg(feature = "record")]
+    pub(crate) fn expect_only(self, cmd: &[u8]) -> Self {
+        self.exchanges
+            .borrow_mut()
+            .push_back((cmd.to_vec(), vec![]));
+        self
+    }
+
+    /// Add another response to the last expected command (the rest of a long response)
+    pub fn then(self, resp: &[u8]) -> Self {
+        if let Some((_, resps)) = self.exchanges.borrow_mut().back_mut() {
+            resps.push(resp.to_vec());
+        }
+        self
+    }
+
+    /// Add an event to the interrupt endpoint
+    pub fn interrupt(self, data: &[u8]) -> Self {
+        self.interrupts.borrow_mut().push_back(data.to_vec());
+        self
+    }
+
+    /// Whether every expected command was sent and every response was read
+    pub fn is_done(&self) -> bool {
+        self.exchanges.borrow().is_empty() && self.pending.borrow().is_empty()
+    }
+}
+
+impl Transport for MockTransport {
+    fn quirks(&self) -> &'static DeviceQuirks {
+        self.quirks
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, DriverError> {
+        let (cmd, resps) = self
+            .exchanges
+            .borrow_mut()
+            .pop_front()
+            .ok_or_else(|| DriverError::MockUnexpectedCommand(data.to_vec()))?;
+
+        if cmd != data {
+            return Err(DriverError::MockUnexpectedCommand(data.to_vec()));
+        }
+
+        self.pending.borrow_mut().extend(resps);
+        Ok(data.len())
+    }
+
+    fn read(&self, out: &mut [u8]) -> Result<usize, DriverError> {
+        let resp = self
+            .pending
+            .borrow_mut()
+            .pop_front()
+            .ok_or(DriverError::UsbReadResponse(rusb::Error::Timeout))?;
+
+        let len = resp.len().min(out.len());
+        out[..len].copy_from_slice(&resp[..len]);
+        Ok(len)
+    }
+
+    fn wait_int(&self, out: &mut [u8], _timeout: Duration) -> Result<usize, DriverError> {
+        let int = self
+            .interrupts
+            .borrow_mut()
+            .pop_front()
+            .ok_or(DriverError::UsbReadInterrupt(rusb::Error::Timeout))?;
+
+        let len = int.len().min(out.len());
+        out[..len].copy_from_slice(&int[..len]);
+        Ok(len)
+    }
+
+    fn reset(&mut self) -> Result<(), DriverError> {
+        Ok(())
+    }
+
+    fn touch_timeout(&self) -> Duration {
+        self.touch_timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::StatusCode;
+
+    fn mock() -> MockTransport {
+        MockTransport::new(&crate::SUPPORTED[0])
+    }
+
+    #[test]
+    fn run_checks_the_response() {
+        let dev = mock().expect(&[0x01], &[0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+        let mut buf = [0u8; 64];
+        let mut resp = dev.run(&Command::GetVersion, &mut buf).unwrap();
+        assert_eq!(resp.u32(), Some(0x0403_0201));
+        assert!(dev.is_done());
+    }
+
+    #[test]
+    fn run_short_response() {
+        let dev = mock().expect(&[0x01], &[0, 0, 1, 2, 3]);
+        let err = dev.run(&Command::GetVersion, &mut [0u8; 64]).unwrap_err();
+        assert!(matches!(
+            err.root(),
+            DriverError::MalformedResponse {
+                cmd: 0x01,
+                got: 3,
+                expected: 11
+            }
+        ));
+        assert_eq!(err.raw_response(), Some(&[0, 0, 1, 2, 3][..]));
+    }
+
+    #[test]
+    fn run_out_of_range() {
+        let dev = mock().expect(&[0x60, 0, 0, 0, 0], &[0, 0, 2, 1, 5, 0, 0, 0]);
+        let err = dev.run(&Command::MatchResult, &mut [0u8; 64]).unwrap_err();
+        assert!(matches!(
+            err.root(),
+            DriverError::ResponseOutOfRange {
+                cmd: 0x60,
+                field: "matched",
+                value: 2
+            }
+        ));
+    }
+
+    #[test]
+    fn run_failed_status() {
+        let dev = mock().expect(&[0x48, 0x05, 0x00], &[0xb3, 0x04]);
+        let err = dev
+            .run(&Command::DeleteRecord(5), &mut [0u8; 64])
+            .unwrap_err();
+        assert!(matches!(
+            err.root(),
+            DriverError::UsbInitFailed(StatusCode::NotFound)
+        ));
+    }
+
+    #[test]
+    fn unexpected_command() {
+        let dev = mock().expect(&[0x01], &[0, 0]);
+        assert!(matches!(
+            dev.run(&Command::Init, &mut [0u8; 64]).unwrap_err().root(),
+            DriverError::MockUnexpectedCommand(cmd) if cmd == &[0x19]
+        ));
+    }
+}
@@ -1,95 +1,619 @@
-use crate::DriverError;
-use core::{ops::Drop, time::Duration};
-use rusb::{Device, DeviceHandle, GlobalContext};
-
-/// A wrapper around the given device, see [`Self::open`]
-#[derive(Debug)]
-pub struct UsbDevice(pub Device<GlobalContext>);
-
-impl UsbDevice {
-    /// Open this device
-    pub fn open(&self) -> Result<OpenedUsbDevice, DriverError> {
-        Ok(OpenedUsbDevice {
-            hnd: self.0.open().map_err(DriverError::OpenDevice)?,
+#[cfg(feature = "async")]
+pub mod r#async;
+
+#[cfg(feature = "trace")]
+use crate::trace::Hex;
+use crate::{
+    DriverError,
+    calibration::{Calibrate, DeadPixelMap},
+    pairing::load_pairing,
+    platform,
+    quirks::DeviceQuirks,
+    secure::{SecureSession, SessionParams},
+    timeouts::{CommandClass, RetryPolicy, Timeouts},
+    trace::{debug, trace, warning},
+    transport::Transport,
+};
+use core::{
+    fmt, mem,
+    ops::Drop,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use rusb::{Device, DeviceHandle, Direction, GlobalContext, TransferType, UsbContext};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Instant,
+};
+
+/// Max packet size of a full speed bulk endpoint, used if the descriptors don't have one
+const FULL_SPEED_PACKET: u16 = 64;
+
+/// Packets written per transfer, see [`Endpoints::write_chunks`]
+pub const WRITE_PACKETS: usize = 64;
+
+/// How many times to look for the device when reconnecting, see [`OpenedUsbDevice::reconnect`]
+const RECONNECT_ATTEMPTS: u32 = 10;
+
+/// Wait between the attempts, the device takes a while to enumerate after a resume
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// Called with the opcode of every command answered and the time it took, see
+/// [`OpenedUsbDevice::on_command`]
+pub type CommandObserver = Arc<dyn Fn(u8, Duration) + Send + Sync>;
+
+/// A wrapper around the given device and its quirks, see [`Self::open`]. The libusb context is
+/// the global one unless the device was found with
+/// [`list_supported_devices_in`](crate::list_supported_devices_in) (or similar).
+pub struct UsbDevice<C: UsbContext = GlobalContext>(pub Device<C>, pub &'static DeviceQuirks);
+
+// Derived it would need the context to be `Debug`, the global one is not
+impl<C: UsbContext> fmt::Debug for UsbDevice<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("UsbDevice")
+            .field(&self.0)
+            .field(&self.1)
+            .finish()
+    }
+}
+
+impl<C: UsbContext> UsbDevice<C> {
+    /// Open this device, select its configuration and claim its interface (detaching the kernel
+    /// driver bound to it, if any). The device is not initialized, see [`Self::open_with`].
+    pub fn open(&self) -> Result<OpenedUsbDevice<C>, DriverError> {
+        self.open_with(OpenOptions::new().init(false))
+    }
+
+    /// Open this device as told by the options, by default it is also initialized (see
+    /// [`Transport::send_init`])
+    pub fn open_with(&self, opts: OpenOptions) -> Result<OpenedUsbDevice<C>, DriverError> {
+        let hnd = self.0.open().map_err(DriverError::OpenDevice)?;
+        let interface = opts.interface.unwrap_or(self.1.interface);
+        claim(&hnd, self.1.configuration, interface)?;
+
+        let mut dev = OpenedUsbDevice {
+            hnd,
+            quirks: self.1,
+            endpoints: Endpoints::discover(&self.0, self.1, interface),
+            interface,
             reset_called: false,
-            default_timeout: Duration::from_secs(1),
-        })
+            reset_on_drop: opts.reset_on_drop,
+            closed: false,
+            timeouts: opts.timeouts,
+            read_timeout: AtomicU64::new(0),
+            retry: opts.retry,
+            pairing: None,
+            dead_pixels: None,
+            serial: None,
+            opts,
+            observer: None,
+            sent: Mutex::new(None),
+        };
+        dev.serial = dev.serial_number();
+
+        if dev.opts.init {
+            dev.send_init()?;
+        }
+        if let Some(path) = &dev.opts.pairing {
+            dev.pairing = Some(load_pairing(path)?);
+        }
+        if dev.opts.repair_dead_pixels {
+            dev.dead_pixels = Some(dev.read_calibration()?.dead_pixel_map());
+        }
+
+        Ok(dev)
+    }
+
+    /// Serial number of the device, it has to be opened to read it
+    pub fn serial_number(&self) -> Option<String> {
+        let desc = self.0.device_descriptor().ok()?;
+        let hnd = self.0.open().ok()?;
+        hnd.read_serial_number_string_ascii(&desc).ok()
     }
 }
 
-#[derive(Debug)]
-pub struct OpenedUsbDevice {
-    pub hnd: DeviceHandle<GlobalContext>,
-    reset_called: bool,
-    pub default_timeout: Duration,
+/// Find a device with these quirks and serial number, waiting for it to enumerate again
+fn find_again<C: UsbContext>(
+    ctx: &C,
+    quirks: &'static DeviceQuirks,
+    serial: Option<&str>,
+) -> Result<UsbDevice<C>, DriverError> {
+    let mut attempt = 0;
+
+    loop {
+        let found = crate::list_supported_devices_in(ctx)?
+            .into_iter()
+            .find(|dev| {
+                dev.1 == quirks && serial.is_none_or(|s| dev.serial_number().as_deref() == Some(s))
+            });
+
+        match found {
+            Some(dev) => return Ok(dev),
+            None if attempt < RECONNECT_ATTEMPTS => {
+                debug!(attempt, "device not found yet");
+                thread::sleep(RECONNECT_DELAY);
+                attempt += 1;
+            }
+            None => return Err(DriverError::GetDeviceNotFound),
+        }
+    }
 }
 
-impl OpenedUsbDevice {
-    /// Send a command to the USB device and wait for a reply (usuallu 1ms)
-    pub fn cmd(&self, data: &[u8], out: &mut [u8]) -> Result<usize, DriverError> {
-        // Write the command (endpoint 1)
-        let wrlen = self
-            .hnd
-            .write_bulk(1, data, self.default_timeout)
-            .map_err(DriverError::UsbWrite)?;
+/// The endpoints used to talk to the device, see [`Endpoints::discover`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoints {
+    /// Bulk OUT, where the commands are written
+    pub bulk_out: u8,
+
+    /// Bulk IN, where the responses are read
+    pub bulk_in: u8,
+
+    /// Interrupt IN, used by the sensor to signal events
+    pub int_in: u8,
 
-        if data.len() != wrlen {
-            return Err(DriverError::UsbWritePartial);
+    /// Max packet size of the bulk OUT endpoint, see [`Self::write_chunks`]
+    pub max_packet_out: u16,
+}
+
+impl Endpoints {
+    /// The endpoints given by the quirks
+    pub fn from_quirks(quirks: &DeviceQuirks) -> Self {
+        Self {
+            bulk_out: quirks.ep_out,
+            bulk_in: quirks.ep_in,
+            int_in: quirks.ep_int,
+            max_packet_out: FULL_SPEED_PACKET,
         }
+    }
 
-        // Now read the response (endpoint 129)
-        let rdlen = self
-            .hnd
-            .read_bulk(129, out, self.default_timeout)
-            .map_err(DriverError::UsbReadResponse)?;
+    /// Find the endpoints in the descriptors of the interface (first alternate setting of the
+    /// active configuration), the ones not found are taken from the quirks
+    pub fn discover<C: UsbContext>(dev: &Device<C>, quirks: &DeviceQuirks, interface: u8) -> Self {
+        let mut res = Self::from_quirks(quirks);
+        let Ok(config) = dev.active_config_descriptor() else {
+            debug!("no configuration descriptor, using the endpoints of the quirks");
+            return res;
+        };
 
-        Ok(rdlen)
+        let Some(desc) = config
+            .interfaces()
+            .find(|i| i.number() == interface)
+            .and_then(|i| i.descriptors().next())
+        else {
+            debug!(
+                interface,
+                "interface not found, using the endpoints of the quirks"
+            );
+            return res;
+        };
+
+        let (mut out, mut bulk_in, mut int) = (None, None, None);
+        for ep in desc.endpoint_descriptors() {
+            let slot = match (ep.transfer_type(), ep.direction()) {
+                (TransferType::Bulk, Direction::Out) => &mut out,
+                (TransferType::Bulk, Direction::In) => &mut bulk_in,
+                (TransferType::Interrupt, Direction::In) => &mut int,
+                _ => continue,
+            };
+            slot.get_or_insert((ep.address(), ep.max_packet_size()));
+        }
+
+        if let Some((_, size)) = out.filter(|(_, size)| *size > 0) {
+            res.max_packet_out = size;
+        }
+        let [out, bulk_in, int] = [out, bulk_in, int].map(|ep| ep.map(|(addr, _)| addr));
+        res.bulk_out = out.unwrap_or(res.bulk_out);
+        res.bulk_in = bulk_in.unwrap_or(res.bulk_in);
+        res.int_in = int.unwrap_or(res.int_in);
+        debug!(?res, "endpoints");
+        res
+    }
+
+    /// Split a command in the transfers written to the bulk OUT endpoint: at most
+    /// [`WRITE_PACKETS`] full packets each, the device only sees the end of the command at a short
+    /// packet. If the last one is full, an empty transfer (a zero length packet) follows.
+    pub fn write_chunks<'a>(&self, data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        let packet = self.max_packet_out.max(1) as usize;
+        let zlp = data.len().is_multiple_of(packet);
+
+        data.chunks(packet * WRITE_PACKETS)
+            .chain(zlp.then_some(&data[..0]))
+    }
+}
+
+/// How to open a device, see [`UsbDevice::open_with`]
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    timeouts: Timeouts,
+    retry: RetryPolicy,
+    init: bool,
+    pairing: Option<PathBuf>,
+    interface: Option<u8>,
+    reset_on_drop: bool,
+    repair_dead_pixels: bool,
+}
+
+impl OpenOptions {
+    /// The default options: default timeouts and retries, initialize the device, no pairing, the
+    /// interface given by the quirks, reset the device when dropped and the frames as read
+    pub fn new() -> Self {
+        Self {
+            timeouts: Timeouts::default(),
+            retry: RetryPolicy::default(),
+            init: true,
+            pairing: None,
+            interface: None,
+            reset_on_drop: true,
+            repair_dead_pixels: false,
+        }
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// How long to wait for the user to touch the sensor
+    pub fn touch_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.touch = timeout;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Whether to send the init sequence after opening
+    pub fn init(mut self, init: bool) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// Load the pairing data from this file, see [`OpenedUsbDevice::pairing`]
+    pub fn pairing(mut self, path: impl Into<PathBuf>) -> Self {
+        self.pairing = Some(path.into());
+        self
+    }
+
+    /// Claim this interface instead of the one given by the quirks
+    pub fn interface(mut self, interface: u8) -> Self {
+        self.interface = Some(interface);
+        self
+    }
+
+    /// Whether to reset the device when it is dropped (otherwise the interface is just released)
+    pub fn reset_on_drop(mut self, reset: bool) -> Self {
+        self.reset_on_drop = reset;
+        self
+    }
+
+    /// Read the dead pixels from the calibration when opening and repair them in every frame, see
+    /// [`DeadPixelMap::repair`]. The device has to be initialized.
+    pub fn repair_dead_pixels(mut self, repair: bool) -> Self {
+        self.repair_dead_pixels = repair;
+        self
     }
+}
 
-    /// Send the init messages and check the answer
-    pub fn send_init(&self) -> Result<(), DriverError> {
-        let mut buf = [0u8; 1024];
-        let _ = self.run_and_check(&[0x01], &mut buf)?;
-        let _ = self.run_and_check(&[0x19], &mut buf)?;
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Select the configuration and claim the interface
+pub(crate) fn claim<C: UsbContext>(
+    hnd: &DeviceHandle<C>,
+    configuration: u8,
+    interface: u8,
+) -> Result<(), DriverError> {
+    if platform::backend_capabilities().detach_kernel_driver {
+        hnd.set_auto_detach_kernel_driver(true)
+            .map_err(DriverError::UsbDetachKernelDriver)?;
+    }
+
+    // Changing the configuration resets the device, only do it if needed
+    if hnd.active_configuration().ok() != Some(configuration) {
+        hnd.set_active_configuration(configuration)
+            .map_err(DriverError::UsbSetConfiguration)?;
+    }
+
+    hnd.claim_interface(interface)
+        .map_err(DriverError::UsbClaimInterface)
+}
+
+pub struct OpenedUsbDevice<C: UsbContext = GlobalContext> {
+    pub hnd: DeviceHandle<C>,
+    pub quirks: &'static DeviceQuirks,
+
+    /// Found when opening, see [`Endpoints::discover`]
+    pub endpoints: Endpoints,
+
+    /// The claimed interface
+    interface: u8,
+    reset_called: bool,
+    reset_on_drop: bool,
+
+    /// Released (and reset) already, see [`Self::close`]
+    closed: bool,
+    pub timeouts: Timeouts,
+
+    /// Timeout of the last command in milliseconds, for the reads of the rest of its response
+    read_timeout: AtomicU64,
+    pub retry: RetryPolicy,
+    pairing: Option<SessionParams>,
+    dead_pixels: Option<DeadPixelMap>,
+
+    /// Used to find the device again, see [`Self::reconnect`]
+    serial: Option<String>,
+    opts: OpenOptions,
+
+    /// See [`Self::on_command`]
+    observer: Option<CommandObserver>,
+
+    /// Opcode and start of the command written, until its response is read
+    sent: Mutex<Option<(u8, Instant)>>,
+}
+
+impl<C: UsbContext> fmt::Debug for OpenedUsbDevice<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenedUsbDevice")
+            .field("hnd", &self.hnd)
+            .field("quirks", &self.quirks)
+            .field("endpoints", &self.endpoints)
+            .field("interface", &self.interface)
+            .field("reset_called", &self.reset_called)
+            .field("reset_on_drop", &self.reset_on_drop)
+            .field("closed", &self.closed)
+            .field("timeouts", &self.timeouts)
+            .field("retry", &self.retry)
+            .field("pairing", &self.pairing)
+            .field("dead_pixels", &self.dead_pixels)
+            .field("serial", &self.serial)
+            .field("opts", &self.opts)
+            .field("observer", &self.observer.is_some())
+            .finish()
+    }
+}
+
+impl<C: UsbContext> OpenedUsbDevice<C> {
+    /// The pairing data loaded when opening, see [`OpenOptions::pairing`]
+    pub fn pairing(&self) -> Option<&SessionParams> {
+        self.pairing.as_ref()
+    }
+
+    /// Repair these dead pixels in every frame instead (or none), see
+    /// [`OpenOptions::repair_dead_pixels`]
+    pub fn set_dead_pixels(&mut self, map: Option<DeadPixelMap>) {
+        self.dead_pixels = map;
+    }
+
+    /// Call the observer once each command is answered (its first response read), with the time
+    /// taken since it was written. Kept when reconnecting.
+    pub fn on_command(&mut self, observer: Option<CommandObserver>) {
+        self.observer = observer;
+    }
+
+    /// Establish a secure session with the pairing data loaded when opening
+    pub fn into_secure(self) -> Result<SecureSession<Self>, DriverError> {
+        let params = self.pairing.clone().ok_or(DriverError::PairingMissing)?;
+        SecureSession::establish(self, &params)
+    }
+
+    /// Find the device again (by its serial number, the bus address changes after a suspend),
+    /// open it with the same options and initialize it. The old handle is dropped.
+    pub fn reconnect(&mut self) -> Result<(), DriverError> {
+        debug!(serial = ?self.serial, "reconnecting");
+        let dev = find_again(self.hnd.context(), self.quirks, self.serial.as_deref())?;
+        let mut new = dev.open_with(self.opts.clone().init(true))?;
+        new.timeouts = self.timeouts;
+        new.retry = self.retry;
+        new.observer = self.observer.clone();
+
+        let mut old = mem::replace(self, new);
+        // The old device is gone, there is nothing to reset
+        old.reset_on_drop = false;
         Ok(())
     }
 
-    fn run_and_check(&self, cmd: &[u8], resp: &mut [u8]) -> Result<usize, DriverError> {
-        let res = self.cmd(cmd, resp)?;
-        let resp = &resp[..res];
+    /// Run the operation, if the device was lost (see [`DriverError::is_disconnected`]) reconnect
+    /// and run it once more
+    pub fn recovering<R, F>(&mut self, mut op: F) -> Result<R, DriverError>
+    where
+        F: FnMut(&Self) -> Result<R, DriverError>,
+    {
+        match op(self) {
+            Err(e) if e.is_disconnected() => {
+                debug!(error = %e, "device lost");
+                self.reconnect()?;
+                op(self)
+            }
+            res => res,
+        }
+    }
+
+    /// Release the interface and reset the device (unless disabled with
+    /// [`OpenOptions::reset_on_drop`]), like dropping it does but reporting the errors
+    pub fn close(mut self) -> Result<(), DriverError> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), DriverError> {
+        self.closed = true;
+
+        // The kernel driver (if any) is attached back once released
+        let released = self
+            .hnd
+            .release_interface(self.interface)
+            .map_err(DriverError::UsbReleaseInterface);
+        if self.reset_on_drop {
+            self.reset()?;
+        }
+        released
+    }
+
+    /// Run the transfer, again after a backoff while it fails with an error accepted by
+    /// `retriable` (see [`RetryPolicy`]). The bulk endpoints are cleared after a stall.
+    fn retrying<T>(
+        &self,
+        retriable: impl Fn(&rusb::Error) -> bool,
+        mut transfer: impl FnMut() -> Result<T, DriverError>,
+    ) -> Result<T, DriverError> {
+        let mut retry = 0;
+
+        loop {
+            let err = match transfer() {
+                Err(DriverError::UsbWrite(e) | DriverError::UsbReadResponse(e))
+                    if retry < self.retry.max_retries && retriable(&e) =>
+                {
+                    e
+                }
+                res => return res,
+            };
 
-        if resp.len() < 2 {
-            return Err(DriverError::UsbInitInvalid);
+            debug!(%err, retry, "retrying transfer");
+            if err == rusb::Error::Pipe {
+                // Nothing else to do if this fails, the retry will tell
+                let _ = self.hnd.clear_halt(self.endpoints.bulk_out);
+                let _ = self.hnd.clear_halt(self.endpoints.bulk_in);
+            }
+
+            thread::sleep(self.retry.delay(retry));
+            retry += 1;
+        }
+    }
+
+    /// The timeout of the class of the command, kept for the reads of the rest of its response
+    fn command_timeout(&self, cmd: &[u8]) -> Duration {
+        let timeout = self.timeouts.get(CommandClass::of(cmd));
+        self.read_timeout
+            .store(timeout.as_millis() as u64, Ordering::Relaxed);
+        timeout
+    }
+
+    /// Write the whole command, in as many transfers as needed (see [`Endpoints::write_chunks`]).
+    /// Only the transfer failing is retried, the chunks written already are not sent again.
+    fn write_all(&self, data: &[u8], timeout: Duration) -> Result<usize, DriverError> {
+        trace!(ep = self.endpoints.bulk_out, data = %Hex(data), "bulk write");
+        for chunk in self.endpoints.write_chunks(data) {
+            let len = self.retrying(RetryPolicy::is_retriable, || {
+                self.hnd
+                    .write_bulk(self.endpoints.bulk_out, chunk, timeout)
+                    .map_err(DriverError::UsbWrite)
+            })?;
+            if len != chunk.len() {
+                return Err(DriverError::UsbWritePartial);
+            }
         }
+        Ok(data.len())
+    }
+}
 
-        // SAFETY: We already check the slice has at least two elements
-        let shrt = u16::from_le_bytes((&resp[..2]).try_into().unwrap());
+impl<C: UsbContext> Transport for OpenedUsbDevice<C> {
+    fn quirks(&self) -> &'static DeviceQuirks {
+        self.quirks
+    }
 
-        if shrt != 0 && shrt == 0x44f {
-            return Err(DriverError::UsbInitSignatureFailed(shrt));
+    /// Write the command (endpoint 1 on most devices) with the timeout of its class, retrying
+    /// each transfer as told by [`Self::retry`]
+    fn write(&self, data: &[u8]) -> Result<usize, DriverError> {
+        let timeout = self.command_timeout(data);
+        let start = Instant::now();
+        let len = self.write_all(data, timeout)?;
+        if self.observer.is_some() {
+            *self.sent.lock().unwrap_or_else(PoisonError::into_inner) =
+                data.first().map(|op| (*op, start));
         }
+        Ok(len)
+    }
 
-        if shrt != 0 {
-            return Err(DriverError::UsbInitFailed(shrt));
+    /// Read the response (endpoint 129 on most devices) with the timeout of the last command,
+    /// retrying as told by [`Self::retry`]. Timeouts are not retried: the response is not coming
+    /// (or it ended, see [`Transport::cmd_vec`]), and the command is never written again.
+    fn read(&self, out: &mut [u8]) -> Result<usize, DriverError> {
+        let timeout = match self.read_timeout.load(Ordering::Relaxed) {
+            0 => self.timeouts.fast,
+            ms => Duration::from_millis(ms),
+        };
+        let retriable =
+            |e: &rusb::Error| *e != rusb::Error::Timeout && RetryPolicy::is_retriable(e);
+        let len = self.retrying(retriable, || {
+            self.hnd
+                .read_bulk(self.endpoints.bulk_in, out, timeout)
+                .map_err(DriverError::UsbReadResponse)
+        })?;
+        trace!(ep = self.endpoints.bulk_in, data = %Hex(&out[..len]), "bulk read");
+
+        if let Some(observer) = &self.observer {
+            let sent = self
+                .sent
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            if let Some((opcode, start)) = sent {
+                observer(opcode, start.elapsed());
+            }
         }
+        Ok(len)
+    }
+
+    fn wait_int(&self, out: &mut [u8], timeout: Duration) -> Result<usize, DriverError> {
+        let len = self
+            .hnd
+            .read_interrupt(self.endpoints.int_in, out, timeout)
+            .map_err(DriverError::UsbReadInterrupt)?;
+        trace!(ep = self.endpoints.int_in, data = %Hex(&out[..len]), "interrupt");
+        Ok(len)
+    }
+
+    fn touch_timeout(&self) -> Duration {
+        self.timeouts.touch
+    }
+
+    fn dead_pixels(&self) -> Option<&DeadPixelMap> {
+        self.dead_pixels.as_ref()
+    }
 
-        Ok(res)
+    /// Read from the string descriptor
+    fn serial_number(&self) -> Option<String> {
+        let desc = self.hnd.device().device_descriptor().ok()?;
+        self.hnd.read_serial_number_string_ascii(&desc).ok()
     }
 
-    /// Reset the device
-    pub fn reset(&mut self) -> Result<(), DriverError> {
+    fn reset(&mut self) -> Result<(), DriverError> {
         if self.reset_called {
             return Ok(());
         }
-        self.hnd.reset().map_err(DriverError::UsbReset)?;
+
+        if platform::backend_capabilities().device_reset {
+            self.hnd.reset().map_err(DriverError::UsbReset)?;
+        } else {
+            // Just leave the endpoints in a clean state
+            for ep in [
+                self.endpoints.bulk_out,
+                self.endpoints.bulk_in,
+                self.endpoints.int_in,
+            ] {
+                self.hnd.clear_halt(ep).map_err(DriverError::UsbReset)?;
+            }
+        }
+
         self.reset_called = true;
         Ok(())
     }
 }
 
-impl Drop for OpenedUsbDevice {
+impl<C: UsbContext> Drop for OpenedUsbDevice<C> {
+    /// The device may be gone already, the errors are only logged (see [`Self::close`])
     fn drop(&mut self) {
-        self.reset()
-            .expect("Could not reset the USB device, try calling reset() manually");
+        if self.closed {
+            return;
+        }
+        if let Err(_e) = self.shutdown() {
+            warning!(error = %_e, "could not close the device");
+        }
     }
 }
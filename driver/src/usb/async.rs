@@ -0,0 +1,596 @@
+//! Async version of [`super::OpenedUsbDevice`], the transfers are submitted with the libusb async
+//! API and completed by a single event thread shared by every device (or by an external main
+//! loop, see [`use_external_event_loop`]).
+
+use super::{Endpoints, OpenOptions, UsbDevice};
+#[cfg(feature = "trace")]
+use crate::trace::Hex;
+use crate::{
+    DriverError,
+    calibration::DeadPixelMap,
+    capture::{CHUNK_SIZE, CaptureMode, Frame, parse_header},
+    events::{FingerEvent, POLL_INTERVAL},
+    firmware::DeviceState,
+    integrity, platform,
+    proto::{Command, Response},
+    quirks::{DeviceQuirks, SensorType},
+    timeouts::{CommandClass, Timeouts},
+    trace::{trace, warning},
+    transport::{INT_FINGER_DOWN, INT_FINGER_UP, INT_SCAN_COMPLETE, check_response},
+};
+use futures_util::{Stream, stream};
+use libusb1_sys::{
+    constants::*, libusb_alloc_transfer, libusb_cancel_transfer, libusb_free_pollfds,
+    libusb_free_transfer, libusb_get_pollfds, libusb_submit_transfer, libusb_transfer,
+};
+use rusb::{DeviceHandle, GlobalContext, UsbContext};
+use std::{
+    ffi::{c_int, c_short, c_void},
+    sync::{
+        Arc, Mutex, Once, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard, oneshot};
+
+/// Data owned by a transfer until libusb is done with it
+struct Pending {
+    buf: Vec<u8>,
+
+    /// The handle can't be closed while the transfer is in flight
+    _hnd: Arc<DeviceHandle<GlobalContext>>,
+    tx: oneshot::Sender<(c_int, Vec<u8>)>,
+
+    /// Set by [`on_complete`] before freeing the transfer, see [`InFlight`]
+    done: Arc<Mutex<bool>>,
+}
+
+/// A submitted transfer, cancelled if dropped before it completed (the future waiting for it
+/// was dropped), so it does not eat the next response or interrupt
+struct InFlight {
+    xfer: *mut libusb_transfer,
+    done: Arc<Mutex<bool>>,
+}
+
+// SAFETY: libusb transfers can be cancelled from any thread
+unsafe impl Send for InFlight {}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let done = self.done.lock().unwrap_or_else(PoisonError::into_inner);
+        if !*done {
+            // SAFETY: `on_complete` only frees the transfer once it set `done`, with the lock
+            // held. The cancelled transfer still completes, freeing its data.
+            unsafe { libusb_cancel_transfer(self.xfer) };
+        }
+    }
+}
+
+/// The timeout of the last command, for the reads of the rest of its response (`None` before
+/// the first one). Locked for a whole exchange, see [`OpenedUsbDevice::cmd`].
+type Exchange<'a> = AsyncMutexGuard<'a, Option<Duration>>;
+
+impl UsbDevice {
+    /// Open this device, for use with async code, with the default options (see
+    /// [`Self::open_async_with`])
+    pub fn open_async(&self) -> Result<OpenedUsbDevice, DriverError> {
+        self.open_async_with(OpenOptions::new())
+    }
+
+    /// Open this device as told by the options, for use with async code. Only the timeouts, the
+    /// interface and [`OpenOptions::reset_on_drop`] are used: the init sequence has to be sent
+    /// with [`OpenedUsbDevice::send_init`], the transfers are not retried and the dead pixels
+    /// are only repaired if given with [`OpenedUsbDevice::set_dead_pixels`].
+    pub fn open_async_with(&self, opts: OpenOptions) -> Result<OpenedUsbDevice, DriverError> {
+        let hnd = self.0.open().map_err(DriverError::OpenDevice)?;
+        let interface = opts.interface.unwrap_or(self.1.interface);
+        super::claim(&hnd, self.1.configuration, interface)?;
+
+        Ok(OpenedUsbDevice {
+            hnd: Arc::new(hnd),
+            quirks: self.1,
+            endpoints: Endpoints::discover(&self.0, self.1, interface),
+            interface,
+            reset_called: false,
+            reset_on_drop: opts.reset_on_drop,
+            closed: false,
+            timeouts: opts.timeouts,
+            exchange: AsyncMutex::new(None),
+            dead_pixels: None,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct OpenedUsbDevice {
+    pub hnd: Arc<DeviceHandle<GlobalContext>>,
+    pub quirks: &'static DeviceQuirks,
+
+    /// Found when opening, see [`Endpoints::discover`]
+    pub endpoints: Endpoints,
+
+    /// The claimed interface
+    interface: u8,
+    reset_called: bool,
+    reset_on_drop: bool,
+
+    /// Released (and reset) already, see [`Self::close`]
+    closed: bool,
+
+    /// Used per class of command, see [`CommandClass::of`]
+    pub timeouts: Timeouts,
+
+    /// See [`Exchange`]
+    exchange: AsyncMutex<Option<Duration>>,
+    dead_pixels: Option<DeadPixelMap>,
+}
+
+impl OpenedUsbDevice {
+    /// Send a command to the USB device and wait for a reply, using the timeout of its class.
+    /// The commands of other tasks wait until this one was answered. Dropping the future cancels
+    /// the transfer in flight.
+    pub async fn cmd(&self, data: &[u8], out: &mut [u8]) -> Result<usize, DriverError> {
+        let mut exchange = self.exchange.lock().await;
+        self.cmd_in(&mut exchange, data, out).await
+    }
+
+    /// Read more data from the bulk IN endpoint, with the timeout of the last command. Other
+    /// tasks may send a command between it and this read, [`Self::read_frame`] does not let
+    /// them.
+    pub async fn read(&self, out: &mut [u8]) -> Result<usize, DriverError> {
+        let exchange = self.exchange.lock().await;
+        self.read_in(&exchange, out).await
+    }
+
+    async fn cmd_in(
+        &self,
+        exchange: &mut Exchange<'_>,
+        data: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, DriverError> {
+        let timeout = self.timeouts.get(CommandClass::of(data));
+        **exchange = Some(timeout);
+
+        // In as many transfers as needed, see [`Endpoints::write_chunks`]
+        for chunk in self.endpoints.write_chunks(data) {
+            let written = self
+                .transfer(
+                    self.endpoints.bulk_out,
+                    LIBUSB_TRANSFER_TYPE_BULK,
+                    chunk.to_vec(),
+                    timeout,
+                )
+                .await
+                .map_err(DriverError::UsbWrite)?;
+
+            if chunk.len() != written.len() {
+                return Err(DriverError::UsbWritePartial);
+            }
+        }
+
+        self.read_in(exchange, out).await
+    }
+
+    async fn read_in(&self, exchange: &Exchange<'_>, out: &mut [u8]) -> Result<usize, DriverError> {
+        let timeout = exchange.unwrap_or(self.timeouts.fast);
+        let resp = self
+            .transfer(
+                self.endpoints.bulk_in,
+                LIBUSB_TRANSFER_TYPE_BULK,
+                vec![0u8; out.len()],
+                timeout,
+            )
+            .await
+            .map_err(DriverError::UsbReadResponse)?;
+
+        out[..resp.len()].copy_from_slice(&resp);
+        Ok(resp.len())
+    }
+
+    /// Wait for an event in the interrupt endpoint, returns the amount of bytes read. Dropping
+    /// the future cancels the transfer, the event is left for the next wait.
+    pub async fn wait_int(&self, out: &mut [u8], timeout: Duration) -> Result<usize, DriverError> {
+        let resp = self
+            .transfer(
+                self.endpoints.int_in,
+                LIBUSB_TRANSFER_TYPE_INTERRUPT,
+                vec![0u8; out.len()],
+                timeout,
+            )
+            .await
+            .map_err(DriverError::UsbReadInterrupt)?;
+
+        out[..resp.len()].copy_from_slice(&resp);
+        Ok(resp.len())
+    }
+
+    /// Send the init messages and check the answer, see
+    /// [`crate::transport::Transport::send_init`]
+    pub async fn send_init(&self) -> Result<(), DriverError> {
+        let mut buf = [0u8; 1024];
+        let mut exchange = self.exchange.lock().await;
+        for cmd in self.quirks.init_sequence {
+            self.run_in(&mut exchange, cmd, &mut buf)
+                .await
+                .map_err(|e| match DeviceState::from_error(&e) {
+                    Some(DeviceState::Bootloader) => DriverError::Bootloader,
+                    _ => e,
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Run the command and check the status code, the response is read into `buf`
+    pub async fn run<'b>(
+        &self,
+        command: &Command<'_>,
+        buf: &'b mut [u8],
+    ) -> Result<Response<'b>, DriverError> {
+        let mut exchange = self.exchange.lock().await;
+        self.run_in(&mut exchange, command, buf).await
+    }
+
+    async fn run_in<'b>(
+        &self,
+        exchange: &mut Exchange<'_>,
+        command: &Command<'_>,
+        buf: &'b mut [u8],
+    ) -> Result<Response<'b>, DriverError> {
+        let cmd = command.to_bytes();
+        let len = self
+            .cmd_in(exchange, &cmd, buf)
+            .await
+            .map_err(|e| e.in_command(&cmd, &[]))?;
+        let buf = &buf[..len];
+        Response::parse(buf)
+            .map_err(DriverError::from)
+            .and_then(|resp| check_response(command, resp))
+            .map_err(|e| e.in_command(&cmd, buf))
+    }
+
+    /// Read the last captured frame (the dead pixels are repaired if given, see
+    /// [`Self::set_dead_pixels`]), see [`crate::capture::Capture::read_frame`]
+    pub async fn read_frame(&self) -> Result<Frame, DriverError> {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut exchange = self.exchange.lock().await;
+        let mut resp = self
+            .run_in(&mut exchange, &Command::ReadFrame, &mut buf)
+            .await?;
+        let (width, height, bpp, total) =
+            parse_header(&mut resp).ok_or(DriverError::CaptureInvalidResponse)?;
+
+        // The size comes from the device, grown as the data arrives
+        let mut data = Vec::with_capacity(total.min(CHUNK_SIZE));
+        integrity::append(&mut data, resp.rest(), total).map_err(DriverError::Integrity)?;
+        while data.len() < total {
+            let len = self.read_in(&exchange, &mut buf).await?;
+            if len == 0 {
+                return Err(DriverError::CaptureIncomplete(data.len(), total));
+            }
+            integrity::append(&mut data, &buf[..len], total).map_err(DriverError::Integrity)?;
+        }
+
+        let mut frame = Frame {
+            width,
+            height,
+            bpp,
+            data,
+        };
+        if let Some(map) = &self.dead_pixels {
+            map.repair(&mut frame);
+        }
+        Ok(frame)
+    }
+
+    /// Async version of [`crate::capture::Capture::stream_frames`]. There is no async drop, so
+    /// the sensor is left armed if the stream is dropped before the finger is lifted (the next
+    /// capture arms it again anyway).
+    pub fn stream_frames(&self) -> impl Stream<Item = Result<Frame, DriverError>> + '_ {
+        // `None` once the stream ended, otherwise whether the sensor was armed
+        stream::unfold(Some(false), move |state| async move {
+            if !state? && let Err(e) = self.arm_capture().await {
+                return Some((Err(e), None));
+            }
+
+            match self.next_frame().await {
+                Ok(Some(frame)) => Some((Ok(frame), Some(true))),
+                Ok(None) => {
+                    self.stop_capture().await;
+                    None
+                }
+                Err(e) => {
+                    self.stop_capture().await;
+                    Some((Err(e), None))
+                }
+            }
+        })
+    }
+
+    /// Async version of [`crate::events::FingerListener`], to `select!` over the touches and
+    /// other futures. The stream ends after the first error, the device is probably gone.
+    /// Dropping it (or losing the `select!`) cancels the interrupt transfer waiting, so the next
+    /// event is left for the next wait.
+    pub fn finger_events(&self) -> impl Stream<Item = Result<FingerEvent, DriverError>> + '_ {
+        stream::unfold(true, move |running| async move {
+            if !running {
+                return None;
+            }
+
+            let mut int = [0u8; 64];
+            loop {
+                // Short transfers, the one in flight is cancelled if the stream is dropped
+                let ev = match self.wait_int(&mut int, POLL_INTERVAL).await {
+                    Ok(0) => continue,
+                    Ok(_) => match int[0] {
+                        INT_FINGER_DOWN => FingerEvent::Down,
+                        INT_FINGER_UP => FingerEvent::Up,
+                        _ => continue,
+                    },
+                    Err(DriverError::UsbReadInterrupt(rusb::Error::Timeout)) => continue,
+                    Err(e) => return Some((Err(e), false)),
+                };
+                return Some((Ok(ev), true));
+            }
+        })
+    }
+
+    /// Wait for the next scan, `None` once the finger is lifted
+    async fn next_frame(&self) -> Result<Option<Frame>, DriverError> {
+        let mut int = [0u8; 64];
+        loop {
+            let len = self.wait_int(&mut int, self.timeouts.touch).await?;
+            match int[..len].first().copied() {
+                Some(INT_SCAN_COMPLETE) => {
+                    let frame = self.read_frame().await?;
+                    // Swipe sensors keep scanning, the others scan once per arm
+                    if self.quirks.sensor_type == SensorType::Press {
+                        self.arm_capture().await?;
+                    }
+                    return Ok(Some(frame));
+                }
+                Some(INT_FINGER_UP) => return Ok(None),
+                _ => (),
+            }
+        }
+    }
+
+    async fn arm_capture(&self) -> Result<(), DriverError> {
+        let cmd = Command::CaptureStart(CaptureMode::Image);
+        self.run(&cmd, &mut [0u8; 64]).await?;
+        Ok(())
+    }
+
+    async fn stop_capture(&self) {
+        if let Err(_e) = self.run(&Command::CaptureStop, &mut [0u8; 64]).await {
+            warning!(error = %_e, "could not stop the capture");
+        }
+    }
+
+    /// Repair these dead pixels in every frame read (see [`DeadPixelMap::repair`]), usually read
+    /// with [`crate::calibration::Calibrate::read_calibration`] on the sync device
+    pub fn set_dead_pixels(&mut self, map: Option<DeadPixelMap>) {
+        self.dead_pixels = map;
+    }
+
+    /// Release the interface and reset the device (unless told otherwise, see
+    /// [`OpenOptions::reset_on_drop`]), like dropping it does but reporting the errors
+    pub fn close(mut self) -> Result<(), DriverError> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), DriverError> {
+        self.closed = true;
+        let released = self
+            .hnd
+            .release_interface(self.interface)
+            .map_err(DriverError::UsbReleaseInterface);
+        if self.reset_on_drop {
+            self.reset()?;
+        }
+        released
+    }
+
+    /// Reset the device, or just clear its endpoints where the platform can't reset it (see
+    /// [`platform::backend_capabilities`])
+    pub fn reset(&mut self) -> Result<(), DriverError> {
+        if self.reset_called {
+            return Ok(());
+        }
+
+        if platform::backend_capabilities().device_reset {
+            self.hnd.reset().map_err(DriverError::UsbReset)?;
+        } else {
+            for ep in [
+                self.endpoints.bulk_out,
+                self.endpoints.bulk_in,
+                self.endpoints.int_in,
+            ] {
+                self.hnd.clear_halt(ep).map_err(DriverError::UsbReset)?;
+            }
+        }
+
+        self.reset_called = true;
+        Ok(())
+    }
+
+    /// Submit a transfer and wait until it completes, returns the transferred data. The timeout
+    /// is in whole milliseconds, at least one (libusb takes 0 as waiting forever).
+    async fn transfer(
+        &self,
+        endpoint: u8,
+        kind: u8,
+        buf: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, rusb::Error> {
+        start_event_thread();
+
+        let (tx, rx) = oneshot::channel();
+        let done = Arc::new(Mutex::new(false));
+        let mut pending = Box::new(Pending {
+            buf,
+            _hnd: self.hnd.clone(),
+            tx,
+            done: done.clone(),
+        });
+        let timeout = timeout.as_millis().clamp(1, u32::MAX.into()) as u32;
+
+        // SAFETY: The buffer and the handle are owned by `pending`, which is only freed once
+        // libusb is done with the transfer (in `on_complete`, or here if the submit failed)
+        let _in_flight = unsafe {
+            let xfer = libusb_alloc_transfer(0);
+            if xfer.is_null() {
+                return Err(rusb::Error::NoMem);
+            }
+
+            (*xfer).dev_handle = self.hnd.as_raw();
+            (*xfer).endpoint = endpoint;
+            (*xfer).transfer_type = kind;
+            (*xfer).timeout = timeout;
+            (*xfer).buffer = pending.buf.as_mut_ptr();
+            (*xfer).length = pending.buf.len() as c_int;
+            (*xfer).callback = on_complete;
+            (*xfer).user_data = Box::into_raw(pending) as *mut c_void;
+
+            let ret = libusb_submit_transfer(xfer);
+            if ret != 0 {
+                drop(Box::from_raw((*xfer).user_data as *mut Pending));
+                libusb_free_transfer(xfer);
+                return Err(error_from_libusb(ret));
+            }
+            InFlight { xfer, done }
+        };
+
+        let (status, buf) = rx.await.map_err(|_| rusb::Error::Other)?;
+        trace!(ep = endpoint, status, data = %Hex(&buf), "transfer");
+        match status {
+            LIBUSB_TRANSFER_COMPLETED => Ok(buf),
+            LIBUSB_TRANSFER_TIMED_OUT => Err(rusb::Error::Timeout),
+            LIBUSB_TRANSFER_CANCELLED => Err(rusb::Error::Interrupted),
+            LIBUSB_TRANSFER_STALL => Err(rusb::Error::Pipe),
+            LIBUSB_TRANSFER_NO_DEVICE => Err(rusb::Error::NoDevice),
+            LIBUSB_TRANSFER_OVERFLOW => Err(rusb::Error::Overflow),
+            _ => Err(rusb::Error::Io),
+        }
+    }
+}
+
+impl Drop for OpenedUsbDevice {
+    /// The device may be gone already, the errors are only logged (see [`Self::close`])
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if let Err(_e) = self.shutdown() {
+            warning!(error = %_e, "could not close the device");
+        }
+    }
+}
+
+/// Called by libusb (from the event thread) once the transfer is done
+extern "system" fn on_complete(xfer: *mut libusb_transfer) {
+    // SAFETY: `user_data` is the `Pending` leaked in `transfer`, libusb calls this only once
+    unsafe {
+        let pending = Box::from_raw((*xfer).user_data as *mut Pending);
+        let status = (*xfer).status;
+        let len = (*xfer).actual_length.max(0) as usize;
+        {
+            let mut done = pending.done.lock().unwrap_or_else(PoisonError::into_inner);
+            *done = true;
+            libusb_free_transfer(xfer);
+        }
+
+        let Pending { mut buf, tx, .. } = *pending;
+        buf.truncate(len);
+        let _ = tx.send((status, buf));
+    }
+}
+
+/// Set once the transfers are completed, by the event thread or by an external loop
+static START: Once = Once::new();
+
+/// Whether [`use_external_event_loop`] was called before the first transfer
+static EXTERNAL: AtomicBool = AtomicBool::new(false);
+
+/// A file descriptor libusb wants polled, see [`pollfds`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollFd {
+    pub fd: c_int,
+
+    /// The `poll(2)` events to wait for (`POLLIN`, `POLLOUT`)
+    pub events: c_short,
+}
+
+/// Complete the transfers from an external main loop (calloop, glib, ...) instead of the event
+/// thread: poll the [`pollfds`] and call [`handle_events_nonblocking`] when they are ready.
+/// Must be called before the first transfer, returns `false` if the thread already started.
+pub fn use_external_event_loop() -> bool {
+    START.call_once(|| EXTERNAL.store(true, Ordering::Relaxed));
+    EXTERNAL.load(Ordering::Relaxed)
+}
+
+/// Complete the transfers that are ready, without blocking
+pub fn handle_events_nonblocking() -> Result<(), DriverError> {
+    GlobalContext::default()
+        .handle_events(Some(Duration::ZERO))
+        .map_err(DriverError::UsbHandleEvents)
+}
+
+/// The file descriptors to poll before calling [`handle_events_nonblocking`]. They change when
+/// devices are opened or closed, so get them again after that.
+pub fn pollfds() -> Result<Vec<PollFd>, DriverError> {
+    let ctx = GlobalContext::default();
+
+    // SAFETY: The list is terminated by a null pointer and freed once copied
+    unsafe {
+        let list = libusb_get_pollfds(ctx.as_raw());
+        if list.is_null() {
+            // Windows and macOS have no pollable descriptors
+            return Err(DriverError::UsbHandleEvents(rusb::Error::NotSupported));
+        }
+
+        let mut fds = vec![];
+        let mut it = list;
+        while !(*it).is_null() {
+            fds.push(PollFd {
+                fd: (**it).fd,
+                events: (**it).events,
+            });
+            it = it.add(1);
+        }
+        libusb_free_pollfds(list);
+        Ok(fds)
+    }
+}
+
+/// Start the thread that completes the transfers, only once (unless an external loop does it)
+fn start_event_thread() {
+    START.call_once(|| {
+        thread::spawn(|| {
+            let ctx = GlobalContext::default();
+            loop {
+                // Errors are reported by the transfers themselves
+                let _ = ctx.handle_events(None);
+            }
+        });
+    });
+}
+
+fn error_from_libusb(err: c_int) -> rusb::Error {
+    match err {
+        LIBUSB_ERROR_IO => rusb::Error::Io,
+        LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        LIBUSB_ERROR_ACCESS => rusb::Error::Access,
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_TIMEOUT => rusb::Error::Timeout,
+        LIBUSB_ERROR_OVERFLOW => rusb::Error::Overflow,
+        LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+        LIBUSB_ERROR_INTERRUPTED => rusb::Error::Interrupted,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
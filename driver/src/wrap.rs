@@ -0,0 +1,242 @@
+//! Commands wrapped with AES-256-GCM instead of the TLS records of [`crate::secure`]: every
+//! message is a header followed by the encrypted command and the tag. The header is
+//! authenticated (but not encrypted), it holds the sequence number each side increments, so
+//! replayed or reordered messages are rejected.
+//!
+//! How the firmwares derive the keys and which commands they take wrapped is not known yet, so
+//! the driver does not use it on its own: the keys are given by the caller.
+//!
+//! The header is: type (u8, [`WRAP_TYPE`]), reserved (u8), sequence number (u32) and length of
+//! the encrypted data (u16). The nonce is 8 zero bytes followed by the sequence number (big
+//! endian), each direction has its own key.
+
+use crate::DriverError;
+use aes_gcm::{
+    Aes256Gcm, Nonce, Tag,
+    aead::{AeadInPlace, KeyInit},
+};
+
+/// First byte of every wrapped message
+pub const WRAP_TYPE: u8 = 0x45;
+
+const HEADER_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+
+/// Wraps the commands sent and unwraps the responses, see the [module](self) docs
+pub struct CommandWrapper {
+    send: Aes256Gcm,
+    recv: Aes256Gcm,
+    send_seq: u32,
+    recv_seq: u32,
+}
+
+impl CommandWrapper {
+    /// A wrapper with the keys of each direction, the sequence numbers start at 0
+    pub fn new(send_key: &[u8; 32], recv_key: &[u8; 32]) -> Self {
+        Self {
+            send: Aes256Gcm::new(send_key.into()),
+            recv: Aes256Gcm::new(recv_key.into()),
+            send_seq: 0,
+            recv_seq: 0,
+        }
+    }
+
+    /// Encrypt the command, once the sequence number runs out the session has to be established
+    /// again
+    pub fn wrap(&mut self, cmd: &[u8]) -> Result<Vec<u8>, DriverError> {
+        let len = u16::try_from(cmd.len() + TAG_SIZE).map_err(|_| DriverError::WrapTooLong)?;
+        let seq = self.send_seq;
+        self.send_seq = seq.checked_add(1).ok_or(DriverError::WrapExhausted)?;
+
+        let mut res = header(seq, len).to_vec();
+        let mut data = cmd.to_vec();
+        let tag = seal(&self.send, &nonce(seq), &res, &mut data)?;
+        res.extend(data);
+        res.extend(tag);
+        Ok(res)
+    }
+
+    /// Check and decrypt the response, it must have the next sequence number
+    pub fn unwrap(&mut self, msg: &[u8]) -> Result<Vec<u8>, DriverError> {
+        let (head, body) = msg
+            .split_first_chunk::<HEADER_SIZE>()
+            .ok_or(DriverError::WrapInvalid)?;
+
+        let seq = u32::from_le_bytes([head[2], head[3], head[4], head[5]]);
+        let len = u16::from_le_bytes([head[6], head[7]]) as usize;
+        if head[0] != WRAP_TYPE || body.len() != len || len < TAG_SIZE {
+            return Err(DriverError::WrapInvalid);
+        }
+        if seq != self.recv_seq {
+            return Err(DriverError::WrapSequence {
+                expected: self.recv_seq,
+                got: seq,
+            });
+        }
+
+        let (data, tag) = body.split_at(len - TAG_SIZE);
+        let mut data = data.to_vec();
+        open(&self.recv, &nonce(seq), head, &mut data, tag)?;
+
+        self.recv_seq = seq.checked_add(1).ok_or(DriverError::WrapExhausted)?;
+        Ok(data)
+    }
+}
+
+/// The keys are left out
+impl core::fmt::Debug for CommandWrapper {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CommandWrapper")
+            .field("send_seq", &self.send_seq)
+            .field("recv_seq", &self.recv_seq)
+            .finish_non_exhaustive()
+    }
+}
+
+fn header(seq: u32, len: u16) -> [u8; HEADER_SIZE] {
+    let mut res = [WRAP_TYPE, 0, 0, 0, 0, 0, 0, 0];
+    res[2..6].copy_from_slice(&seq.to_le_bytes());
+    res[6..].copy_from_slice(&len.to_le_bytes());
+    res
+}
+
+fn nonce(seq: u32) -> [u8; 12] {
+    let mut res = [0u8; 12];
+    res[8..].copy_from_slice(&seq.to_be_bytes());
+    res
+}
+
+/// Encrypt the data in place, returns the tag
+fn seal(
+    cipher: &Aes256Gcm,
+    nonce: &[u8; 12],
+    aad: &[u8],
+    data: &mut [u8],
+) -> Result<[u8; TAG_SIZE], DriverError> {
+    cipher
+        .encrypt_in_place_detached(&Nonce::from(*nonce), aad, data)
+        .map(Into::into)
+        .map_err(|_| DriverError::WrapTooLong)
+}
+
+/// Check the tag and decrypt the data in place
+fn open(
+    cipher: &Aes256Gcm,
+    nonce: &[u8; 12],
+    aad: &[u8],
+    data: &mut [u8],
+    tag: &[u8],
+) -> Result<(), DriverError> {
+    let tag = <[u8; TAG_SIZE]>::try_from(tag).map_err(|_| DriverError::WrapBadTag)?;
+    cipher
+        .decrypt_in_place_detached(&Nonce::from(*nonce), aad, data, &Tag::from(tag))
+        .map_err(|_| DriverError::WrapBadTag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Checks [`seal`] and [`open`] against a vector of the GCM spec (AES-256 test cases)
+    fn check_vector(key: &str, iv: &str, plain: &str, aad: &str, cipher: &str, tag: &str) {
+        let gcm = Aes256Gcm::new(hex(key).as_slice().into());
+        let iv: [u8; 12] = hex(iv).try_into().unwrap();
+
+        let mut data = hex(plain);
+        let got = seal(&gcm, &iv, &hex(aad), &mut data).unwrap();
+        assert_eq!(data, hex(cipher));
+        assert_eq!(got.to_vec(), hex(tag));
+
+        open(&gcm, &iv, &hex(aad), &mut data, &got).unwrap();
+        assert_eq!(data, hex(plain));
+    }
+
+    #[test]
+    fn gcm_empty() {
+        check_vector(
+            &"00".repeat(32),
+            &"00".repeat(12),
+            "",
+            "",
+            "",
+            "530f8afbc74536b9a963b4f1c4cb738b",
+        );
+    }
+
+    #[test]
+    fn gcm_zero_block() {
+        check_vector(
+            &"00".repeat(32),
+            &"00".repeat(12),
+            &"00".repeat(16),
+            "",
+            "cea7403d4d606b6e074ec5d3baf39d18",
+            "d0d1c8a799996bf0265b98b5d48ab919",
+        );
+    }
+
+    #[test]
+    fn gcm_with_aad() {
+        check_vector(
+            "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+            "cafebabefacedbaddecaf888",
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+            "feedfacedeadbeeffeedfacedeadbeefabaddad2",
+            "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+             8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662",
+            "76fc6ece0f4e1768cddf8853bb2d551b",
+        );
+    }
+
+    /// The host and the sensor, each one sends with the key the other receives with
+    fn pair() -> (CommandWrapper, CommandWrapper) {
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        (CommandWrapper::new(&a, &b), CommandWrapper::new(&b, &a))
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut host, mut sensor) = pair();
+        for cmd in [&b"\x01"[..], &[0x3e; 100], &[]] {
+            let msg = host.wrap(cmd).unwrap();
+            assert_eq!(msg[0], WRAP_TYPE);
+            assert_eq!(sensor.unwrap(&msg).unwrap(), cmd);
+        }
+    }
+
+    #[test]
+    fn tampered() {
+        let (mut host, mut sensor) = pair();
+        let msg = host.wrap(b"\x19\x00").unwrap();
+
+        // Header (authenticated), data and tag
+        for i in [1, HEADER_SIZE, msg.len() - 1] {
+            let mut bad = msg.clone();
+            bad[i] ^= 1;
+            assert!(matches!(sensor.unwrap(&bad), Err(DriverError::WrapBadTag)));
+        }
+        assert_eq!(sensor.unwrap(&msg).unwrap(), b"\x19\x00");
+    }
+
+    #[test]
+    fn replayed() {
+        let (mut host, mut sensor) = pair();
+        let msg = host.wrap(b"\x01").unwrap();
+        sensor.unwrap(&msg).unwrap();
+        assert!(matches!(
+            sensor.unwrap(&msg),
+            Err(DriverError::WrapSequence {
+                expected: 1,
+                got: 0
+            })
+        ));
+    }
+}
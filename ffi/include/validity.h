@@ -0,0 +1,92 @@
+/*
+ * C bindings for the Validity fingerprint sensor driver, link with -lvalidity_sens_ffi
+ *
+ * Every function returns VS_OK or one of the (negative) VS_ERR_* codes, vs_last_error() gives the
+ * message of the last error of the calling thread. A vs_device must not be used by several
+ * threads at the same time.
+ */
+
+#ifndef VALIDITY_H
+#define VALIDITY_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define VS_OK 0
+#define VS_ERR_INVALID_ARGUMENT -1
+#define VS_ERR_NOT_FOUND -2
+#define VS_ERR_DISCONNECTED -3
+#define VS_ERR_AGAIN -4
+#define VS_ERR_FAILED -5
+#define VS_ERR_PANIC -6
+
+typedef struct vs_device vs_device;
+
+typedef struct {
+    uint16_t width;
+    uint16_t height;
+    /* Bits per pixel */
+    uint8_t bpp;
+    /* The pixels, row by row */
+    uint8_t *data;
+    size_t len;
+} vs_frame;
+
+typedef struct {
+    uint16_t template_id;
+    uint8_t finger_id;
+    uint16_t score;
+} vs_match;
+
+typedef struct {
+    /* Starting at 1 */
+    uint32_t sample;
+    uint16_t remaining;
+    /* 0-100 */
+    uint16_t quality;
+    /* 0-100 */
+    uint16_t coverage;
+} vs_enroll_progress;
+
+typedef void (*vs_enroll_cb)(const vs_enroll_progress *progress, void *user_data);
+
+/* Open and initialize the device at the given bus and address */
+int vs_open(uint8_t bus, uint8_t addr, vs_device **out);
+
+/* Open and initialize the first supported device */
+int vs_open_first(vs_device **out);
+
+/* Reset and release the device, dev may be NULL */
+void vs_close(vs_device *dev);
+
+/* Enroll a new finger, cb (may be NULL) is called after every touch */
+int vs_enroll(vs_device *dev, uint8_t finger_id, vs_enroll_cb cb, void *user_data,
+              uint16_t *template_id);
+
+/* Scan a finger and check it matches the template, result may be NULL */
+int vs_verify(vs_device *dev, uint16_t template_id, bool *matched, vs_match *result);
+
+/* Scan a finger and match it against every enrolled template, result may be NULL */
+int vs_identify(vs_device *dev, bool *matched, vs_match *result);
+
+/* Wait for a finger and read the raw image, release it with vs_frame_free() */
+int vs_capture(vs_device *dev, vs_frame **out);
+
+void vs_frame_free(vs_frame *frame);
+
+/* Message of the last error of the calling thread (NULL if none), valid until the next call */
+const char *vs_last_error(void);
+
+/* Static description of an error code */
+const char *vs_strerror(int code);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
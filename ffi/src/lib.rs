@@ -0,0 +1,295 @@
+//! C bindings for the driver, so lock screens and display managers written in C or C++ can use
+//! the sensor. The declarations are in `include/validity.h`, link with `-lvalidity_sens_ffi`.
+//!
+//! Every function returns `VS_OK` or one of the (negative) `VS_ERR_*` codes, the message of the
+//! last error of the calling thread is returned by `vs_last_error`. Devices are opaque handles,
+//! opened with `vs_open` (or `vs_open_first`) and released with `vs_close`. A handle must not be
+//! used by several threads at the same time.
+
+use driver::{
+    DriverError,
+    capture::Capture,
+    enroll::Enroll,
+    identify::Identify,
+    identify::MatchResult,
+    transport::Transport,
+    usb::{OpenOptions, OpenedUsbDevice, UsbDevice},
+};
+use std::{
+    cell::RefCell,
+    ffi::{CString, c_char, c_int, c_void},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+pub const VS_OK: c_int = 0;
+pub const VS_ERR_INVALID_ARGUMENT: c_int = -1;
+pub const VS_ERR_NOT_FOUND: c_int = -2;
+pub const VS_ERR_DISCONNECTED: c_int = -3;
+pub const VS_ERR_AGAIN: c_int = -4;
+pub const VS_ERR_FAILED: c_int = -5;
+pub const VS_ERR_PANIC: c_int = -6;
+
+thread_local! {
+    /// Message of the last error in this thread, see [`vs_last_error`]
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Opaque `vs_device`
+pub struct VsDevice(OpenedUsbDevice);
+
+/// `vs_frame`, allocated by [`vs_capture`] and released with [`vs_frame_free`]
+#[repr(C)]
+pub struct VsFrame {
+    pub width: u16,
+    pub height: u16,
+    pub bpp: u8,
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// `vs_match`
+#[repr(C)]
+pub struct VsMatch {
+    pub template_id: u16,
+    pub finger_id: u8,
+    pub score: u16,
+}
+
+/// `vs_enroll_progress`
+#[repr(C)]
+pub struct VsEnrollProgress {
+    pub sample: u32,
+    pub remaining: u16,
+    pub quality: u16,
+    pub coverage: u16,
+}
+
+/// `vs_enroll_cb`
+pub type VsEnrollCb = Option<unsafe extern "C" fn(*const VsEnrollProgress, *mut c_void)>;
+
+/// # Safety
+/// `out` must be a valid pointer
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vs_open(bus: u8, addr: u8, out: *mut *mut VsDevice) -> c_int {
+    guard(|| {
+        let out = unsafe { out.as_mut() }.ok_or(Invalid)?;
+        let dev = open(driver::get_device(bus, addr)?)?;
+        *out = Box::into_raw(Box::new(dev));
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `out` must be a valid pointer
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vs_open_first(out: *mut *mut VsDevice) -> c_int {
+    guard(|| {
+        let out = unsafe { out.as_mut() }.ok_or(Invalid)?;
+        let dev = driver::list_supported_devices()?
+            .into_iter()
+            .next()
+            .ok_or(DriverError::GetDeviceNotFound)?;
+        *out = Box::into_raw(Box::new(open(dev)?));
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `dev` must be null or a handle returned by `vs_open`, it can't be used afterwards
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vs_close(dev: *mut VsDevice) {
+    if !dev.is_null() {
+        // SAFETY: The handle was created with `Box::into_raw`
+        let mut dev = unsafe { Box::from_raw(dev) };
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| dev.0.reset()));
+    }
+}
+
+/// # Safety
+/// `dev` must be a valid handle and `template_id` a valid pointer, `cb` (if given) is called with
+/// `user_data` after every sample
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vs_enroll(
+    dev: *mut VsDevice,
+    finger_id: u8,
+    cb: VsEnrollCb,
+    user_data: *mut c_void,
+    template_id: *mut u16,
+) -> c_int {
+    guard(|| {
+        let dev = unsafe { dev.as_ref() }.ok_or(Invalid)?;
+        let template_id = unsafe { template_id.as_mut() }.ok_or(Invalid)?;
+
+        let id = dev.0.enroll(finger_id, |p| {
+            let p = VsEnrollProgress {
+                sample: p.sample,
+                remaining: p.remaining,
+                quality: p.quality,
+                coverage: p.coverage,
+            };
+            if let Some(cb) = cb {
+                // SAFETY: The caller gave us a valid callback
+                unsafe { cb(&p, user_data) };
+            }
+        })?;
+
+        *template_id = id.0;
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `dev` must be a valid handle and `matched` a valid pointer, `result` may be null
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vs_verify(
+    dev: *mut VsDevice,
+    template_id: u16,
+    matched: *mut bool,
+    result: *mut VsMatch,
+) -> c_int {
+    guard(|| {
+        let dev = unsafe { dev.as_ref() }.ok_or(Invalid)?;
+        let matched = unsafe { matched.as_mut() }.ok_or(Invalid)?;
+        let res = dev.0.verify(driver::enroll::TemplateId(template_id))?;
+        // SAFETY: The caller gives a valid pointer or null
+        fill_match(res, matched, unsafe { result.as_mut() });
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `dev` must be a valid handle and `matched` a valid pointer, `result` may be null
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vs_identify(
+    dev: *mut VsDevice,
+    matched: *mut bool,
+    result: *mut VsMatch,
+) -> c_int {
+    guard(|| {
+        let dev = unsafe { dev.as_ref() }.ok_or(Invalid)?;
+        let matched = unsafe { matched.as_mut() }.ok_or(Invalid)?;
+        let res = dev.0.identify()?;
+        // SAFETY: The caller gives a valid pointer or null
+        fill_match(res, matched, unsafe { result.as_mut() });
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `dev` must be a valid handle and `out` a valid pointer
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vs_capture(dev: *mut VsDevice, out: *mut *mut VsFrame) -> c_int {
+    guard(|| {
+        let dev = unsafe { dev.as_ref() }.ok_or(Invalid)?;
+        let out = unsafe { out.as_mut() }.ok_or(Invalid)?;
+        let frame = dev.0.capture()?;
+
+        let data = Box::into_raw(frame.data.into_boxed_slice());
+        *out = Box::into_raw(Box::new(VsFrame {
+            width: frame.width,
+            height: frame.height,
+            bpp: frame.bpp,
+            data: data as *mut u8,
+            len: data.len(),
+        }));
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `frame` must be null or a frame returned by `vs_capture`, it can't be used afterwards
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vs_frame_free(frame: *mut VsFrame) {
+    if !frame.is_null() {
+        // SAFETY: Both were created with `Box::into_raw` in `vs_capture`
+        unsafe {
+            let frame = Box::from_raw(frame);
+            let data = ptr::slice_from_raw_parts_mut(frame.data, frame.len);
+            drop(Box::from_raw(data));
+        }
+    }
+}
+
+/// Message of the last error in the calling thread (null if there was none), valid until the next
+/// call from the same thread
+#[unsafe(no_mangle)]
+pub extern "C" fn vs_last_error() -> *const c_char {
+    LAST_ERROR.with_borrow(|e| e.as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Description of an error code, as a static string
+#[unsafe(no_mangle)]
+pub extern "C" fn vs_strerror(code: c_int) -> *const c_char {
+    match code {
+        VS_OK => c"Success",
+        VS_ERR_INVALID_ARGUMENT => c"Invalid argument",
+        VS_ERR_NOT_FOUND => c"No supported device found",
+        VS_ERR_DISCONNECTED => c"The device is gone or can't be accessed",
+        VS_ERR_AGAIN => c"Temporary failure, try again",
+        VS_ERR_FAILED => c"The device failed",
+        VS_ERR_PANIC => c"Internal error",
+        _ => c"Unknown error",
+    }
+    .as_ptr()
+}
+
+/// A null pointer was given
+struct Invalid;
+
+/// Why a call failed
+enum Failure {
+    Invalid,
+    Driver(DriverError),
+}
+
+impl From<Invalid> for Failure {
+    fn from(_: Invalid) -> Self {
+        Self::Invalid
+    }
+}
+
+impl From<DriverError> for Failure {
+    fn from(e: DriverError) -> Self {
+        Self::Driver(e)
+    }
+}
+
+/// Run the call, turning errors and panics (which must never unwind into C) into error codes
+fn guard(f: impl FnOnce() -> Result<(), Failure>) -> c_int {
+    let (code, msg) = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return VS_OK,
+        Ok(Err(Failure::Invalid)) => (VS_ERR_INVALID_ARGUMENT, "Null pointer given".to_string()),
+        Ok(Err(Failure::Driver(e))) => (error_code(&e), e.to_string()),
+        Err(_) => (VS_ERR_PANIC, "The driver panicked".to_string()),
+    };
+
+    let msg = CString::new(msg).ok();
+    LAST_ERROR.with_borrow_mut(|e| *e = msg);
+    code
+}
+
+fn error_code(e: &DriverError) -> c_int {
+    match e.root() {
+        DriverError::GetDeviceNotFound | DriverError::GetDeviceFoundUnsupported => VS_ERR_NOT_FOUND,
+        _ if e.is_fatal() => VS_ERR_DISCONNECTED,
+        _ if e.is_transient() => VS_ERR_AGAIN,
+        _ => VS_ERR_FAILED,
+    }
+}
+
+/// Open and initialize the device
+fn open(dev: UsbDevice) -> Result<VsDevice, DriverError> {
+    dev.open_with(OpenOptions::new()).map(VsDevice)
+}
+
+fn fill_match(res: Option<MatchResult>, matched: &mut bool, out: Option<&mut VsMatch>) {
+    *matched = res.is_some();
+    if let (Some(m), Some(out)) = (res, out) {
+        *out = VsMatch {
+            template_id: m.template.0,
+            finger_id: m.finger_id,
+            score: m.score,
+        };
+    }
+}
@@ -0,0 +1,11 @@
+//! The frame header and the frame data split in several reads
+
+#![no_main]
+
+use driver::capture::Capture;
+use libfuzzer_sys::fuzz_target;
+use validity_sens_fuzz::FuzzTransport;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = FuzzTransport::new(data).read_frame();
+});
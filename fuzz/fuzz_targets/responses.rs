@@ -0,0 +1,42 @@
+//! Every command answered with arbitrary responses, the first byte selects what to run
+
+#![no_main]
+
+use driver::{
+    enroll::TemplateId,
+    prelude::*,
+    secure::{SecureSession, SessionParams},
+};
+use libfuzzer_sys::fuzz_target;
+use p256::SecretKey;
+use validity_sens_fuzz::FuzzTransport;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&op, data)) = data.split_first() else {
+        return;
+    };
+    let dev = FuzzTransport::new(data);
+
+    match op % 9 {
+        0 => drop(dev.send_init()),
+        1 => drop(dev.device_info()),
+        2 => drop(dev.partition_table()),
+        3 => drop(dev.list_templates()),
+        4 => drop(dev.identify()),
+        5 => drop(dev.verify(TemplateId(1))),
+        6 => drop(dev.enroll(1, |_| ())),
+        7 => drop(dev.read_calibration()),
+        _ => drop(SecureSession::establish(dev, &params())),
+    }
+});
+
+/// Fixed keys, the handshake fails long before they matter
+fn params() -> SessionParams {
+    let host_key = SecretKey::from_slice(&[1; 32]).unwrap();
+    let device_key = SecretKey::from_slice(&[2; 32]).unwrap().public_key();
+    SessionParams {
+        host_key: host_key.into(),
+        host_cert: vec![0; 72],
+        device_key,
+    }
+}
@@ -0,0 +1,19 @@
+//! The status code and the field reads of every response
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use validity_proto::Response;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut resp) = Response::parse(data) else {
+        return;
+    };
+
+    // A length followed by that many bytes, as most responses have
+    let len = resp.u16().unwrap_or(0);
+    let _ = resp.bytes(len as usize);
+    let _ = resp.u32();
+    let _ = resp.u8();
+    let _ = resp.rest();
+});
@@ -0,0 +1,60 @@
+//! A device answering every command with the fuzzer input, shared by the fuzz targets
+
+use driver::{
+    DriverError, proto::opcodes::INT_SCAN_COMPLETE, quirks::DeviceQuirks, transport::Transport,
+};
+use std::{cell::Cell, time::Duration};
+
+/// Every read gets the next chunk of the input, each chunk is prefixed by its length (u16)
+pub struct FuzzTransport<'a> {
+    data: Cell<&'a [u8]>,
+}
+
+impl<'a> FuzzTransport<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data: Cell::new(data),
+        }
+    }
+
+    fn next_chunk(&self) -> Option<&'a [u8]> {
+        let data = self.data.get();
+        let len = u16::from_le_bytes([*data.first()?, *data.get(1)?]) as usize;
+        let chunk = &data[2..];
+        let (chunk, rest) = chunk.split_at(len.min(chunk.len()));
+        self.data.set(rest);
+        Some(chunk)
+    }
+}
+
+impl Transport for FuzzTransport<'_> {
+    fn quirks(&self) -> &'static DeviceQuirks {
+        &driver::SUPPORTED[0]
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, DriverError> {
+        Ok(data.len())
+    }
+
+    fn read(&self, out: &mut [u8]) -> Result<usize, DriverError> {
+        // Timeout once the input is consumed, so every loop ends
+        let chunk = self
+            .next_chunk()
+            .ok_or(DriverError::UsbReadResponse(rusb::Error::Timeout))?;
+        let len = chunk.len().min(out.len());
+        out[..len].copy_from_slice(&chunk[..len]);
+        Ok(len)
+    }
+
+    fn wait_int(&self, out: &mut [u8], _timeout: Duration) -> Result<usize, DriverError> {
+        if self.data.get().is_empty() {
+            return Err(DriverError::UsbReadInterrupt(rusb::Error::Timeout));
+        }
+        out[0] = INT_SCAN_COMPLETE;
+        Ok(1)
+    }
+
+    fn reset(&mut self) -> Result<(), DriverError> {
+        Ok(())
+    }
+}
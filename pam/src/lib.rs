@@ -0,0 +1,215 @@
+//! PAM module authenticating with the fingerprint sensor, to use it copy the library to the PAM
+//! modules directory as `pam_validity.so` and add it before the password module, for example:
+//!
+//! ```text
+//! auth sufficient pam_validity.so timeout=10 retries=3 fallback
+//! auth required   pam_unix.so
+//! ```
+//!
+//! Options:
+//! - `timeout=SECS`: how long to wait for the finger on each try (default: 10)
+//! - `retries=N`: how many fingers to try before failing (default: 3)
+//! - `fallback`: if the sensor is missing or broken, ignore this module (so the next one, usually
+//!   the password, is used) instead of failing
+//! - `templates=DIR`: directory with a file per user listing (one per line) the templates that
+//!   user may authenticate with (default: `/var/lib/validity/templates`)
+
+use driver::{
+    DriverError,
+    enroll::TemplateId,
+    identify::Identify,
+    metadata::{self, MetadataStore},
+    storage::{Storage, UserNamespace},
+    transport::Transport,
+    usb::{OpenOptions, OpenedUsbDevice},
+};
+use std::{
+    ffi::{CStr, c_char, c_int, c_void},
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    ptr,
+    time::Duration,
+};
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+const PAM_USER_UNKNOWN: c_int = 10;
+const PAM_IGNORE: c_int = 25;
+
+/// Opaque `pam_handle_t`
+#[repr(C)]
+pub struct PamHandle {
+    _private: [u8; 0],
+}
+
+// Provided by libpam, which is already loaded by the application using this module
+unsafe extern "C" {
+    fn pam_get_user(pamh: *mut PamHandle, user: *mut *const c_char, prompt: *const c_char)
+    -> c_int;
+    fn pam_info(pamh: *mut PamHandle, fmt: *const c_char, ...) -> c_int;
+}
+
+/// The module options
+struct Config {
+    timeout: Duration,
+    retries: u32,
+    fallback: bool,
+    templates: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            retries: 3,
+            fallback: false,
+            templates: PathBuf::from(metadata::DEFAULT_DIR),
+        }
+    }
+}
+
+impl Config {
+    /// Parse the options, unknown ones are ignored
+    fn parse<'a>(args: impl Iterator<Item = &'a str>) -> Self {
+        let mut cfg = Self::default();
+
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("timeout", v)) => {
+                    if let Ok(secs) = v.parse() {
+                        cfg.timeout = Duration::from_secs(secs);
+                    }
+                }
+                Some(("retries", v)) => cfg.retries = v.parse().unwrap_or(cfg.retries),
+                Some(("templates", v)) => cfg.templates = PathBuf::from(v),
+                None if arg == "fallback" => cfg.fallback = true,
+                _ => (),
+            }
+        }
+
+        cfg
+    }
+
+    /// What to return when the sensor can't be used
+    fn unavailable(&self) -> c_int {
+        if self.fallback {
+            PAM_IGNORE
+        } else {
+            PAM_AUTHINFO_UNAVAIL
+        }
+    }
+
+    /// The templates the user can authenticate with
+    fn allowed_templates(&self, user: &str) -> Vec<TemplateId> {
+        MetadataStore::new(&self.templates).templates(user)
+    }
+}
+
+/// # Safety
+/// Called by libpam, with a valid handle and `argc` arguments
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pam_sm_authenticate(
+    pamh: *mut PamHandle,
+    _flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    // SAFETY: libpam gives us `argc` valid strings
+    let args = (0..argc.max(0) as usize)
+        .filter_map(|i| unsafe { CStr::from_ptr(*argv.add(i)) }.to_str().ok());
+    let cfg = Config::parse(args);
+
+    let mut user: *const c_char = ptr::null();
+    // SAFETY: `user` is only read if libpam says it was set
+    if unsafe { pam_get_user(pamh, &mut user, ptr::null()) } != PAM_SUCCESS || user.is_null() {
+        return PAM_USER_UNKNOWN;
+    }
+    let Ok(user) = unsafe { CStr::from_ptr(user) }.to_str() else {
+        return PAM_USER_UNKNOWN;
+    };
+
+    let allowed = cfg.allowed_templates(user);
+    if allowed.is_empty() {
+        // Nothing enrolled for this user, let the next module handle it
+        return cfg.unavailable();
+    }
+
+    // Never unwind into C
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        authenticate(pamh, &cfg, user, &allowed)
+    }))
+    .unwrap_or_else(|_| cfg.unavailable())
+}
+
+/// # Safety
+/// Called by libpam
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pam_sm_setcred(
+    _pamh: *mut PamHandle,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_SUCCESS
+}
+
+fn authenticate(pamh: *mut PamHandle, cfg: &Config, user: &str, allowed: &[TemplateId]) -> c_int {
+    let mut dev = match open(cfg) {
+        Ok(dev) => dev,
+        Err(_) => return cfg.unavailable(),
+    };
+
+    // Only match the user's fingers, unless they were enrolled before the namespaces
+    let namespace = MetadataStore::new(&cfg.templates)
+        .namespace(user)
+        .unwrap_or(UserNamespace::SHARED);
+    let namespaced = namespace != UserNamespace::SHARED
+        && match dev.list_templates_in(namespace) {
+            Ok(templates) => !templates.is_empty(),
+            Err(_) => return cfg.unavailable(),
+        };
+
+    let mut res = PAM_AUTH_ERR;
+    for _ in 0..cfg.retries {
+        info(pamh, c"Place your finger on the fingerprint sensor");
+
+        let matched = if namespaced {
+            dev.identify_in(namespace)
+        } else {
+            dev.identify()
+        };
+        match matched {
+            Ok(Some(m)) if allowed.contains(&m.template) => {
+                res = PAM_SUCCESS;
+                break;
+            }
+            Ok(_) => info(pamh, c"Fingerprint not recognized"),
+            Err(_) => {
+                res = cfg.unavailable();
+                break;
+            }
+        }
+    }
+
+    let _ = dev.reset();
+    res
+}
+
+/// Open and initialize the first supported device
+fn open(cfg: &Config) -> Result<OpenedUsbDevice, DriverError> {
+    let dev = driver::list_supported_devices()?
+        .into_iter()
+        .next()
+        .ok_or(DriverError::GetDeviceNotFound)?;
+
+    dev.open_with(OpenOptions::new().touch_timeout(cfg.timeout))
+}
+
+/// Show a message to the user
+fn info(pamh: *mut PamHandle, msg: &CStr) {
+    // SAFETY: The format string only uses `%s` with a valid string
+    unsafe {
+        pam_info(pamh, c"%s".as_ptr(), msg.as_ptr() as *const c_void);
+    }
+}
@@ -0,0 +1,669 @@
+//! The commands understood by the sensor and the responses it sends. Every command starts with
+//! an opcode byte followed by little endian fields, every response starts with a status code
+//! (u16, `0` on success) followed by the fields of the command.
+//!
+//! Only the encoding lives here (it is `no_std`, needs just `alloc`), talking to the device is
+//! left to the transports, like the USB one of the `driver` crate.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod opcodes;
+pub mod responses;
+
+use alloc::{vec, vec::Vec};
+use core::fmt;
+use opcodes::{ANY_RECORD, MATCH_TEMPLATE, MAX_FINGER, Opcode, REBOOT_RESTART, REGISTER_SIZE};
+
+/// A failure status sent by the sensor, only the codes seen so far are known, the rest are kept
+/// as [`Self::Unknown`]. The codes for a busy sensor and a bad parameter were not seen yet, so
+/// they end up there too
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    /// The signature of the firmware (or of a partition) is wrong (`0x044f`)
+    SignatureFailed,
+
+    /// The record (a user, a template, ...) does not exist (`0x04b3`)
+    NotFound,
+
+    /// No room left for another record, sent by [`Command::NewFinger`] (`0x04b6`)
+    StorageFull,
+
+    /// There is no firmware in the partition, sent by [`Command::GetFirmwareInfo`] (`0xb004`)
+    NoFirmware,
+
+    /// A code not decoded yet, like the ones for a busy sensor or a bad parameter
+    Unknown(u16),
+}
+
+impl StatusCode {
+    /// Decode the status, `None` for the success one (`0`)
+    pub fn from_u16(code: u16) -> Option<Self> {
+        Some(match code {
+            0 => return None,
+            0x044f => Self::SignatureFailed,
+            0x04b3 => Self::NotFound,
+            0x04b6 => Self::StorageFull,
+            0xb004 => Self::NoFirmware,
+            code => Self::Unknown(code),
+        })
+    }
+
+    /// The raw code sent by the sensor
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::SignatureFailed => 0x044f,
+            Self::NotFound => 0x04b3,
+            Self::StorageFull => 0x04b6,
+            Self::NoFirmware => 0xb004,
+            Self::Unknown(code) => *code,
+        }
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SignatureFailed => write!(f, "Signature validation failed")?,
+            Self::NotFound => write!(f, "Not found")?,
+            Self::StorageFull => write!(f, "Storage full")?,
+            Self::NoFirmware => write!(f, "No firmware")?,
+            Self::Unknown(_) => write!(f, "Failed")?,
+        }
+        write!(f, ", code: {:04x}", self.code())
+    }
+}
+
+/// What the sensor should do with the next scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CaptureMode {
+    /// Keep the raw image, so it can be read with [`Command::ReadFrame`]
+    Image = 0x00,
+
+    /// Use the scan as an enrollment sample
+    Enroll = 0x01,
+
+    /// Match the scan against the stored templates
+    Identify = 0x02,
+}
+
+/// How much of the sensor stays powered, see [`Command::SetPowerState`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PowerState {
+    /// Fully powered, ready for commands and scans
+    Active = 0x00,
+
+    /// The sensor is off, commands wake it up
+    Idle = 0x01,
+
+    /// Most of the chip is off, waking up takes longer
+    DeepSleep = 0x02,
+
+    /// Idle, but armed so a touch wakes it up (and sends a finger down interrupt)
+    WakeOnFinger = 0x03,
+}
+
+/// A response without a successful status, see [`Response::parse`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusError {
+    /// The response is too short to have a status
+    Missing,
+
+    /// The command failed with this status
+    Failed(StatusCode),
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "Response without a status"),
+            Self::Failed(status) => status.fmt(f),
+        }
+    }
+}
+
+impl core::error::Error for StatusError {}
+
+/// A response not matching the layout of its command, see [`Command::check_response`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedResponse {
+    /// Too short for the fields, or for the counts and sizes it has
+    Short {
+        /// Length of the response, without the status
+        got: usize,
+
+        /// The length its fields need
+        expected: usize,
+    },
+
+    /// A field with a value out of its range (a record `0xffff`, a finger over 10, ...)
+    OutOfRange { field: &'static str, value: u32 },
+}
+
+impl fmt::Display for MalformedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Short { got, expected } => write!(f, "Got {got} bytes, expected {expected}"),
+            Self::OutOfRange { field, value } => write!(f, "Field {field} out of range: {value}"),
+        }
+    }
+}
+
+impl core::error::Error for MalformedResponse {}
+
+/// A command sent to the sensor, see [`Command::to_bytes`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    /// Get the version of the ROM, the first command sent (`0x01`)
+    GetVersion,
+
+    /// Finish the initialization of the sensor (`0x19`)
+    Init,
+
+    /// Get the internal status of the firmware: uptime, last error, temperature, ... (`0x1a`)
+    GetDiagnostics,
+
+    /// Reboot the sensor, the device disconnects and enumerates again (`0x05 0x02 0x00`)
+    Reboot,
+
+    /// Read a 32 bits hardware register (`0x07`)
+    ReadRegister { addr: u32 },
+
+    /// Write a 32 bits hardware register (`0x08`)
+    WriteRegister { addr: u32, value: u32 },
+
+    /// Erase the pairing, the records and the firmware extension (`0x10`)
+    FactoryReset,
+
+    /// Arm the sensor for the next scan (`0x02`)
+    CaptureStart(CaptureMode),
+
+    /// Disarm the sensor, dropping the scan in progress (`0x04`)
+    CaptureStop,
+
+    /// Read the last captured frame (`0x0d`)
+    ReadFrame,
+
+    /// Run the built-in diagnostics of the firmware (`0x36`)
+    SelfTest,
+
+    /// Read `size` bytes of the one-time programmable memory at `addr` (`0x38`)
+    ReadOtp { addr: u16, size: u16 },
+
+    /// Run a LED script (`0x39`)
+    LedCtrl(&'a [u8]),
+
+    /// Change the power state of the sensor (`0x3a`)
+    SetPowerState(PowerState),
+
+    /// Get the size of the flash and its partitions (`0x3e`)
+    GetFlashInfo,
+
+    /// Erase a flash partition (`0x3f`)
+    EraseFlash { partition: u8 },
+
+    /// Read `size` bytes at `addr`, relative to the partition start (`0x40`)
+    ReadFlash { partition: u8, addr: u32, size: u32 },
+
+    /// Write the data at `addr`, relative to the partition start (`0x41`)
+    WriteFlash {
+        partition: u8,
+        addr: u32,
+        data: &'a [u8],
+    },
+
+    /// Write the signature of a partition (`0x42`)
+    WriteSignature { partition: u8, signature: &'a [u8] },
+
+    /// Get the version of the firmware stored in a partition (`0x43`)
+    GetFirmwareInfo { partition: u8 },
+
+    /// List the finger records stored in the flash (`0x46`)
+    ListRecords,
+
+    /// Store a new finger record for the given template, under the user (`0x47`). User `0` is
+    /// the shared one.
+    NewFinger {
+        user: u16,
+        finger_id: u8,
+        template: &'a [u8],
+    },
+
+    /// Delete a finger record (`0x48`)
+    DeleteRecord(u16),
+
+    /// Delete every finger record and overwrite their storage (`0x4f`)
+    WipeRecords,
+
+    /// Send the host certificate to pair with the sensor (`0x50`)
+    Pair(&'a [u8]),
+
+    /// Match the scanned finger against a template, `0xffff` for any (`0x5e`)
+    Match(u16),
+
+    /// Get the result of the last match (`0x60`)
+    MatchResult,
+
+    /// Free the resources used by the last match (`0x62`)
+    MatchCleanup,
+
+    /// Prepare the sensor for the next enrollment sample (`0x68`)
+    EnrollUpdateStart { key: u32 },
+
+    /// Start (`true`) or end (`false`) an enrollment (`0x69`)
+    Enroll(bool),
+
+    /// Merge the last sample into the template being built (`0x6b`)
+    EnrollUpdate { key: u32 },
+
+    /// Anything else, sent as is
+    Raw(&'a [u8]),
+}
+
+impl Command<'_> {
+    /// The opcode of the command, `None` for a [`Self::Raw`] one not known yet
+    pub fn kind(&self) -> Option<Opcode> {
+        Some(match self {
+            Self::GetVersion => Opcode::GetVersion,
+            Self::Init => Opcode::Init,
+            Self::GetDiagnostics => Opcode::GetDiagnostics,
+            Self::Reboot => Opcode::Reboot,
+            Self::ReadRegister { .. } => Opcode::ReadRegister,
+            Self::WriteRegister { .. } => Opcode::WriteRegister,
+            Self::CaptureStart(_) => Opcode::CaptureStart,
+            Self::CaptureStop => Opcode::CaptureStop,
+            Self::ReadFrame => Opcode::ReadFrame,
+            Self::FactoryReset => Opcode::FactoryReset,
+            Self::SelfTest => Opcode::SelfTest,
+            Self::ReadOtp { .. } => Opcode::ReadOtp,
+            Self::LedCtrl(_) => Opcode::LedCtrl,
+            Self::SetPowerState(_) => Opcode::SetPowerState,
+            Self::GetFlashInfo => Opcode::GetFlashInfo,
+            Self::EraseFlash { .. } => Opcode::EraseFlash,
+            Self::ReadFlash { .. } => Opcode::ReadFlash,
+            Self::WriteFlash { .. } => Opcode::WriteFlash,
+            Self::WriteSignature { .. } => Opcode::WriteSignature,
+            Self::GetFirmwareInfo { .. } => Opcode::GetFirmwareInfo,
+            Self::ListRecords => Opcode::ListRecords,
+            Self::NewFinger { .. } => Opcode::NewFinger,
+            Self::DeleteRecord(_) => Opcode::DeleteRecord,
+            Self::WipeRecords => Opcode::WipeRecords,
+            Self::Pair(_) => Opcode::Pair,
+            Self::Match(_) => Opcode::Match,
+            Self::MatchResult => Opcode::MatchResult,
+            Self::MatchCleanup => Opcode::MatchCleanup,
+            Self::EnrollUpdateStart { .. } => Opcode::EnrollUpdateStart,
+            Self::Enroll(_) => Opcode::Enroll,
+            Self::EnrollUpdate { .. } => Opcode::EnrollUpdate,
+            Self::Raw(data) => return data.first().copied().and_then(Opcode::from_u8),
+        })
+    }
+
+    /// The first byte of the command
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Self::Raw(data) => data.first().copied().unwrap_or(0),
+            cmd => cmd.kind().map_or(0, |opcode| opcode as u8),
+        }
+    }
+
+    /// Serialize the command, as written to the device
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if let Self::Raw(data) = self {
+            return data.to_vec();
+        }
+
+        let mut res = vec![self.opcode()];
+        match *self {
+            Self::GetVersion
+            | Self::Init
+            | Self::GetDiagnostics
+            | Self::ReadFrame
+            | Self::CaptureStop
+            | Self::GetFlashInfo
+            | Self::SelfTest
+            | Self::MatchCleanup
+            | Self::WipeRecords
+            | Self::Raw(_) => {}
+            Self::Reboot => res.extend([REBOOT_RESTART, 0x00]),
+            Self::FactoryReset => res.extend([0x00; 0x61]),
+            Self::ReadRegister { addr } => {
+                res.extend(addr.to_le_bytes());
+                res.push(REGISTER_SIZE);
+            }
+            Self::WriteRegister { addr, value } => {
+                res.extend(addr.to_le_bytes());
+                res.extend(value.to_le_bytes());
+                res.push(REGISTER_SIZE);
+            }
+            Self::CaptureStart(mode) => res.push(mode as u8),
+            Self::ReadOtp { addr, size } => {
+                res.extend(addr.to_le_bytes());
+                res.extend(size.to_le_bytes());
+            }
+            Self::LedCtrl(script) => res.extend(script),
+            Self::SetPowerState(state) => res.push(state as u8),
+            Self::EraseFlash { partition } => res.push(partition),
+            Self::ReadFlash {
+                partition,
+                addr,
+                size,
+            } => {
+                // partition (u8), 1 (u8), 0 (u16), address (u32), size (u32)
+                res.extend([partition, 1, 0, 0]);
+                res.extend(addr.to_le_bytes());
+                res.extend(size.to_le_bytes());
+            }
+            Self::WriteFlash {
+                partition,
+                addr,
+                data,
+            } => {
+                // Same as the read, followed by the data
+                res.extend([partition, 1, 0, 0]);
+                res.extend(addr.to_le_bytes());
+                res.extend((data.len() as u32).to_le_bytes());
+                res.extend(data);
+            }
+            Self::WriteSignature {
+                partition,
+                signature,
+            } => {
+                res.extend([partition, 0]);
+                res.extend((signature.len() as u16).to_le_bytes());
+                res.extend(signature);
+            }
+            Self::GetFirmwareInfo { partition } => res.push(partition),
+            Self::ListRecords => res.extend([0x00, 0x00]),
+            Self::NewFinger {
+                user,
+                finger_id,
+                template,
+            } => {
+                res.extend(user.to_le_bytes());
+                res.push(finger_id);
+                res.extend((template.len() as u16).to_le_bytes());
+                res.extend(template);
+            }
+            Self::DeleteRecord(id) => res.extend(id.to_le_bytes()),
+            Self::Pair(cert) => res.extend(cert),
+            Self::Match(template) => {
+                res.push(MATCH_TEMPLATE);
+                res.extend(template.to_le_bytes());
+            }
+            Self::MatchResult => res.extend([0x00; 4]),
+            Self::EnrollUpdateStart { key } => {
+                res.extend(key.to_le_bytes());
+                res.extend(0u32.to_le_bytes());
+            }
+            Self::Enroll(start) => res.extend((start as u32).to_le_bytes()),
+            Self::EnrollUpdate { key } => res.extend(key.to_le_bytes()),
+        }
+        res
+    }
+
+    /// Check the response (without the status) has every field of the layout of the command
+    /// (see [`opcodes`]), the counts and sizes it has included. Commands not known are not
+    /// checked.
+    pub fn check_response(&self, data: &[u8]) -> Result<(), MalformedResponse> {
+        let u16_at = |at: usize| match data.get(at..at + 2) {
+            Some(b) => u16::from_le_bytes([b[0], b[1]]) as usize,
+            None => 0,
+        };
+
+        let expected = match *self {
+            Self::GetVersion => 11,
+            Self::ReadRegister { .. } => 4,
+            // Only the header, the pixels come in the next reads
+            Self::ReadFrame => 10,
+            Self::GetDiagnostics => 9,
+            Self::SelfTest => 3,
+            Self::ReadOtp { size, .. } => size as usize,
+            Self::GetFlashInfo => 14 + u16_at(12) * 12,
+            Self::ReadFlash { size, .. } => 6 + size as usize,
+            Self::GetFirmwareInfo { .. } => 10,
+            Self::ListRecords => 2 + u16_at(0) * 6,
+            Self::NewFinger { .. } => 2,
+            Self::Pair(_) => 2 + u16_at(0),
+            Self::MatchResult => 6,
+            Self::EnrollUpdateStart { .. } => 4,
+            Self::EnrollUpdate { .. } => 8 + u16_at(6),
+            _ => 0,
+        };
+
+        if data.len() < expected {
+            return Err(MalformedResponse::Short {
+                got: data.len(),
+                expected,
+            });
+        }
+
+        let u8_at = |at: usize| data[at] as u32;
+        let u16_at = |at: usize| u16_at(at) as u32;
+        let (any, max_finger) = (ANY_RECORD as u32, MAX_FINGER as u32);
+        let check = |field: &'static str, value: u32, valid: bool| match valid {
+            true => Ok(()),
+            false => Err(MalformedResponse::OutOfRange { field, value }),
+        };
+
+        match *self {
+            // A wrong size is left to the integrity checks, but not one past the bytes
+            Self::ReadFlash { .. } => {
+                let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                check("size", size, size as usize <= data.len() - 6)
+            }
+            Self::ListRecords => (0..u16_at(0) as usize).try_for_each(|i| {
+                let at = 2 + i * 6;
+                check("record", u16_at(at), u16_at(at) != any)?;
+                check("finger", u8_at(at + 4), u8_at(at + 4) <= max_finger)
+            }),
+            Self::NewFinger { .. } => check("record", u16_at(0), u16_at(0) != any),
+            Self::MatchResult => {
+                check("matched", u8_at(0), u8_at(0) <= 1)?;
+                check("finger", u8_at(1), u8_at(1) <= max_finger)?;
+                // Nothing matched has no record
+                check("record", u16_at(2), u8_at(0) == 0 || u16_at(2) != any)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A response with a successful status, the fields are read in order with [`Self::u8`],
+/// [`Self::u16`], ... which return `None` once the response is too short.
+#[derive(Debug, Clone)]
+pub struct Response<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Response<'a> {
+    /// Check the status code and skip it
+    pub fn parse(resp: &'a [u8]) -> Result<Self, StatusError> {
+        let (status, data) = resp.split_first_chunk().ok_or(StatusError::Missing)?;
+
+        match StatusCode::from_u16(u16::from_le_bytes(*status)) {
+            None => Ok(Self { data }),
+            Some(status) => Err(StatusError::Failed(status)),
+        }
+    }
+
+    /// Read fields from data without a status code (like the one stored in the flash)
+    pub fn raw(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.array().map(u8::from_le_bytes)
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    /// The next `len` bytes
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (res, rest) = self.data.split_at_checked(len)?;
+        self.data = rest;
+        Some(res)
+    }
+
+    /// Everything not read yet
+    pub fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (res, rest) = self.data.split_first_chunk()?;
+        self.data = rest;
+        Some(*res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_status() {
+        let mut resp = Response::parse(&[0, 0, 0x34, 0x12]).unwrap();
+        assert_eq!(resp.u16(), Some(0x1234));
+        assert_eq!(resp.u8(), None);
+
+        assert_eq!(
+            Response::parse(&[0xb3, 0x04]).unwrap_err(),
+            StatusError::Failed(StatusCode::NotFound)
+        );
+        assert_eq!(Response::parse(&[0]).unwrap_err(), StatusError::Missing);
+    }
+
+    #[test]
+    fn check_fixed_response() {
+        assert_eq!(Command::GetVersion.check_response(&[0; 11]), Ok(()));
+        assert_eq!(
+            Command::GetVersion.check_response(&[0; 10]),
+            Err(MalformedResponse::Short {
+                got: 10,
+                expected: 11
+            })
+        );
+        assert!(
+            Command::EnrollUpdateStart { key: 0 }
+                .check_response(&[0; 3])
+                .is_err()
+        );
+        // Nothing expected
+        assert_eq!(Command::CaptureStop.check_response(&[]), Ok(()));
+    }
+
+    #[test]
+    fn check_counted_response() {
+        // Two records of 6 bytes
+        let mut data = [0u8; 14];
+        data[0] = 2;
+        assert_eq!(Command::ListRecords.check_response(&data), Ok(()));
+        assert_eq!(
+            Command::ListRecords.check_response(&data[..13]),
+            Err(MalformedResponse::Short {
+                got: 13,
+                expected: 14
+            })
+        );
+    }
+
+    fn out_of_range(field: &'static str, value: u32) -> Result<(), MalformedResponse> {
+        Err(MalformedResponse::OutOfRange { field, value })
+    }
+
+    #[test]
+    fn check_record_ids() {
+        // One record: id 3, user 0, finger 2, flags 0
+        let mut data = [1, 0, 3, 0, 0, 0, 2, 0];
+        assert_eq!(Command::ListRecords.check_response(&data), Ok(()));
+        data[2..4].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(
+            Command::ListRecords.check_response(&data),
+            out_of_range("record", 0xffff)
+        );
+
+        let new = Command::NewFinger {
+            user: 0,
+            finger_id: 1,
+            template: &[],
+        };
+        assert_eq!(new.check_response(&[3, 0]), Ok(()));
+        assert_eq!(
+            new.check_response(&[0xff, 0xff]),
+            out_of_range("record", 0xffff)
+        );
+    }
+
+    #[test]
+    fn check_finger_ids() {
+        let data = [1, 0, 3, 0, 0, 0, 11, 0];
+        assert_eq!(
+            Command::ListRecords.check_response(&data),
+            out_of_range("finger", 11)
+        );
+        assert_eq!(
+            Command::MatchResult.check_response(&[1, 11, 3, 0, 0, 0]),
+            out_of_range("finger", 11)
+        );
+    }
+
+    #[test]
+    fn check_match_result() {
+        assert_eq!(
+            Command::MatchResult.check_response(&[1, 2, 3, 0, 9, 0]),
+            Ok(())
+        );
+        // Nothing matched, any record
+        assert_eq!(
+            Command::MatchResult.check_response(&[0, 0, 0xff, 0xff, 0, 0]),
+            Ok(())
+        );
+        assert_eq!(
+            Command::MatchResult.check_response(&[2, 2, 3, 0, 9, 0]),
+            out_of_range("matched", 2)
+        );
+        assert_eq!(
+            Command::MatchResult.check_response(&[1, 2, 0xff, 0xff, 9, 0]),
+            out_of_range("record", 0xffff)
+        );
+    }
+
+    #[test]
+    fn check_flash_size() {
+        let read = Command::ReadFlash {
+            partition: 1,
+            addr: 0,
+            size: 4,
+        };
+        assert_eq!(read.check_response(&[4, 0, 0, 0, 0, 0, 1, 2, 3, 4]), Ok(()));
+        // More bytes than the response has
+        assert_eq!(
+            read.check_response(&[5, 0, 0, 0, 0, 0, 1, 2, 3, 4]),
+            out_of_range("size", 5)
+        );
+    }
+
+    #[test]
+    fn check_count_past_the_end() {
+        // Says 3 records, has 2
+        let mut data = [0u8; 14];
+        data[0] = 3;
+        assert_eq!(
+            Command::ListRecords.check_response(&data),
+            Err(MalformedResponse::Short {
+                got: 14,
+                expected: 20
+            })
+        );
+    }
+}
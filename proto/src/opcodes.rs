@@ -0,0 +1,176 @@
+//! Every opcode known so far, with the layout of its request (after the opcode) and of its
+//! response (after the status). The fields are little endian, [`Command`](crate::Command)
+//! encodes the requests and the fixed responses are decoded by [`crate::responses`].
+
+/// The first byte of a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Opcode {
+    /// Request: nothing. Response: build time (u32), build (u32), major (u8), minor (u8),
+    /// hardware id (u8) and some bytes not known yet.
+    GetVersion = 0x01,
+
+    /// Request: mode (u8, see [`CaptureMode`](crate::CaptureMode)). Response: nothing, an
+    /// interrupt follows once the finger was scanned.
+    CaptureStart = 0x02,
+
+    /// Request: nothing. Response: nothing.
+    CaptureStop = 0x04,
+
+    /// Request: sub-command (u8, [`REBOOT_RESTART`]), 0 (u8). Response: none, the device
+    /// disconnects.
+    Reboot = 0x05,
+
+    /// Request: address (u32), size ([`REGISTER_SIZE`]). Response: value (u32).
+    ReadRegister = 0x07,
+
+    /// Request: address (u32), value (u32), size ([`REGISTER_SIZE`]). Response: nothing.
+    WriteRegister = 0x08,
+
+    /// Request: nothing. Response: width (u16), height (u16), bits per pixel (u8), a byte not
+    /// known, size (u32) and the pixels, split in several bulk reads.
+    ReadFrame = 0x0d,
+
+    /// Request: 0x61 zeros. Response: nothing.
+    FactoryReset = 0x10,
+
+    /// Request: nothing. Response: nothing.
+    Init = 0x19,
+
+    /// Request: nothing. Response: uptime in milliseconds (u32), last status (u16),
+    /// temperature in hundredths of degree (i16), reset reason (u8).
+    GetDiagnostics = 0x1a,
+
+    /// Request: nothing. Response: failed tests (u8, a bit each), dead pixels (u16).
+    SelfTest = 0x36,
+
+    /// Request: address (u16), size (u16). Response: the bytes.
+    ReadOtp = 0x38,
+
+    /// Request: the LED script. Response: nothing.
+    LedCtrl = 0x39,
+
+    /// Request: state (u8, see [`PowerState`](crate::PowerState)). Response: nothing.
+    SetPowerState = 0x3a,
+
+    /// Request: nothing. Response: the flash geometry and the partition table.
+    GetFlashInfo = 0x3e,
+
+    /// Request: partition (u8). Response: nothing.
+    EraseFlash = 0x3f,
+
+    /// Request: partition (u8), 1 (u8), 0 (u16), address (u32), size (u32). Response: size
+    /// (u32), CRC-16 of the bytes (u16, `0` on older firmwares) and the bytes.
+    ReadFlash = 0x40,
+
+    /// Request: like [`Self::ReadFlash`], followed by the bytes. Response: nothing.
+    WriteFlash = 0x41,
+
+    /// Request: partition (u8), 0 (u8), size (u16), the signature. Response: nothing.
+    WriteSignature = 0x42,
+
+    /// Request: partition (u8). Response: major (u16), minor (u16), modules (u16), build time
+    /// (u32), ... or [`StatusCode::NoFirmware`](crate::StatusCode::NoFirmware).
+    GetFirmwareInfo = 0x43,
+
+    /// Request: 0 (u16). Response: count (u16) and an entry per record: record (u16), user
+    /// (u16), finger (u8), flags (u8).
+    ListRecords = 0x46,
+
+    /// Request: user (u16), finger (u8), size (u16), the template. Response: record (u16).
+    NewFinger = 0x47,
+
+    /// Request: record (u16). Response: nothing.
+    DeleteRecord = 0x48,
+
+    /// Request: nothing. Response: nothing.
+    WipeRecords = 0x4f,
+
+    /// Request: the host certificate. Response: key size (u16) and the public key of the
+    /// sensor.
+    Pair = 0x50,
+
+    /// Request: sub-command (u8, [`MATCH_TEMPLATE`]), record (u16, `0xffff` for any).
+    /// Response: nothing, an interrupt follows once the finger was scanned.
+    Match = 0x5e,
+
+    /// Request: 0 (u32). Response: matched (u8), finger (u8), record (u16), score (u16).
+    MatchResult = 0x60,
+
+    /// Request: nothing. Response: nothing.
+    MatchCleanup = 0x62,
+
+    /// Request: key (u32), 0 (u32). Response: the key of the next [`Self::EnrollUpdate`] (u32).
+    EnrollUpdateStart = 0x68,
+
+    /// Request: start (u32, `1`) or end (u32, `0`). Response: nothing.
+    Enroll = 0x69,
+
+    /// Request: key (u32). Response: quality (u16), coverage (u16), remaining (u16), template
+    /// size (u16) and the template, once the enrollment finished.
+    EnrollUpdate = 0x6b,
+}
+
+impl Opcode {
+    /// Decode the first byte of a command, `None` for the ones not known yet
+    pub fn from_u8(opcode: u8) -> Option<Self> {
+        Some(match opcode {
+            0x01 => Self::GetVersion,
+            0x02 => Self::CaptureStart,
+            0x04 => Self::CaptureStop,
+            0x05 => Self::Reboot,
+            0x07 => Self::ReadRegister,
+            0x08 => Self::WriteRegister,
+            0x0d => Self::ReadFrame,
+            0x10 => Self::FactoryReset,
+            0x19 => Self::Init,
+            0x1a => Self::GetDiagnostics,
+            0x36 => Self::SelfTest,
+            0x38 => Self::ReadOtp,
+            0x39 => Self::LedCtrl,
+            0x3a => Self::SetPowerState,
+            0x3e => Self::GetFlashInfo,
+            0x3f => Self::EraseFlash,
+            0x40 => Self::ReadFlash,
+            0x41 => Self::WriteFlash,
+            0x42 => Self::WriteSignature,
+            0x43 => Self::GetFirmwareInfo,
+            0x46 => Self::ListRecords,
+            0x47 => Self::NewFinger,
+            0x48 => Self::DeleteRecord,
+            0x4f => Self::WipeRecords,
+            0x50 => Self::Pair,
+            0x5e => Self::Match,
+            0x60 => Self::MatchResult,
+            0x62 => Self::MatchCleanup,
+            0x68 => Self::EnrollUpdateStart,
+            0x69 => Self::Enroll,
+            0x6b => Self::EnrollUpdate,
+            _ => return None,
+        })
+    }
+}
+
+/// Sub-command of [`Opcode::Reboot`]: restart the firmware
+pub const REBOOT_RESTART: u8 = 0x02;
+
+/// Sub-command of [`Opcode::Match`]: match against the stored templates
+pub const MATCH_TEMPLATE: u8 = 0x02;
+
+/// Record of [`Opcode::Match`] matching any template, never the id of a stored one
+pub const ANY_RECORD: u16 = 0xffff;
+
+/// Highest finger id of the records, `0` is an unknown finger
+pub const MAX_FINGER: u8 = 10;
+
+/// Size of the registers, sent with [`Opcode::ReadRegister`] and [`Opcode::WriteRegister`]
+pub const REGISTER_SIZE: u8 = 4;
+
+/// Interrupt sent by the sensor when a finger touches it
+pub const INT_FINGER_DOWN: u8 = 0x02;
+
+/// Interrupt sent by the sensor once a finger was scanned
+pub const INT_SCAN_COMPLETE: u8 = 0x03;
+
+/// Interrupt sent by the sensor when the finger is removed
+pub const INT_FINGER_UP: u8 = 0x04;
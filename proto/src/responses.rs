@@ -0,0 +1,196 @@
+//! The responses with a fixed layout (see [`Opcode`](crate::opcodes::Opcode)), decoded from a
+//! [`Response`]. Each `parse` returns `None` if the response is too short, the fields are only
+//! decoded, giving them a meaning is left to the `driver` crate.
+
+use crate::Response;
+
+/// The response to [`Command::GetVersion`](crate::Command::GetVersion), the bytes after the
+/// hardware id are not known yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    pub build_time: u32,
+    pub build: u32,
+    pub major: u8,
+    pub minor: u8,
+    pub hardware_id: u8,
+}
+
+impl Version {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        Some(Self {
+            build_time: resp.u32()?,
+            build: resp.u32()?,
+            major: resp.u8()?,
+            minor: resp.u8()?,
+            hardware_id: resp.u8()?,
+        })
+    }
+}
+
+/// The response to [`Command::ReadRegister`](crate::Command::ReadRegister)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Register {
+    pub value: u32,
+}
+
+impl Register {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        Some(Self { value: resp.u32()? })
+    }
+}
+
+/// The header of the response to [`Command::ReadFrame`](crate::Command::ReadFrame), the pixels
+/// follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub width: u16,
+    pub height: u16,
+    pub bpp: u8,
+
+    /// Size of the pixels
+    pub size: u32,
+}
+
+impl FrameHeader {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        let width = resp.u16()?;
+        let height = resp.u16()?;
+        let bpp = resp.u8()?;
+        // Not known
+        resp.u8()?;
+        Some(Self {
+            width,
+            height,
+            bpp,
+            size: resp.u32()?,
+        })
+    }
+}
+
+/// The response to [`Command::GetDiagnostics`](crate::Command::GetDiagnostics)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub uptime_ms: u32,
+
+    /// `0` if there was no error
+    pub last_status: u16,
+
+    /// In hundredths of degree
+    pub temperature: i16,
+    pub reset_reason: u8,
+}
+
+impl Diagnostics {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        Some(Self {
+            uptime_ms: resp.u32()?,
+            last_status: resp.u16()?,
+            temperature: resp.u16()? as i16,
+            reset_reason: resp.u8()?,
+        })
+    }
+}
+
+/// The response to [`Command::SelfTest`](crate::Command::SelfTest)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTest {
+    /// A bit per failed test
+    pub failed: u8,
+    pub dead_pixels: u16,
+}
+
+impl SelfTest {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        Some(Self {
+            failed: resp.u8()?,
+            dead_pixels: resp.u16()?,
+        })
+    }
+}
+
+/// The header of the response to [`Command::ReadFlash`](crate::Command::ReadFlash), the bytes
+/// follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashHeader {
+    pub size: u32,
+
+    /// CRC-16 of the bytes, `0` on older firmwares
+    pub crc: u16,
+}
+
+impl FlashHeader {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        Some(Self {
+            size: resp.u32()?,
+            crc: resp.u16()?,
+        })
+    }
+}
+
+/// The response to [`Command::NewFinger`](crate::Command::NewFinger)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewRecord {
+    pub record: u16,
+}
+
+impl NewRecord {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        Some(Self {
+            record: resp.u16()?,
+        })
+    }
+}
+
+/// The response to [`Command::MatchResult`](crate::Command::MatchResult)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchResult {
+    pub matched: bool,
+    pub finger: u8,
+    pub record: u16,
+    pub score: u16,
+}
+
+impl MatchResult {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        Some(Self {
+            matched: resp.u8()? != 0,
+            finger: resp.u8()?,
+            record: resp.u16()?,
+            score: resp.u16()?,
+        })
+    }
+}
+
+/// The response to [`Command::EnrollUpdateStart`](crate::Command::EnrollUpdateStart): the key
+/// of the next [`Command::EnrollUpdate`](crate::Command::EnrollUpdate)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnrollKey {
+    pub key: u32,
+}
+
+impl EnrollKey {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        Some(Self { key: resp.u32()? })
+    }
+}
+
+/// The fixed part of the response to [`Command::EnrollUpdate`](crate::Command::EnrollUpdate),
+/// the template follows once the enrollment finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnrollUpdate {
+    pub quality: u16,
+    pub coverage: u16,
+    pub remaining: u16,
+    pub template_size: u16,
+}
+
+impl EnrollUpdate {
+    pub fn parse(resp: &mut Response<'_>) -> Option<Self> {
+        Some(Self {
+            quality: resp.u16()?,
+            coverage: resp.u16()?,
+            remaining: resp.u16()?,
+            template_size: resp.u16()?,
+        })
+    }
+}
@@ -0,0 +1,161 @@
+//! Python bindings for the driver, build them with `maturin build` (or `maturin develop`), for
+//! example:
+//!
+//! ```python
+//! import validity_sens
+//!
+//! print(validity_sens.list_devices())
+//! dev = validity_sens.Device.open()
+//! tid = dev.enroll(1, lambda sample, remaining, quality, coverage: print(sample, remaining))
+//! print(dev.verify(tid))
+//! ```
+//!
+//! The blocking calls release the GIL while they wait for the finger.
+
+use driver::{
+    DriverError,
+    enroll::{Enroll, TemplateId},
+    identify::{Identify, MatchResult},
+    usb::{OpenOptions, OpenedUsbDevice, UsbDevice},
+};
+use pyo3::{create_exception, exceptions::PyException, prelude::*};
+
+create_exception!(validity_sens, ValidityError, PyException);
+
+fn to_py(e: DriverError) -> PyErr {
+    ValidityError::new_err(e.to_string())
+}
+
+/// A supported sensor, as returned by `list_devices`
+#[pyclass(frozen, get_all)]
+struct DeviceInfo {
+    bus: u8,
+    address: u8,
+    vid: u16,
+    pid: u16,
+}
+
+#[pymethods]
+impl DeviceInfo {
+    fn __repr__(&self) -> String {
+        format!(
+            "DeviceInfo(bus={}, address={}, vid=0x{:04x}, pid=0x{:04x})",
+            self.bus, self.address, self.vid, self.pid
+        )
+    }
+}
+
+/// A successful match
+#[pyclass(frozen, get_all)]
+struct Match {
+    template_id: u16,
+    finger_id: u8,
+    score: u16,
+}
+
+#[pymethods]
+impl Match {
+    fn __repr__(&self) -> String {
+        format!(
+            "Match(template_id={}, finger_id={}, score={})",
+            self.template_id, self.finger_id, self.score
+        )
+    }
+}
+
+impl From<MatchResult> for Match {
+    fn from(m: MatchResult) -> Self {
+        Self {
+            template_id: m.template.0,
+            finger_id: m.finger_id,
+            score: m.score,
+        }
+    }
+}
+
+/// An opened and initialized sensor
+#[pyclass(frozen)]
+struct Device(OpenedUsbDevice);
+
+#[pymethods]
+impl Device {
+    /// Open the device at the given bus and address, or the first supported one
+    #[staticmethod]
+    #[pyo3(signature = (bus=None, address=None))]
+    fn open(py: Python<'_>, bus: Option<u8>, address: Option<u8>) -> PyResult<Self> {
+        py.detach(|| {
+            let dev = match (bus, address) {
+                (Some(bus), Some(addr)) => driver::get_device(bus, addr)?,
+                _ => driver::list_supported_devices()?
+                    .into_iter()
+                    .next()
+                    .ok_or(DriverError::GetDeviceNotFound)?,
+            };
+
+            dev.open_with(OpenOptions::new()).map(Self)
+        })
+        .map_err(to_py)
+    }
+
+    /// Enroll a new finger, `progress(sample, remaining, quality, coverage)` is called after every
+    /// touch. Returns the id of the new template.
+    #[pyo3(signature = (finger_id, progress=None))]
+    fn enroll(&self, py: Python<'_>, finger_id: u8, progress: Option<Py<PyAny>>) -> PyResult<u16> {
+        let mut cb_err = None;
+        let res = py.detach(|| {
+            self.0.enroll(finger_id, |p| {
+                let Some(cb) = &progress else { return };
+                Python::attach(|py| {
+                    if let Err(e) = cb.call1(py, (p.sample, p.remaining, p.quality, p.coverage)) {
+                        cb_err.get_or_insert(e);
+                    }
+                });
+            })
+        });
+
+        // The enrollment can't be stopped halfway, errors in the callback are raised at the end
+        if let Some(e) = cb_err {
+            return Err(e);
+        }
+        res.map(|TemplateId(id)| id).map_err(to_py)
+    }
+
+    /// Scan a finger and check it matches the template, returns `None` if it doesn't
+    fn verify(&self, py: Python<'_>, template_id: u16) -> PyResult<Option<Match>> {
+        py.detach(|| self.0.verify(TemplateId(template_id)))
+            .map(|m| m.map(Match::from))
+            .map_err(to_py)
+    }
+
+    /// Scan a finger and match it against every enrolled template, returns `None` if none matched
+    fn identify(&self, py: Python<'_>) -> PyResult<Option<Match>> {
+        py.detach(|| self.0.identify())
+            .map(|m| m.map(Match::from))
+            .map_err(to_py)
+    }
+}
+
+/// List the supported sensors
+#[pyfunction]
+fn list_devices() -> PyResult<Vec<DeviceInfo>> {
+    let devs = driver::list_supported_devices().map_err(to_py)?;
+    Ok(devs
+        .into_iter()
+        .map(|UsbDevice(dev, quirks)| DeviceInfo {
+            bus: dev.bus_number(),
+            address: dev.address(),
+            vid: quirks.vid,
+            pid: quirks.pid,
+        })
+        .collect())
+}
+
+#[pymodule]
+fn validity_sens(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("ValidityError", m.py().get_type::<ValidityError>())?;
+    m.add_class::<DeviceInfo>()?;
+    m.add_class::<Match>()?;
+    m.add_class::<Device>()?;
+    m.add_function(wrap_pyfunction!(list_devices, m)?)?;
+    Ok(())
+}